@@ -0,0 +1,309 @@
+//! Brightness keys on laptops whose firmware reports them only as ACPI video
+//! events, with no evdev key, read from the `acpi_event` generic netlink family
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    thread,
+};
+
+use crossbeam::channel::Sender;
+use log::{debug, info, warn};
+
+use crate::{command::Command, config::Config, Error, Result};
+
+/// Family and multicast group the kernel sends ACPI events on
+const FAMILY: &str = "acpi_event";
+const GROUP: &str = "acpi_mc_group";
+
+/// From linux/genetlink.h
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+const CTRL_ATTR_MCAST_GROUPS: u16 = 7;
+const CTRL_ATTR_MCAST_GRP_NAME: u16 = 1;
+const CTRL_ATTR_MCAST_GRP_ID: u16 = 2;
+
+/// Attribute holding a `struct acpi_genl_event`, from drivers/acpi/event.c
+const ACPI_GENL_ATTR_EVENT: u16 = 1;
+
+/// Notifications of the ACPI video driver, from acpi/video.h
+const ACPI_VIDEO_NOTIFY_INC_BRIGHTNESS: u32 = 0x86;
+const ACPI_VIDEO_NOTIFY_DEC_BRIGHTNESS: u32 = 0x87;
+
+/// `nlmsghdr` and `genlmsghdr`
+const HEADER_LEN: usize = 16;
+const GENL_HEADER_LEN: usize = 4;
+
+/// `struct acpi_genl_event`: the device class, bus id, then the event type
+/// and data, aligned to 4 bytes
+const CLASS_LEN: usize = 20;
+const TYPE_OFFSET: usize = 36;
+const EVENT_LEN: usize = 44;
+
+fn align(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+/// `(type, payload)` of each netlink attribute in `buf`
+fn attributes(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & 0x3fff;
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        let payload = &buf[4..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((kind, payload))
+    })
+}
+
+fn attribute(buf: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    buf.extend_from_slice(&((4 + payload.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    buf.resize(align(buf.len()), 0);
+}
+
+/// Generic netlink messages in a datagram: the message type and the
+/// attributes after the headers
+fn messages(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        if len < HEADER_LEN || len > buf.len() {
+            return None;
+        }
+        let body = &buf[HEADER_LEN..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((kind, body.get(GENL_HEADER_LEN..).unwrap_or(&[])))
+    })
+}
+
+/// Request for the id and multicast groups of [`FAMILY`]
+fn family_request() -> Vec<u8> {
+    let mut attrs = Vec::new();
+    attribute(
+        &mut attrs,
+        CTRL_ATTR_FAMILY_NAME,
+        format!("{}\0", FAMILY).as_bytes(),
+    );
+
+    let mut request = Vec::new();
+    let len = (HEADER_LEN + GENL_HEADER_LEN + attrs.len()) as u32;
+    request.extend_from_slice(&len.to_ne_bytes());
+    request.extend_from_slice(&GENL_ID_CTRL.to_ne_bytes());
+    request.extend_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+    request.extend_from_slice(&1u32.to_ne_bytes());
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    request.extend_from_slice(&[CTRL_CMD_GETFAMILY, 1, 0, 0]);
+    request.extend_from_slice(&attrs);
+    request
+}
+
+/// Family id and the id of [`GROUP`] from the controller's reply
+fn parse_family(reply: &[u8]) -> Result<(u16, u32)> {
+    let not_found = || Error::NotFound(format!("the {} netlink family", FAMILY));
+    let (kind, attrs) = messages(reply).next().ok_or_else(not_found)?;
+    if kind != GENL_ID_CTRL {
+        return Err(not_found());
+    }
+
+    let mut family = None;
+    let mut group = None;
+    for (kind, payload) in attributes(attrs) {
+        match kind {
+            CTRL_ATTR_FAMILY_ID if payload.len() >= 2 => {
+                family = Some(u16::from_ne_bytes([payload[0], payload[1]]));
+            }
+            CTRL_ATTR_MCAST_GROUPS => {
+                for (_, entry) in attributes(payload) {
+                    let mut name = None;
+                    let mut id = None;
+                    for (kind, payload) in attributes(entry) {
+                        match kind {
+                            CTRL_ATTR_MCAST_GRP_NAME => {
+                                name = Some(payload.split(|x| *x == 0).next().unwrap_or(&[]));
+                            }
+                            CTRL_ATTR_MCAST_GRP_ID if payload.len() >= 4 => {
+                                id = Some(u32::from_ne_bytes([
+                                    payload[0], payload[1], payload[2], payload[3],
+                                ]));
+                            }
+                            _ => {}
+                        }
+                    }
+                    if name == Some(GROUP.as_bytes()) {
+                        group = id;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    family.zip(group).ok_or_else(not_found)
+}
+
+fn check(ret: libc::c_int) -> Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Subscribes to ACPI events, returning the socket and the family id they
+/// arrive with
+fn subscribe() -> Result<(File, u16)> {
+    // SAFETY: socket has no preconditions; the fd is owned right away
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_GENERIC,
+        )
+    };
+    check(fd)?;
+    let mut socket = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    // Unbound netlink sockets send to the kernel
+    socket.write_all(&family_request())?;
+    let mut buf = [0u8; 4096];
+    let len = socket.read(&mut buf)?;
+    let (family, group) = parse_family(&buf[..len])?;
+
+    // SAFETY: the socket is open and group lives across the call
+    check(unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_NETLINK,
+            libc::NETLINK_ADD_MEMBERSHIP,
+            &group as *const u32 as *const libc::c_void,
+            mem::size_of::<u32>() as libc::socklen_t,
+        )
+    })?;
+    Ok((socket, family))
+}
+
+/// The command for a brightness key in an ACPI event, either a notification
+/// of the video driver or an event named after the key
+fn brightness_key(event: &[u8], step: i8) -> Option<Command> {
+    if event.len() < EVENT_LEN {
+        return None;
+    }
+    let class = event[..CLASS_LEN].split(|x| *x == 0).next().unwrap_or(&[]);
+    let kind = u32::from_ne_bytes(event[TYPE_OFFSET..TYPE_OFFSET + 4].try_into().ok()?);
+    match (class, kind) {
+        (b"video/brightnessup", _) | (b"video", ACPI_VIDEO_NOTIFY_INC_BRIGHTNESS) => {
+            Some(Command::Increase(step))
+        }
+        (b"video/brightnessdown", _) | (b"video", ACPI_VIDEO_NOTIFY_DEC_BRIGHTNESS) => {
+            Some(Command::Decrease(step))
+        }
+        _ => None,
+    }
+}
+
+fn forward(step: i8, sender: &Sender<Command>) -> Result<()> {
+    let (mut socket, family) = subscribe()?;
+    info!("Listening for ACPI brightness events");
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = socket.read(&mut buf)?;
+        let commands = messages(&buf[..len])
+            .filter(|(kind, _)| *kind == family)
+            .flat_map(|(_, attrs)| attributes(attrs))
+            .filter(|(kind, _)| *kind == ACPI_GENL_ATTR_EVENT)
+            .filter_map(|(_, event)| brightness_key(event, step))
+            .collect::<Vec<_>>();
+        for command in commands {
+            debug!("ACPI brightness key: {}", command);
+            if sender.send(command).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// With `[acpi]` in the config, sends an increase or decrease for every ACPI
+/// brightness up or down event. Stops if the events can't be received.
+pub fn watch(config: &Config, sender: Sender<Command>) {
+    let Some(acpi) = &config.acpi else {
+        return;
+    };
+    let step = acpi.step;
+    thread::spawn(move || {
+        if let Err(e) = forward(step, &sender) {
+            warn!("Not listening for ACPI brightness events: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(class: &str, kind: u32) -> Vec<u8> {
+        let mut event = vec![0; EVENT_LEN];
+        event[..class.len()].copy_from_slice(class.as_bytes());
+        event[CLASS_LEN..CLASS_LEN + 6].copy_from_slice(b"LCD0\0\0");
+        event[TYPE_OFFSET..TYPE_OFFSET + 4].copy_from_slice(&kind.to_ne_bytes());
+        event
+    }
+
+    #[test]
+    fn maps_brightness_keys() {
+        assert_eq!(
+            brightness_key(&event("video", 0x86), 5),
+            Some(Command::Increase(5))
+        );
+        assert_eq!(
+            brightness_key(&event("video", 0x87), 5),
+            Some(Command::Decrease(5))
+        );
+        assert_eq!(
+            brightness_key(&event("video/brightnessup", 0), 5),
+            Some(Command::Increase(5))
+        );
+        assert_eq!(
+            brightness_key(&event("video/brightnessdown", 0), 5),
+            Some(Command::Decrease(5))
+        );
+        assert_eq!(brightness_key(&event("video", 0x80), 5), None);
+        assert_eq!(brightness_key(&event("button/lid", 0x86), 5), None);
+        assert_eq!(brightness_key(&event("video", 0x86)[..40], 5), None);
+    }
+
+    #[test]
+    fn parses_family_reply() {
+        let mut group = Vec::new();
+        attribute(&mut group, CTRL_ATTR_MCAST_GRP_NAME, b"acpi_mc_group\0");
+        attribute(&mut group, CTRL_ATTR_MCAST_GRP_ID, &7u32.to_ne_bytes());
+        let mut groups = Vec::new();
+        attribute(&mut groups, 1, &group);
+
+        let mut attrs = Vec::new();
+        attribute(&mut attrs, CTRL_ATTR_FAMILY_NAME, b"acpi_event\0");
+        attribute(&mut attrs, CTRL_ATTR_FAMILY_ID, &27u16.to_ne_bytes());
+        attribute(&mut attrs, CTRL_ATTR_MCAST_GROUPS, &groups);
+
+        let mut reply = family_request();
+        reply.truncate(HEADER_LEN + GENL_HEADER_LEN);
+        reply.extend_from_slice(&attrs);
+        let len = reply.len() as u32;
+        reply[..4].copy_from_slice(&len.to_ne_bytes());
+
+        assert_eq!(parse_family(&reply).unwrap(), (27, 7));
+        assert!(parse_family(&reply[..HEADER_LEN]).is_err());
+    }
+}
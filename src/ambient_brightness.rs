@@ -1,64 +1,217 @@
-use anyhow::Result;
-use industrial_io::{Channel, Context};
 use log::{debug, trace};
-use yata::{core::Method, methods::WMA};
+
+use crate::{
+    config::FilterConfig, filter::Filter, levels::ambient_value, redact::Lux, sensor::Sensor,
+    Result,
+};
+
+/// Raw readings are log-scaled and capped at this many decades
+const MAX: u32 = (2500000u32).ilog10();
+
+/// Share of the ambient percent followed while idle, unless an output sets its own
+pub(crate) const IDLE_SCALE: f64 = 0.25;
+
+/// Ambient percent for a log-scaled, capped reading
+pub(crate) fn percent(level: f64) -> f64 {
+    (level * 100f64) / MAX as f64
+}
+
+/// Readings below this are log-scaled as this, so a sensor reading 0 in a
+/// pitch-black room doesn't become -inf and leave the filters at NaN
+pub(crate) const FLOOR: f64 = 1.0;
+
+/// Log-scaled raw reading, before the cap
+fn log_scaled(raw: f64) -> f64 {
+    raw.max(FLOOR).log10()
+}
+
+/// Ambient percent for a raw reading once the filter has settled on it
+pub(crate) fn settled_percent(raw: f64) -> f64 {
+    percent(log_scaled(raw).min(MAX as f64))
+}
+
+/// One pass through the pipeline, from raw reading to the idle-adjusted percent
+pub(crate) struct Sample {
+    pub(crate) raw: f64,
+    /// Log-scaled and capped, before smoothing
+    pub(crate) level: f64,
+    pub(crate) smoothed: f64,
+    pub(crate) percent: f64,
+    pub(crate) idle: bool,
+    /// Whether the filter started over at this reading
+    pub(crate) resynced: bool,
+    pub(crate) value: u32,
+}
+
+/// A sensor and filter set up to take over, see [`AmbientBrightness::stage`]
+pub(crate) struct Staged {
+    sensor: Option<Box<dyn Sensor>>,
+    filter_config: FilterConfig,
+    filter: Filter,
+    level: f64,
+}
+
+impl Staged {
+    /// Log-scaled, capped reading the filter starts from
+    pub(crate) fn level(&self) -> f64 {
+        self.level
+    }
+}
 
 pub(crate) struct AmbientBrightness {
-    chan: Channel,
+    sensor: Box<dyn Sensor>,
     max: u32,
-    wma: Option<WMA>,
+    filter_config: FilterConfig,
+    filter: Option<Filter>,
+    /// Latest log-scaled, capped reading
+    level: f64,
+    /// Output of the filter for the latest reading
+    smoothed: f64,
+    /// Ambient percent a reading may land from the smoothed one before the
+    /// filter starts over from it
+    resync_above: Option<f64>,
     idle: bool,
 }
 
 impl AmbientBrightness {
-    pub(crate) fn new() -> Result<Self> {
-        let ctx = Context::new()?;
-
-        let max = (2500000u32).ilog10();
-        let dev = ctx.find_device("als").expect("Couldn't find als device");
-        let chan = dev.get_channel(0)?;
-
-        Ok(Self {
-            chan,
-            max,
-            wma: None,
+    pub(crate) fn new(sensor: Box<dyn Sensor>, filter_config: FilterConfig) -> Self {
+        Self {
+            sensor,
+            max: MAX,
+            filter_config,
+            filter: None,
+            level: 0.0,
+            smoothed: 0.0,
+            resync_above: None,
             idle: false,
-        })
+        }
     }
 
     pub(crate) fn init(mut self) -> Result<Self> {
         let initial = self.read()?;
-        let wma = WMA::new(10, &initial)?;
-        self.wma = Some(wma);
+        let filter = Filter::new(&self.filter_config, initial)?;
+        self.filter = Some(filter);
+        self.level = initial.min(self.max as f64);
+        self.smoothed = self.level;
         Ok(self)
     }
 
+    /// Starts over with a new filter, and a new sensor if given
+    pub(crate) fn reconfigure(
+        &mut self,
+        sensor: Option<Box<dyn Sensor>>,
+        filter_config: FilterConfig,
+    ) -> Result<()> {
+        let staged = self.stage(sensor, filter_config)?;
+        self.apply(staged);
+        Ok(())
+    }
+
+    /// Reads the new sensor, or the current one, and sets up the new filter
+    /// from it, leaving the running ones alone until [`Self::apply`]
+    pub(crate) fn stage(
+        &self,
+        sensor: Option<Box<dyn Sensor>>,
+        filter_config: FilterConfig,
+    ) -> Result<Staged> {
+        let initial = log_scaled(sensor.as_ref().unwrap_or(&self.sensor).read()?);
+        Ok(Staged {
+            filter: Filter::new(&filter_config, initial)?,
+            sensor,
+            filter_config,
+            level: initial.min(self.max as f64),
+        })
+    }
+
+    pub(crate) fn apply(&mut self, staged: Staged) {
+        if let Some(sensor) = staged.sensor {
+            self.sensor = sensor;
+        }
+        self.filter = Some(staged.filter);
+        self.filter_config = staged.filter_config;
+        self.level = staged.level;
+        self.smoothed = self.level;
+    }
+
+    /// Starts the filter over once a reading is `step` ambient percent away
+    /// from the smoothed value, e.g. after the room lights are switched
+    pub(crate) fn resync_above(&mut self, step: Option<f64>) {
+        self.resync_above = step;
+    }
+
+    /// Starts the filter over from a fresh reading, returning its level
+    pub(crate) fn resync(&mut self) -> Result<f64> {
+        let level = self.read()?.min(self.max as f64);
+        self.reset(level);
+        Ok(level)
+    }
+
+    fn reset(&mut self, level: f64) {
+        debug!("Filter starts over at {:.4}", Lux(level));
+        self.filter
+            .as_mut()
+            .expect("AmbientBrightness not Initialized")
+            .reset(level);
+        self.level = level;
+        self.smoothed = level;
+    }
+
+    /// Latest log-scaled, capped reading, e.g. to start another filter from
+    pub(crate) fn level(&self) -> f64 {
+        self.level
+    }
+
     fn read(&self) -> Result<f64> {
-        Ok((self.chan.attr_read_int("raw")? as f64).log10())
+        Ok(log_scaled(self.sensor.read()?))
     }
 
-    pub(crate) fn update(&mut self) -> Result<u32> {
-        let val = self.read()?;
-        trace!("Val: {}", val);
+    pub(crate) fn sample(&mut self) -> Result<Sample> {
+        let raw = self.sensor.read()?;
+        let val = log_scaled(raw);
+        trace!("Val: {}", Lux(val));
         let max_val = val.min(self.max as f64);
-        trace!("Max Val: {}", max_val);
+        trace!("Max Val: {}", Lux(max_val));
+        self.level = max_val;
+        let resynced = self
+            .resync_above
+            .is_some_and(|step| (percent(max_val) - percent(self.smoothed)).abs() > step);
+        if resynced {
+            self.reset(max_val);
+        }
         let new_val = self
-            .wma
+            .filter
             .as_mut()
             .expect("AmbientBrightness not Initialized")
-            .next(&max_val);
-        trace!("New Val: {}", new_val);
-        let new_pct = (new_val * 100f64) / self.max as f64;
-        trace!("New PCT: {}", new_pct);
+            .next(max_val);
+        self.smoothed = new_val;
+        trace!("New Val: {}", Lux(new_val));
+        let new_pct = percent(new_val);
+        trace!("New PCT: {}", Lux(new_pct));
 
-        let idlemed = if self.idle { new_pct / 4f64 } else { new_pct };
-        trace!("Idlemed: {}", idlemed);
+        let idlemed = if self.idle {
+            new_pct * IDLE_SCALE
+        } else {
+            new_pct
+        };
+        trace!("Idlemed: {}", Lux(idlemed));
 
         debug!(
             "Ambient - val:{:.4}, max_val:{:.4}, new_val:{:.4}, new_pct:{:.4}, idlemed:{:.4}",
-            val, max_val, new_val, new_pct, idlemed
+            Lux(val),
+            Lux(max_val),
+            Lux(new_val),
+            Lux(new_pct),
+            Lux(idlemed)
         );
-        Ok(idlemed.round() as u32)
+        Ok(Sample {
+            raw,
+            level: max_val,
+            smoothed: new_val,
+            percent: new_pct,
+            idle: self.idle,
+            resynced,
+            value: ambient_value(idlemed),
+        })
     }
 
     pub(crate) fn idle(&mut self) {
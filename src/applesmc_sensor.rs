@@ -0,0 +1,96 @@
+use std::fs;
+
+use log::info;
+
+use crate::{
+    sensor::Sensor,
+    sysfs::{Attribute, Sysfs},
+    Error, Result,
+};
+
+/// applesmc's highest reading, in direct sunlight
+const FULL_SCALE: f64 = 255.0;
+
+/// Decades of lux the rest of the pipeline spans, see `ambient_brightness`
+const DECADES: f64 = 6.0;
+
+/// The MacBook ALS behind the SMC, read from `light` on the applesmc platform
+/// device as `(left,right)`. Older models only have a left sensor and report
+/// the right one as 0.
+pub(crate) struct AppleSmcSensor {
+    attribute: Attribute,
+}
+
+/// Reading of the brighter of both sensors
+fn parse(val: &str) -> Option<u32> {
+    val.trim()
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .split(',')
+        .map(|x| x.trim().parse::<u32>().ok())
+        .try_fold(0, |max, x| Some(max.max(x?)))
+}
+
+/// applesmc readings already grow roughly with the log of the light, so they
+/// are spread evenly over the decades the pipeline expects instead of being
+/// treated as lux, which would leave daylight looking like a dim room
+fn to_lux(reading: u32) -> f64 {
+    10f64.powf(reading as f64 / FULL_SCALE * DECADES)
+}
+
+impl AppleSmcSensor {
+    pub(crate) fn new(sysfs: &Sysfs) -> Result<Self> {
+        let platform = sysfs.path("devices/platform");
+        let path = fs::read_dir(&platform)
+            .map_err(|e| Error::Sysfs {
+                path: platform.clone(),
+                source: e,
+            })?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("applesmc"))
+            .map(|entry| entry.path().join("light"))
+            .find(|path| path.exists())
+            .ok_or_else(|| Error::NotFound("an applesmc light sensor".to_string()))?;
+
+        let sensor = Self {
+            attribute: Attribute::open(path)?,
+        };
+        sensor.read()?;
+        info!(
+            "Using applesmc sensor: {}",
+            sensor.attribute.path().display()
+        );
+        Ok(sensor)
+    }
+}
+
+impl Sensor for AppleSmcSensor {
+    fn read(&self) -> Result<f64> {
+        let reading = self.attribute.read_with(|val| {
+            parse(val).ok_or_else(|| {
+                Error::Sensor(format!("Unexpected applesmc light reading {:?}", val))
+            })
+        })?;
+        Ok(to_lux(reading))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_brighter_sensor() {
+        assert_eq!(parse("(12,40)\n"), Some(40));
+        assert_eq!(parse("(7,0)"), Some(7));
+        assert_eq!(parse("12"), None);
+        assert_eq!(parse("(12,x)"), None);
+    }
+
+    #[test]
+    fn spreads_readings_over_pipeline_range() {
+        assert_eq!(to_lux(0), 1.0);
+        assert!((to_lux(255).log10() - DECADES).abs() < 1e-9);
+        assert!(to_lux(128).log10() > 2.9 && to_lux(128).log10() < 3.1);
+    }
+}
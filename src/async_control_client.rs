@@ -0,0 +1,129 @@
+use std::{io, os::unix::net::UnixStream, path::Path};
+
+use async_io::{Async, Timer};
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    command::Command,
+    config::Config,
+    control_server::configured_socket_path,
+    health::Status,
+    protocol::{
+        decode_profile_reply, decode_status, decode_version, status_outputs_len, ProfileEdit,
+        Request, Response, Version, PING_TIMEOUT, STATUS_LEN,
+    },
+    Error, Result,
+};
+
+/// [`crate::control_client::ControlClient`] for async programs. It doesn't
+/// depend on a particular executor.
+pub struct AsyncControlClient {
+    client: Async<UnixStream>,
+}
+
+impl AsyncControlClient {
+    /// Connects to the socket named in the config, or the default one
+    pub async fn new(config: &Config) -> Result<Self> {
+        Self::connect(configured_socket_path(config)).await
+    }
+
+    pub async fn connect(socket_path: impl AsRef<Path>) -> Result<Self> {
+        let client = Async::<UnixStream>::connect(socket_path).await?;
+
+        Ok(Self { client })
+    }
+
+    /// Sends a request and waits for its reply, if it gets one. The daemon
+    /// serves a single request per connection.
+    pub async fn request(&mut self, request: Request) -> Result<Response> {
+        self.write(&request).await?;
+        match request {
+            Request::Command(_) => Ok(Response::Sent),
+            Request::Ping => Ok(Response::Status(self.read_status().await?)),
+            Request::Version => Ok(Response::Version(self.read_version().await?)),
+            Request::Profile(_) => Ok(Response::Profile(self.read_profile_reply().await?)),
+        }
+    }
+
+    async fn write(&mut self, request: &Request) -> Result<()> {
+        self.client.write_all(&request.encode()?).await?;
+        self.client.flush().await?;
+        Ok(())
+    }
+
+    async fn read_status(&mut self) -> Result<Status> {
+        let (reply, outputs) = future::or(
+            async {
+                let mut reply = [0; STATUS_LEN];
+                self.client.read_exact(&mut reply).await?;
+                let mut outputs = vec![0; status_outputs_len(&reply)];
+                self.client.read_exact(&mut outputs).await?;
+                Ok((reply, outputs))
+            },
+            async {
+                Timer::after(PING_TIMEOUT).await;
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            },
+        )
+        .await?;
+        decode_status(&reply, &outputs)
+    }
+
+    async fn read_version(&mut self) -> Result<Version> {
+        let body = future::or(
+            async {
+                let mut len = [0; 2];
+                self.client.read_exact(&mut len).await?;
+                let mut body = vec![0; u16::from_be_bytes(len) as usize];
+                self.client.read_exact(&mut body).await?;
+                Ok(body)
+            },
+            async {
+                Timer::after(PING_TIMEOUT).await;
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            },
+        )
+        .await?;
+        decode_version(&body)
+    }
+
+    async fn read_profile_reply(&mut self) -> Result<std::result::Result<(), String>> {
+        let (ok, message) = future::or(
+            async {
+                let mut header = [0; 3];
+                self.client.read_exact(&mut header).await?;
+                let mut message = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+                self.client.read_exact(&mut message).await?;
+                Ok((header[0], message))
+            },
+            async {
+                Timer::after(PING_TIMEOUT).await;
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            },
+        )
+        .await?;
+        Ok(decode_profile_reply(ok, message))
+    }
+
+    pub async fn send(&mut self, command: Command) -> Result<()> {
+        self.write(&Request::Command(command)).await
+    }
+
+    /// Asks the daemon how it's doing
+    pub async fn ping(&mut self) -> Result<Status> {
+        self.write(&Request::Ping).await?;
+        self.read_status().await
+    }
+
+    /// Asks the daemon for its version and what it was built with
+    pub async fn version(&mut self) -> Result<Version> {
+        self.write(&Request::Version).await?;
+        self.read_version().await
+    }
+
+    /// Merges TOML keys into a profile in the daemon's config file
+    pub async fn edit_profile(&mut self, edit: ProfileEdit) -> Result<()> {
+        self.write(&Request::Profile(edit)).await?;
+        self.read_profile_reply().await?.map_err(Error::Protocol)
+    }
+}
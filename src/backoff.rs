@@ -0,0 +1,184 @@
+//! Retrying operations that fail for a moment, e.g. a sensor busy on its I2C
+//! bus or a D-Bus call that timed out, as set under `[retry]`
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind},
+    thread,
+    time::Duration,
+};
+
+use log::debug;
+
+use crate::{config::RetryConfig, sensor::Sensor, Error, Result};
+
+/// D-Bus errors that mean the other end was slow rather than refused
+const DBUS_TRANSIENT: &[&str] = &[
+    "org.freedesktop.DBus.Error.NoReply",
+    "org.freedesktop.DBus.Error.Timeout",
+    "org.freedesktop.DBus.Error.TimedOut",
+    "org.freedesktop.DBus.Error.LimitsExceeded",
+];
+
+/// Whether an error may go away by trying again
+pub(crate) trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for io::Error {
+    fn is_transient(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ) || matches!(
+            self.raw_os_error(),
+            Some(libc::EBUSY | libc::EAGAIN | libc::EIO | libc::ETIMEDOUT)
+        )
+    }
+}
+
+impl Transient for zbus::Error {
+    fn is_transient(&self) -> bool {
+        match self {
+            zbus::Error::InputOutput(e) => e.is_transient(),
+            zbus::Error::MethodError(name, _, _) => DBUS_TRANSIENT.contains(&name.as_str()),
+            zbus::Error::FDO(e) => matches!(
+                **e,
+                zbus::fdo::Error::NoReply(_)
+                    | zbus::fdo::Error::Timeout(_)
+                    | zbus::fdo::Error::TimedOut(_)
+                    | zbus::fdo::Error::LimitsExceeded(_)
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl Transient for Error {
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(e) | Error::Sysfs { source: e, .. } => e.is_transient(),
+            Error::DBus(e) => e.is_transient(),
+            #[cfg(feature = "iio")]
+            Error::Iio(industrial_io::Error::Io(e)) => e.is_transient(),
+            #[cfg(feature = "iio")]
+            Error::Iio(industrial_io::Error::Nix(errno)) => {
+                io::Error::from_raw_os_error(*errno as i32).is_transient()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Wait before retry number `retry`, counting from 0
+fn delay(config: &RetryConfig, retry: u32) -> Duration {
+    let base = config.delay_ms as f64 * config.backoff.max(1.0).powi(retry as i32);
+    // Uniform in -1..1, from the random keys std seeds hash maps with
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64 * 2.0 - 1.0;
+    let jitter = config.jitter.clamp(0.0, 1.0) * random;
+    Duration::from_secs_f64((base * (1.0 + jitter)).max(0.0) / 1000.0)
+}
+
+/// Runs `operation` until it succeeds, fails for good, or runs out of attempts
+pub(crate) fn retry<T, E: Transient + Display>(
+    config: &RetryConfig,
+    what: &str,
+    mut operation: impl FnMut() -> std::result::Result<T, E>,
+) -> std::result::Result<T, E> {
+    let mut retry = 0;
+    loop {
+        match operation() {
+            Err(e) if e.is_transient() && retry + 1 < config.attempts => {
+                let delay = delay(config, retry);
+                debug!("{} failed, retrying in {:?}: {}", what, delay, e);
+                thread::sleep(delay);
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Retries a sensor's reads under the configured policy
+pub(crate) struct RetryingSensor {
+    sensor: Box<dyn Sensor>,
+    config: RetryConfig,
+}
+
+impl RetryingSensor {
+    pub(crate) fn new(sensor: Box<dyn Sensor>, config: RetryConfig) -> Self {
+        Self { sensor, config }
+    }
+}
+
+impl Sensor for RetryingSensor {
+    fn read(&self) -> Result<f64> {
+        retry(&self.config, "Sensor read", || self.sensor.read())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn config(attempts: u32) -> RetryConfig {
+        RetryConfig {
+            attempts,
+            delay_ms: 1,
+            ..RetryConfig::default()
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let tries = Cell::new(0);
+        let result = retry(&config(3), "Test", || {
+            tries.set(tries.get() + 1);
+            match tries.get() {
+                3 => Ok(tries.get()),
+                _ => Err(io::Error::from_raw_os_error(libc::EBUSY)),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        tries.set(0);
+        let result: io::Result<()> = retry(&config(2), "Test", || {
+            tries.set(tries.get() + 1);
+            Err(io::Error::from(ErrorKind::WouldBlock))
+        });
+        assert!(result.is_err());
+        assert_eq!(tries.get(), 2);
+    }
+
+    #[test]
+    fn gives_up_on_lasting_errors() {
+        let tries = Cell::new(0);
+        let result: Result<()> = retry(&config(5), "Test", || {
+            tries.set(tries.get() + 1);
+            Err(Error::Sysfs {
+                path: "/sys/class/leds/x/brightness".into(),
+                source: io::Error::from(ErrorKind::PermissionDenied),
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(tries.get(), 1);
+    }
+
+    #[test]
+    fn waits_grow_within_the_jitter() {
+        let config = RetryConfig {
+            delay_ms: 100,
+            backoff: 2.0,
+            jitter: 0.2,
+            ..RetryConfig::default()
+        };
+        for _ in 0..20 {
+            let wait = delay(&config, 2);
+            assert!(wait >= Duration::from_millis(320) && wait <= Duration::from_millis(480));
+        }
+    }
+}
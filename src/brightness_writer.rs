@@ -0,0 +1,290 @@
+#[cfg(feature = "control")]
+use std::path::PathBuf;
+use std::{
+    cell::OnceCell,
+    collections::HashMap,
+    io,
+    process::Command,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use log::debug;
+
+#[cfg(feature = "control")]
+use crate::helper;
+use crate::{
+    backoff::retry,
+    config::{Backend, Config, RetryConfig},
+    health::Health,
+    record::{Event, Recorder},
+    session::{self, Binding},
+    sysfs::Device,
+    Error, Result,
+};
+
+/// Runs a brightness command, failing with its stderr
+fn run(command: &mut Command) -> Result<()> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(Error::Io(io::Error::other(format!(
+            "{} failed: {}",
+            command.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(())
+}
+
+/// Subsystem and name of a device
+type Key = (String, String);
+
+/// How writes are made, shared with the worker and replaced when the config
+/// is reloaded
+#[derive(Clone)]
+struct Settings {
+    retry: RetryConfig,
+    /// Socket of the helper writing for devices with `backend = "helper"`
+    #[cfg(feature = "control")]
+    helper: PathBuf,
+}
+
+impl Settings {
+    /// Writes through anything but sysfs
+    fn write_remote(
+        &self,
+        session: &mut Binding,
+        backend: Backend,
+        subsystem: &str,
+        name: &str,
+        level: u32,
+    ) -> Result<()> {
+        match backend {
+            Backend::Brightnessctl => run(Command::new("brightnessctl").args([
+                "--quiet".to_string(),
+                format!("--class={}", subsystem),
+                format!("--device={}", name),
+                "set".to_string(),
+                level.to_string(),
+            ])),
+            // Raw mode, so the level isn't taken as a percent
+            Backend::Light => run(Command::new("light").args([
+                "-s".to_string(),
+                format!("sysfs/{}/{}", subsystem, name),
+                "-r".to_string(),
+                "-S".to_string(),
+                level.to_string(),
+            ])),
+            #[cfg(feature = "control")]
+            Backend::Helper => helper::write(&self.helper, subsystem, name, level),
+            #[cfg(not(feature = "control"))]
+            Backend::Helper => Err(Error::Config(
+                "Helper support was not compiled in".to_string(),
+            )),
+            // Auto only gets here when the attribute isn't writable
+            Backend::Auto | Backend::Sysfs | Backend::Logind => {
+                match session.get()?.set_brightness(subsystem, name, level) {
+                    // Ended before its removal came through; once more on the new one
+                    Err(e) if session::is_gone(&e) => {
+                        session.reset();
+                        session.get()?.set_brightness(subsystem, name, level)?;
+                    }
+                    result => result?,
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes waiting for the worker, at most one per device
+#[derive(Default)]
+struct Queue {
+    /// Only the latest level of each device is kept
+    pending: HashMap<Key, (Backend, u32)>,
+    /// Failures since a device's last write, handed back on its next one
+    errors: HashMap<Key, Error>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// Writes through logind and brightness commands on their own thread, so a slow
+/// D-Bus roundtrip doesn't hold up sensor reads and commands
+struct Worker {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(health: Arc<Health>, settings: Arc<Mutex<Settings>>, seat: Option<String>) -> Self {
+        let shared = Arc::new(Shared::default());
+        let thread = thread::spawn({
+            let shared = shared.clone();
+            move || work(&shared, &health, &settings, Binding::new(seat))
+        });
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.shared.queue.lock().expect("Write queue poisoned")
+    }
+
+    /// Queues a write, replacing any the worker hasn't got to yet, and returns
+    /// the error of the device's previous write, if it failed
+    fn send(&self, device: &Device, level: u32) -> Result<()> {
+        let key = (device.subsystem.clone(), device.name.clone());
+        let mut queue = self.lock();
+        let error = queue.errors.remove(&key);
+        if queue.pending.insert(key, (device.backend, level)).is_some() {
+            debug!(
+                "Replaced a pending write to {}/{}",
+                device.subsystem, device.name
+            );
+        }
+        self.shared.ready.notify_one();
+        error.map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for Worker {
+    /// Finishes the queued writes, e.g. restoring the keyboard on shutdown
+    fn drop(&mut self) {
+        self.lock().closed = true;
+        self.shared.ready.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn work(shared: &Shared, health: &Health, settings: &Mutex<Settings>, mut session: Binding) {
+    loop {
+        let (key, (backend, level)) = {
+            let mut queue = shared.queue.lock().expect("Write queue poisoned");
+            loop {
+                if let Some(key) = queue.pending.keys().next().cloned() {
+                    let write = queue.pending.remove(&key).expect("Pending write");
+                    break (key, write);
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.ready.wait(queue).expect("Write queue poisoned");
+            }
+        };
+
+        let (subsystem, name) = &key;
+        let settings = settings.lock().expect("Write settings poisoned").clone();
+        let result = retry(&settings.retry, "Brightness write", || {
+            settings.write_remote(&mut session, backend, subsystem, name, level)
+        });
+        health.write(&result);
+        if let Err(e) = result {
+            let mut queue = shared.queue.lock().expect("Write queue poisoned");
+            queue.errors.insert(key, e);
+        }
+    }
+}
+
+/// Applies brightness levels through each device's backend, by default
+/// directly through sysfs when writable and through logind otherwise, or only
+/// prints them in dry-run mode. Writes other than to sysfs happen in the
+/// background; their errors show up on the device's next write.
+pub(crate) struct BrightnessWriter {
+    dry_run: bool,
+    recorder: Option<Arc<Recorder>>,
+    health: Arc<Health>,
+    settings: Arc<Mutex<Settings>>,
+    /// Seat whose session logind writes go through, by default the caller's
+    seat: Option<String>,
+    /// Only started once a device needs it, and finishes its queue when dropped
+    worker: OnceCell<Worker>,
+}
+
+impl BrightnessWriter {
+    pub(crate) fn new(
+        dry_run: bool,
+        recorder: Option<Arc<Recorder>>,
+        health: Arc<Health>,
+        config: &Config,
+    ) -> Self {
+        let settings = Settings {
+            retry: config.retry.clone(),
+            #[cfg(feature = "control")]
+            helper: config.helper.socket.clone(),
+        };
+        Self {
+            dry_run,
+            recorder,
+            health,
+            settings: Arc::new(Mutex::new(settings)),
+            seat: config.seat.clone(),
+            worker: OnceCell::new(),
+        }
+    }
+
+    fn settings(&self) -> std::sync::MutexGuard<'_, Settings> {
+        self.settings.lock().expect("Write settings poisoned")
+    }
+
+    /// Retries later writes under `policy`
+    pub(crate) fn retry(&self, policy: RetryConfig) {
+        self.settings().retry = policy;
+    }
+
+    /// Sends later helper writes to `socket`
+    #[cfg(feature = "control")]
+    pub(crate) fn helper(&self, socket: PathBuf) {
+        self.settings().helper = socket;
+    }
+
+    pub(crate) fn set_brightness(&self, device: &Device, level: u32) -> Result<()> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&Event::Write {
+                subsystem: device.subsystem.clone(),
+                name: device.name.clone(),
+                level,
+            })?;
+        }
+
+        if self.dry_run {
+            println!(
+                "Would set {}/{} to {}",
+                device.subsystem, device.name, level
+            );
+            return Ok(());
+        }
+
+        match device.backend {
+            Backend::Auto if device.is_writable() => self.write_sysfs(device, level),
+            Backend::Sysfs => self.write_sysfs(device, level),
+            _ => self
+                .worker
+                .get_or_init(|| {
+                    Worker::spawn(
+                        self.health.clone(),
+                        self.settings.clone(),
+                        self.seat.clone(),
+                    )
+                })
+                .send(device, level),
+        }
+    }
+
+    fn write_sysfs(&self, device: &Device, level: u32) -> Result<()> {
+        let policy = self.settings().retry.clone();
+        let result = retry(&policy, "Brightness write", || {
+            device.write_brightness(level)
+        });
+        self.health.write(&result);
+        result
+    }
+}
@@ -0,0 +1,429 @@
+//! Room lights over the network: each level is published to an MQTT topic or
+//! sent to an HTTP endpoint, from a thread of its own so a slow hub doesn't hold
+//! up the other outputs
+
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{debug, info};
+
+use crate::{
+    backoff::retry,
+    config::{BulbConfig, MqttConfig, RetryConfig},
+    levels::rounded_level,
+    output::{exceeds_min_delta, Offset, Output, StepCurve},
+    redact::Lux,
+    Error, Result,
+};
+
+/// How long connecting to, writing to, or waiting on a hub may take
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds the broker keeps the connection without hearing from us; each
+/// publish connects anew, so it never comes to that
+const KEEP_ALIVE: u16 = 30;
+
+/// Where a bulb's levels go
+#[derive(Debug, PartialEq)]
+enum Target {
+    Mqtt(MqttConfig),
+    Http {
+        host: String,
+        port: u16,
+        path: String,
+        method: String,
+    },
+}
+
+impl Target {
+    fn new(config: &BulbConfig) -> Result<Self> {
+        match (&config.mqtt, &config.http) {
+            (Some(mqtt), None) => Ok(Self::Mqtt(mqtt.clone())),
+            (None, Some(http)) => {
+                let (host, port, path) = parse_url(&http.url)?;
+                Ok(Self::Http {
+                    host,
+                    port,
+                    path,
+                    method: http.method.clone(),
+                })
+            }
+            _ => Err(Error::Config(
+                "bulbs need exactly one of mqtt and http".to_string(),
+            )),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Self::Mqtt(mqtt) => format!("mqtt://{}:{}/{}", mqtt.host, mqtt.port, mqtt.topic),
+            Self::Http {
+                host, port, path, ..
+            } => format!("http://{}:{}{}", host, port, path),
+        }
+    }
+
+    fn send(&self, payload: &str) -> io::Result<()> {
+        match self {
+            Self::Mqtt(mqtt) => publish(mqtt, payload),
+            Self::Http {
+                host,
+                port,
+                path,
+                method,
+            } => request(host, *port, path, method, payload),
+        }
+    }
+}
+
+/// Splits `http://host[:port]/path`
+fn parse_url(url: &str) -> Result<(String, u16, String)> {
+    let invalid = |reason| Error::Config(format!("Bulb URL {} {}", url, reason));
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("isn't an http:// URL"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse().map_err(|_| invalid("has an invalid port"))?,
+        ),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(invalid("has no host"));
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(ErrorKind::NotFound, format!("{} didn't resolve", host));
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Appends an MQTT string: a big endian u16 length, then the bytes
+fn put_field(packet: &mut Vec<u8>, field: &str) {
+    packet.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    packet.extend_from_slice(field.as_bytes());
+}
+
+/// An MQTT packet: its type and flags, the length of `body` 7 bits at a time,
+/// then `body`
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// MQTT 3.1.1 connect, publish at QoS 0, and disconnect
+fn publish(config: &MqttConfig, payload: &str) -> io::Result<()> {
+    // Protocol name and level, then a clean session, with credentials if given
+    let mut connect_body = b"\x00\x04MQTT\x04".to_vec();
+    let mut flags = 0x02;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    connect_body.push(flags);
+    connect_body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+    put_field(&mut connect_body, &config.client_id);
+    for field in [&config.username, &config.password].into_iter().flatten() {
+        put_field(&mut connect_body, field);
+    }
+
+    let mut stream = connect(&config.host, config.port)?;
+    stream.write_all(&packet(0x10, &connect_body))?;
+    let mut connack = [0; 4];
+    stream.read_exact(&mut connack)?;
+    if connack[0] != 0x20 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} didn't acknowledge the connection", config.host),
+        ));
+    }
+    if connack[3] != 0 {
+        return Err(io::Error::other(format!(
+            "{} refused the connection with code {}",
+            config.host, connack[3]
+        )));
+    }
+
+    let mut publish_body = Vec::new();
+    put_field(&mut publish_body, &config.topic);
+    publish_body.extend_from_slice(payload.as_bytes());
+    stream.write_all(&packet(0x30 | config.retain as u8, &publish_body))?;
+    stream.write_all(&packet(0xe0, &[]))?;
+    Ok(())
+}
+
+/// HTTP/1.1 request with `payload` as the body, failing unless answered 2xx
+fn request(host: &str, port: u16, path: &str, method: &str, payload: &str) -> io::Result<()> {
+    let content_type = if payload.starts_with(['{', '[']) {
+        "application/json"
+    } else {
+        "text/plain"
+    };
+    let mut stream = connect(host, port)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        method,
+        path,
+        host,
+        port,
+        content_type,
+        payload.len(),
+        payload
+    )?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "{} answered {:?}",
+            host,
+            status.trim()
+        ))),
+    }
+}
+
+/// Sends the newest payload, skipping any that were replaced meanwhile, until
+/// the bulb is dropped
+fn work(target: Target, policy: RetryConfig, payloads: Receiver<String>, errors: Sender<Error>) {
+    while let Ok(payload) = payloads.recv() {
+        let payload = payloads.try_iter().last().unwrap_or(payload);
+        if let Err(e) = retry(&policy, "Bulb update", || target.send(&payload)) {
+            let _ = errors.send(e.into());
+        }
+    }
+}
+
+pub(crate) struct BulbBrightness {
+    name: String,
+    curve: StepCurve,
+    max_level: u32,
+    payload: String,
+    offset: Offset,
+    min_delta: u32,
+    /// Last level sent, since the bulb can't be asked
+    cur_level: Option<u32>,
+    /// None in dry-run mode
+    payloads: Option<Sender<String>>,
+    /// Failed sends, handed back on the next adjustment
+    errors: Receiver<Error>,
+}
+
+impl BulbBrightness {
+    pub(crate) fn new(
+        config: &BulbConfig,
+        min_delta: u32,
+        retry: &RetryConfig,
+        dry_run: bool,
+    ) -> Result<Self> {
+        if config.max_level == 0 {
+            return Err(Error::Config("bulb max_level must be above 0".to_string()));
+        }
+        let target = Target::new(config)?;
+        let name = target.name();
+        let (error_sender, errors) = unbounded();
+        let payloads = (!dry_run).then(|| {
+            let (sender, receiver) = unbounded();
+            let policy = retry.clone();
+            thread::spawn(move || work(target, policy, receiver, error_sender));
+            sender
+        });
+        info!("Using bulb: {}", name);
+
+        Ok(Self {
+            name,
+            curve: config.curve.clone(),
+            max_level: config.max_level,
+            payload: config.payload.clone(),
+            offset: Offset::default(),
+            min_delta,
+            cur_level: None,
+            payloads,
+            errors,
+        })
+    }
+}
+
+impl Output for BulbBrightness {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        if let Ok(e) = self.errors.try_recv() {
+            // Unknown now, so the next adjustment sends again
+            self.cur_level = None;
+            return Err(e);
+        }
+
+        let new_pct = self.offset.apply(self.curve.percent(new_val));
+        let new_level = rounded_level(new_pct, self.max_level);
+        debug!(
+            "Bulb {}: nv:{:?}, np:{:?}, nl:{:?}, cl:{:?}",
+            self.name,
+            Lux(new_val),
+            new_pct,
+            new_level,
+            self.cur_level
+        );
+        // Turning off always goes through, however small the step
+        let changes = match self.cur_level {
+            None => true,
+            Some(cur) => {
+                cur != new_level
+                    && (new_level == 0
+                        || exceeds_min_delta(cur, new_level, self.max_level, self.min_delta))
+            }
+        };
+        if !changes {
+            return Ok(false);
+        }
+
+        info!(
+            "Adjusting bulb {}: val:{:?} old:{:?} new:{:?}->{:?}",
+            self.name,
+            Lux(new_val),
+            self.cur_level,
+            new_pct,
+            new_level
+        );
+        let payload = self.payload.replace("{level}", &new_level.to_string());
+        match &self.payloads {
+            Some(payloads) => payloads
+                .send(payload)
+                .map_err(|_| Error::Io(io::Error::other("bulb thread stopped")))?,
+            None => println!("Would send {} to {}", payload, self.name),
+        }
+        self.cur_level = Some(new_level);
+        Ok(true)
+    }
+
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        Some(&mut self.curve)
+    }
+
+    fn increase(&mut self, amount: i8) {
+        self.offset.increase(amount)
+    }
+
+    fn decrease(&mut self, amount: i8) {
+        self.offset.decrease(amount)
+    }
+
+    fn set(&mut self, percent: u8) {
+        self.offset.set(percent)
+    }
+
+    fn percent(&self) -> Option<u32> {
+        Some(self.offset.percent())
+    }
+
+    fn offset(&self) -> Option<i8> {
+        Some(self.offset.offset())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn config(toml: &str) -> BulbConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn parses_targets() {
+        let http = config("http = { url = \"http://hub.local:8080/lights/1\" }");
+        assert_eq!(
+            Target::new(&http).unwrap(),
+            Target::Http {
+                host: "hub.local".to_string(),
+                port: 8080,
+                path: "/lights/1".to_string(),
+                method: "PUT".to_string(),
+            }
+        );
+        assert!(parse_url("https://hub.local/").is_err());
+        assert!(parse_url("http://hub.local:port/").is_err());
+        assert_eq!(parse_url("http://hub").unwrap().2, "/");
+        assert!(Target::new(&config("")).is_err());
+    }
+
+    #[test]
+    fn encodes_mqtt_lengths() {
+        assert_eq!(packet(0xe0, &[]), [0xe0, 0]);
+        let long = packet(0x30, &[0; 200]);
+        assert_eq!(&long[..3], [0x30, 0xc8, 0x01]);
+        assert_eq!(long.len(), 203);
+    }
+
+    #[test]
+    fn publishes_inverted_levels() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 256];
+            let len = stream.read(&mut buf).unwrap();
+            assert_eq!(buf[0], 0x10);
+            stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+            received.extend_from_slice(&buf[..len]);
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let config = config(&format!(
+            "max_level = 254\npayload = '{{\"brightness\": {{level}}}}'\n\
+             mqtt = {{ host = \"127.0.0.1\", port = {}, topic = \"lamp/set\" }}",
+            port
+        ));
+        let mut bulb = BulbBrightness::new(&config, 0, &RetryConfig::default(), false).unwrap();
+        // Dark rooms get the full lamp
+        assert!(bulb.adjust(0).unwrap());
+        assert!(!bulb.adjust(0).unwrap());
+        drop(bulb);
+
+        let received = broker.join().unwrap();
+        let publish = packet(0x30, b"\x00\x08lamp/set{\"brightness\": 254}");
+        assert!(received.ends_with(&[&publish[..], &[0xe0, 0]].concat()));
+    }
+}
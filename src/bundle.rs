@@ -0,0 +1,78 @@
+//! Settings bundles: the whole config in one file, to move tuning to another
+//! machine or share it
+
+use std::{fs, io::Write, path::Path};
+
+use toml::{Table, Value};
+
+use crate::{
+    config::{hostname, read_table, resolved_table, write_validated},
+    Error, Result,
+};
+
+/// Bumped when bundles change in a way older versions can't import
+const FORMAT: i64 = 1;
+
+/// Writes the config at `path`, or the built-in one, as a bundle. Includes and
+/// this machine's host section are folded in, so the bundle stands alone, and
+/// profiles and calibrated curves come along. Offsets only live in the running
+/// daemon, so they aren't part of it.
+pub fn export(path: Option<&Path>, out: &mut dyn Write) -> Result<()> {
+    let mut table = resolved_table(path)?;
+    let mut bundle = Table::new();
+    bundle.insert("format".to_string(), Value::Integer(FORMAT));
+    bundle.insert(
+        "version".to_string(),
+        Value::String(env!("CARGO_PKG_VERSION").to_string()),
+    );
+    if let Some(host) = hostname() {
+        bundle.insert("host".to_string(), Value::String(host));
+    }
+    table.insert("bundle".to_string(), Value::Table(bundle));
+
+    let contents = toml::to_string(&table)
+        .map_err(|e| Error::Config(format!("Couldn't write bundle: {}", e)))?;
+    out.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// Replaces the config file at `path` with the bundle at `bundle`, keeping the
+/// old file next to it as `.bak`. Bundles that don't make a valid config are
+/// refused.
+pub fn import(bundle: &Path, path: &Path) -> Result<()> {
+    let mut table = read_table(bundle)?;
+    match table.remove("bundle") {
+        Some(Value::Table(header)) => match header.get("format") {
+            Some(Value::Integer(format)) if *format <= FORMAT => (),
+            Some(format) => {
+                return Err(Error::Config(format!(
+                    "{} is a format {} bundle, newer than this version imports",
+                    bundle.display(),
+                    format
+                )))
+            }
+            None => {
+                return Err(Error::Config(format!(
+                    "{} has no bundle format",
+                    bundle.display()
+                )))
+            }
+        },
+        _ => {
+            return Err(Error::Config(format!(
+                "{} isn't a settings bundle",
+                bundle.display()
+            )))
+        }
+    }
+
+    let old = fs::read(path).ok();
+    write_validated(path, &table)?;
+    if let Some(old) = old {
+        let backup = path.with_extension("toml.bak");
+        fs::write(&backup, old).map_err(|e| {
+            Error::Config(format!("Couldn't back up to {}: {}", backup.display(), e))
+        })?;
+    }
+    Ok(())
+}
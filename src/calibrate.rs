@@ -0,0 +1,166 @@
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use toml::{Table, Value};
+
+use crate::{
+    ambient_brightness::settled_percent,
+    config::{read_table, Config},
+    levels::ambient_value,
+    sensor::{self, Sensor},
+    sysfs::Sysfs,
+    Error, Result,
+};
+
+/// Lighting the wizard asks for, darkest first
+const CONDITIONS: &[&str] = &["a dark room", "indoor lighting", "daylight or outdoors"];
+
+/// Readings averaged for each condition
+const SAMPLES: u32 = 10;
+
+/// Walks through [`calibrate`] on the terminal with the configured sensor,
+/// writing the curves to the config file at `path`
+pub fn run(path: &Path) -> Result<()> {
+    let config = Config::load(path.exists().then_some(path))?;
+    let sensor = sensor::from_config(&Sysfs::default(), &config.sensor)?;
+    calibrate(
+        sensor.as_ref(),
+        &config,
+        path,
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+        Duration::from_millis(200),
+    )
+}
+
+/// Samples the sensor in each lighting condition, asks for the screen and
+/// keyboard brightness wanted there, and writes curves through those points
+/// into the config file. The current curves provide the suggested answers.
+pub fn calibrate(
+    sensor: &dyn Sensor,
+    config: &Config,
+    path: &Path,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+    interval: Duration,
+) -> Result<()> {
+    let mut points = Vec::new();
+    for condition in CONDITIONS {
+        writeln!(out, "Set up {}, then press Enter", condition)?;
+        read_line(input)?;
+
+        let mut total = 0.0;
+        for _ in 0..SAMPLES {
+            total += sensor.read()?;
+            thread::sleep(interval);
+        }
+        let ambient = ambient_value(settled_percent(total / SAMPLES as f64));
+        writeln!(out, "Ambient: {}%", ambient)?;
+
+        let screen = ask(
+            input,
+            out,
+            "Screen brightness percent",
+            config.screen.curve().percent(ambient),
+        )?;
+        let kbd = ask(
+            input,
+            out,
+            "Keyboard brightness percent",
+            config.kbd.curve.percent(ambient),
+        )?;
+        points.push((ambient, screen, kbd));
+    }
+
+    points.sort_by_key(|(ambient, _, _)| *ambient);
+    points.dedup_by_key(|(ambient, _, _)| *ambient);
+    let screen = curve(
+        points
+            .iter()
+            .map(|(ambient, screen, _)| (*ambient, *screen)),
+    );
+    let kbd = curve(points.iter().map(|(ambient, _, kbd)| (*ambient, *kbd)));
+
+    let mut table = if path.exists() {
+        read_table(path)?
+    } else {
+        Table::new()
+    };
+    for (name, curve) in [("screen", screen), ("kbd", kbd)] {
+        let Value::Table(section) = table
+            .entry(name)
+            .or_insert_with(|| Value::Table(Table::new()))
+        else {
+            return Err(Error::Config(format!(
+                "{} in {} isn't a table",
+                name,
+                path.display()
+            )));
+        };
+        section.insert("curve".to_string(), curve);
+    }
+
+    let contents = toml::to_string(&table)
+        .map_err(|e| Error::Config(format!("Couldn't write config: {}", e)))?;
+    std::fs::write(path, contents).map_err(|e| {
+        Error::Config(format!(
+            "Couldn't write config file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    writeln!(
+        out,
+        "Wrote the screen and keyboard curves to {}",
+        path.display()
+    )?;
+
+    Ok(())
+}
+
+fn read_line(input: &mut dyn BufRead) -> Result<String> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(Error::Config("Calibration cancelled".to_string()));
+    }
+    Ok(line.trim().to_string())
+}
+
+/// Asks for a percent until it gets one, an empty answer taking the suggestion
+fn ask(input: &mut dyn BufRead, out: &mut dyn Write, prompt: &str, suggested: u32) -> Result<u32> {
+    loop {
+        write!(out, "{} [{}]: ", prompt, suggested)?;
+        out.flush()?;
+        let answer = read_line(input)?;
+        if answer.is_empty() {
+            return Ok(suggested);
+        }
+        match answer.parse() {
+            Ok(pct) if pct <= 100 => return Ok(pct),
+            _ => writeln!(out, "Enter a percent from 0 to 100")?,
+        }
+    }
+}
+
+/// Steps from each calibrated point to the next, switching halfway between
+/// their ambient values
+fn curve(points: impl Iterator<Item = (u32, u32)>) -> Value {
+    let mut steps = Vec::new();
+    let mut previous = None;
+    for (ambient, pct) in points {
+        let threshold = match previous {
+            None => 0,
+            Some(previous) => (previous + ambient) / 2,
+        };
+        steps.push(Value::Array(vec![
+            Value::Integer(threshold.into()),
+            Value::Integer(pct.into()),
+        ]));
+        previous = Some(ambient);
+    }
+    Value::Array(steps)
+}
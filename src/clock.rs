@@ -0,0 +1,198 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{after, bounded, tick, Receiver, Sender, TrySendError};
+
+/// Source of time for the controller loop, so time-based behaviour can be
+/// driven deterministically in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Channel that delivers a message every `interval`, like
+    /// [`crossbeam::channel::tick`]
+    fn ticker(&self, interval: Duration) -> Receiver<Instant>;
+
+    /// Channel that delivers a single message after `delay`, like
+    /// [`crossbeam::channel::after`]
+    fn after(&self, delay: Duration) -> Receiver<Instant>;
+}
+
+/// Wall-clock time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn ticker(&self, interval: Duration) -> Receiver<Instant> {
+        tick(interval)
+    }
+
+    fn after(&self, delay: Duration) -> Receiver<Instant> {
+        after(delay)
+    }
+}
+
+struct Ticker {
+    interval: Duration,
+    next: Instant,
+    sender: Sender<Instant>,
+}
+
+struct MockState {
+    now: Instant,
+    tickers: Vec<Ticker>,
+    /// When each one-shot timer fires
+    timers: Vec<(Instant, Sender<Instant>)>,
+}
+
+/// Clock that only moves when [`MockClock::advance`] is called
+pub struct MockClock {
+    state: Mutex<MockState>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                now: Instant::now(),
+                tickers: Vec::new(),
+                timers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Moves time forward, firing every ticker whose interval elapsed. As with
+    /// real tickers, at most one unreceived tick is kept per ticker.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().expect("MockClock poisoned");
+        state.now += by;
+        let now = state.now;
+        state.tickers.retain_mut(|ticker| {
+            while ticker.next <= now {
+                if let Err(TrySendError::Disconnected(_)) = ticker.sender.try_send(ticker.next) {
+                    return false;
+                }
+                ticker.next += ticker.interval;
+            }
+            true
+        });
+        state.timers.retain(|(at, sender)| {
+            if *at > now {
+                return true;
+            }
+            let _ = sender.try_send(*at);
+            false
+        });
+    }
+
+    /// Number of timers from [`Clock::after`] that are still to fire, so a
+    /// test can wait until something is waiting on one before advancing
+    pub fn timers(&self) -> usize {
+        self.state.lock().expect("MockClock poisoned").timers.len()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state.lock().expect("MockClock poisoned").now
+    }
+
+    fn ticker(&self, interval: Duration) -> Receiver<Instant> {
+        let (sender, receiver) = bounded(1);
+        let mut state = self.state.lock().expect("MockClock poisoned");
+        let next = state.now + interval;
+        state.tickers.push(Ticker {
+            interval,
+            next,
+            sender,
+        });
+        receiver
+    }
+
+    fn after(&self, delay: Duration) -> Receiver<Instant> {
+        let (sender, receiver) = bounded(1);
+        let mut state = self.state.lock().expect("MockClock poisoned");
+        let at = state.now + delay;
+        state.timers.push((at, sender));
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_now_only_moves_on_advance() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn mock_ticker_fires_after_interval() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let ticker = clock.ticker(Duration::from_secs(5));
+
+        clock.advance(Duration::from_secs(4));
+        assert!(ticker.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(ticker.try_recv(), Ok(start + Duration::from_secs(5)));
+        assert!(ticker.try_recv().is_err());
+    }
+
+    #[test]
+    fn mock_ticker_drops_missed_ticks() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let ticker = clock.ticker(Duration::from_secs(5));
+
+        clock.advance(Duration::from_secs(16));
+        assert_eq!(ticker.try_recv(), Ok(start + Duration::from_secs(5)));
+        assert!(ticker.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(ticker.try_recv(), Ok(start + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn mock_timer_fires_once() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let timer = clock.after(Duration::from_secs(5));
+        assert_eq!(clock.timers(), 1);
+
+        clock.advance(Duration::from_secs(4));
+        assert!(timer.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(timer.try_recv(), Ok(start + Duration::from_secs(5)));
+        assert_eq!(clock.timers(), 0);
+        clock.advance(Duration::from_secs(5));
+        assert!(timer.try_recv().is_err());
+    }
+
+    #[test]
+    fn mock_ticker_is_dropped_with_receiver() {
+        let clock = MockClock::new();
+        drop(clock.ticker(Duration::from_secs(1)));
+
+        clock.advance(Duration::from_secs(2));
+        assert!(clock.state.lock().unwrap().tickers.is_empty());
+    }
+}
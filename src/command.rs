@@ -0,0 +1,123 @@
+use std::{fmt, str::FromStr};
+
+/// Kinds of outputs commands can single out. `led`, `hid`, and `bulb` cover
+/// every configured LED, HID keyboard, and room light.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputKind {
+    Kbd,
+    Screen,
+    Led,
+    Hid,
+    Bulb,
+}
+
+impl OutputKind {
+    pub(crate) const ALL: [Self; 5] = [Self::Kbd, Self::Screen, Self::Led, Self::Hid, Self::Bulb];
+}
+
+impl fmt::Display for OutputKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kbd => write!(f, "kbd"),
+            Self::Screen => write!(f, "screen"),
+            Self::Led => write!(f, "led"),
+            Self::Hid => write!(f, "hid"),
+            Self::Bulb => write!(f, "bulb"),
+        }
+    }
+}
+
+impl FromStr for OutputKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|x| x.to_string() == kind)
+            .ok_or_else(|| format!("{} isn't kbd, screen, led, hid, or bulb", kind))
+    }
+}
+
+/// Desktop color scheme picked by hand, or left to the room's light again with
+/// `auto`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Appearance {
+    Auto,
+    Dark,
+    Light,
+}
+
+impl Appearance {
+    pub(crate) const ALL: [Self; 3] = [Self::Auto, Self::Dark, Self::Light];
+}
+
+impl fmt::Display for Appearance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Dark => write!(f, "dark"),
+            Self::Light => write!(f, "light"),
+        }
+    }
+}
+
+impl FromStr for Appearance {
+    type Err = String;
+
+    fn from_str(appearance: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|x| x.to_string() == appearance)
+            .ok_or_else(|| format!("{} isn't auto, dark, or light", appearance))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Idle,
+    Active,
+    Increase(i8),
+    Decrease(i8),
+    /// Moves the screen to this percent, by offsetting it from the curve
+    Set(u8),
+    /// Offsets only outputs of this kind, raising them if positive, whether or
+    /// not they follow the other offset commands
+    Nudge(OutputKind, i8),
+    /// Moves only outputs of this kind to this percent, by offsetting them
+    SetOutput(OutputKind, u8),
+    /// Hands outputs back to automatic control
+    Enable(OutputKind),
+    /// Stops adjusting outputs, leaving them at their level for other tools,
+    /// while the rest keep following the sensor
+    Disable(OutputKind),
+    /// Holds the desktop color scheme, until `auto` hands it back to
+    /// `[environment]`
+    Appearance(Appearance),
+    /// Starts the filters over from the current reading, e.g. right after
+    /// switching the room lights
+    Resync,
+    /// Saves the outputs' levels and the mode under a name in the state file
+    Snapshot(String),
+    /// Brings back the levels and mode saved under a name
+    Restore(String),
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Idle => write!(f, "idle"),
+            Self::Active => write!(f, "active"),
+            Self::Increase(amount) => write!(f, "increase {}", amount),
+            Self::Decrease(amount) => write!(f, "decrease {}", amount),
+            Self::Set(percent) => write!(f, "set {}", percent),
+            Self::Nudge(kind, amount) => write!(f, "nudge {} {}", kind, amount),
+            Self::SetOutput(kind, percent) => write!(f, "set-output {} {}", kind, percent),
+            Self::Enable(kind) => write!(f, "enable {}", kind),
+            Self::Disable(kind) => write!(f, "disable {}", kind),
+            Self::Appearance(appearance) => write!(f, "appearance {}", appearance),
+            Self::Resync => write!(f, "resync"),
+            Self::Snapshot(name) => write!(f, "snapshot {}", name),
+            Self::Restore(name) => write!(f, "restore {}", name),
+        }
+    }
+}
@@ -0,0 +1,1734 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, info};
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::{
+    ambient_brightness::{settled_percent, IDLE_SCALE},
+    command::OutputKind,
+    levels::{self, Clamp},
+    output::{Darkness, StepCurve, Tuning},
+    quirks,
+    sysfs::Sysfs,
+    Error, Result, SCREEN_SUBSYSTEM,
+};
+
+/// Environment variables starting with this override config keys, with `__`
+/// between nested keys, e.g. `IIO_KBD_WATCHDOG__TICKS=0`
+const ENV_PREFIX: &str = "IIO_KBD_";
+
+/// Deep enough for layered dotfiles, shallow enough to stop include cycles
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Ambient value in the config: a number is the log-scaled 0–100 ambient
+/// percent, and a string like `"500 lux"` or `"500lx"` the reading that lands on
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "AmbientValue")]
+pub(crate) struct Ambient(pub(crate) f64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AmbientValue {
+    Percent(f64),
+    Lux(String),
+}
+
+impl TryFrom<AmbientValue> for Ambient {
+    type Error = String;
+
+    fn try_from(value: AmbientValue) -> Result<Self, Self::Error> {
+        let lux = match value {
+            AmbientValue::Percent(percent) => return Ok(Self(percent)),
+            AmbientValue::Lux(lux) => lux,
+        };
+        let number = lux
+            .trim()
+            .strip_suffix("lux")
+            .or_else(|| lux.trim().strip_suffix("lx"))
+            .and_then(|x| x.trim().parse::<f64>().ok())
+            .filter(|x| *x >= 0.0)
+            .ok_or_else(|| {
+                format!(
+                    "{:?} is neither an ambient percent nor lux, e.g. \"500 lux\"",
+                    lux
+                )
+            })?;
+        // Below 1 lux the log reading is negative, and clamps to the darkest step
+        Ok(Self(settled_percent(number).max(0.0)))
+    }
+}
+
+/// Deserializes an [`Ambient`] into a plain ambient percent
+fn ambient<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    Ambient::deserialize(deserializer).map(|x| x.0)
+}
+
+/// Deserializes a percent, refusing anything above 100
+fn percent<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let percent = u32::deserialize(deserializer)?;
+    if percent > 100 {
+        return Err(serde::de::Error::custom(format!(
+            "percent {} is above 100",
+            percent
+        )));
+    }
+    Ok(percent)
+}
+
+fn some_percent<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    percent(deserializer).map(Some)
+}
+
+/// Deserializes a number of seconds, refusing 0
+fn some_seconds<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("an interval of 0 seconds")),
+        seconds => Ok(Some(seconds)),
+    }
+}
+
+/// Directory the config file goes in, under the user's config directory or `/etc`
+const CONFIG_DIR: &str = "iio_ambient_brightness";
+
+/// Keyboard LEDs in the order they are preferred when more than one is present
+pub(crate) const KNOWN_KBD_LEDS: &[&str] = &[
+    "asus::kbd_backlight",
+    "tpacpi::kbd_backlight",
+    "dell::kbd_backlight",
+    "platform::kbd_backlight",
+    "smc::kbd_backlight",
+    "applesmc::kbd_backlight",
+    "apple::kbd_backlight",
+    "system76_acpi::kbd_backlight",
+    "hp::kbd_backlight",
+    "samsung::kbd_backlight",
+    "chromeos::kbd_backlight",
+];
+
+/// Factors turning raw readings into lux for IIO drivers whose units are far
+/// off, by IIO device name
+#[cfg(any(feature = "iio", feature = "sysfs"))]
+const SCALE_PRESETS: &[(&str, f64)] = &[
+    // HID sensor hub ALS on Surface devices, reporting hundredths of a lux
+    ("als", 0.01),
+    // ACPI _ALI on firmware reporting tenths of a lux
+    ("acpi-als", 0.1),
+    // Chrome EC ALS behind cros-ec, reporting raw photodiode counts
+    ("cros-ec-light", 0.5),
+];
+
+/// Formulas combining broadband and IR readings, by IIO device name
+#[cfg(any(feature = "iio", feature = "sysfs"))]
+const COMBINATION_PRESETS: &[(&str, Combination)] = &[
+    ("tsl2561", Combination::Tsl2561),
+    ("tsl2563", Combination::Tsl2561),
+    ("tsl2591", Combination::Tsl2591),
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Named overlay from `[profiles.<name>]` applied on top of the rest
+    pub(crate) profile: Option<String>,
+    pub(crate) sensor: SensorConfig,
+    /// Second sensor on convertibles, read instead of `sensor` in tablet mode
+    pub(crate) tablet: Option<TabletConfig>,
+    /// Hold brightness while an accelerometer says the screen faces down, e.g.
+    /// lying on a table or with the lid closed
+    pub(crate) orientation: Option<OrientationConfig>,
+    pub(crate) filter: FilterConfig,
+    /// Seconds between sensor reads; 5 by default
+    #[serde(deserialize_with = "some_seconds")]
+    pub(crate) interval: Option<u64>,
+    /// Skip screen and LED writes that change brightness by less than this percent
+    pub(crate) min_delta: u32,
+    /// Start the filters over from a reading that lands this many ambient
+    /// percent away from the smoothed value, so switching the room lights
+    /// doesn't take many samples to catch up
+    pub(crate) resync_above: Option<f64>,
+    /// Stop reading the sensor after being idle this many seconds, until there is
+    /// activity again. Updates always stop while the session is locked.
+    pub(crate) suspend_after: Option<u64>,
+    /// Seconds outputs take to move to the levels of a new profile, or into
+    /// and out of idle, instead of jumping there
+    pub(crate) crossfade: u64,
+    /// Keep ambient light values out of logs and recordings, since they show
+    /// when someone is around
+    pub(crate) privacy: bool,
+    pub(crate) watchdog: WatchdogConfig,
+    /// How sensor reads, brightness writes, and control socket accepts that
+    /// fail for a moment are retried
+    pub(crate) retry: RetryConfig,
+    pub(crate) control: ControlConfig,
+    pub(crate) screen: ScreenConfig,
+    /// Dim the screen further while its content is dark
+    pub(crate) content: Option<ContentConfig>,
+    /// Run a command or emit a D-Bus signal when the room turns dark or bright
+    pub(crate) environment: Option<EnvironmentConfig>,
+    /// Fixed screen and keyboard levels for a pitch-black room
+    pub(crate) darkness: Option<DarknessConfig>,
+    /// Serve readings and levels as properties on the session bus, with
+    /// methods for idle, active, screen offsets, and the status
+    pub(crate) dbus: bool,
+    /// Bounds no automatic change may take the screen or keyboard past
+    pub(crate) limits: LimitsConfig,
+    /// Turn brightness up and down on ACPI video events, for laptops whose
+    /// brightness keys don't show up as keys
+    pub(crate) acpi: Option<AcpiConfig>,
+    /// Hold brightness while the screen is recorded or cast
+    pub(crate) screencast: Option<ScreencastConfig>,
+    pub(crate) kbd: KbdConfig,
+    pub(crate) hid: Vec<HidConfig>,
+    pub(crate) led: Vec<LedConfig>,
+    /// Room lights driven over MQTT or HTTP, e.g. a Zigbee bulb
+    pub(crate) bulb: Vec<BulbConfig>,
+    /// Privileged instance writing brightness for `backend = "helper"`
+    pub(crate) helper: HelperConfig,
+    /// File snapshots are saved in; by default `state.toml` under
+    /// `$XDG_STATE_HOME/iio_ambient_brightness`
+    pub(crate) state: Option<PathBuf>,
+    /// Once set up, restrict file access with Landlock to devices, sockets,
+    /// the config, and commands on PATH, and refuse syscalls the daemon never
+    /// makes. Commands relying on setuid stop working.
+    pub(crate) sandbox: bool,
+    /// When started as root, switch to this user, by name or uid, once the
+    /// devices are open
+    pub(crate) user: Option<String>,
+    /// Group to switch to along with `user`; by default the user's own
+    pub(crate) group: Option<String>,
+    /// logind seat whose active session this instance follows, e.g. `seat1`;
+    /// by default the session it was started in
+    pub(crate) seat: Option<String>,
+    /// Name given with `--instance`, whose `[instances.<name>]` section
+    /// overrides the rest; each instance has a socket of its own. On
+    /// multi-seat machines the section names the seat and its devices.
+    #[serde(skip)]
+    pub(crate) instance: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum SensorConfig {
+    Iio(IioConfig),
+    /// IIO light channels read directly from /sys/bus/iio, without libiio
+    Sysfs(IioConfig),
+    Hwmon {
+        /// hwmon attribute to read, e.g. /sys/class/hwmon/hwmon2/device/lux
+        path: PathBuf,
+    },
+    /// The MacBook SMC light sensor, also used when no IIO sensor is found
+    Applesmc,
+    /// The IOKit light sensor of Macs running macOS
+    Macos,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TabletConfig {
+    /// Sensor facing the user in tablet and tent mode, usually the one in the base
+    pub(crate) sensor: SensorConfig,
+    /// evdev device with the tablet mode switch; by default the first one
+    /// reporting it
+    #[serde(default)]
+    pub(crate) switch: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OrientationConfig {
+    /// IIO device name; by default the first device with accelerometer channels
+    pub(crate) device: Option<String>,
+    /// Degrees from straight down that still count as facing down
+    pub(crate) angle: f64,
+    /// For accelerometers whose z axis points into the screen instead of out of it
+    pub(crate) invert: bool,
+}
+
+impl Default for OrientationConfig {
+    fn default() -> Self {
+        Self {
+            device: None,
+            angle: 30.0,
+            invert: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AcpiConfig {
+    /// Percent each key press moves the screen
+    pub(crate) step: i8,
+}
+
+impl Default for AcpiConfig {
+    fn default() -> Self {
+        Self { step: 10 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ScreencastConfig {
+    /// Seconds between looks for capture sessions
+    pub(crate) interval: u64,
+    /// Count Mutter's screencast sessions, as used on GNOME
+    pub(crate) mutter: bool,
+    /// Count the desktop portal's sessions, as used on other desktops. Any
+    /// portal session counts, so a client keeping e.g. global shortcuts open
+    /// holds brightness for as long as it runs.
+    pub(crate) portal: bool,
+}
+
+impl Default for ScreencastConfig {
+    fn default() -> Self {
+        Self {
+            interval: 2,
+            mutter: true,
+            portal: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum FilterConfig {
+    /// Weighted moving average over the last `window` samples
+    Wma {
+        #[serde(default = "default_window")]
+        window: u8,
+    },
+    /// Reacts quickly to rising light and slowly to falling light
+    Asymmetric { rise_window: u8, fall_window: u8 },
+    /// Picks its window from recent sensor noise: `min_window` for clean
+    /// sensors up to `max_window` for noisy ones
+    Auto {
+        #[serde(default = "default_min_window")]
+        min_window: u8,
+        #[serde(default = "default_max_window")]
+        max_window: u8,
+    },
+}
+
+fn default_window() -> u8 {
+    10
+}
+
+fn default_min_window() -> u8 {
+    2
+}
+
+fn default_max_window() -> u8 {
+    30
+}
+
+fn default_idle_scale() -> f64 {
+    IDLE_SCALE
+}
+
+fn default_bulb_max() -> u32 {
+    100
+}
+
+fn default_payload() -> String {
+    "{level}".to_string()
+}
+
+/// Lamps are slow to change and noticeable when they do
+fn default_bulb_dwell() -> u64 {
+    60
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "iio_ambient_brightness".to_string()
+}
+
+fn default_http_method() -> String {
+    "PUT".to_string()
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self::Wma {
+            window: default_window(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct WatchdogConfig {
+    /// Report the update loop as stuck once a single update takes this many ticks,
+    /// 0 disables the watchdog
+    pub(crate) ticks: u32,
+    /// Abort when stuck instead of only logging, leaving the restart to systemd
+    pub(crate) abort: bool,
+    /// Reopen the sensor once it has returned the same reading for this many
+    /// seconds, since that usually means the driver hung; 0 disables the check
+    pub(crate) sensor_stuck_after: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            ticks: 6,
+            abort: false,
+            sensor_stuck_after: 900,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RetryConfig {
+    /// Tries in all, 1 doesn't retry
+    pub(crate) attempts: u32,
+    /// Wait before the first retry
+    pub(crate) delay_ms: u64,
+    /// Factor the wait grows by with each retry
+    pub(crate) backoff: f64,
+    /// Fraction each wait is randomly lengthened or shortened by, so retries
+    /// from several processes don't line up
+    pub(crate) jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay_ms: 100,
+            backoff: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+/// A system instance run with `helper`, owning the brightness writes of user
+/// instances on systems where they may not write sysfs or go through logind
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HelperConfig {
+    /// Socket the helper listens on and user instances write to
+    pub(crate) socket: PathBuf,
+    /// Permissions of the socket, e.g. 0o660; by default from the umask
+    pub(crate) mode: Option<u32>,
+    /// Group allowed to write through the helper, by name or gid
+    pub(crate) group: Option<String>,
+    /// Backlights and LEDs the helper writes, by name; by default all of them
+    pub(crate) devices: Vec<String>,
+}
+
+impl Default for HelperConfig {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from("/run/iio_ambient_brightness/helper.sock"),
+            mode: None,
+            group: None,
+            devices: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ControlConfig {
+    /// Permissions of the control socket, e.g. 0o660; by default only the
+    /// owner's, and the group's too with `group`
+    pub(crate) mode: Option<u32>,
+    /// Control socket path; by default in `$XDG_RUNTIME_DIR`, or one per user
+    /// in the temp dir without it
+    pub(crate) socket: Option<PathBuf>,
+    /// Group owning the control socket, by name or gid
+    pub(crate) group: Option<String>,
+    /// Append every control request, who sent it, and what happened to this file
+    pub(crate) audit: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ScreenConfig {
+    /// Backlight under /sys/class/backlight; found automatically if unset
+    pub(crate) name: Option<String>,
+    pub(crate) backend: Backend,
+    /// What to do when the desktop, e.g. GNOME or KDE, adjusts the screen too
+    pub(crate) on_conflict: ConflictPolicy,
+    /// Screen brightness percent for each ambient percent; by default
+    /// built-in steps, see [`ScreenConfig::curve`]
+    pub(crate) curve: Option<StepCurve>,
+    /// Highest percent the curve reaches: the top of the built-in steps,
+    /// which is 50 unless set, or a cap on a configured curve
+    #[serde(deserialize_with = "some_percent")]
+    pub(crate) max: Option<u32>,
+    /// Move in a line between the curve's points instead of stepping at each
+    pub(crate) interpolate: bool,
+    /// Brightness once the sensor saturates in direct sunlight, above `max`
+    pub(crate) outdoor: Option<OutdoorConfig>,
+    /// Smoothing for the screen alone; by default the top-level filter
+    pub(crate) filter: Option<FilterConfig>,
+    /// Share of the ambient percent the screen follows while idle
+    pub(crate) idle_scale: f64,
+    /// Whether increase, decrease, and set commands move the screen
+    pub(crate) offsets: bool,
+    /// Seconds a new brightness is held before the sensor may change it again
+    pub(crate) dwell: u64,
+    /// Milliseconds the screen takes to move to a new step of its curve;
+    /// right away unless set
+    pub(crate) transition_ms: u64,
+    /// Leave the screen alone while the compositor has it powered off
+    pub(crate) power: PowerConfig,
+}
+
+impl Default for ScreenConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            backend: Backend::default(),
+            on_conflict: ConflictPolicy::default(),
+            curve: None,
+            max: None,
+            interpolate: false,
+            outdoor: None,
+            filter: None,
+            idle_scale: IDLE_SCALE,
+            offsets: true,
+            dwell: 0,
+            transition_ms: 0,
+            power: PowerConfig::default(),
+        }
+    }
+}
+
+/// Backlight types by preference: native ones driven by the GPU have the
+/// finest steps, firmware ones like `acpi_video0` are often broken
+const BACKLIGHT_TYPES: &[&str] = &["raw", "platform", "firmware"];
+
+impl ScreenConfig {
+    /// The configured backlight, or the best one present that can be set
+    pub(crate) fn backlight(&self, sysfs: &Sysfs) -> Result<String> {
+        if let Some(name) = &self.name {
+            return Ok(name.clone());
+        }
+
+        let class = sysfs.class(SCREEN_SUBSYSTEM);
+        let mut candidates = fs::read_dir(&class)
+            .map_err(|source| Error::Sysfs {
+                path: class.clone(),
+                source,
+            })?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            // Some drivers register a backlight with no range to set
+            .filter(|name| {
+                fs::read_to_string(class.join(name).join("max_brightness"))
+                    .ok()
+                    .and_then(|x| x.trim().parse::<u32>().ok())
+                    .is_some_and(|x| x > 0)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|name| {
+            let kind = fs::read_to_string(class.join(name).join("type")).unwrap_or_default();
+            let rank = BACKLIGHT_TYPES
+                .iter()
+                .position(|x| *x == kind.trim())
+                .unwrap_or(BACKLIGHT_TYPES.len());
+            (rank, name.clone())
+        });
+        debug!("Backlight candidates: {:?}", candidates);
+
+        let name = candidates
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::NotFound(format!("a backlight in {}", class.display())))?;
+        info!("Detected screen backlight: {}", name);
+        Ok(name)
+    }
+
+    /// The curve the screen follows, up to `max`, interpolated if asked,
+    /// and boosted outdoors
+    pub(crate) fn curve(&self) -> StepCurve {
+        let curve = match (&self.curve, self.max) {
+            (Some(curve), Some(max)) => curve.capped(max),
+            (Some(curve), None) => curve.clone(),
+            (None, max) => levels::screen_curve(max.unwrap_or(levels::SCREEN_MAX)),
+        };
+        let curve = match self.interpolate {
+            true => curve.interpolated(),
+            false => curve,
+        };
+        match &self.outdoor {
+            Some(outdoor) => curve.boosted(outdoor.above.round() as u32, outdoor.percent),
+            None => curve,
+        }
+    }
+
+    #[cfg(feature = "screen")]
+    pub(crate) fn tuning(&self) -> Tuning<'_> {
+        Tuning {
+            filter: self.filter.as_ref(),
+            idle_scale: self.idle_scale,
+            offsets: self.offsets,
+            dwell: Duration::from_secs(self.dwell),
+            transition: Duration::from_millis(self.transition_ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OutdoorConfig {
+    /// Ambient percent, or lux as in `"20000 lux"`, from which the boost
+    /// applies; by default where most sensors saturate
+    #[serde(deserialize_with = "ambient")]
+    pub(crate) above: f64,
+    /// Screen brightness percent from there up
+    #[serde(deserialize_with = "percent")]
+    pub(crate) percent: u32,
+}
+
+impl Default for OutdoorConfig {
+    fn default() -> Self {
+        Self {
+            above: 95.0,
+            percent: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PowerConfig {
+    /// Lists Wayland outputs as `<name> on|off` lines, as wlopm does for the
+    /// wlr-output-power-management protocol; empty disables the check
+    pub(crate) command: Vec<String>,
+    /// Wayland output showing the screen; by default the backlight's DRM
+    /// connector, e.g. `eDP-1`
+    pub(crate) output: Option<String>,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            command: vec!["wlopm".to_string()],
+            output: None,
+        }
+    }
+}
+
+/// How brightness changes reach a backlight or LED
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Backend {
+    /// sysfs when the brightness attribute is writable, logind otherwise
+    #[default]
+    Auto,
+    Sysfs,
+    Logind,
+    /// The brightnessctl command, for setups whose permissions are already
+    /// set up for it
+    Brightnessctl,
+    /// The light command
+    Light,
+    /// A privileged instance run with `helper`, see [`HelperConfig`]
+    Helper,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConflictPolicy {
+    /// Keep driving the screen and log how to turn the desktop's adjustment off
+    #[default]
+    Warn,
+    /// Leave the screen to the desktop, only driving keyboard and LED backlights.
+    /// Ambient readings stay available through `--status`, e.g. for desktop
+    /// scripts.
+    Yield,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ContentConfig {
+    /// Prints a binary PPM capture of the screen; small captures are cheaper
+    pub(crate) command: Vec<String>,
+    /// How much darker the screen gets for black content, from 0 to 1
+    pub(crate) strength: f64,
+}
+
+impl Default for ContentConfig {
+    fn default() -> Self {
+        Self {
+            command: ["grim", "-t", "ppm", "-s", "0.05", "-"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            strength: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LimitsConfig {
+    /// Screen brightness percent, e.g. `{ min = 30 }` to always stay readable
+    pub(crate) screen: Clamp,
+    /// Keyboard backlight level, in the LED's own steps
+    pub(crate) kbd: Clamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DarknessConfig {
+    /// Ambient percent, or lux as in `"1 lux"`, below which the room counts
+    /// as pitch black
+    #[serde(deserialize_with = "ambient")]
+    pub(crate) below: f64,
+    /// Ambient percent or lux above which the curves take over again
+    #[serde(deserialize_with = "ambient")]
+    pub(crate) above: f64,
+    /// Screen brightness percent in the dark
+    #[serde(deserialize_with = "percent")]
+    pub(crate) screen: u32,
+    /// Keyboard backlight percent in the dark
+    #[serde(deserialize_with = "percent")]
+    pub(crate) kbd: u32,
+}
+
+impl Default for DarknessConfig {
+    fn default() -> Self {
+        Self {
+            below: 2.0,
+            above: 8.0,
+            screen: 1,
+            kbd: 100,
+        }
+    }
+}
+
+impl DarknessConfig {
+    /// What darkness does to an output of `kind`, if anything
+    pub(crate) fn for_kind(&self, kind: OutputKind) -> Option<Darkness> {
+        let percent = match kind {
+            OutputKind::Screen => self.screen,
+            OutputKind::Kbd | OutputKind::Hid => self.kbd,
+            OutputKind::Led | OutputKind::Bulb => return None,
+        };
+        Some(Darkness {
+            below: self.below,
+            above: self.above,
+            percent,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EnvironmentConfig {
+    /// Ambient percent, or lux as in `"50 lux"`, below which the room counts as dark
+    #[serde(deserialize_with = "ambient")]
+    pub(crate) dark_below: f64,
+    /// Ambient percent or lux above which it counts as bright again; the gap
+    /// keeps light around a single threshold from toggling back and forth
+    #[serde(deserialize_with = "ambient")]
+    pub(crate) bright_above: f64,
+    /// Started on each change with `IIO_AMBIENT_ENVIRONMENT` set to `dark` or
+    /// `bright`
+    pub(crate) command: Vec<String>,
+    /// Also emit `EnvironmentChanged` on the session bus, from
+    /// `/io/github/jeffutter/IioAmbientBrightness`
+    pub(crate) signal: bool,
+    /// Also switch the desktop's `color-scheme` preference, which the settings
+    /// portal passes on to apps: `prefer-dark` in the dark, `default` in
+    /// bright rooms
+    pub(crate) color_scheme: bool,
+    /// Seconds the room has to stay dark or bright before it counts, so a
+    /// passing shadow doesn't flip the theme
+    pub(crate) sustain: u64,
+}
+
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        Self {
+            dark_below: 30.0,
+            bright_above: 40.0,
+            command: Vec::new(),
+            signal: false,
+            color_scheme: false,
+            sustain: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct KbdConfig {
+    /// Always use this LED, skipping auto-detection
+    pub(crate) name: Option<String>,
+    /// LED names to prefer when several are detected, most preferred first
+    pub(crate) priority: Vec<String>,
+    /// Keyboard brightness percent for each ambient percent, also used for HID
+    /// keyboards
+    pub(crate) curve: StepCurve,
+    pub(crate) backend: Backend,
+    /// Smoothing for the keyboard alone; by default the top-level filter
+    pub(crate) filter: Option<FilterConfig>,
+    /// Share of the ambient percent the keyboard follows while idle
+    pub(crate) idle_scale: f64,
+    /// Whether increase, decrease, and set commands move the keyboard too
+    pub(crate) offsets: bool,
+    /// Seconds a new level is held before the sensor may change it again, so
+    /// light near a curve step doesn't flicker between two levels
+    pub(crate) dwell: u64,
+    /// Milliseconds the keyboard takes to move to a new step of its curve
+    pub(crate) transition_ms: u64,
+    /// Turn the keyboard off while an external keyboard is being typed on
+    pub(crate) external: Option<ExternalKeyboardConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ExternalKeyboardConfig {
+    /// Seconds after the last key press that an external keyboard counts as in use
+    pub(crate) idle_after: u64,
+    /// Buses external keyboards are on, `usb` and `bluetooth`
+    pub(crate) buses: Vec<String>,
+    /// Keyboards to leave out by part of their name, e.g. security keys that
+    /// show up as USB keyboards
+    pub(crate) ignore: Vec<String>,
+}
+
+impl Default for ExternalKeyboardConfig {
+    fn default() -> Self {
+        Self {
+            idle_after: 60,
+            buses: vec!["usb".to_string(), "bluetooth".to_string()],
+            ignore: Vec::new(),
+        }
+    }
+}
+
+impl KbdConfig {
+    #[cfg(any(feature = "kbd", feature = "hid"))]
+    pub(crate) fn tuning(&self) -> Tuning<'_> {
+        Tuning {
+            filter: self.filter.as_ref(),
+            idle_scale: self.idle_scale,
+            offsets: self.offsets,
+            dwell: Duration::from_secs(self.dwell),
+            transition: Duration::from_millis(self.transition_ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "hid"), allow(dead_code))]
+pub(crate) struct HidConfig {
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+    /// Output report written to the hidraw device, starting with the report id
+    pub(crate) report: Vec<u8>,
+    /// Position in `report` that receives the brightness level
+    pub(crate) level_index: usize,
+    pub(crate) max_level: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LedConfig {
+    /// Device name under /sys/class/leds
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) curve: StepCurve,
+    #[serde(default)]
+    pub(crate) backend: Backend,
+    /// Smoothing for this LED alone; by default the top-level filter
+    pub(crate) filter: Option<FilterConfig>,
+    /// Share of the ambient percent the LED follows while idle
+    #[serde(default = "default_idle_scale")]
+    pub(crate) idle_scale: f64,
+    /// Whether increase, decrease, and set commands move this LED too
+    #[serde(default)]
+    pub(crate) offsets: bool,
+    /// Seconds a new level is held before the sensor may change it again
+    #[serde(default)]
+    pub(crate) dwell: u64,
+}
+
+impl LedConfig {
+    pub(crate) fn tuning(&self) -> Tuning<'_> {
+        Tuning {
+            filter: self.filter.as_ref(),
+            idle_scale: self.idle_scale,
+            offsets: self.offsets,
+            dwell: Duration::from_secs(self.dwell),
+            transition: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "bulb"), allow(dead_code))]
+pub(crate) struct BulbConfig {
+    /// Publish to an MQTT broker, e.g. zigbee2mqtt's
+    pub(crate) mqtt: Option<MqttConfig>,
+    /// Send to a plain HTTP endpoint instead, e.g.
+    /// `http://bridge.local/api/key/lights/1/state`
+    pub(crate) http: Option<HttpConfig>,
+    /// Lamp brightness percent for each ambient percent; by default it
+    /// brightens as the room darkens and turns off in daylight
+    #[serde(default = "levels::bulb_curve")]
+    pub(crate) curve: StepCurve,
+    /// Level published for full brightness, e.g. 254 for Zigbee
+    #[serde(default = "default_bulb_max")]
+    pub(crate) max_level: u32,
+    /// Message sent, with `{level}` replaced, e.g. `{"brightness": {level}}`
+    #[serde(default = "default_payload")]
+    pub(crate) payload: String,
+    /// Smoothing for this bulb alone; by default the top-level filter
+    pub(crate) filter: Option<FilterConfig>,
+    /// Share of the ambient percent the bulb follows while idle
+    #[serde(default = "default_idle_scale")]
+    pub(crate) idle_scale: f64,
+    /// Whether increase, decrease, and set commands move this bulb too
+    #[serde(default)]
+    pub(crate) offsets: bool,
+    /// Seconds a new level is held before the sensor may change it again
+    #[serde(default = "default_bulb_dwell")]
+    pub(crate) dwell: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MqttConfig {
+    pub(crate) host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub(crate) port: u16,
+    /// e.g. `zigbee2mqtt/living_room/set`
+    pub(crate) topic: String,
+    #[serde(default = "default_client_id")]
+    pub(crate) client_id: String,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    /// Have the broker keep the last level for subscribers that join later
+    #[serde(default)]
+    pub(crate) retain: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "bulb"), allow(dead_code))]
+pub(crate) struct HttpConfig {
+    /// Only plain `http://` URLs
+    pub(crate) url: String,
+    #[serde(default = "default_http_method")]
+    pub(crate) method: String,
+}
+
+impl BulbConfig {
+    #[cfg(feature = "bulb")]
+    pub(crate) fn tuning(&self) -> Tuning<'_> {
+        Tuning {
+            filter: self.filter.as_ref(),
+            idle_scale: self.idle_scale,
+            offsets: self.offsets,
+            dwell: Duration::from_secs(self.dwell),
+            transition: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct IioConfig {
+    /// IIO device name; by default the first device with a light channel
+    pub(crate) device: Option<String>,
+    /// Channel id or name, e.g. `illuminance` or `intensity_both`
+    pub(crate) channel: Option<String>,
+    /// Channel modifier, e.g. `clear` or `both`, matched against the channel id
+    pub(crate) modifier: Option<String>,
+    /// Multiplies raw readings; by default from a preset for known drivers
+    pub(crate) scale: Option<f64>,
+    /// Infrared channel id, e.g. `intensity_ir`, read along with the broadband
+    /// channel to correct it. The broadband channel is then the other
+    /// intensity channel, unless `channel` names one.
+    pub(crate) ir: Option<String>,
+    /// How the broadband and IR readings make lux; by default from a preset
+    /// for known drivers
+    pub(crate) combine: Option<Combination>,
+}
+
+/// Lux from a broadband and an IR reading, before the scale
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum Combination {
+    /// The piecewise formula from the TSL2561 datasheet, over the IR share
+    Tsl2561,
+    /// The TSL2591's `(broadband - ir) * (1 - ir / broadband)`
+    Tsl2591,
+    /// `broadband - ir_factor * ir`, e.g. `combine = { linear = { ir_factor = 1.9 } }`
+    Linear { ir_factor: f64 },
+}
+
+impl IioConfig {
+    /// Scale for readings from the named IIO device
+    #[cfg(any(feature = "iio", feature = "sysfs"))]
+    pub(crate) fn scale_for(&self, device: &str) -> f64 {
+        self.scale.unwrap_or_else(|| {
+            SCALE_PRESETS
+                .iter()
+                .find(|(name, _)| *name == device)
+                .map_or(1.0, |(_, scale)| *scale)
+        })
+    }
+
+    /// How readings from the named IIO device combine with its IR channel, or
+    /// `None` without one
+    #[cfg(any(feature = "iio", feature = "sysfs"))]
+    pub(crate) fn combination_for(&self, device: &str) -> Result<Option<Combination>> {
+        if self.ir.is_none() {
+            return Ok(None);
+        }
+        self.combine
+            .or_else(|| {
+                COMBINATION_PRESETS
+                    .iter()
+                    .find(|(name, _)| *name == device)
+                    .map(|(_, combination)| *combination)
+            })
+            .map(Some)
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "No formula known for combining {}'s IR channel, set combine",
+                    device
+                ))
+            })
+    }
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        if cfg!(feature = "iio") {
+            Self::Iio(IioConfig::default())
+        } else {
+            Self::Sysfs(IioConfig::default())
+        }
+    }
+}
+
+impl SensorConfig {
+    /// Backend name, as in the `type` key
+    #[cfg(feature = "control")]
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Iio(_) => "iio",
+            Self::Sysfs(_) => "sysfs",
+            Self::Hwmon { .. } => "hwmon",
+            Self::Applesmc => "applesmc",
+            Self::Macos => "macos",
+        }
+    }
+}
+
+impl Default for KbdConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            priority: KNOWN_KBD_LEDS.iter().map(|x| x.to_string()).collect(),
+            curve: StepCurve::default(),
+            backend: Backend::default(),
+            filter: None,
+            idle_scale: IDLE_SCALE,
+            offsets: false,
+            dwell: 0,
+            transition_ms: 0,
+            external: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file, if any, then applies `IIO_KBD_*` overrides from
+    /// the environment.
+    ///
+    /// A file may list other files under `include`, relative to itself, which
+    /// it overrides, and override itself for one machine in `[host.<hostname>]`.
+    /// The profile named by `profile` overrides all of them.
+    /// Without a file, known laptops get built-in settings for their hardware.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        Self::load_instance(path, None)
+    }
+
+    /// Loads the config as [`Config::load`] does, with the named instance's
+    /// `[instances.<name>]` section over the file and under the profile
+    pub fn load_instance(path: Option<&Path>, instance: Option<&str>) -> Result<Self> {
+        let mut table = resolved_table(path)?;
+        apply_instance(&mut table, instance)?;
+        apply_profile(&mut table)?;
+        apply_env(&mut table, std::env::vars())?;
+
+        let mut config: Self = Value::Table(table).try_into().map_err(|e| {
+            let source = match path {
+                Some(path) => format!("config file {}", path.display()),
+                None => "config".to_string(),
+            };
+            Error::Config(format!("Couldn't parse {}: {}", source, e))
+        })?;
+        config.instance = instance.map(str::to_string);
+        Ok(config)
+    }
+
+    /// Overrides the control socket's path from the command line
+    pub fn socket_path(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            self.control.socket = Some(path);
+        }
+        self
+    }
+
+    /// Overrides the control socket's permissions and group from the command line
+    pub fn socket_permissions(mut self, mode: Option<u32>, group: Option<String>) -> Self {
+        if let Some(mode) = mode {
+            self.control.mode = Some(mode);
+        }
+        if let Some(group) = group {
+            self.control.group = Some(group);
+        }
+        self
+    }
+
+    /// Overrides the screen and keyboard backlights from the command line
+    pub fn devices(mut self, screen: Option<String>, kbd: Option<String>) -> Self {
+        if let Some(screen) = screen {
+            self.screen.name = Some(screen);
+        }
+        if let Some(kbd) = kbd {
+            self.kbd.name = Some(kbd);
+        }
+        self
+    }
+}
+
+/// The config file to use without `--config`: the user's, under
+/// `$XDG_CONFIG_HOME` or `~/.config`, then the system-wide one in `/etc`
+pub fn default_path() -> Option<PathBuf> {
+    find_default(
+        env::var_os("XDG_CONFIG_HOME"),
+        env::var_os("HOME"),
+        Path::new("/etc"),
+    )
+}
+
+fn find_default(xdg: Option<OsString>, home: Option<OsString>, etc: &Path) -> Option<PathBuf> {
+    let user = xdg
+        .map(PathBuf::from)
+        .filter(|x| x.is_absolute())
+        .or_else(|| home.map(|x| Path::new(&x).join(".config")));
+    user.into_iter()
+        .chain([etc.to_path_buf()])
+        .map(|x| x.join(CONFIG_DIR).join("config.toml"))
+        .find(|x| x.is_file())
+}
+
+/// The config file with its includes and this machine's host section layered
+/// in, or the built-in settings without one. Profiles are still separate.
+pub(crate) fn resolved_table(path: Option<&Path>) -> Result<Table> {
+    match path {
+        Some(path) => read_layered(path, hostname().as_deref(), 0),
+        None => quirks::detected(),
+    }
+}
+
+pub(crate) fn read_table(path: &Path) -> Result<Table> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        Error::Config(format!(
+            "Couldn't read config file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    toml::from_str(&contents).map_err(|e| {
+        Error::Config(format!(
+            "Couldn't parse config file {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+pub(crate) fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: buf is writable for its whole length
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Overrides `base` with `layer`, merging tables and replacing everything else
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// A config file on top of its includes, then its section for this host
+fn read_layered(path: &Path, hostname: Option<&str>, depth: usize) -> Result<Table> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(Error::Config(format!(
+            "Includes nested too deeply at {}",
+            path.display()
+        )));
+    }
+
+    let mut file = read_table(path)?;
+    let includes = match file.remove("include") {
+        None => Vec::new(),
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Array(includes)) => includes
+            .into_iter()
+            .map(|x| match x {
+                Value::String(include) => Ok(include),
+                _ => Err(Error::Config(format!(
+                    "include in {} must list file names",
+                    path.display()
+                ))),
+            })
+            .collect::<Result<_>>()?,
+        Some(_) => {
+            return Err(Error::Config(format!(
+                "include in {} must be a file name or a list of them",
+                path.display()
+            )))
+        }
+    };
+    let hosts = match file.remove("host") {
+        None => Table::new(),
+        Some(Value::Table(hosts)) => hosts,
+        Some(_) => {
+            return Err(Error::Config(format!(
+                "host in {} must be a table of hostnames",
+                path.display()
+            )))
+        }
+    };
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut table = Table::new();
+    for include in includes {
+        merge(
+            &mut table,
+            read_layered(&dir.join(include), hostname, depth + 1)?,
+        );
+    }
+    merge(&mut table, file);
+    if let Some(Value::Table(host)) = hostname.and_then(|x| hosts.get(x)) {
+        merge(&mut table, host.clone());
+    }
+
+    Ok(table)
+}
+
+/// Layers the named instance's section over the rest of the config, dropping
+/// the others
+fn apply_instance(table: &mut Table, instance: Option<&str>) -> Result<()> {
+    let mut instances = match table.remove("instances") {
+        None => Table::new(),
+        Some(Value::Table(instances)) => instances,
+        Some(_) => {
+            return Err(Error::Config(
+                "instances must be a table of instance names".to_string(),
+            ))
+        }
+    };
+    let Some(name) = instance else {
+        return Ok(());
+    };
+    // The name ends up in the socket's file name
+    if name.is_empty() || name.contains('/') {
+        return Err(Error::Config(format!("Invalid instance name {:?}", name)));
+    }
+    match instances.remove(name) {
+        Some(Value::Table(instance)) => {
+            merge(table, instance);
+            Ok(())
+        }
+        Some(_) => Err(Error::Config(format!("instance {} isn't a table", name))),
+        None => Err(Error::Config(format!(
+            "instance {} isn't defined, add an [instances.{}] section",
+            name, name
+        ))),
+    }
+}
+
+/// Layers the selected profile over the rest of the config, dropping the others
+fn apply_profile(table: &mut Table) -> Result<()> {
+    let mut profiles = match table.remove("profiles") {
+        None => Table::new(),
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err(Error::Config(
+                "profiles must be a table of profile names".to_string(),
+            ))
+        }
+    };
+    let Some(Value::String(name)) = table.get("profile") else {
+        return Ok(());
+    };
+    match profiles.remove(name) {
+        Some(Value::Table(profile)) => {
+            merge(table, profile);
+            Ok(())
+        }
+        Some(_) => Err(Error::Config(format!("profile {} isn't a table", name))),
+        None => Err(Error::Config(format!("profile {} isn't defined", name))),
+    }
+}
+
+/// Top-level keys a profile edit may set: how levels follow the light, never
+/// commands, sockets, or users
+#[cfg(feature = "control")]
+const PROFILE_KEYS: &[&str] = &["filter", "limits", "darkness", "min_delta", "crossfade"];
+
+/// Keys of `[screen]` and `[kbd]` a profile edit may set
+#[cfg(feature = "control")]
+const PROFILE_OUTPUT_KEYS: &[&str] = &[
+    "curve",
+    "max",
+    "interpolate",
+    "outdoor",
+    "filter",
+    "idle_scale",
+    "offsets",
+    "dwell",
+    "transition_ms",
+];
+
+/// Refuses overlays reaching past curves and tuning, as anyone who can reach
+/// the control socket may send one
+#[cfg(feature = "control")]
+fn check_overlay(overlay: &Table) -> Result<()> {
+    let refused = |key: &str| {
+        Err(Error::Config(format!(
+            "Profile edits can't set {}, only curves and tuning",
+            key
+        )))
+    };
+    for (key, value) in overlay {
+        match (key.as_str(), value) {
+            ("screen" | "kbd", Value::Table(section)) => {
+                if let Some(inner) = section
+                    .keys()
+                    .find(|x| !PROFILE_OUTPUT_KEYS.contains(&x.as_str()))
+                {
+                    return refused(&format!("{}.{}", key, inner));
+                }
+            }
+            (key, _) if PROFILE_KEYS.contains(&key) => {}
+            (key, _) => return refused(key),
+        }
+    }
+    Ok(())
+}
+
+/// Merges `overlay` into `[profiles.<name>]` of the config file at `path`,
+/// also switching to it when `activate` is set. The file is only replaced once
+/// the result loads, so a running daemon picks up the change.
+#[cfg(feature = "control")]
+pub(crate) fn edit_profile(path: &Path, name: &str, overlay: Table, activate: bool) -> Result<()> {
+    check_overlay(&overlay)?;
+    let mut table = if path.exists() {
+        read_table(path)?
+    } else {
+        Table::new()
+    };
+    let Value::Table(profiles) = table
+        .entry("profiles")
+        .or_insert_with(|| Value::Table(Table::new()))
+    else {
+        return Err(Error::Config(format!(
+            "profiles in {} isn't a table",
+            path.display()
+        )));
+    };
+    let Value::Table(profile) = profiles
+        .entry(name)
+        .or_insert_with(|| Value::Table(Table::new()))
+    else {
+        return Err(Error::Config(format!("profile {} isn't a table", name)));
+    };
+    merge(profile, overlay);
+    if activate {
+        table.insert("profile".to_string(), Value::String(name.to_string()));
+    }
+    write_validated(path, &table)
+}
+
+/// Replaces the config file at `path` with `table`, once it's known to load
+pub(crate) fn write_validated(path: &Path, table: &Table) -> Result<()> {
+    let contents = toml::to_string(table)
+        .map_err(|e| Error::Config(format!("Couldn't write config: {}", e)))?;
+    // Next to the config, so includes resolve the same and the rename is atomic
+    let edited = path.with_extension("toml.edited");
+    let write_error = |e: std::io::Error| {
+        Error::Config(format!(
+            "Couldn't write config file {}: {}",
+            path.display(),
+            e
+        ))
+    };
+    fs::write(&edited, contents).map_err(write_error)?;
+    if let Err(e) = Config::load(Some(&edited)) {
+        let _ = fs::remove_file(&edited);
+        return Err(e);
+    }
+    fs::rename(&edited, path).map_err(write_error)
+}
+
+/// A TOML value such as `0`, `true`, or `[1, 2]`, anything else as a string
+fn parse_env_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut x| x.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+fn apply_env(table: &mut Table, vars: impl Iterator<Item = (String, String)>) -> Result<()> {
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = key.to_lowercase();
+        let mut path = key.split("__").collect::<Vec<_>>();
+        let Some(last) = path.pop().filter(|x| !x.is_empty()) else {
+            return Err(Error::Config(format!("Invalid override {}", name)));
+        };
+
+        let mut current = &mut *table;
+        for part in path {
+            current = match current
+                .entry(part)
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                Value::Table(x) => x,
+                _ => {
+                    return Err(Error::Config(format!(
+                        "{} overrides {} inside a key that isn't a table",
+                        name, last
+                    )))
+                }
+            };
+        }
+        current.insert(last.to_string(), parse_env_value(&raw));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(feature = "iio", feature = "sysfs"))]
+    fn scale_presets_follow_device_name() {
+        let config = IioConfig::default();
+        assert_eq!(config.scale_for("als"), 0.01);
+        assert_eq!(config.scale_for("ltr501"), 1.0);
+
+        let config = IioConfig {
+            scale: Some(2.0),
+            ..IioConfig::default()
+        };
+        assert_eq!(config.scale_for("als"), 2.0);
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn env_overrides_nested_keys() {
+        let mut table = toml::from_str::<Table>("min_delta = 5\n[watchdog]\nticks = 3").unwrap();
+        apply_env(
+            &mut table,
+            vars(&[
+                ("IIO_KBD_MIN_DELTA", "10"),
+                ("IIO_KBD_WATCHDOG__ABORT", "true"),
+                ("IIO_KBD_KBD__NAME", "tpacpi::kbd_backlight"),
+                ("IIO_KBD_CONTROL__SOCKET", "/run/ambient.sock"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+
+        let config: Config = Value::Table(table).try_into().unwrap();
+        assert_eq!(config.min_delta, 10);
+        assert_eq!(config.watchdog.ticks, 3);
+        assert!(config.watchdog.abort);
+        assert_eq!(config.kbd.name.as_deref(), Some("tpacpi::kbd_backlight"));
+        assert_eq!(
+            config.control.socket.as_deref(),
+            Some(Path::new("/run/ambient.sock"))
+        );
+    }
+
+    #[test]
+    fn includes_and_host_sections_layer() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("common.toml"),
+            "min_delta = 5\nsuspend_after = 60\n[kbd]\nname = \"asus::kbd_backlight\"\n",
+        )
+        .unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            "include = \"common.toml\"\nmin_delta = 10\n\
+             [host.work]\nkbd = { name = \"tpacpi::kbd_backlight\" }\n\
+             [host.home]\nmin_delta = 20\n",
+        )
+        .unwrap();
+
+        let config: Config = Value::Table(read_layered(&path, Some("work"), 0).unwrap())
+            .try_into()
+            .unwrap();
+        assert_eq!(config.min_delta, 10);
+        assert_eq!(config.suspend_after, Some(60));
+        assert_eq!(config.kbd.name.as_deref(), Some("tpacpi::kbd_backlight"));
+
+        let config: Config = Value::Table(read_layered(&path, Some("other"), 0).unwrap())
+            .try_into()
+            .unwrap();
+        assert_eq!(config.kbd.name.as_deref(), Some("asus::kbd_backlight"));
+    }
+
+    #[test]
+    fn include_cycles_are_an_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "include = \"config.toml\"\n").unwrap();
+        assert!(read_layered(&path, None, 0).is_err());
+    }
+
+    #[test]
+    fn env_override_inside_value_is_an_error() {
+        let mut table = toml::from_str::<Table>("min_delta = 5").unwrap();
+        let result = apply_env(&mut table, vars(&[("IIO_KBD_MIN_DELTA__X", "1")]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn screen_ceiling_and_outdoor_boost() {
+        let config = Config::default();
+        assert_eq!(config.screen.curve().percent(100), 50);
+
+        let config: Config = toml::from_str("[screen]\nmax = 100\n").unwrap();
+        assert_eq!(config.screen.curve().percent(0), 5);
+        assert_eq!(config.screen.curve().percent(100), 100);
+
+        let config: Config =
+            toml::from_str("[screen]\ncurve = [[0, 5], [50, 80]]\nmax = 60\n[screen.outdoor]\n")
+                .unwrap();
+        assert_eq!(config.screen.curve().percent(50), 60);
+        assert_eq!(config.screen.curve().percent(94), 60);
+        assert_eq!(config.screen.curve().percent(95), 100);
+
+        let config: Config =
+            toml::from_str("[screen]\ncurve = [[0, 10], [50, 60]]\ninterpolate = true\n").unwrap();
+        assert_eq!(config.screen.curve().percent(25), 35);
+        assert_eq!(config.screen.curve().percent(80), 60);
+
+        assert!(toml::from_str::<Config>("[screen]\nmax = 120\n").is_err());
+        assert!(toml::from_str::<Config>("[screen.outdoor]\npercent = 101\n").is_err());
+    }
+
+    #[test]
+    fn limits_need_min_at_or_below_max() {
+        let config: Config = toml::from_str("[limits]\nscreen = { min = 20, max = 20 }\n").unwrap();
+        assert_eq!(config.limits.screen.apply(5), 20);
+        assert_eq!(config.limits.kbd.apply(5), 5);
+        assert!(toml::from_str::<Config>("[limits]\nkbd = { min = 3, max = 1 }\n").is_err());
+    }
+
+    #[test]
+    fn thresholds_accept_lux() {
+        let config: Config = toml::from_str(
+            "[screen]\ncurve = [[0, 5], [\"1000 lux\", 60]]\n\
+             [environment]\ndark_below = \"100lx\"\nbright_above = 40\n",
+        )
+        .unwrap();
+        assert_eq!(config.screen.curve().percent(49), 5);
+        assert_eq!(config.screen.curve().percent(50), 60);
+        let environment = config.environment.unwrap();
+        assert!((environment.dark_below - 100.0 / 3.0).abs() < 1e-9);
+        assert_eq!(environment.bright_above, 40.0);
+
+        assert!(toml::from_str::<Config>("[screen]\ncurve = [[\"bright\", 60]]\n").is_err());
+    }
+
+    #[test]
+    fn selected_profile_overrides_config() {
+        let mut table = toml::from_str::<Table>(
+            "profile = \"night\"\nmin_delta = 5\n\
+             [profiles.night]\nmin_delta = 20\n[profiles.day]\nmin_delta = 1\n",
+        )
+        .unwrap();
+        apply_profile(&mut table).unwrap();
+        let config: Config = Value::Table(table).try_into().unwrap();
+        assert_eq!(config.min_delta, 20);
+        assert_eq!(config.profile.as_deref(), Some("night"));
+
+        let mut table = toml::from_str::<Table>("profile = \"missing\"\n").unwrap();
+        assert!(apply_profile(&mut table).is_err());
+    }
+
+    #[test]
+    fn named_instance_overrides_config() {
+        let contents = "min_delta = 5\n[instances.seat1]\nseat = \"seat1\"\nmin_delta = 20\n\
+                        [instances.seat1.kbd]\nname = \"input9::kbd_backlight\"\n";
+        let mut table = toml::from_str::<Table>(contents).unwrap();
+        apply_instance(&mut table, Some("seat1")).unwrap();
+        let config: Config = Value::Table(table).try_into().unwrap();
+        assert_eq!(config.min_delta, 20);
+        assert_eq!(config.seat.as_deref(), Some("seat1"));
+        assert_eq!(config.kbd.name.as_deref(), Some("input9::kbd_backlight"));
+
+        // Other instances' sections are dropped
+        let mut table = toml::from_str::<Table>(contents).unwrap();
+        apply_instance(&mut table, None).unwrap();
+        let config: Config = Value::Table(table).try_into().unwrap();
+        assert_eq!((config.min_delta, config.seat), (5, None));
+
+        let mut table = toml::from_str::<Table>(contents).unwrap();
+        assert!(apply_instance(&mut table, Some("seat2")).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "control")]
+    fn edited_profiles_are_validated() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "min_delta = 5\n").unwrap();
+
+        let overlay = toml::from_str::<Table>("min_delta = 20").unwrap();
+        edit_profile(&path, "night", overlay, true).unwrap();
+        assert_eq!(Config::load(Some(&path)).unwrap().min_delta, 20);
+
+        let overlay = toml::from_str::<Table>("min_delta = \"lots\"").unwrap();
+        assert!(edit_profile(&path, "night", overlay, false).is_err());
+        assert_eq!(Config::load(Some(&path)).unwrap().min_delta, 20);
+        assert!(!path.with_extension("toml.edited").exists());
+    }
+
+    #[test]
+    #[cfg(feature = "control")]
+    fn profile_edits_only_reach_curves_and_tuning() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "min_delta = 5\n").unwrap();
+
+        let overlay = toml::from_str::<Table>("screen = { curve = [[0, 5]], max = 80 }").unwrap();
+        edit_profile(&path, "night", overlay, false).unwrap();
+        for refused in [
+            "environment = { command = \"touch /tmp/owned\" }",
+            "screen = { name = \"acpi_video0\" }",
+            "control = { mode = 0o666 }",
+            "user = \"root\"",
+        ] {
+            let overlay = toml::from_str::<Table>(refused).unwrap();
+            assert!(edit_profile(&path, "night", overlay, true).is_err());
+        }
+        let table = read_table(&path).unwrap();
+        assert!(!table.contains_key("profile"));
+        assert!(!table["profiles"]["night"]
+            .as_table()
+            .unwrap()
+            .contains_key("environment"));
+    }
+
+    #[test]
+    fn prefers_native_backlights() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sysfs = Sysfs::new(dir.path());
+        let config = ScreenConfig::default();
+        assert!(config.backlight(&sysfs).is_err());
+
+        for (name, kind, max) in [
+            ("acpi_video0", "firmware", Some("255")),
+            ("amdgpu_bl0", "raw", Some("255")),
+            ("aa_bl0", "raw", Some("0")),
+            ("ab_bl0", "raw", None),
+        ] {
+            let device = dir.path().join("class/backlight").join(name);
+            fs::create_dir_all(&device).unwrap();
+            fs::write(device.join("type"), format!("{}\n", kind)).unwrap();
+            if let Some(max) = max {
+                fs::write(device.join("max_brightness"), format!("{}\n", max)).unwrap();
+            }
+        }
+        // Backlights without a range to set are skipped
+        assert_eq!(config.backlight(&sysfs).unwrap(), "amdgpu_bl0");
+
+        let config = ScreenConfig {
+            name: Some("acpi_video0".to_string()),
+            ..ScreenConfig::default()
+        };
+        assert_eq!(config.backlight(&sysfs).unwrap(), "acpi_video0");
+
+        let config = Config::default().devices(Some("acpi_video0".to_string()), None);
+        assert_eq!(config.screen.backlight(&sysfs).unwrap(), "acpi_video0");
+    }
+
+    #[test]
+    fn intervals_must_be_positive() {
+        let config = toml::from_str::<Config>("interval = 30").unwrap();
+        assert_eq!(config.interval, Some(30));
+        assert!(toml::from_str::<Config>("interval = 0").is_err());
+    }
+
+    #[test]
+    fn user_config_comes_before_etc() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (home, etc) = (dir.path().join("home"), dir.path().join("etc"));
+        let find = || find_default(None, Some(home.clone().into()), &etc);
+        assert_eq!(find(), None);
+
+        let system = etc.join("iio_ambient_brightness/config.toml");
+        fs::create_dir_all(system.parent().unwrap()).unwrap();
+        fs::write(&system, "").unwrap();
+        assert_eq!(find(), Some(system.clone()));
+
+        let user = home.join(".config/iio_ambient_brightness/config.toml");
+        fs::create_dir_all(user.parent().unwrap()).unwrap();
+        fs::write(&user, "").unwrap();
+        assert_eq!(find(), Some(user));
+
+        // A relative XDG_CONFIG_HOME is ignored
+        let relative = find_default(Some("config".into()), None, &etc);
+        assert_eq!(relative, Some(system));
+    }
+}
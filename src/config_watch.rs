@@ -0,0 +1,114 @@
+use std::{
+    ffi::{CString, OsStr},
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    thread,
+};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{error, info, warn};
+
+use crate::{config::Config, Error, Result};
+
+/// Watches the directory rather than the file, since editors often save by
+/// renaming a new file over the old one
+fn inotify(dir: &Path) -> Result<File> {
+    // SAFETY: inotify_init1 has no preconditions; the fd is owned right away
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    let dir_name = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| Error::Config(format!("Invalid config directory {}", dir.display())))?;
+    // SAFETY: fd is an open inotify instance and dir_name a valid C string
+    let wd = unsafe {
+        libc::inotify_add_watch(
+            fd,
+            dir_name.as_ptr(),
+            libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO,
+        )
+    };
+    if wd < 0 {
+        return Err(Error::Sysfs {
+            path: dir.to_path_buf(),
+            source: io::Error::last_os_error(),
+        });
+    }
+
+    Ok(file)
+}
+
+/// Names of the files touched in a buffer of inotify events
+fn changed_names(buf: &[u8]) -> Vec<&OsStr> {
+    let header = mem::size_of::<libc::inotify_event>();
+    let mut names = Vec::new();
+    let mut offset = 0;
+    while offset + header <= buf.len() {
+        // SAFETY: the kernel only writes whole events, and the header is read
+        // unaligned from within the buffer
+        let event =
+            unsafe { (buf.as_ptr().add(offset) as *const libc::inotify_event).read_unaligned() };
+        let name_start = offset + header;
+        let name_end = (name_start + event.len as usize).min(buf.len());
+        let name = &buf[name_start..name_end];
+        let name = &name[..name.iter().position(|x| *x == 0).unwrap_or(name.len())];
+        names.push(OsStr::from_bytes(name));
+        offset = name_end;
+    }
+    names
+}
+
+fn forward(path: &Path, instance: Option<&str>, sender: &Sender<Config>) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::Config(format!("Invalid config path {}", path.display())))?;
+    let mut inotify = inotify(dir)?;
+    info!("Watching {} for changes", path.display());
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = inotify.read(&mut buf)?;
+        if !changed_names(&buf[..len]).contains(&file_name) {
+            continue;
+        }
+
+        match Config::load_instance(Some(path), instance) {
+            Ok(config) => {
+                if sender.send(config).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => error!("Ignoring config change: {}", e),
+        }
+    }
+}
+
+/// Sends the config again every time the file changes, skipping edits that
+/// don't parse. The channel disconnects if the file can't be watched.
+pub fn watch(path: impl Into<PathBuf>) -> Receiver<Config> {
+    watch_instance(path, None)
+}
+
+/// Watches the config as [`watch`] does, loading it for the named instance
+pub fn watch_instance(path: impl Into<PathBuf>, instance: Option<String>) -> Receiver<Config> {
+    let path = path.into();
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        if let Err(e) = forward(&path, instance.as_deref(), &sender) {
+            warn!("Not watching {} for changes: {}", path.display(), e);
+        }
+    });
+    receiver
+}
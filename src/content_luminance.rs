@@ -0,0 +1,152 @@
+use std::{cell::Cell, process::Command};
+
+use log::{debug, warn};
+
+use crate::{config::ContentConfig, Error, Result};
+
+/// Average luminance of a binary PPM (P6) image, from 0 for black to 1 for white
+fn ppm_luminance(data: &[u8]) -> Result<f64> {
+    let invalid = |what: &str| Error::Sensor(format!("Invalid screen capture: {}", what));
+
+    // Header: magic, width, height, and maxval separated by whitespace, with
+    // comments, then a single whitespace byte before the pixels
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if data.get(pos) == Some(&b'#') {
+            while pos < data.len() && data[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(invalid("truncated header"));
+        }
+        fields.push(std::str::from_utf8(&data[start..pos]).map_err(|_| invalid("header"))?);
+    }
+    if fields[0] != "P6" {
+        return Err(invalid("not a binary PPM"));
+    }
+    let number = |x: &str| x.parse::<usize>().map_err(|_| invalid("header"));
+    let (width, height, max) = (number(fields[1])?, number(fields[2])?, number(fields[3])?);
+    if max == 0 || max > 255 {
+        return Err(invalid("unsupported maxval"));
+    }
+
+    let pixels = data
+        .get(pos + 1..pos + 1 + width * height * 3)
+        .ok_or_else(|| invalid("truncated pixels"))?;
+    if pixels.is_empty() {
+        return Err(invalid("empty image"));
+    }
+    let total = pixels
+        .chunks_exact(3)
+        .map(|x| 0.2126 * x[0] as f64 + 0.7152 * x[1] as f64 + 0.0722 * x[2] as f64)
+        .sum::<f64>();
+    Ok(total / (width * height) as f64 / max as f64)
+}
+
+/// Dims the screen further while its content is mostly dark, sampling a
+/// downscaled capture from an external command such as grim
+pub(crate) struct ContentLuminance {
+    command: Vec<String>,
+    strength: f64,
+    /// Failures are logged once, then the screen is left undimmed
+    warned: Cell<bool>,
+}
+
+impl ContentLuminance {
+    pub(crate) fn new(config: &ContentConfig) -> Result<Self> {
+        if config.command.is_empty() {
+            return Err(Error::Config("content command is empty".to_string()));
+        }
+        if !(0.0..=1.0).contains(&config.strength) {
+            return Err(Error::Config(format!(
+                "content strength {} is outside of 0 to 1",
+                config.strength
+            )));
+        }
+        Ok(Self {
+            command: config.command.clone(),
+            strength: config.strength,
+            warned: Cell::new(false),
+        })
+    }
+
+    fn sample(&self) -> Result<f64> {
+        let output = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::Sensor(format!(
+                "{} failed: {}",
+                self.command[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        ppm_luminance(&output.stdout)
+    }
+
+    /// Scales a screen percent down by up to `strength` for black content
+    pub(crate) fn adjust(&self, pct: u32) -> u32 {
+        let luminance = match self.sample() {
+            Ok(luminance) => {
+                self.warned.set(false);
+                luminance
+            }
+            Err(e) => {
+                if !self.warned.replace(true) {
+                    warn!("Not dimming for screen content: {}", e);
+                }
+                1.0
+            }
+        };
+        debug!("Screen content luminance: {:.3}", luminance);
+        blend(pct, luminance, self.strength)
+    }
+}
+
+fn blend(pct: u32, luminance: f64, strength: f64) -> u32 {
+    (pct as f64 * (1.0 - strength * (1.0 - luminance.clamp(0.0, 1.0)))).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ppm(pixels: &[[u8; 3]]) -> Vec<u8> {
+        let mut data = format!("P6\n# capture\n{} 1\n255\n", pixels.len()).into_bytes();
+        data.extend(pixels.iter().flatten());
+        data
+    }
+
+    #[test]
+    fn luminance_of_black_and_white() {
+        assert_eq!(ppm_luminance(&ppm(&[[0, 0, 0]])).unwrap(), 0.0);
+        let white = ppm_luminance(&ppm(&[[255, 255, 255]])).unwrap();
+        assert!((white - 1.0).abs() < 1e-9);
+        let half = ppm_luminance(&ppm(&[[0, 0, 0], [255, 255, 255]])).unwrap();
+        assert!((half - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn invalid_captures_are_errors() {
+        assert!(ppm_luminance(b"P3\n1 1\n255\n0 0 0").is_err());
+        assert!(ppm_luminance(b"P6\n2 2\n255\n\0\0\0").is_err());
+        assert!(ppm_luminance(b"").is_err());
+    }
+
+    #[test]
+    fn dark_content_dims() {
+        assert_eq!(blend(50, 1.0, 0.3), 50);
+        assert_eq!(blend(50, 0.0, 0.3), 35);
+        assert_eq!(blend(50, 0.5, 0.0), 50);
+    }
+}
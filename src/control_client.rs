@@ -1,43 +1,168 @@
-use std::{env, io::Write, os::unix::net::UnixStream, path::Path};
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+};
 
-use anyhow::Result;
-use byteorder::WriteBytesExt;
+use crate::{
+    command::{Appearance, Command, OutputKind},
+    config::Config,
+    control_server::configured_socket_path,
+    health::Status,
+    protocol::{
+        decode_profile_reply, decode_status, decode_version, status_outputs_len, ProfileEdit,
+        Request, Response, Version, PING_TIMEOUT, STATUS_LEN,
+    },
+    Error, Result,
+};
 
 pub struct ControlClient {
     client: UnixStream,
 }
 
 impl ControlClient {
-    pub fn new() -> Result<Self> {
-        let socket_path = Path::new(&env::temp_dir()).join("ambient_brightness.sock");
-        let client = std::os::unix::net::UnixStream::connect(socket_path)?;
+    /// Connects to the socket named in the config, or the default one
+    pub fn new(config: &Config) -> Result<Self> {
+        Self::connect(configured_socket_path(config))
+    }
+
+    pub fn connect(socket_path: impl AsRef<Path>) -> Result<Self> {
+        let client = UnixStream::connect(socket_path)?;
 
         Ok(Self { client })
     }
 
-    pub fn idle(&mut self) -> Result<()> {
-        self.client.write_u8(0)?;
+    /// Sends a request and waits for its reply, if it gets one. The daemon
+    /// serves a single request per connection.
+    pub fn request(&mut self, request: Request) -> Result<Response> {
+        self.write(&request)?;
+        match request {
+            Request::Command(_) => Ok(Response::Sent),
+            Request::Ping => Ok(Response::Status(self.read_status()?)),
+            Request::Version => Ok(Response::Version(self.read_version()?)),
+            Request::Profile(_) => Ok(Response::Profile(self.read_profile_reply()?)),
+        }
+    }
+
+    fn write(&mut self, request: &Request) -> Result<()> {
+        self.client.write_all(&request.encode()?)?;
         self.client.flush()?;
         Ok(())
     }
 
+    fn read_status(&mut self) -> Result<Status> {
+        self.client.set_read_timeout(Some(PING_TIMEOUT))?;
+        let mut reply = [0; STATUS_LEN];
+        self.client.read_exact(&mut reply)?;
+        let mut outputs = vec![0; status_outputs_len(&reply)];
+        self.client.read_exact(&mut outputs)?;
+        decode_status(&reply, &outputs)
+    }
+
+    fn read_version(&mut self) -> Result<Version> {
+        self.client.set_read_timeout(Some(PING_TIMEOUT))?;
+        let mut len = [0; 2];
+        self.client.read_exact(&mut len)?;
+        let mut body = vec![0; u16::from_be_bytes(len) as usize];
+        self.client.read_exact(&mut body)?;
+        decode_version(&body)
+    }
+
+    fn read_profile_reply(&mut self) -> Result<std::result::Result<(), String>> {
+        self.client.set_read_timeout(Some(PING_TIMEOUT))?;
+        let mut header = [0; 3];
+        self.client.read_exact(&mut header)?;
+        let mut message = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+        self.client.read_exact(&mut message)?;
+        Ok(decode_profile_reply(header[0], message))
+    }
+
+    pub fn send(&mut self, command: Command) -> Result<()> {
+        self.write(&Request::Command(command))
+    }
+
+    pub fn idle(&mut self) -> Result<()> {
+        self.send(Command::Idle)
+    }
+
     pub fn active(&mut self) -> Result<()> {
-        self.client.write_u8(1)?;
-        self.client.flush()?;
-        Ok(())
+        self.send(Command::Active)
     }
 
     pub fn increase(&mut self, amount: i8) -> Result<()> {
-        self.client.write_u8(2)?;
-        self.client.write_i8(amount)?;
-        self.client.flush()?;
-        Ok(())
+        self.send(Command::Increase(amount))
     }
 
     pub fn decrease(&mut self, amount: i8) -> Result<()> {
-        self.client.write_u8(3)?;
-        self.client.write_i8(amount)?;
-        self.client.flush()?;
-        Ok(())
+        self.send(Command::Decrease(amount))
+    }
+
+    /// Moves the screen to `percent` until the next offset change
+    pub fn set(&mut self, percent: u8) -> Result<()> {
+        self.send(Command::Set(percent))
+    }
+
+    /// Offsets only outputs of this kind, e.g. the keyboard up a level while
+    /// the screen stays put
+    pub fn nudge(&mut self, kind: OutputKind, amount: i8) -> Result<()> {
+        self.send(Command::Nudge(kind, amount))
+    }
+
+    /// Moves only outputs of this kind to `percent` until their next offset
+    /// change
+    pub fn set_output(&mut self, kind: OutputKind, percent: u8) -> Result<()> {
+        self.send(Command::SetOutput(kind, percent))
+    }
+
+    /// Hands outputs of this kind back to automatic control
+    pub fn enable(&mut self, kind: OutputKind) -> Result<()> {
+        self.send(Command::Enable(kind))
+    }
+
+    /// Stops the daemon from adjusting outputs of this kind until they're
+    /// enabled again, e.g. while another tool drives the keyboard
+    pub fn disable(&mut self, kind: OutputKind) -> Result<()> {
+        self.send(Command::Disable(kind))
+    }
+
+    /// Holds the desktop color scheme dark or light, or with `auto` lets it
+    /// follow the room again
+    pub fn appearance(&mut self, appearance: Appearance) -> Result<()> {
+        self.send(Command::Appearance(appearance))
+    }
+
+    /// Starts the daemon's filters over from the current reading
+    pub fn resync(&mut self) -> Result<()> {
+        self.send(Command::Resync)
+    }
+
+    /// Saves the outputs' levels and the mode under `name` in the daemon's
+    /// state file
+    pub fn snapshot(&mut self, name: &str) -> Result<()> {
+        self.send(Command::Snapshot(name.to_string()))
+    }
+
+    /// Brings back what was saved under `name`
+    pub fn restore(&mut self, name: &str) -> Result<()> {
+        self.send(Command::Restore(name.to_string()))
+    }
+
+    /// Asks the daemon how it's doing
+    pub fn ping(&mut self) -> Result<Status> {
+        self.write(&Request::Ping)?;
+        self.read_status()
+    }
+
+    /// Asks the daemon for its version and what it was built with
+    pub fn version(&mut self) -> Result<Version> {
+        self.write(&Request::Version)?;
+        self.read_version()
+    }
+
+    /// Merges TOML keys into a profile in the daemon's config file, failing
+    /// with the daemon's reason when they don't make a valid config
+    pub fn edit_profile(&mut self, edit: ProfileEdit) -> Result<()> {
+        self.write(&Request::Profile(edit))?;
+        self.read_profile_reply()?.map_err(Error::Protocol)
     }
 }
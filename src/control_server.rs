@@ -1,137 +1,666 @@
 use std::{
-    env, fs,
-    io::ErrorKind,
-    path::Path,
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    mem,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{chown, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
     sync::{
         atomic::{self, AtomicBool},
         Arc,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{debug, error, info, trace, warn};
+use mio::{
+    net::{UnixListener, UnixStream},
+    Events, Interest, Poll, Token, Waker,
+};
+
+use crate::{
+    backoff::retry,
+    command::Command,
+    config::{edit_profile, Config, RetryConfig},
+    health::Health,
+    privileges::find_gid,
+    protocol::{
+        check_snapshot_name, decode_appearance, decode_kind, encode_profile_reply, encode_status,
+        encode_version, ProfileEdit, Request, Version, ACTIVE, APPEARANCE, DECREASE, DISABLE,
+        ENABLE, IDLE, INCREASE, NUDGE, PING, PROFILE, RESTORE, RESYNC, SET, SET_OUTPUT, SNAPSHOT,
+        VERSION,
+    },
+    systemd, Error, Result,
 };
 
-use anyhow::Result;
-use byteorder::ReadBytesExt;
-use crossbeam::channel::{bounded, Receiver, Sender};
-use log::{debug, error, info, trace};
-use mio::{net::UnixListener, Events, Interest, Poll, Token};
-use retry::{delay::Fixed, retry, OperationResult};
+fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and always succeeds
+    unsafe { libc::getuid() }
+}
+
+/// Socket shared by the server and client unless another path is given, in
+/// the user's runtime directory, or one per user in the temp dir without it
+pub fn socket_path() -> PathBuf {
+    default_socket(env::var_os("XDG_RUNTIME_DIR"), None)
+}
+
+fn default_socket(runtime_dir: Option<OsString>, instance: Option<&str>) -> PathBuf {
+    let suffix = instance.map(|x| format!("-{}", x)).unwrap_or_default();
+    match runtime_dir.map(PathBuf::from).filter(|x| x.is_absolute()) {
+        Some(dir) => dir.join(format!("ambient_brightness{}.sock", suffix)),
+        None => env::temp_dir().join(format!(
+            "ambient_brightness-{}{}.sock",
+            current_uid(),
+            suffix
+        )),
+    }
+}
+
+/// Permissions of the control socket unless configured: the owner's alone,
+/// or its group's too when a group is given
+fn default_mode(group: Option<&str>) -> u32 {
+    match group {
+        Some(_) => 0o660,
+        None => 0o600,
+    }
+}
+
+/// Applies the configured mode and group to a freshly bound socket
+pub(crate) fn set_permissions(
+    socket_path: &Path,
+    mode: Option<u32>,
+    group: Option<&str>,
+) -> Result<()> {
+    if let Some(group) = group {
+        let gid = find_gid(group)?;
+        chown(socket_path, None, Some(gid)).map_err(|e| {
+            Error::Socket(format!(
+                "Couldn't give {} to group {}: {}",
+                socket_path.display(),
+                group,
+                e
+            ))
+        })?;
+    }
+    if let Some(mode) = mode {
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(mode))?;
+    }
+    debug!(
+        "Socket {} mode:{:?} group:{:?}",
+        socket_path.display(),
+        mode.map(|x| format!("{:o}", x)),
+        group
+    );
+    Ok(())
+}
+
+/// Process, user, and group on the other end of a connection
+pub(crate) fn peer_cred(socket: &impl AsRawFd) -> Option<libc::ucred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len describe a buffer of the size SO_PEERCRED fills in
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0).then_some(cred)
+}
 
-pub enum Command {
-    Idle,
-    Active,
-    Increase(i8),
-    Decrease(i8),
+/// One line per control request: when, from whom, and what came of it
+struct AuditLog {
+    file: File,
+    path: PathBuf,
+}
+
+impl AuditLog {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                Error::Config(format!("Couldn't open audit log {}: {}", path.display(), e))
+            })?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn record(&mut self, peer: Option<libc::ucred>, outcome: &Result<String>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let peer = match peer {
+            Some(cred) => format!("pid={} uid={} gid={}", cred.pid, cred.uid, cred.gid),
+            None => "pid=? uid=? gid=?".to_string(),
+        };
+        let outcome = match outcome {
+            Ok(request) => request.clone(),
+            Err(e) => format!("failed: {}", e),
+        };
+        let line = format!(
+            "{}.{:03} {} {}\n",
+            time.as_secs(),
+            time.subsec_millis(),
+            peer,
+            outcome
+        );
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            error!("Couldn't write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Socket named in the config, or the default one, with the instance's name
+/// in it for named instances
+pub(crate) fn configured_socket_path(config: &Config) -> PathBuf {
+    match &config.control.socket {
+        Some(socket) => socket.clone(),
+        None => default_socket(env::var_os("XDG_RUNTIME_DIR"), config.instance.as_deref()),
+    }
+}
+
+/// Removes a socket left behind by an earlier run, as long as it is ours
+pub(crate) fn remove_stale(socket_path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(socket_path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let uid = current_uid();
+    if metadata.uid() != uid {
+        return Err(Error::Socket(format!(
+            "{} belongs to uid {}, not {}; refusing to remove it",
+            socket_path.display(),
+            metadata.uid(),
+            uid
+        )));
+    }
+
+    fs::remove_file(socket_path)?;
+    Ok(())
+}
+
+/// How long a connection may take to send its request and read the reply
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes read from a connection at once
+const READ_CHUNK: usize = 512;
+
+/// Longest request there is: a profile edit with the longest name and overlay
+const MAX_REQUEST: usize = 1 + 1 + 1 + u8::MAX as usize + 2 + u16::MAX as usize;
+
+/// Connections served at once, past which new ones are closed right away
+const MAX_CONNECTIONS: usize = 64;
+
+/// A complete request, or an opcode this daemon doesn't know
+enum Decoded {
+    Request(Request),
+    Unknown(u8),
+}
+
+fn decode_string(reader: &mut &[u8], len: usize) -> Result<String> {
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| Error::Protocol(format!("Request isn't UTF-8: {}", e)))
+}
+
+fn decode_request(reader: &mut &[u8]) -> Result<Decoded> {
+    let command = match reader.read_u8()? {
+        IDLE => Command::Idle,
+        ACTIVE => Command::Active,
+        INCREASE => Command::Increase(reader.read_i8()?),
+        DECREASE => Command::Decrease(reader.read_i8()?),
+        SET => Command::Set(reader.read_u8()?),
+        NUDGE => {
+            let kind = decode_kind(reader.read_u8()?)?;
+            Command::Nudge(kind, reader.read_i8()?)
+        }
+        SET_OUTPUT => {
+            let kind = decode_kind(reader.read_u8()?)?;
+            Command::SetOutput(kind, reader.read_u8()?)
+        }
+        ENABLE => Command::Enable(decode_kind(reader.read_u8()?)?),
+        DISABLE => Command::Disable(decode_kind(reader.read_u8()?)?),
+        APPEARANCE => Command::Appearance(decode_appearance(reader.read_u8()?)?),
+        RESYNC => Command::Resync,
+        SNAPSHOT => {
+            let len = reader.read_u8()? as usize;
+            let name = decode_string(reader, len)?;
+            check_snapshot_name(&name)?;
+            Command::Snapshot(name)
+        }
+        RESTORE => {
+            let len = reader.read_u8()? as usize;
+            let name = decode_string(reader, len)?;
+            check_snapshot_name(&name)?;
+            Command::Restore(name)
+        }
+        PING => return Ok(Decoded::Request(Request::Ping)),
+        VERSION => return Ok(Decoded::Request(Request::Version)),
+        PROFILE => {
+            let activate = reader.read_u8()? != 0;
+            let name_len = reader.read_u8()? as usize;
+            let name = decode_string(reader, name_len)?;
+            let overlay_len = reader.read_u16::<BigEndian>()? as usize;
+            let overlay = decode_string(reader, overlay_len)?;
+            return Ok(Decoded::Request(Request::Profile(ProfileEdit {
+                name,
+                overlay,
+                activate,
+            })));
+        }
+        opcode => return Ok(Decoded::Unknown(opcode)),
+    };
+    Ok(Decoded::Request(Request::Command(command)))
+}
+
+/// The request in the bytes received so far, or None while it's incomplete
+fn decode(mut buf: &[u8]) -> Result<Option<Decoded>> {
+    match decode_request(&mut buf) {
+        Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+/// An accepted connection, read and answered as its socket becomes ready so
+/// a slow client doesn't hold up the others
+struct Connection {
+    socket: UnixStream,
+    /// Bytes of the request so far
+    request: Vec<u8>,
+    /// Once handled: the reply, how much of it was sent, and the outcome
+    reply: Option<(Vec<u8>, usize, Result<String>)>,
+    deadline: Instant,
+}
+
+impl Connection {
+    fn new(socket: UnixStream, now: Instant) -> Self {
+        Self {
+            socket,
+            request: Vec::new(),
+            reply: None,
+            deadline: now + REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Reads what arrived, returning the request once it's complete, or
+    /// failing when the client hung up before sending all of it
+    fn read(&mut self) -> Result<Option<Decoded>> {
+        let mut closed = false;
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match self.socket.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(len) => {
+                    self.request.extend_from_slice(&chunk[..len]);
+                    if self.request.len() > MAX_REQUEST {
+                        return Err(Error::Protocol("Request too long".to_string()));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match decode(&self.request)? {
+            None if closed => Err(Error::Protocol("Request cut short".to_string())),
+            decoded => Ok(decoded),
+        }
+    }
+
+    /// Sends what the socket takes of the reply, returning the outcome once
+    /// all of it went out
+    fn write(&mut self) -> Option<Result<String>> {
+        let (reply, written, _) = self.reply.as_mut()?;
+        while *written < reply.len() {
+            match self.socket.write(&reply[*written..]) {
+                Ok(len) => *written += len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("Reply Error: {:?}", e);
+                    let (_, _, outcome) = self.reply.take()?;
+                    return Some(outcome.map(|x| format!("{} not answered: {}", x, e)));
+                }
+            }
+        }
+        self.reply.take().map(|(_, _, outcome)| outcome)
+    }
+}
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// Stops a running [`ControlServer`] right away, from any thread
+#[derive(Clone)]
+pub struct Stopper {
+    exit_bool: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl Stopper {
+    pub fn stop(&self) -> Result<()> {
+        self.exit_bool.store(true, atomic::Ordering::Relaxed);
+        self.waker.wake()?;
+        Ok(())
+    }
 }
 
 pub struct ControlServer {
     poll: Poll,
     listener: UnixListener,
+    stopper: Stopper,
     command_sender: Sender<Command>,
+    health: Arc<Health>,
+    version: Version,
+    retry: RetryConfig,
+    /// Where profile edits are saved
+    config_file: Option<PathBuf>,
+    audit: Option<AuditLog>,
 }
 
 impl ControlServer {
-    pub fn new() -> Result<(Self, Receiver<Command>)> {
-        let socket_path = Path::new(&env::temp_dir()).join("ambient_brightness.sock");
-        match fs::remove_file(socket_path.clone()) {
-            Ok(()) => (),
-            Err(e) if e.kind() == ErrorKind::NotFound => (),
-            err => err?,
-        };
-        let mut listener = UnixListener::bind(socket_path)?;
+    /// Serves on the socket systemd passes when socket-activated, or else on
+    /// the configured one
+    pub fn new(config: &Config, health: Arc<Health>) -> Result<(Self, Receiver<Command>)> {
+        if let Some(listener) = systemd::listener() {
+            info!("Using the control socket passed by systemd");
+            listener.set_nonblocking(true)?;
+            return Self::serve(UnixListener::from_std(listener), config, health);
+        }
+        Self::bind(configured_socket_path(config), config, health)
+    }
+
+    pub fn bind(
+        socket_path: impl AsRef<Path>,
+        config: &Config,
+        health: Arc<Health>,
+    ) -> Result<(Self, Receiver<Command>)> {
+        let socket_path = socket_path.as_ref();
+        remove_stale(socket_path)?;
+        let listener = UnixListener::bind(socket_path)?;
+        let group = config.control.group.as_deref();
+        let mode = config.control.mode.unwrap_or_else(|| default_mode(group));
+        set_permissions(socket_path, Some(mode), group)?;
+        Self::serve(listener, config, health)
+    }
+
+    fn serve(
+        mut listener: UnixListener,
+        config: &Config,
+        health: Arc<Health>,
+    ) -> Result<(Self, Receiver<Command>)> {
         let poll = Poll::new()?;
         poll.registry().register(
             &mut listener,
-            Token(0),
+            LISTENER,
             Interest::READABLE | Interest::WRITABLE,
         )?;
-        let (command_sender, command_receiver) = bounded(1);
+        let stopper = Stopper {
+            exit_bool: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Waker::new(poll.registry(), WAKER)?),
+        };
+        // Sending never blocks the other connections, and the controller
+        // coalesces whatever piles up
+        let (command_sender, command_receiver) = unbounded();
+        let audit = config
+            .control
+            .audit
+            .as_deref()
+            .map(AuditLog::open)
+            .transpose()?;
 
         Ok((
             Self {
                 poll,
                 listener,
+                stopper,
                 command_sender,
+                health,
+                version: Version::current(config.sensor.kind()),
+                retry: config.retry.clone(),
+                config_file: None,
+                audit,
             },
             command_receiver,
         ))
     }
 
-    pub fn run(mut self, exit_bool: Arc<AtomicBool>) -> JoinHandle<Result<()>> {
+    /// Saves profile edits to this config file, the one the daemon was started
+    /// with. Without one, they are refused.
+    pub fn config_file(mut self, path: Option<PathBuf>) -> Self {
+        self.config_file = path;
+        self
+    }
+
+    fn edit_profile(&self, name: &str, overlay: &str, activate: bool) -> Result<()> {
+        let path = self.config_file.as_deref().ok_or_else(|| {
+            Error::Config("the daemon was started without a config file".to_string())
+        })?;
+        if name.is_empty() {
+            return Err(Error::Config("profiles need a name".to_string()));
+        }
+        let overlay = toml::from_str(overlay)
+            .map_err(|e| Error::Config(format!("Couldn't parse the profile: {}", e)))?;
+        edit_profile(path, name, overlay, activate)
+    }
+
+    pub fn stopper(&self) -> Stopper {
+        self.stopper.clone()
+    }
+
+    /// Sends commands to the controller alongside the socket's, e.g. from
+    /// [`crate::acpi_events::watch`]
+    pub fn command_sender(&self) -> Sender<Command> {
+        self.command_sender.clone()
+    }
+
+    /// Carries out a request, returning the reply to send and a description
+    /// of the request and its outcome
+    fn respond(&self, decoded: Decoded) -> (Vec<u8>, Result<String>) {
+        let request = match decoded {
+            Decoded::Request(request) => request,
+            Decoded::Unknown(opcode) => {
+                return (Vec::new(), Ok(format!("ignored opcode {}", opcode)))
+            }
+        };
+        debug!("Got Request: {:?}", request);
+
+        match request {
+            Request::Command(command) => {
+                let described = format!("command {}", command);
+                let outcome = self
+                    .command_sender
+                    .send(command)
+                    .map(|()| described)
+                    .map_err(|_| Error::Protocol("Command channel closed".to_string()));
+                (Vec::new(), outcome)
+            }
+            Request::Ping => (encode_status(&self.health.status()), Ok("ping".to_string())),
+            Request::Version => (encode_version(&self.version), Ok("version".to_string())),
+            Request::Profile(edit) => {
+                let result = self.edit_profile(&edit.name, &edit.overlay, edit.activate);
+                let outcome = match &result {
+                    Ok(()) => format!("profile {} saved", edit.name),
+                    Err(e) => format!("profile {} not saved: {}", edit.name, e),
+                };
+                (encode_profile_reply(&result), Ok(outcome))
+            }
+        }
+    }
+
+    /// Moves a connection along, returning its outcome once it's done
+    fn advance(&self, connection: &mut Connection) -> Option<Result<String>> {
+        if connection.reply.is_none() {
+            match connection.read() {
+                Ok(Some(decoded)) => {
+                    let (reply, outcome) = self.respond(decoded);
+                    connection.reply = Some((reply, 0, outcome));
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        connection.write()
+    }
+
+    /// Closes a connection, logging what came of it
+    fn finish(&mut self, mut connection: Connection, outcome: Result<String>) {
+        let _ = self.poll.registry().deregister(&mut connection.socket);
+        if let Some(audit) = &mut self.audit {
+            audit.record(peer_cred(&connection.socket), &outcome);
+        }
+        // One client's bad request shouldn't take control away from the rest
+        if let Err(e) = outcome {
+            warn!("Request Error: {}", e);
+        }
+    }
+
+    /// Accepts every pending connection, since events are edge triggered
+    fn accept(&mut self, connections: &mut HashMap<Token, Connection>, next: &mut usize) {
+        loop {
+            let accepted = retry(&self.retry, "Accept", || match self.listener.accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+                accepted => accepted.map(Some),
+            });
+            // Failing to take one connection shouldn't stop serving the rest
+            let (mut socket, _addr) = match accepted {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Accept Error: {:?}", e);
+                    return;
+                }
+            };
+            if connections.len() >= MAX_CONNECTIONS {
+                warn!(
+                    "Closing a connection past the {} being served",
+                    MAX_CONNECTIONS
+                );
+                continue;
+            }
+
+            let token = Token(*next);
+            *next += 1;
+            if let Err(e) = self.poll.registry().register(
+                &mut socket,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                error!("Couldn't watch a connection: {:?}", e);
+                continue;
+            }
+            connections.insert(token, Connection::new(socket, Instant::now()));
+        }
+    }
+
+    pub fn run(mut self) -> JoinHandle<Result<()>> {
         thread::spawn(move || {
             let mut events = Events::with_capacity(1024);
+            let mut connections = HashMap::new();
+            let mut next = WAKER.0 + 1;
 
             loop {
-                if exit_bool.load(atomic::Ordering::Relaxed) {
+                if self.stopper.exit_bool.load(atomic::Ordering::Relaxed) {
                     info!("Control Server Shutting Down");
                     break;
                 }
 
-                retry(Fixed::from_millis(100), || {
-                    match self
-                        .poll
-                        .poll(&mut events, Some(Duration::from_millis(100)))
-                    {
-                        Ok(_) => OperationResult::Ok(()),
-                        Err(e) => match e.kind() {
-                            ErrorKind::Interrupted => OperationResult::Retry(e),
-                            _ => {
-                                error!("Poll Error: {:?}", e);
-                                OperationResult::Err(e)
-                            }
-                        },
+                // Wakes for the first connection to run out of time
+                let timeout = connections
+                    .values()
+                    .map(|x: &Connection| x.deadline.saturating_duration_since(Instant::now()))
+                    .min();
+                // Signals interrupt polls without anything having failed
+                loop {
+                    match self.poll.poll(&mut events, timeout) {
+                        Ok(_) => break,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                        Err(e) => {
+                            error!("Poll Error: {:?}", e);
+                            return Err(e.into());
+                        }
                     }
-                })?;
+                }
 
                 for event in &events {
                     trace!("Event: {:?}", event);
-
-                    if event.token() == Token(0) && event.is_readable() {
-                        let (mut socket, _addr) = retry(Fixed::from_millis(100).take(3), || {
-                            match self.listener.accept() {
-                                Err(e) => match e.kind() {
-                                    ErrorKind::Interrupted => OperationResult::Retry(e),
-                                    _ => {
-                                        error!("Accept Error: {:?}", e);
-                                        OperationResult::Err(e)
-                                    }
-                                },
-                                Ok(socket_addr) => OperationResult::Ok(socket_addr),
-                            }
-                        })?;
-
-                        let socket_read =
-                            retry(Fixed::from_millis(100).take(3), || match socket.read_u8() {
-                                Err(e) => match e.kind() {
-                                    ErrorKind::Interrupted => OperationResult::Retry(e),
-                                    _ => {
-                                        error!("Read Error: {:?}", e);
-                                        OperationResult::Err(e)
-                                    }
-                                },
-                                Ok(socket_read) => OperationResult::Ok(socket_read),
-                            })?;
-
-                        debug!("Got Message: {}", socket_read);
-
-                        match socket_read {
-                            0 => self.command_sender.send(Command::Idle)?,
-                            1 => self.command_sender.send(Command::Active)?,
-                            2 => {
-                                let amount = socket.read_i8()?;
-                                self.command_sender.send(Command::Increase(amount))?
+                    match event.token() {
+                        LISTENER => self.accept(&mut connections, &mut next),
+                        WAKER => (),
+                        token => {
+                            let Some(mut connection) = connections.remove(&token) else {
+                                continue;
+                            };
+                            match self.advance(&mut connection) {
+                                Some(outcome) => self.finish(connection, outcome),
+                                None => {
+                                    connections.insert(token, connection);
+                                }
                             }
-                            3 => {
-                                let amount = socket.read_i8()?;
-                                self.command_sender.send(Command::Decrease(amount))?
-                            }
-                            _ => (),
                         }
                     }
                 }
+
+                let now = Instant::now();
+                let expired = connections
+                    .iter()
+                    .filter(|(_, x)| x.deadline <= now)
+                    .map(|(token, _)| *token)
+                    .collect::<Vec<_>>();
+                for token in expired {
+                    let connection = connections.remove(&token).expect("expired connection");
+                    self.finish(
+                        connection,
+                        Err(Error::Protocol("Request timed out".to_string())),
+                    );
+                }
             }
 
             Ok(())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockets_go_in_the_runtime_dir() {
+        let runtime = Some(OsString::from("/run/user/1000"));
+        assert_eq!(
+            default_socket(runtime.clone(), None),
+            Path::new("/run/user/1000/ambient_brightness.sock")
+        );
+        assert_eq!(
+            default_socket(runtime, Some("seat1")),
+            Path::new("/run/user/1000/ambient_brightness-seat1.sock")
+        );
+        let fallback = default_socket(Some(OsString::from("relative")), None);
+        assert_eq!(fallback.parent(), Some(env::temp_dir().as_path()));
+    }
+}
@@ -0,0 +1,1461 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Write},
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crossbeam::{
+    channel::{never, Receiver},
+    select,
+};
+use log::{debug, error, info, trace, warn};
+use ouroboros::self_referencing;
+
+#[cfg(feature = "bulb")]
+use crate::bulb::BulbBrightness;
+#[cfg(feature = "content")]
+use crate::content_luminance::ContentLuminance;
+#[cfg(feature = "kbd")]
+use crate::external_keyboard::ExternalKeyboards;
+#[cfg(feature = "hid")]
+use crate::hid_brightness::HidBrightness;
+#[cfg(feature = "kbd")]
+use crate::kbd_brightness::{detect_kbd_led, KBDBrightness};
+#[cfg(all(feature = "macos", target_os = "macos"))]
+use crate::macos::MacDisplay;
+#[cfg(feature = "screen")]
+use crate::SCREEN_SUBSYSTEM;
+use crate::{
+    ambient_brightness::AmbientBrightness,
+    brightness_writer::BrightnessWriter,
+    clock::{Clock, MockClock, SystemClock},
+    command::{Command, OutputKind},
+    config::Config,
+    environment::EnvironmentEvents,
+    health::{Health, Level, Mode, MonitoredSensor},
+    led_brightness::LEDBrightness,
+    orientation::Accelerometer,
+    output::{restore_all, Degradable, Output, Tuned, Tuning},
+    privileges::drop_privileges,
+    record::{Event, Recorder, RecordingSensor},
+    redact::{self, Lux},
+    sensor::{self, Sensor},
+    snapshot::{self, Snapshot},
+    sysfs::{Device, Sysfs},
+    systemd,
+    watchdog::{Heartbeat, Watchdog},
+    Error, Result,
+};
+#[cfg(feature = "dbus")]
+use crate::{
+    ambient_brightness::Sample,
+    dbus_service::{DbusService, Properties},
+    output::Report,
+};
+#[cfg(feature = "screen")]
+use crate::{
+    config::ConflictPolicy,
+    desktop::{self, Conflict},
+    output_power::OutputPower,
+    screen_brightness::ScreenBrightness,
+};
+
+/// Tracks when updates stop: while the session is locked or the screen is
+/// captured, or once it has been idle for longer than `after`
+struct Suspension {
+    after: Option<Duration>,
+    idle_since: Option<Instant>,
+    locked: bool,
+    capturing: bool,
+    suspended: bool,
+}
+
+impl Suspension {
+    /// Whether something besides idling keeps updates stopped
+    fn held(&self) -> bool {
+        self.locked || self.capturing
+    }
+
+    fn due(&self, now: Instant) -> bool {
+        self.held()
+            || match (self.idle_since, self.after) {
+                (Some(since), Some(after)) => now.duration_since(since) >= after,
+                _ => false,
+            }
+    }
+}
+
+/// Time between sensor reads, unless the config sets `interval`
+const TICK: Duration = Duration::from_secs(5);
+
+/// Time between steps of a crossfade
+const FADE_STEP: Duration = Duration::from_millis(250);
+
+/// How long increase and decrease commands are gathered into one, e.g. while
+/// a brightness key is held
+const BURST: Duration = Duration::from_millis(100);
+
+/// Quiet time after a hotplug event before looking for devices, so a burst
+/// of events leads to a single rescan
+const HOTPLUG_SETTLE: Duration = Duration::from_millis(500);
+
+/// What woke up the run loop
+enum Step {
+    Tick,
+    Fade,
+    Command(Command),
+    Lock(bool),
+    Capture(bool),
+    Reload(Box<Config>),
+    Hotplug,
+}
+
+impl Step {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Tick => "a tick",
+            Self::Fade => "a crossfade step",
+            Self::Command(_) => "a command",
+            Self::Lock(_) => "a lock change",
+            Self::Capture(_) => "a screen capture change",
+            Self::Reload(_) => "a config reload",
+            Self::Hotplug => "a device rescan",
+        }
+    }
+}
+
+/// Everything the run loop waits on besides its ticker
+struct Channels {
+    close: Receiver<()>,
+    command: Receiver<Command>,
+    lock: Receiver<bool>,
+    capture: Receiver<bool>,
+    reload: Receiver<Config>,
+    hotplug: Receiver<()>,
+}
+
+/// What the controller was built with, kept to apply config changes
+struct Settings {
+    config: Config,
+    clock: Arc<dyn Clock>,
+    sysfs: Sysfs,
+    recorder: Option<Arc<Recorder>>,
+    health: Arc<Health>,
+    dry_run: bool,
+    hid: bool,
+    /// The builder's sensor stays in use whatever the config says
+    custom_sensor: bool,
+    /// Holds brightness while the screen faces down
+    accelerometer: Option<Accelerometer>,
+    environment: Option<EnvironmentEvents>,
+    /// Outputs left to other tools by disable commands, also after reloads
+    disabled: HashSet<OutputKind>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<DbusService>,
+    /// Desktop service also setting the screen brightness, found at startup
+    #[cfg(feature = "screen")]
+    conflict: Option<&'static Conflict>,
+}
+
+impl Settings {
+    fn record_device(&self, device: &Device) -> Result<()> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&Event::Device {
+                subsystem: device.subsystem.clone(),
+                name: device.name.clone(),
+                max_brightness: device.max_brightness,
+                brightness: device.brightness()?,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn open_accelerometer(&self, config: &Config) -> Result<Option<Accelerometer>> {
+        config
+            .orientation
+            .as_ref()
+            .map(|orientation| Accelerometer::new(&self.sysfs, orientation))
+            .transpose()
+    }
+
+    fn environment_events(&self, config: &Config) -> Result<Option<EnvironmentEvents>> {
+        config
+            .environment
+            .as_ref()
+            .map(|environment| {
+                EnvironmentEvents::new(environment, self.clock.clone(), self.dry_run)
+            })
+            .transpose()
+    }
+
+    #[cfg(feature = "dbus")]
+    fn dbus_service(&self, config: &Config) -> Result<Option<DbusService>> {
+        config
+            .dbus
+            .then(|| DbusService::new(self.health.clone()))
+            .transpose()
+    }
+
+    fn open_sensor(&self, config: &Config) -> Result<Box<dyn Sensor>> {
+        let sensor = sensor::selected(&self.sysfs, config)?;
+        Ok(self.wrap_sensor(config, sensor))
+    }
+
+    /// Tracks the sensor's health and, outside of privacy mode, records its readings
+    fn wrap_sensor(&self, config: &Config, sensor: Box<dyn Sensor>) -> Box<dyn Sensor> {
+        redact::set_private(config.privacy);
+        let sensor = Box::new(MonitoredSensor::new(sensor, self.health.clone()));
+        match &self.recorder {
+            Some(_) if config.privacy => {
+                info!("Privacy mode, leaving sensor readings out of the recording");
+                sensor
+            }
+            Some(recorder) => Box::new(RecordingSensor::new(sensor, recorder.clone())),
+            None => sensor,
+        }
+    }
+
+    /// Whether to drive the screen, given what the desktop is doing with it
+    #[cfg(feature = "screen")]
+    fn drives_screen(&self, config: &Config) -> bool {
+        let Some(conflict) = self.conflict else {
+            return true;
+        };
+        match config.screen.on_conflict {
+            ConflictPolicy::Warn => {
+                warn!(
+                    "{} also adjusts the screen brightness, so the two will fight over it. \
+                     Either {}, or set on_conflict = \"yield\" under [screen] to leave the \
+                     screen to it",
+                    conflict.desktop, conflict.disable
+                );
+                true
+            }
+            ConflictPolicy::Yield => {
+                info!(
+                    "{} adjusts the screen brightness, only driving keyboard and LED \
+                     backlights and reporting ambient readings in status",
+                    conflict.desktop
+                );
+                false
+            }
+        }
+    }
+
+    fn open_devices(&self, config: &Config) -> Result<Devices> {
+        #[cfg(feature = "kbd")]
+        let kbd = self.sysfs.device(
+            "leds",
+            &detect_kbd_led(&self.sysfs, &config.kbd)?,
+            config.kbd.backend,
+        )?;
+        #[cfg(feature = "kbd")]
+        self.record_device(&kbd)?;
+        #[cfg(feature = "screen")]
+        let screen = if !self.drives_screen(config) {
+            None
+        } else {
+            // Docked laptops and desktops may only get one later, e.g. a
+            // monitor with a backlight, picked up on hotplug
+            match config.screen.backlight(&self.sysfs) {
+                Ok(name) if self.sysfs.class(SCREEN_SUBSYSTEM).join(&name).exists() => {
+                    let screen =
+                        self.sysfs
+                            .device(SCREEN_SUBSYSTEM, &name, config.screen.backend)?;
+                    self.record_device(&screen)?;
+                    let power = OutputPower::new(&config.screen.power, &self.sysfs, &name);
+                    Some((screen, power))
+                }
+                Ok(name) => {
+                    warn!(
+                        "No screen backlight {} yet, driving it once it appears",
+                        name
+                    );
+                    None
+                }
+                Err(e) => {
+                    warn!(
+                        "No screen backlight yet, driving one once it appears: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        };
+        let leds = config
+            .led
+            .iter()
+            .map(|led| {
+                let device = self.sysfs.device("leds", &led.name, led.backend)?;
+                self.record_device(&device)?;
+                Ok(device)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Devices {
+            #[cfg(feature = "kbd")]
+            kbd,
+            #[cfg(feature = "kbd")]
+            external: config
+                .kbd
+                .external
+                .as_ref()
+                .and_then(|x| ExternalKeyboards::new(&self.sysfs, x, self.clock.clone())),
+            #[cfg(feature = "screen")]
+            screen,
+            leds,
+        })
+    }
+}
+
+/// Output devices, opened before the outputs borrow the writer
+struct Devices {
+    #[cfg(feature = "kbd")]
+    kbd: Device,
+    /// Followed to turn the keyboard off while they're typed on
+    #[cfg(feature = "kbd")]
+    external: Option<ExternalKeyboards>,
+    /// With its output's power state, None while leaving the screen to the desktop
+    #[cfg(feature = "screen")]
+    screen: Option<(Device, Option<OutputPower>)>,
+    /// In config order
+    leds: Vec<Device>,
+}
+
+/// Every configured output, in the order they are adjusted. `initial` starts
+/// the filters of outputs with their own.
+#[cfg_attr(not(any(feature = "hid", feature = "bulb")), allow(unused_variables))]
+fn outputs<'w>(
+    writer: &'w BrightnessWriter,
+    devices: Devices,
+    config: &Config,
+    initial: f64,
+    clock: &Arc<dyn Clock>,
+    hid: bool,
+    dry_run: bool,
+) -> Result<Vec<Tuned<'w>>> {
+    let mut outputs: Vec<(Box<dyn Output>, OutputKind, Tuning)> = Vec::new();
+    #[cfg(feature = "kbd")]
+    outputs.push((
+        Box::new(KBDBrightness::new(
+            writer,
+            devices.kbd,
+            config.kbd.curve.clone(),
+            config.limits.kbd,
+            devices.external,
+        )?),
+        OutputKind::Kbd,
+        config.kbd.tuning(),
+    ));
+    #[cfg(feature = "screen")]
+    if let Some((screen, power)) = devices.screen {
+        let output = Box::new(ScreenBrightness::new(
+            writer,
+            screen,
+            config.screen.curve(),
+            #[cfg(feature = "content")]
+            config
+                .content
+                .as_ref()
+                .map(ContentLuminance::new)
+                .transpose()?,
+            config.limits.screen,
+            config.min_delta,
+            power,
+        ));
+        outputs.push((output, OutputKind::Screen, config.screen.tuning()));
+    }
+    // Without sysfs, the built-in display stands in for the backlight
+    #[cfg(all(feature = "macos", target_os = "macos"))]
+    outputs.push((
+        Box::new(MacDisplay::new(
+            config.screen.curve(),
+            config.limits.screen,
+            config.min_delta,
+        )?),
+        OutputKind::Screen,
+        config.screen.tuning(),
+    ));
+    for (device, led) in devices.leds.into_iter().zip(&config.led) {
+        outputs.push((
+            Box::new(LEDBrightness::new(
+                writer,
+                device,
+                led.curve.clone(),
+                config.min_delta,
+            )),
+            OutputKind::Led,
+            led.tuning(),
+        ));
+    }
+    #[cfg(feature = "hid")]
+    for hid in config.hid.iter().filter(|_| hid) {
+        outputs.push((
+            Box::new(HidBrightness::new(hid, config.kbd.curve.clone(), dry_run)?),
+            OutputKind::Hid,
+            config.kbd.tuning(),
+        ));
+    }
+    #[cfg(feature = "bulb")]
+    for bulb in &config.bulb {
+        outputs.push((
+            Box::new(BulbBrightness::new(
+                bulb,
+                config.min_delta,
+                &config.retry,
+                dry_run,
+            )?),
+            OutputKind::Bulb,
+            bulb.tuning(),
+        ));
+    }
+    #[cfg(not(feature = "bulb"))]
+    if !config.bulb.is_empty() {
+        return Err(Error::Config(
+            "Smart bulb support was not compiled in".to_string(),
+        ));
+    }
+    #[cfg(not(feature = "content"))]
+    if config.content.is_some() {
+        return Err(Error::Config(
+            "Screen content dimming was not compiled in".to_string(),
+        ));
+    }
+    #[cfg(not(feature = "hid"))]
+    if hid && !config.hid.is_empty() {
+        return Err(Error::Config("HID support was not compiled in".to_string()));
+    }
+    #[cfg(not(feature = "dbus"))]
+    if config.dbus {
+        return Err(Error::Config(
+            "D-Bus support was not compiled in".to_string(),
+        ));
+    }
+    let darkness = config.darkness.as_ref();
+    if let Some(darkness) = darkness {
+        if darkness.below > darkness.above {
+            return Err(Error::Config(format!(
+                "darkness below {} is higher than above {}",
+                darkness.below, darkness.above
+            )));
+        }
+    }
+    let crossfade = Duration::from_secs(config.crossfade);
+    outputs
+        .into_iter()
+        .map(|(output, kind, tuning)| {
+            Tuned::new(
+                Degradable::new(output),
+                kind,
+                tuning,
+                initial,
+                crossfade,
+                clock.clone(),
+            )
+            .map(|x| x.darkness(darkness.and_then(|d| d.for_kind(kind))))
+        })
+        .collect()
+}
+
+#[self_referencing]
+struct AmbientBrightnessController {
+    ambient_brightness: AmbientBrightness,
+    writer: BrightnessWriter,
+    #[borrows(writer)]
+    #[not_covariant]
+    outputs: Vec<Tuned<'this>>,
+    settings: Settings,
+    suspension: Suspension,
+    channels: Channels,
+}
+
+impl AmbientBrightnessController {
+    fn create(builder: Builder) -> Result<Self> {
+        let Builder {
+            config,
+            dry_run,
+            clock,
+            sysfs,
+            sensor,
+            recorder,
+            health,
+            hid,
+            close_receiver,
+            command_receiver,
+            lock_receiver,
+            capture_receiver,
+            reload_receiver,
+            hotplug_receiver,
+            ready_fd: _,
+        } = builder;
+        let health = health.unwrap_or_else(|| Arc::new(Health::new(clock.clone())));
+        let writer = BrightnessWriter::new(dry_run, recorder.clone(), health.clone(), config);
+        let mut settings = Settings {
+            config: config.clone(),
+            clock,
+            sysfs,
+            recorder,
+            health,
+            dry_run,
+            hid,
+            custom_sensor: sensor.is_some(),
+            accelerometer: None,
+            environment: None,
+            disabled: HashSet::new(),
+            #[cfg(feature = "dbus")]
+            dbus: None,
+            #[cfg(feature = "screen")]
+            conflict: desktop::conflict(),
+        };
+        settings.accelerometer = settings.open_accelerometer(config)?;
+        settings.environment = settings.environment_events(config)?;
+        #[cfg(feature = "dbus")]
+        {
+            settings.dbus = settings.dbus_service(config)?;
+        }
+
+        let sensor = match sensor {
+            Some(sensor) => settings.wrap_sensor(config, sensor),
+            None => settings.open_sensor(config)?,
+        };
+        let mut ambient_brightness =
+            AmbientBrightness::new(sensor, config.filter.clone()).init()?;
+        ambient_brightness.resync_above(config.resync_above);
+        let devices = settings.open_devices(config)?;
+        let initial = ambient_brightness.level();
+        let clock = settings.clock.clone();
+
+        let controller = Self::try_new(
+            ambient_brightness,
+            writer,
+            |writer: &BrightnessWriter| {
+                outputs(writer, devices, config, initial, &clock, hid, dry_run)
+            },
+            settings,
+            Suspension {
+                after: config.suspend_after.map(Duration::from_secs),
+                idle_since: None,
+                locked: false,
+                capturing: false,
+                suspended: false,
+            },
+            Channels {
+                close: close_receiver,
+                command: command_receiver,
+                lock: lock_receiver,
+                capture: capture_receiver,
+                reload: reload_receiver,
+                hotplug: hotplug_receiver,
+            },
+        )?;
+        drop_privileges(config)?;
+        Ok(controller)
+    }
+
+    fn record(&self, event: &Event) -> Result<()> {
+        match &self.borrow_settings().recorder {
+            Some(recorder) => recorder.record(event),
+            None => Ok(()),
+        }
+    }
+
+    /// Reports whether brightness follows the sensor, for pings
+    fn report_mode(&self) {
+        let suspension = self.borrow_suspension();
+        let mode = if suspension.suspended {
+            Mode::Suspended
+        } else if suspension.idle_since.is_some() {
+            Mode::Idle
+        } else {
+            Mode::Active
+        };
+        let settings = self.borrow_settings();
+        settings.health.mode(mode);
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = &settings.dbus {
+            if let Err(e) = dbus.mode(mode) {
+                error!("Couldn't publish the mode on D-Bus: {}", e);
+            }
+        }
+    }
+
+    /// Publishes what an update read and set, outside of privacy mode
+    #[cfg(feature = "dbus")]
+    fn publish(&self, sample: &Sample) {
+        let settings = self.borrow_settings();
+        let Some(dbus) = settings.dbus.as_ref().filter(|_| !settings.config.privacy) else {
+            return;
+        };
+        let mut outputs = Report::default();
+        self.with_outputs(|x| x.iter().for_each(|x| x.report(&mut outputs)));
+        let properties = Properties {
+            current_lux: sample.raw,
+            smoothed_value: sample.smoothed,
+            outputs,
+        };
+        if let Err(e) = dbus.publish(properties) {
+            error!("Couldn't publish readings on D-Bus: {}", e);
+        }
+    }
+
+    fn update(&mut self) -> Result<()> {
+        let sample = self.with_ambient_brightness_mut(|x| x.sample())?;
+        let new_val = sample.value;
+        trace!("New Val POST: {}", Lux(new_val));
+        let settings = self.borrow_settings();
+        if !settings.config.privacy {
+            settings.health.sample(new_val, sample.raw);
+        }
+        if let Some(environment) = &settings.environment {
+            environment.observe(sample.percent);
+        }
+        self.report_mode();
+        self.drive(|output| output.follow(&sample));
+        #[cfg(feature = "dbus")]
+        self.publish(&sample);
+        Ok(())
+    }
+
+    /// Adjusts every enabled output with `f`. One output failing doesn't keep
+    /// the others from following the sensor.
+    fn drive(&mut self, f: impl Fn(&mut Tuned) -> Result<bool>) {
+        let health = self.borrow_settings().health.clone();
+        self.each_enabled(|output| {
+            let result = f(output);
+            let name = output.name();
+            match (health.output(name, &result), result) {
+                (0, Err(e)) => {
+                    error!("Couldn't adjust {}, still adjusting the rest: {}", name, e)
+                }
+                (_, Err(e)) => debug!("{} still failing: {}", name, e),
+                (0, Ok(_)) => (),
+                (failures, Ok(_)) => {
+                    info!("{} adjusted again after {} failed updates", name, failures)
+                }
+            }
+        });
+        // Levels follow the light as closely as readings do
+        if !self.borrow_settings().config.privacy {
+            health.levels(self.with_outputs(|x| {
+                x.iter()
+                    .filter_map(|x| {
+                        Some(Level {
+                            name: x.name().to_string(),
+                            percent: x.percent()?,
+                            offset: x.offset()?,
+                        })
+                    })
+                    .collect()
+            }));
+        }
+    }
+
+    /// Commands called over D-Bus, or none without the service
+    fn bus_commands(&self) -> Receiver<Command> {
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = &self.borrow_settings().dbus {
+            return dbus.commands().clone();
+        }
+        never()
+    }
+
+    /// Whether any enabled output is still crossfading
+    fn fading(&self) -> bool {
+        let disabled = &self.borrow_settings().disabled;
+        self.with_outputs(|x| {
+            x.iter()
+                .any(|x| x.fading() && !disabled.contains(&x.kind()))
+        })
+    }
+
+    /// Moves crossfading outputs along between sensor reads
+    fn fade(&mut self) {
+        if !self.face_down() {
+            self.drive(|output| output.step());
+        }
+    }
+
+    /// Runs `f` on every output that wasn't disabled
+    fn each_enabled(&mut self, f: impl FnMut(&mut Tuned)) {
+        self.with_mut(|fields| {
+            let disabled = &fields.settings.disabled;
+            fields
+                .outputs
+                .iter_mut()
+                .filter(|x| !disabled.contains(&x.kind()))
+                .for_each(f)
+        })
+    }
+
+    /// Takes outputs of `kind` away from automatic control, or hands them back
+    fn enable(&mut self, kind: OutputKind, enabled: bool) {
+        if !self.with_outputs(|x| x.iter().any(|x| x.kind() == kind)) {
+            warn!("No {} output right now, remembering it for later", kind);
+        }
+        let changed = self.with_settings_mut(|x| match enabled {
+            true => x.disabled.remove(&kind),
+            false => x.disabled.insert(kind),
+        });
+        match (changed, enabled) {
+            (false, _) => debug!(
+                "{} already {}",
+                kind,
+                if enabled { "enabled" } else { "disabled" }
+            ),
+            (true, true) => info!("Adjusting {} again", kind),
+            (true, false) => info!("Leaving {} at its level until it's enabled again", kind),
+        }
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        self.record(&Event::Tick)?;
+        let now = self.borrow_settings().clock.now();
+        if self.borrow_suspension().due(now) {
+            info!("Suspending updates until activity");
+            self.with_suspension_mut(|x| x.suspended = true);
+            self.report_mode();
+            return Ok(());
+        }
+        if self.face_down() {
+            return Ok(());
+        }
+        self.update()?;
+        self.check_stuck()
+    }
+
+    /// Whether the accelerometer says the screen faces down
+    fn face_down(&self) -> bool {
+        self.borrow_settings()
+            .accelerometer
+            .as_ref()
+            .is_some_and(|x| x.face_down())
+    }
+
+    /// Reopens a sensor that keeps returning the same reading, in case its
+    /// driver hung
+    fn check_stuck(&mut self) -> Result<()> {
+        let settings = self.borrow_settings();
+        let after = settings.config.watchdog.sensor_stuck_after;
+        if after == 0 {
+            return Ok(());
+        }
+        let Some(unchanged) = settings.health.stuck(Duration::from_secs(after)) else {
+            return Ok(());
+        };
+        if settings.custom_sensor {
+            warn!("Sensor reading hasn't changed in {}s", unchanged.as_secs());
+            return Ok(());
+        }
+
+        warn!(
+            "Sensor reading hasn't changed in {}s, reopening the sensor",
+            unchanged.as_secs()
+        );
+        let sensor = match settings.open_sensor(&settings.config) {
+            Ok(sensor) => sensor,
+            Err(e) => {
+                error!("Couldn't reopen the sensor: {}", e);
+                return Ok(());
+            }
+        };
+        let filter = settings.config.filter.clone();
+        if let Err(e) = self.with_ambient_brightness_mut(|x| x.reconfigure(Some(sensor), filter)) {
+            error!("Couldn't read the reopened sensor: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Merges offset commands arriving within [`BURST`] of `first` into one,
+    /// returning it and the first other command received meanwhile
+    fn coalesce(&self, first: Command) -> (Command, Option<Command>) {
+        let offset = |command: &Command| match command {
+            Command::Increase(amount) => Some(*amount as i32),
+            Command::Decrease(amount) => Some(-(*amount as i32)),
+            _ => None,
+        };
+        let Some(mut total) = offset(&first) else {
+            return (first, None);
+        };
+
+        let burst = self.borrow_settings().clock.after(BURST);
+        let commands = &self.borrow_channels().command;
+        let mut merged = 1;
+        let mut next = None;
+        loop {
+            // Commands already waiting arrived within the burst, whether or
+            // not it has ended since
+            let command = match commands.try_recv() {
+                Ok(command) => command,
+                Err(_) => select! {
+                    recv(commands) -> command => match command {
+                        Ok(command) => command,
+                        Err(_) => break,
+                    },
+                    recv(burst) -> _ => break,
+                },
+            };
+            match offset(&command) {
+                Some(amount) => {
+                    total += amount;
+                    merged += 1;
+                }
+                None => {
+                    next = Some(command);
+                    break;
+                }
+            }
+        }
+        if merged > 1 {
+            debug!("Merged {} offset commands into {:+}", merged, total);
+        }
+
+        let total = total.clamp(-(i8::MAX as i32), i8::MAX as i32) as i8;
+        let command = if total < 0 {
+            Command::Decrease(-total)
+        } else {
+            Command::Increase(total)
+        };
+        (command, next)
+    }
+
+    /// Lets updates run again, unless a lock or screen capture holds them
+    fn resume(&mut self) {
+        let suspension = self.borrow_suspension();
+        if suspension.suspended && !suspension.held() {
+            info!("Resuming updates");
+            self.with_suspension_mut(|x| x.suspended = false);
+        }
+    }
+
+    fn command(&mut self, command: Command) -> Result<()> {
+        self.record(&Event::Command(command.clone()))?;
+        match command {
+            Command::Idle => {
+                self.enter_idle();
+                if self.borrow_suspension().suspended {
+                    return Ok(());
+                }
+            }
+            Command::Active => self.leave_idle(),
+            Command::Increase(amount) => {
+                self.resume();
+                self.each_enabled(|x| x.increase(amount))
+            }
+            Command::Decrease(amount) => {
+                self.resume();
+                self.each_enabled(|x| x.decrease(amount))
+            }
+            Command::Set(percent) => {
+                self.resume();
+                self.each_enabled(|x| x.set(percent))
+            }
+            Command::Nudge(kind, amount) => {
+                self.resume();
+                self.each_enabled(|x| {
+                    if x.kind() == kind {
+                        x.nudge(amount)
+                    }
+                })
+            }
+            Command::SetOutput(kind, percent) => {
+                self.resume();
+                self.each_enabled(|x| {
+                    if x.kind() == kind {
+                        x.return_to(percent)
+                    }
+                })
+            }
+            Command::Resync => {
+                if self.borrow_suspension().held() {
+                    debug!("Not reading the sensor to resync while held");
+                    return Ok(());
+                }
+                self.resume();
+                let level = self.with_ambient_brightness_mut(|x| x.resync())?;
+                self.with_outputs_mut(|x| x.iter_mut().for_each(|x| x.resync(level)));
+            }
+            Command::Appearance(appearance) => {
+                match &self.borrow_settings().environment {
+                    Some(environment) => environment.appearance(appearance),
+                    None => warn!("Not setting the color scheme without [environment]"),
+                }
+                return Ok(());
+            }
+            Command::Enable(kind) | Command::Disable(kind) => {
+                self.enable(kind, command == Command::Enable(kind));
+                if self.borrow_suspension().suspended {
+                    return Ok(());
+                }
+            }
+            Command::Snapshot(name) => {
+                match self.snapshot(&name) {
+                    Ok(()) => info!("Saved snapshot {}", name),
+                    Err(e) => warn!("Couldn't save snapshot {}: {}", name, e),
+                }
+                return Ok(());
+            }
+            Command::Restore(name) => {
+                let path = snapshot::state_path(&self.borrow_settings().config);
+                let snapshot = match snapshot::load(&path, &name) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        warn!("Couldn't restore snapshot {}: {}", name, e);
+                        return Ok(());
+                    }
+                };
+                info!("Restoring snapshot {}", name);
+                match snapshot.idle {
+                    true => self.enter_idle(),
+                    false => self.leave_idle(),
+                }
+                self.resume();
+                self.each_enabled(|x| {
+                    if let Some(percent) = snapshot.outputs.get(x.name()) {
+                        x.return_to((*percent).min(100) as u8);
+                    }
+                });
+            }
+        }
+        // Held, the command is recorded and applied once the hold is released
+        if self.borrow_suspension().suspended {
+            return Ok(());
+        }
+        self.update()
+    }
+
+    fn enter_idle(&mut self) {
+        let now = self.borrow_settings().clock.now();
+        self.with_suspension_mut(|x| {
+            x.idle_since.get_or_insert(now);
+        });
+        self.with_ambient_brightness_mut(|x| x.idle());
+    }
+
+    fn leave_idle(&mut self) {
+        self.with_suspension_mut(|x| x.idle_since = None);
+        self.resume();
+        self.with_ambient_brightness_mut(|x| x.active())
+    }
+
+    /// Saves the mode and where each output is to the state file
+    fn snapshot(&self, name: &str) -> Result<()> {
+        let snapshot = Snapshot {
+            idle: self.borrow_suspension().idle_since.is_some(),
+            outputs: self.with_outputs(|x| {
+                x.iter()
+                    .filter_map(|x| Some((x.name().to_string(), x.percent()?)))
+                    .collect()
+            }),
+        };
+        snapshot::save(
+            &snapshot::state_path(&self.borrow_settings().config),
+            name,
+            snapshot,
+        )
+    }
+
+    /// Locking suspends updates right away, unlocking resumes them
+    fn lock(&mut self, locked: bool) -> Result<()> {
+        if self.borrow_suspension().locked == locked {
+            return Ok(());
+        }
+        self.record(&Event::Lock(locked))?;
+        self.with_suspension_mut(|x| x.locked = locked);
+        if locked {
+            info!("Session locked, suspending updates");
+            self.with_suspension_mut(|x| x.suspended = true);
+            self.report_mode();
+            Ok(())
+        } else if self.borrow_suspension().held() {
+            info!("Session unlocked, still holding during screen capture");
+            Ok(())
+        } else {
+            self.resume();
+            self.update()
+        }
+    }
+
+    /// Screen capture holds brightness where it is until it ends
+    fn capture(&mut self, capturing: bool) -> Result<()> {
+        if self.borrow_suspension().capturing == capturing {
+            return Ok(());
+        }
+        self.record(&Event::Capture(capturing))?;
+        self.with_suspension_mut(|x| x.capturing = capturing);
+        if capturing {
+            info!("Screen capture started, holding brightness");
+            self.with_suspension_mut(|x| x.suspended = true);
+            self.report_mode();
+            Ok(())
+        } else if self.borrow_suspension().held() {
+            Ok(())
+        } else {
+            self.resume();
+            self.update()
+        }
+    }
+
+    /// Applies a changed config, keeping the current one when devices or the
+    /// sensor from the new one can't be set up, and going back to it when the
+    /// first update with the new one fails. A failed reload only logs, it
+    /// never stops the daemon.
+    fn reload(&mut self, config: Config) -> Result<()> {
+        let previous = self.borrow_settings().config.clone();
+        if let Err(e) = self.try_reload(config) {
+            error!(
+                "Keeping the current config, couldn't apply the new one: {}",
+                e
+            );
+            return Ok(());
+        }
+        info!("Reloaded config");
+        if self.borrow_suspension().suspended {
+            return Ok(());
+        }
+        if let Err(e) = self.update() {
+            error!(
+                "Going back to the previous config, the new one failed: {}",
+                e
+            );
+            // Neither failure ends the daemon, the next tick tries again
+            if let Err(e) = self.try_reload(previous) {
+                error!(
+                    "Keeping the new config, couldn't go back to the previous one: {}",
+                    e
+                );
+            } else if let Err(e) = self.update() {
+                error!("The previous config failed to update too: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks for devices again after a hotplug, keeping the current outputs
+    /// when the configured ones can't be found
+    fn rescan(&mut self) -> Result<()> {
+        // One device often sends a burst of events, e.g. a monitor and its card
+        while self
+            .borrow_channels()
+            .hotplug
+            .recv_timeout(HOTPLUG_SETTLE)
+            .is_ok()
+        {}
+
+        let config = self.borrow_settings().config.clone();
+        if let Err(e) = self.try_reload(config) {
+            error!("Keeping the current outputs after a hotplug: {}", e);
+            return Ok(());
+        }
+        info!("Rescanned outputs after a hotplug");
+        if self.borrow_suspension().suspended {
+            return Ok(());
+        }
+        self.update()
+    }
+
+    fn try_reload(&mut self, config: Config) -> Result<()> {
+        let settings = self.borrow_settings();
+        let unchanged = settings.config.sensor == config.sensor
+            && settings.config.tablet == config.tablet
+            && settings.config.retry == config.retry;
+        let sensor = if settings.custom_sensor || unchanged {
+            None
+        } else {
+            Some(settings.open_sensor(&config)?)
+        };
+        let accelerometer = if settings.config.orientation == config.orientation {
+            None
+        } else {
+            Some(settings.open_accelerometer(&config)?)
+        };
+        let environment = if settings.config.environment == config.environment {
+            None
+        } else {
+            Some(settings.environment_events(&config)?)
+        };
+        #[cfg(feature = "dbus")]
+        let dbus = if settings.config.dbus == config.dbus {
+            None
+        } else {
+            Some(settings.dbus_service(&config)?)
+        };
+        let devices = settings.open_devices(&config)?;
+
+        self.with_mut(|fields| {
+            // Everything that can fail is set up before anything running is
+            // touched, so a bad config leaves the old one whole
+            let (hid, dry_run) = (fields.settings.hid, fields.settings.dry_run);
+            let staged = fields
+                .ambient_brightness
+                .stage(sensor, config.filter.clone())?;
+            let clock = &fields.settings.clock;
+            let mut outputs = outputs(
+                fields.writer,
+                devices,
+                &config,
+                staged.level(),
+                clock,
+                hid,
+                dry_run,
+            )?;
+
+            fields.ambient_brightness.apply(staged);
+            fields.ambient_brightness.resync_above(config.resync_above);
+            fields.writer.retry(config.retry.clone());
+            #[cfg(feature = "control")]
+            fields.writer.helper(config.helper.socket.clone());
+            // Outputs that stay pick up where their old selves left off
+            for output in &mut outputs {
+                let old = fields
+                    .outputs
+                    .iter_mut()
+                    .find(|x| x.kind() == output.kind() && x.name() == output.name());
+                if let Some(old) = old {
+                    output.fade_from(old);
+                }
+            }
+            *fields.outputs = outputs;
+            fields.suspension.after = config.suspend_after.map(Duration::from_secs);
+            if let Some(accelerometer) = accelerometer {
+                fields.settings.accelerometer = accelerometer;
+            }
+            if let Some(environment) = environment {
+                if let (Some(new), Some(old)) = (&environment, &fields.settings.environment) {
+                    new.carry_over(old);
+                }
+                fields.settings.environment = environment;
+            }
+            #[cfg(feature = "dbus")]
+            if let Some(dbus) = dbus {
+                fields.settings.dbus = dbus;
+            }
+            fields.settings.config = config;
+            Ok(())
+        })
+    }
+
+    /// Restores outputs to their level from before the daemon, except those
+    /// handed to other tools
+    fn restore(&self) -> Result<()> {
+        let disabled = &self.borrow_settings().disabled;
+        self.with_outputs(|x| restore_all(x.iter().filter(|x| !disabled.contains(&x.kind()))))
+    }
+
+    fn run(mut self) -> Result<()> {
+        let clock = self.borrow_settings().clock.clone();
+        let tick = |config: &Config| config.interval.map_or(TICK, Duration::from_secs);
+        let mut interval = tick(&self.borrow_settings().config);
+        let mut ticker = clock.ticker(interval);
+        let fade_ticker = clock.ticker(FADE_STEP);
+        let heartbeat = Arc::new(Heartbeat::default());
+        let watchdog = |config: &Config| {
+            Watchdog::spawn(
+                &config.watchdog,
+                tick(config),
+                clock.clone(),
+                heartbeat.clone(),
+            )
+        };
+        let mut _watchdog = watchdog(&self.borrow_settings().config);
+
+        // Pinged from this loop, so systemd restarts us when it's stuck
+        let pings = systemd::watchdog_interval().map_or_else(never, crossbeam::channel::tick);
+
+        heartbeat.start("the first update", clock.now());
+        self.update()?;
+        heartbeat.finish();
+
+        loop {
+            // No sensor reads at all while suspended
+            let ticks = if self.borrow_suspension().suspended {
+                never()
+            } else {
+                ticker.clone()
+            };
+            let fades = if !self.borrow_suspension().suspended && self.fading() {
+                fade_ticker.clone()
+            } else {
+                never()
+            };
+            let bus_commands = self.bus_commands();
+
+            let step = select! {
+                recv(self.borrow_channels().close) -> _ => {
+                    info!("Received Shutdown");
+                    break
+                },
+                recv(self.borrow_channels().command) -> msg => match msg {
+                    Err(e) => {
+                        info!("Command Channel Terminated: {:#}", e);
+                        break;
+                    },
+                    Ok(command) => Step::Command(command),
+                },
+                recv(bus_commands) -> msg => match msg {
+                    Err(_) => continue,
+                    Ok(command) => Step::Command(command),
+                },
+                recv(self.borrow_channels().lock) -> msg => match msg {
+                    Err(_) => {
+                        // Nobody is watching the session lock anymore
+                        self.with_channels_mut(|x| x.lock = never());
+                        continue;
+                    },
+                    Ok(locked) => Step::Lock(locked),
+                },
+                recv(self.borrow_channels().capture) -> msg => match msg {
+                    Err(_) => {
+                        self.with_channels_mut(|x| x.capture = never());
+                        continue;
+                    },
+                    Ok(capturing) => Step::Capture(capturing),
+                },
+                recv(self.borrow_channels().reload) -> msg => match msg {
+                    Err(_) => {
+                        self.with_channels_mut(|x| x.reload = never());
+                        continue;
+                    },
+                    Ok(config) => Step::Reload(Box::new(config)),
+                },
+                recv(self.borrow_channels().hotplug) -> msg => match msg {
+                    Err(_) => {
+                        self.with_channels_mut(|x| x.hotplug = never());
+                        continue;
+                    },
+                    Ok(()) => Step::Hotplug,
+                },
+                recv(ticks) -> _  => Step::Tick,
+                recv(fades) -> _  => Step::Fade,
+                recv(pings) -> _ => {
+                    systemd::notify("WATCHDOG=1");
+                    continue;
+                },
+            };
+
+            heartbeat.start(step.name(), clock.now());
+            match step {
+                Step::Tick => self.tick()?,
+                Step::Fade => self.fade(),
+                Step::Command(command) => {
+                    let (command, next) = self.coalesce(command);
+                    self.command(command)?;
+                    if let Some(next) = next {
+                        self.command(next)?;
+                    }
+                }
+                Step::Lock(locked) => self.lock(locked)?,
+                Step::Capture(capturing) => self.capture(capturing)?,
+                Step::Reload(config) => {
+                    self.reload(*config)?;
+                    let config = &self.borrow_settings().config;
+                    if tick(config) != interval {
+                        interval = tick(config);
+                        ticker = clock.ticker(interval);
+                    }
+                    _watchdog = watchdog(config);
+                }
+                Step::Hotplug => self.rescan()?,
+            }
+            heartbeat.finish();
+        }
+
+        systemd::notify("STOPPING=1");
+        heartbeat.start("restoring outputs", clock.now());
+        self.restore()
+    }
+
+    /// Repeats the ticks and commands of a recording at their recorded times
+    fn replay(mut self, clock: &MockClock, events: Vec<(Duration, Event)>) -> Result<()> {
+        let start = clock.now();
+        self.update()?;
+
+        for (at, event) in events {
+            let elapsed = clock.now().duration_since(start);
+            clock.advance(at.saturating_sub(elapsed));
+            match event {
+                Event::Tick => self.tick()?,
+                Event::Command(command) => self.command(command)?,
+                Event::Lock(locked) => self.lock(locked)?,
+                Event::Capture(capturing) => self.capture(capturing)?,
+                _ => (),
+            }
+        }
+
+        self.restore()
+    }
+}
+
+/// Sets up the controller. By default devices are found under `/sys`, the sensor
+/// comes from the config, and time follows the system clock.
+pub struct Builder<'c> {
+    config: &'c Config,
+    dry_run: bool,
+    clock: Arc<dyn Clock>,
+    sysfs: Sysfs,
+    sensor: Option<Box<dyn Sensor>>,
+    recorder: Option<Arc<Recorder>>,
+    health: Option<Arc<Health>>,
+    hid: bool,
+    close_receiver: Receiver<()>,
+    command_receiver: Receiver<Command>,
+    lock_receiver: Receiver<bool>,
+    capture_receiver: Receiver<bool>,
+    reload_receiver: Receiver<Config>,
+    hotplug_receiver: Receiver<()>,
+    ready_fd: Option<RawFd>,
+}
+
+/// Takes over the readiness fd handed down by the supervisor, keeping it from
+/// commands the daemon runs
+fn readiness_file(fd: RawFd) -> Result<File> {
+    // SAFETY: fcntl only inspects and flags the fd
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(Error::Config(format!(
+            "Readiness fd {} isn't open: {}",
+            fd,
+            io::Error::last_os_error()
+        )));
+    }
+    // SAFETY: the fd is open and handed to this process to write and close
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+impl<'c> Builder<'c> {
+    pub fn new(config: &'c Config) -> Self {
+        Self {
+            config,
+            dry_run: false,
+            clock: Arc::new(SystemClock),
+            sysfs: Sysfs::default(),
+            sensor: None,
+            recorder: None,
+            health: None,
+            hid: true,
+            close_receiver: never(),
+            command_receiver: never(),
+            lock_receiver: never(),
+            capture_receiver: never(),
+            reload_receiver: never(),
+            hotplug_receiver: never(),
+            ready_fd: None,
+        }
+    }
+
+    /// Print brightness changes instead of applying them
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Directory standing in for `/sys`, e.g. a fake device tree in tests
+    pub fn sysfs_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.sysfs = Sysfs::new(root);
+        self
+    }
+
+    /// Use this sensor instead of the one in the config
+    pub fn sensor(mut self, sensor: Box<dyn Sensor>) -> Self {
+        self.sensor = Some(sensor);
+        self
+    }
+
+    /// Record sensor readings, ticks, commands, and writes
+    pub fn recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Track sensor reads and writes here, e.g. to answer pings
+    pub fn health(mut self, health: Arc<Health>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Leave configured HID outputs alone
+    pub(crate) fn without_hid(mut self) -> Self {
+        self.hid = false;
+        self
+    }
+
+    /// Stops [`Builder::run`] when it fires
+    pub fn close_receiver(mut self, close_receiver: Receiver<()>) -> Self {
+        self.close_receiver = close_receiver;
+        self
+    }
+
+    pub fn command_receiver(mut self, command_receiver: Receiver<Command>) -> Self {
+        self.command_receiver = command_receiver;
+        self
+    }
+
+    /// Session lock state changes, e.g. from [`crate::session_lock::watch`]
+    pub fn lock_receiver(mut self, lock_receiver: Receiver<bool>) -> Self {
+        self.lock_receiver = lock_receiver;
+        self
+    }
+
+    /// Whether the screen is being recorded or cast, e.g. from
+    /// [`crate::screencast::watch`]
+    pub fn capture_receiver(mut self, capture_receiver: Receiver<bool>) -> Self {
+        self.capture_receiver = capture_receiver;
+        self
+    }
+
+    /// Changed configs to apply while running, e.g. from [`crate::config_watch::watch`]
+    pub fn reload_receiver(mut self, reload_receiver: Receiver<Config>) -> Self {
+        self.reload_receiver = reload_receiver;
+        self
+    }
+
+    /// Fires when outputs may have come or gone, e.g. from
+    /// [`crate::hotplug::watch`], to look for devices again
+    pub fn hotplug_receiver(mut self, hotplug_receiver: Receiver<()>) -> Self {
+        self.hotplug_receiver = hotplug_receiver;
+        self
+    }
+
+    /// Writes a newline to `fd` and closes it once the sensor, outputs, and
+    /// D-Bus service are up, as s6 and runit expect from services that
+    /// notify readiness. Under systemd, `$NOTIFY_SOCKET` is told regardless.
+    pub fn ready_fd(mut self, fd: RawFd) -> Self {
+        self.ready_fd = Some(fd);
+        self
+    }
+
+    /// Runs the ambient brightness loop until the close receiver fires or the
+    /// command channel closes, restoring outputs on the way out
+    pub fn run(mut self) -> Result<()> {
+        let ready = self.ready_fd.take().map(readiness_file).transpose()?;
+        let controller = AmbientBrightnessController::create(self)?;
+        if let Some(mut ready) = ready {
+            match ready.write_all(b"\n") {
+                Ok(()) => debug!("Notified readiness"),
+                Err(e) => warn!("Couldn't notify readiness: {}", e),
+            }
+        }
+        systemd::notify("READY=1");
+        controller.run()
+    }
+
+    /// Reads the sensor once and applies the resulting brightness
+    pub fn once(self) -> Result<()> {
+        AmbientBrightnessController::create(self)?.update()
+    }
+
+    pub(crate) fn replay(
+        self,
+        clock: Arc<MockClock>,
+        events: Vec<(Duration, Event)>,
+    ) -> Result<()> {
+        AmbientBrightnessController::create(self)?.replay(&clock, events)
+    }
+}
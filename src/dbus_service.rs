@@ -0,0 +1,262 @@
+//! Readings and levels as properties on the session bus, so monitoring tools
+//! can bind to them and follow PropertiesChanged, and methods taking the
+//! commands of the control socket, so widgets and scripts need no socket
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_io::block_on;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use zbus::{
+    blocking::{connection, object_server::InterfaceRef, Connection},
+    fdo, interface,
+    zvariant::Value,
+    SignalContext,
+};
+
+use crate::{
+    command::{Command, OutputKind},
+    health::{Health, Mode, Status},
+    output::Report,
+    Result, DBUS_PATH,
+};
+
+/// Well-known name owned while the properties are served
+const BUS_NAME: &str = "io.github.jeffutter.IioAmbientBrightness";
+
+/// What an update left the outputs at, and the readings it was based on
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Properties {
+    pub(crate) current_lux: f64,
+    /// Log-scaled reading after smoothing
+    pub(crate) smoothed_value: f64,
+    pub(crate) outputs: Report,
+}
+
+struct Ambient {
+    properties: Properties,
+    mode: Mode,
+    commands: Sender<Command>,
+    health: Arc<Health>,
+}
+
+impl Ambient {
+    fn send(&self, command: Command) -> fdo::Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| fdo::Error::Failed("The daemon is shutting down".to_string()))
+    }
+}
+
+/// A status as a dictionary of the fields `status --format` knows, leaving out
+/// the percent and lux when there are none, with each output's level by name
+fn status_dict(status: &Status) -> HashMap<String, Value<'static>> {
+    let mut dict = HashMap::from([
+        ("uptime", Value::from(status.uptime.as_secs_f64())),
+        ("sensor_errors", Value::from(status.sensor_errors)),
+        ("write_errors", Value::from(status.write_errors)),
+        ("stuck", Value::from(status.sensor_stuck)),
+        ("failing_outputs", Value::from(status.failing_outputs)),
+        ("mode", Value::from(status.mode.to_string())),
+        ("mode_for", Value::from(status.mode_for.as_secs_f64())),
+        ("adjustments", Value::from(status.adjustments)),
+        ("output_errors", Value::from(status.output_errors)),
+        (
+            "levels",
+            Value::from(
+                status
+                    .levels
+                    .iter()
+                    .map(|x| (x.name.clone(), x.percent))
+                    .collect::<HashMap<_, _>>(),
+            ),
+        ),
+    ]);
+    if let Some(percent) = status.ambient {
+        dict.insert("percent", Value::from(percent));
+    }
+    if let Some(lux) = status.lux {
+        dict.insert("lux", Value::from(lux));
+    }
+    dict.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+}
+
+#[interface(name = "io.github.jeffutter.IioAmbientBrightness")]
+impl Ambient {
+    /// Dims outputs as if the session went idle
+    fn set_idle(&self) -> fdo::Result<()> {
+        self.send(Command::Idle)
+    }
+
+    /// Brings outputs back from idle
+    fn set_active(&self) -> fdo::Result<()> {
+        self.send(Command::Active)
+    }
+
+    /// Offsets the screen by `amount` percent, raising it if positive
+    fn adjust_screen(&self, amount: i32) -> fdo::Result<()> {
+        let amount = amount.clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+        self.send(Command::Nudge(OutputKind::Screen, amount))
+    }
+
+    fn get_status(&self) -> HashMap<String, Value<'static>> {
+        status_dict(&self.health.status())
+    }
+
+    /// Sent whenever an update moves the screen or keyboard, with 0 for
+    /// one that isn't there
+    #[zbus(signal)]
+    async fn brightness_changed(
+        context: &SignalContext<'_>,
+        screen_percent: u32,
+        kbd_level: u32,
+    ) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn current_lux(&self) -> f64 {
+        self.properties.current_lux
+    }
+
+    #[zbus(property)]
+    fn smoothed_value(&self) -> f64 {
+        self.properties.smoothed_value
+    }
+
+    /// 0 without a screen output
+    #[zbus(property)]
+    fn screen_percent(&self) -> u32 {
+        self.properties.outputs.screen_percent.unwrap_or(0)
+    }
+
+    /// 0 without a keyboard output
+    #[zbus(property)]
+    fn kbd_level(&self) -> u32 {
+        self.properties.outputs.kbd_level.unwrap_or(0)
+    }
+
+    #[zbus(property)]
+    fn mode(&self) -> String {
+        self.mode.to_string()
+    }
+}
+
+/// Serves [`Properties`] at [`DBUS_PATH`], signalling each change, and
+/// passes on the commands its methods are called with
+pub(crate) struct DbusService {
+    ambient: InterfaceRef<Ambient>,
+    commands: Receiver<Command>,
+    /// Keeps serving for as long as the service lives
+    _connection: Connection,
+}
+
+impl DbusService {
+    pub(crate) fn new(health: Arc<Health>) -> Result<Self> {
+        let (sender, commands) = unbounded();
+        let ambient = Ambient {
+            properties: Properties::default(),
+            mode: Mode::default(),
+            commands: sender,
+            health,
+        };
+        let connection = connection::Builder::session()?
+            .name(BUS_NAME)?
+            .serve_at(DBUS_PATH, ambient)?
+            .build()?;
+        let ambient = connection
+            .object_server()
+            .interface::<_, Ambient>(DBUS_PATH)?;
+        Ok(Self {
+            ambient,
+            commands,
+            _connection: connection,
+        })
+    }
+
+    /// Commands called over the bus, for the controller to handle like the
+    /// control socket's
+    pub(crate) fn commands(&self) -> &Receiver<Command> {
+        &self.commands
+    }
+
+    pub(crate) fn publish(&self, properties: Properties) -> Result<()> {
+        let mut ambient = self.ambient.get_mut();
+        let old = std::mem::replace(&mut ambient.properties, properties);
+        let context = self.ambient.signal_context();
+        block_on(async {
+            if old.current_lux != properties.current_lux {
+                ambient.current_lux_changed(context).await?;
+            }
+            if old.smoothed_value != properties.smoothed_value {
+                ambient.smoothed_value_changed(context).await?;
+            }
+            if old.outputs.screen_percent != properties.outputs.screen_percent {
+                ambient.screen_percent_changed(context).await?;
+            }
+            if old.outputs.kbd_level != properties.outputs.kbd_level {
+                ambient.kbd_level_changed(context).await?;
+            }
+            if old.outputs.screen_percent != properties.outputs.screen_percent
+                || old.outputs.kbd_level != properties.outputs.kbd_level
+            {
+                Ambient::brightness_changed(
+                    context,
+                    properties.outputs.screen_percent.unwrap_or(0),
+                    properties.outputs.kbd_level.unwrap_or(0),
+                )
+                .await?;
+            }
+            Ok(())
+        })
+    }
+
+    pub(crate) fn mode(&self, mode: Mode) -> Result<()> {
+        let mut ambient = self.ambient.get_mut();
+        if ambient.mode == mode {
+            return Ok(());
+        }
+        ambient.mode = mode;
+        Ok(block_on(
+            ambient.mode_changed(self.ambient.signal_context()),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::health::Level;
+
+    #[test]
+    fn status_dict_leaves_out_missing_readings() {
+        let status = Status {
+            uptime: Duration::from_secs(3),
+            last_read: None,
+            last_write: None,
+            sensor_errors: 0,
+            write_errors: 0,
+            sensor_stuck: false,
+            failing_outputs: 0,
+            ambient: Some(42),
+            lux: None,
+            mode: Mode::Idle,
+            mode_for: Duration::ZERO,
+            adjustments: 2,
+            output_errors: 0,
+            last_adjusted: Vec::new(),
+            levels: vec![Level {
+                name: "intel_backlight".to_string(),
+                percent: 35,
+                offset: 0,
+            }],
+        };
+        let dict = status_dict(&status);
+        assert_eq!(dict["percent"], Value::from(42u32));
+        assert_eq!(dict["mode"], Value::from("idle"));
+        assert!(!dict.contains_key("lux"));
+        assert_eq!(
+            dict["levels"],
+            Value::from(HashMap::from([("intel_backlight".to_string(), 35u32)]))
+        );
+    }
+}
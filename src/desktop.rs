@@ -0,0 +1,57 @@
+use log::debug;
+use zbus::{
+    blocking::{fdo::DBusProxy, Connection},
+    names::BusName,
+};
+
+use crate::{Error, Result};
+
+/// A desktop service that sets the screen brightness itself, fighting over the
+/// backlight with this daemon
+pub(crate) struct Conflict {
+    pub(crate) desktop: &'static str,
+    /// Session bus name the service owns while running
+    bus_name: &'static str,
+    /// How to stop it from changing the screen brightness
+    pub(crate) disable: &'static str,
+}
+
+const CONFLICTS: &[Conflict] = &[
+    Conflict {
+        desktop: "GNOME settings-daemon's power plugin",
+        bus_name: "org.gnome.SettingsDaemon.Power",
+        disable:
+            "run `gsettings set org.gnome.settings-daemon.plugins.power ambient-enabled false`",
+    },
+    Conflict {
+        desktop: "KDE PowerDevil",
+        bus_name: "org.kde.Solid.PowerManagement",
+        disable: "turn off \"Change screen brightness\" in System Settings > Energy Saving",
+    },
+];
+
+fn running(connection: &Connection) -> Result<Option<&'static Conflict>> {
+    let proxy = DBusProxy::new(connection)?;
+    for conflict in CONFLICTS {
+        let name = BusName::try_from(conflict.bus_name).map_err(zbus::Error::from)?;
+        if proxy.name_has_owner(name).map_err(zbus::Error::from)? {
+            return Ok(Some(conflict));
+        }
+    }
+    Ok(None)
+}
+
+/// The first conflicting service running in this session, if any. Without a
+/// session bus there is no desktop to conflict with.
+pub(crate) fn conflict() -> Option<&'static Conflict> {
+    match Connection::session()
+        .map_err(Error::from)
+        .and_then(|connection| running(&connection))
+    {
+        Ok(conflict) => conflict,
+        Err(e) => {
+            debug!("Not checking for conflicting desktop services: {}", e);
+            None
+        }
+    }
+}
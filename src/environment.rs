@@ -0,0 +1,299 @@
+//! Events for when the room turns dark or bright, e.g. to switch between
+//! light and dark themes from the same sensor
+
+use std::{
+    cell::{Cell, OnceCell},
+    fmt::Display,
+    process::Command,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use zbus::{blocking::Connection, names::BusName};
+
+use crate::{
+    clock::Clock, command::Appearance, config::EnvironmentConfig, Error, Result, DBUS_INTERFACE,
+    DBUS_PATH,
+};
+
+const SIGNAL: &str = "EnvironmentChanged";
+
+/// GSettings key GNOME and the settings portal take the preferred color scheme from
+const SCHEME_SCHEMA: &str = "org.gnome.desktop.interface";
+const SCHEME_KEY: &str = "color-scheme";
+
+/// Set for the command, to `dark` or `bright`
+const ENV_VAR: &str = "IIO_AMBIENT_ENVIRONMENT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Environment {
+    Dark,
+    Bright,
+}
+
+impl Display for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dark => write!(f, "dark"),
+            Self::Bright => write!(f, "bright"),
+        }
+    }
+}
+
+impl Environment {
+    fn color_scheme(self) -> &'static str {
+        match self {
+            Self::Dark => "prefer-dark",
+            Self::Bright => "default",
+        }
+    }
+}
+
+/// Tells the hook and the session bus whenever the ambient percent crosses
+/// into dark or bright, and switches the color scheme unless it was set by
+/// hand. Between the two thresholds it keeps the last verdict.
+pub(crate) struct EnvironmentEvents {
+    dark_below: f64,
+    bright_above: f64,
+    command: Vec<String>,
+    signal: bool,
+    color_scheme: bool,
+    sustain: Duration,
+    clock: Arc<dyn Clock>,
+    dry_run: bool,
+    current: Cell<Option<Environment>>,
+    /// Verdict waiting out `sustain`, and since when
+    pending: Cell<Option<(Environment, Instant)>>,
+    appearance: Cell<Appearance>,
+    connection: OnceCell<Connection>,
+}
+
+impl EnvironmentEvents {
+    pub(crate) fn new(
+        config: &EnvironmentConfig,
+        clock: Arc<dyn Clock>,
+        dry_run: bool,
+    ) -> Result<Self> {
+        if config.dark_below > config.bright_above {
+            return Err(Error::Config(format!(
+                "environment dark_below {} is above bright_above {}",
+                config.dark_below, config.bright_above
+            )));
+        }
+        Ok(Self {
+            dark_below: config.dark_below,
+            bright_above: config.bright_above,
+            command: config.command.clone(),
+            signal: config.signal,
+            color_scheme: config.color_scheme,
+            sustain: Duration::from_secs(config.sustain),
+            clock,
+            dry_run,
+            current: Cell::new(None),
+            pending: Cell::new(None),
+            appearance: Cell::new(Appearance::Auto),
+            connection: OnceCell::new(),
+        })
+    }
+
+    fn classify(&self, percent: f64) -> Option<Environment> {
+        if percent < self.dark_below {
+            Some(Environment::Dark)
+        } else if percent > self.bright_above {
+            Some(Environment::Bright)
+        } else {
+            self.current.get()
+        }
+    }
+
+    /// Whether `environment` has lasted for `sustain`, starting the wait when
+    /// it's new
+    fn sustained(&self, environment: Environment) -> bool {
+        let now = self.clock.now();
+        match self.pending.get() {
+            Some((pending, since)) if pending == environment => {
+                now.duration_since(since) >= self.sustain
+            }
+            _ => {
+                debug!("Room looks {}, waiting {:?}", environment, self.sustain);
+                self.pending.set(Some((environment, now)));
+                self.sustain.is_zero()
+            }
+        }
+    }
+
+    /// Takes the ambient percent before idle dimming, firing on changes that
+    /// last. The first verdict fires right away, so a theme matches the room
+    /// from the start.
+    pub(crate) fn observe(&self, percent: f64) {
+        let environment = self.classify(percent);
+        if environment == self.current.get() {
+            self.pending.set(None);
+            return;
+        }
+        let Some(environment) = environment else {
+            return;
+        };
+        if self.current.get().is_some() && !self.sustained(environment) {
+            return;
+        }
+        self.pending.set(None);
+        self.current.set(Some(environment));
+
+        info!("Environment became {}", environment);
+        if self.dry_run {
+            return;
+        }
+        if let Err(e) = self.run(environment) {
+            error!("Couldn't run the environment command: {}", e);
+        }
+        if let Err(e) = self.emit(environment) {
+            error!("Couldn't emit the environment signal: {}", e);
+        }
+        if self.color_scheme && self.appearance.get() == Appearance::Auto {
+            self.set_color_scheme(environment);
+        }
+    }
+
+    /// Holds the color scheme at `appearance`, or with `auto` has it follow the
+    /// room again, starting with the current verdict
+    pub(crate) fn appearance(&self, appearance: Appearance) {
+        if !self.color_scheme {
+            warn!("Not setting the color scheme, color_scheme is off in [environment]");
+            return;
+        }
+        self.appearance.set(appearance);
+        let environment = match appearance {
+            Appearance::Auto => {
+                info!("Color scheme follows the room again");
+                self.current.get()
+            }
+            Appearance::Dark => Some(Environment::Dark),
+            Appearance::Light => Some(Environment::Bright),
+        };
+        if let Some(environment) = environment {
+            self.set_color_scheme(environment);
+        }
+    }
+
+    /// Keeps a color scheme set by hand on `previous` across a config reload
+    pub(crate) fn carry_over(&self, previous: &Self) {
+        self.appearance.set(previous.appearance.get());
+    }
+
+    fn set_color_scheme(&self, environment: Environment) {
+        let scheme = environment.color_scheme();
+        info!("Setting the color scheme to {}", scheme);
+        if self.dry_run {
+            return;
+        }
+        let result = Command::new("gsettings")
+            .args(["set", SCHEME_SCHEMA, SCHEME_KEY, scheme])
+            .output();
+        match result {
+            Ok(output) if output.status.success() => (),
+            Ok(output) => error!(
+                "Couldn't set the color scheme: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => error!("Couldn't run gsettings: {}", e),
+        }
+    }
+
+    /// Starts the command without waiting for it, reaping it in the background
+    fn run(&self, environment: Environment) -> Result<()> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Ok(());
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .env(ENV_VAR, environment.to_string())
+            .spawn()?;
+        thread::spawn(move || child.wait());
+        Ok(())
+    }
+
+    fn emit(&self, environment: Environment) -> Result<()> {
+        if !self.signal {
+            return Ok(());
+        }
+        let connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => {
+                let connection = Connection::session()?;
+                self.connection.get_or_init(|| connection)
+            }
+        };
+        connection.emit_signal(
+            None::<BusName>,
+            DBUS_PATH,
+            DBUS_INTERFACE,
+            SIGNAL,
+            &(environment.to_string(),),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn config(dark_below: f64, bright_above: f64) -> EnvironmentConfig {
+        EnvironmentConfig {
+            dark_below,
+            bright_above,
+            ..EnvironmentConfig::default()
+        }
+    }
+
+    #[test]
+    fn thresholds_have_hysteresis() {
+        let events =
+            EnvironmentEvents::new(&config(30.0, 40.0), Arc::new(MockClock::new()), true).unwrap();
+
+        events.observe(35.0);
+        assert_eq!(events.current.get(), None);
+        events.observe(20.0);
+        assert_eq!(events.current.get(), Some(Environment::Dark));
+        events.observe(35.0);
+        assert_eq!(events.current.get(), Some(Environment::Dark));
+        events.observe(45.0);
+        assert_eq!(events.current.get(), Some(Environment::Bright));
+        events.observe(35.0);
+        assert_eq!(events.current.get(), Some(Environment::Bright));
+    }
+
+    #[test]
+    fn changes_have_to_last() {
+        let clock = Arc::new(MockClock::new());
+        let config = EnvironmentConfig {
+            sustain: 60,
+            ..config(30.0, 40.0)
+        };
+        let events = EnvironmentEvents::new(&config, clock.clone(), true).unwrap();
+
+        events.observe(20.0);
+        assert_eq!(events.current.get(), Some(Environment::Dark));
+        events.observe(45.0);
+        clock.advance(Duration::from_secs(30));
+        // A passing brightening starts over
+        events.observe(35.0);
+        events.observe(45.0);
+        clock.advance(Duration::from_secs(59));
+        events.observe(45.0);
+        assert_eq!(events.current.get(), Some(Environment::Dark));
+        clock.advance(Duration::from_secs(1));
+        events.observe(45.0);
+        assert_eq!(events.current.get(), Some(Environment::Bright));
+    }
+
+    #[test]
+    fn inverted_thresholds_are_an_error() {
+        let clock = Arc::new(MockClock::new());
+        assert!(EnvironmentEvents::new(&config(50.0, 40.0), clock, false).is_err());
+    }
+}
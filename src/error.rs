@@ -0,0 +1,59 @@
+use std::{io, path::PathBuf};
+
+/// Errors returned by the library
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The light sensor couldn't be found, initialized, or read
+    #[error("Sensor error: {0}")]
+    Sensor(String),
+    #[cfg(feature = "iio")]
+    #[error("IIO error: {0}")]
+    Iio(#[from] industrial_io::Error),
+    /// A sysfs attribute couldn't be accessed
+    #[error("Couldn't access {}: {source}", path.display())]
+    Sysfs {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// A sysfs attribute didn't contain the expected value
+    #[error("Couldn't parse {}: {value:?}", path.display())]
+    Parse { path: PathBuf, value: String },
+    /// No device of the given kind was found
+    #[error("Couldn't find {0}")]
+    NotFound(String),
+    #[error("D-Bus error: {0}")]
+    DBus(#[from] zbus::Error),
+    /// The control socket spoke something unexpected, or a command couldn't be delivered
+    #[error("Control protocol error: {0}")]
+    Protocol(String),
+    /// The control socket couldn't be set up
+    #[error("Control socket error: {0}")]
+    Socket(String),
+    #[error("Config error: {0}")]
+    Config(String),
+    /// A session recording couldn't be written or replayed
+    #[error("Recording error: {0}")]
+    Record(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    /// Whether the write was refused for lack of permissions rather than failing
+    pub(crate) fn is_permission_denied(&self) -> bool {
+        match self {
+            Self::Sysfs { source, .. } | Self::Io(source) => {
+                source.kind() == io::ErrorKind::PermissionDenied
+            }
+            Self::DBus(zbus::Error::MethodError(name, _, _)) => matches!(
+                name.as_str(),
+                "org.freedesktop.DBus.Error.AccessDenied" | "org.freedesktop.login1.NotInControl"
+            ),
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,290 @@
+//! Typing on external keyboards, so the laptop keyboard can go dark while its
+//! keys aren't the ones in use
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    mem,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{clock::Clock, config::ExternalKeyboardConfig, sysfs::Sysfs};
+
+/// From linux/input-event-codes.h
+const EV_KEY: u16 = 0x01;
+const KEY_A: u32 = 30;
+const KEY_Z: u32 = 44;
+const KEY_SPACE: u32 = 57;
+
+/// Bus types from linux/input.h, by the names used in the config
+const BUSES: &[(&str, u16)] = &[("usb", 0x03), ("bluetooth", 0x05)];
+
+/// How often the reader checks whether it should stop
+const POLL_TIMEOUT_MS: i32 = 1000;
+
+/// Whether a `capabilities/key` bitmask, space separated hex words with the
+/// lowest last, has letter keys and a space bar, unlike mice and media buttons
+fn has_letter_keys(capabilities: &str) -> bool {
+    capabilities
+        .split_whitespace()
+        .last()
+        .and_then(|x| u64::from_str_radix(x, 16).ok())
+        .is_some_and(|x| {
+            [KEY_A, KEY_Z, KEY_SPACE]
+                .iter()
+                .all(|key| x & (1 << key) != 0)
+        })
+}
+
+/// Whether a buffer of `input_event`s has a key press or repeat
+fn has_key_press(buf: &[u8]) -> bool {
+    buf.chunks_exact(mem::size_of::<libc::input_event>())
+        .any(|chunk| {
+            // SAFETY: the chunk is exactly one event, read unaligned
+            let event = unsafe { (chunk.as_ptr() as *const libc::input_event).read_unaligned() };
+            event.type_ == EV_KEY && event.value != 0
+        })
+}
+
+/// evdev keyboards on the configured buses, skipping ignored names
+fn detect(sysfs: &Sysfs, config: &ExternalKeyboardConfig) -> Vec<(String, PathBuf)> {
+    let buses = BUSES
+        .iter()
+        .filter(|(name, _)| config.buses.iter().any(|x| x == name))
+        .map(|(_, bus)| *bus)
+        .collect::<Vec<_>>();
+    let input = sysfs.class("input");
+    let Ok(entries) = fs::read_dir(&input) else {
+        return Vec::new();
+    };
+    let mut events = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with("event"))
+        .collect::<Vec<_>>();
+    events.sort();
+
+    events
+        .into_iter()
+        .filter_map(|event| {
+            let device = input.join(&event).join("device");
+            let read = |attribute: &str| fs::read_to_string(device.join(attribute)).ok();
+            let bus = u16::from_str_radix(read("id/bustype")?.trim(), 16).ok()?;
+            let name = read("name")?.trim().to_string();
+            let keyboard = buses.contains(&bus)
+                && read("capabilities/key").is_some_and(|x| has_letter_keys(&x))
+                && !config.ignore.iter().any(|x| name.contains(x.as_str()));
+            keyboard.then(|| (name, Path::new("/dev/input").join(event)))
+        })
+        .collect()
+}
+
+/// Follows key presses on every external keyboard found when it was created
+pub(crate) struct ExternalKeyboards {
+    clock: Arc<dyn Clock>,
+    idle_after: Duration,
+    last_press: Arc<Mutex<Option<Instant>>>,
+    stop: Arc<AtomicBool>,
+    active: bool,
+}
+
+impl ExternalKeyboards {
+    /// `None` when there are no external keyboards that can be read
+    pub(crate) fn new(
+        sysfs: &Sysfs,
+        config: &ExternalKeyboardConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Option<Self> {
+        let keyboards = detect(sysfs, config)
+            .into_iter()
+            .filter_map(|(name, path)| match File::open(&path) {
+                Ok(file) => {
+                    info!("Watching external keyboard: {} ({})", name, path.display());
+                    Some(file)
+                }
+                Err(e) => {
+                    warn!(
+                        "Can't read external keyboard {} at {}, add the user to the input \
+                         group to follow it: {}",
+                        name,
+                        path.display(),
+                        e
+                    );
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if keyboards.is_empty() {
+            debug!("No external keyboards");
+            return None;
+        }
+
+        let last_press = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        thread::spawn({
+            let (clock, last_press, stop) = (clock.clone(), last_press.clone(), stop.clone());
+            move || watch(keyboards, &*clock, &last_press, &stop)
+        });
+        Some(Self {
+            clock,
+            idle_after: Duration::from_secs(config.idle_after),
+            last_press,
+            stop,
+            active: false,
+        })
+    }
+
+    /// Whether an external keyboard was typed on within the idle time
+    pub(crate) fn active(&mut self) -> bool {
+        let last_press = *self.last_press.lock().expect("Keyboard state poisoned");
+        let active =
+            last_press.is_some_and(|at| self.clock.now().duration_since(at) < self.idle_after);
+        if active != self.active {
+            self.active = active;
+            if active {
+                info!("Typing on an external keyboard, turning the KBD Backlight off");
+            } else {
+                info!("External keyboard idle, following the sensor again");
+            }
+        }
+        active
+    }
+}
+
+impl Drop for ExternalKeyboards {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Records the time of every key press until stopped or every keyboard is gone
+fn watch(
+    mut keyboards: Vec<File>,
+    clock: &dyn Clock,
+    last_press: &Mutex<Option<Instant>>,
+    stop: &AtomicBool,
+) {
+    let mut buf = [0u8; 64 * mem::size_of::<libc::input_event>()];
+    while !keyboards.is_empty() && !stop.load(Ordering::Relaxed) {
+        let mut fds = keyboards
+            .iter()
+            .map(|file| libc::pollfd {
+                fd: file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+        // SAFETY: fds is valid for its length and the files outlive the call
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, POLL_TIMEOUT_MS) };
+        if ready <= 0 {
+            continue;
+        }
+
+        let mut gone = Vec::new();
+        for (index, fd) in fds.iter().enumerate().filter(|(_, fd)| fd.revents != 0) {
+            match keyboards[index].read(&mut buf) {
+                Ok(len) if len > 0 && has_key_press(&buf[..len]) => {
+                    *last_press.lock().expect("Keyboard state poisoned") = Some(clock.now());
+                }
+                Ok(0) => gone.push(index),
+                Err(e) if e.kind() != ErrorKind::Interrupted => gone.push(index),
+                _ if fd.revents & (libc::POLLERR | libc::POLLHUP) != 0 => gone.push(index),
+                _ => (),
+            }
+        }
+        // Unplugged keyboards are found again on the next rescan
+        for index in gone.into_iter().rev() {
+            keyboards.remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_letter_keys() {
+        // A full keyboard's lowest word, and one with only media keys
+        assert!(has_letter_keys("1f 0 fffffffffffffffe"));
+        assert!(!has_letter_keys("1f 0 0"));
+        assert!(!has_letter_keys(""));
+    }
+
+    #[test]
+    fn detects_external_keyboards() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sysfs = Sysfs::new(dir.path());
+        for (event, bus, name, keys) in [
+            (
+                "event3",
+                "0011",
+                "AT Translated Set 2 keyboard",
+                "fffffffffffffffe",
+            ),
+            ("event7", "0003", "Logitech USB Mouse", "0"),
+            ("event8", "0003", "Keychron K2", "fffffffffffffffe"),
+            (
+                "event9",
+                "0003",
+                "Yubico YubiKey OTP+FIDO",
+                "fffffffffffffffe",
+            ),
+        ] {
+            let device = sysfs.class("input").join(event).join("device");
+            fs::create_dir_all(device.join("id")).unwrap();
+            fs::create_dir_all(device.join("capabilities")).unwrap();
+            fs::write(device.join("id/bustype"), format!("{}\n", bus)).unwrap();
+            fs::write(device.join("name"), format!("{}\n", name)).unwrap();
+            fs::write(device.join("capabilities/key"), format!("{}\n", keys)).unwrap();
+        }
+        let config = ExternalKeyboardConfig {
+            ignore: vec!["YubiKey".to_string()],
+            ..ExternalKeyboardConfig::default()
+        };
+
+        assert_eq!(
+            detect(&sysfs, &config),
+            vec![(
+                "Keychron K2".to_string(),
+                PathBuf::from("/dev/input/event8")
+            )]
+        );
+    }
+
+    #[test]
+    fn counts_presses_and_repeats() {
+        let event = |type_: u16, value: i32| {
+            let event = libc::input_event {
+                time: libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                },
+                type_,
+                code: KEY_A as u16,
+                value,
+            };
+            // SAFETY: input_event is plain data
+            unsafe {
+                std::slice::from_raw_parts(
+                    &event as *const libc::input_event as *const u8,
+                    mem::size_of::<libc::input_event>(),
+                )
+            }
+            .to_vec()
+        };
+        assert!(has_key_press(&event(EV_KEY, 1)));
+        assert!(has_key_press(&event(EV_KEY, 2)));
+        assert!(!has_key_press(&event(EV_KEY, 0)));
+        assert!(!has_key_press(&event(0x02, 1)));
+    }
+}
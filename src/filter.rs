@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+
+use log::debug;
+use yata::{core::Method, methods::WMA};
+
+use crate::{config::FilterConfig, Error, Result};
+
+/// Smoothing applied to the log-scaled sensor readings
+pub(crate) enum Filter {
+    /// Weighted moving average, with its window to start over with
+    Wma(WMA, u8),
+    /// Exponential smoothing with separate rates for rising and falling values
+    Asymmetric { value: f64, rise: f64, fall: f64 },
+    /// Exponential smoothing with a window that follows the noise in `history`
+    Auto {
+        value: f64,
+        min_window: u8,
+        max_window: u8,
+        window: u8,
+        history: VecDeque<f64>,
+    },
+}
+
+/// Readings the auto filter judges noise over
+const HISTORY: usize = 20;
+
+/// Reading to reading jitter, in decades, that gets the widest auto window
+const NOISY: f64 = 0.1;
+
+/// Standard deviation of the steps between consecutive readings, which
+/// ignores steady changes in the light itself
+fn jitter(history: &VecDeque<f64>) -> f64 {
+    let steps = history
+        .iter()
+        .zip(history.iter().skip(1))
+        .map(|(a, b)| b - a)
+        .collect::<Vec<_>>();
+    if steps.len() < 2 {
+        return 0.0;
+    }
+    let mean = steps.iter().sum::<f64>() / steps.len() as f64;
+    let variance = steps.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / steps.len() as f64;
+    variance.sqrt()
+}
+
+/// Smoothing factor of an exponential moving average spanning `window` samples
+fn alpha(window: u8) -> f64 {
+    2f64 / (window.max(1) as f64 + 1f64)
+}
+
+impl Filter {
+    pub(crate) fn new(config: &FilterConfig, initial: f64) -> Result<Self> {
+        Ok(match config {
+            FilterConfig::Wma { window } => Self::Wma(
+                WMA::new(*window, &initial)
+                    .map_err(|e| Error::Config(format!("Invalid WMA window: {}", e)))?,
+                *window,
+            ),
+            FilterConfig::Asymmetric {
+                rise_window,
+                fall_window,
+            } => Self::Asymmetric {
+                value: initial,
+                rise: alpha(*rise_window),
+                fall: alpha(*fall_window),
+            },
+            FilterConfig::Auto {
+                min_window,
+                max_window,
+            } => {
+                if min_window > max_window {
+                    return Err(Error::Config(format!(
+                        "Auto filter min_window {} is above max_window {}",
+                        min_window, max_window
+                    )));
+                }
+                Self::Auto {
+                    value: initial,
+                    min_window: *min_window,
+                    max_window: *max_window,
+                    window: *min_window,
+                    history: VecDeque::from([initial]),
+                }
+            }
+        })
+    }
+
+    /// Forgets past readings, as if the filter had just started at `initial`
+    pub(crate) fn reset(&mut self, initial: f64) {
+        match self {
+            Self::Wma(wma, window) => {
+                *wma = WMA::new(*window, &initial).expect("WMA window checked in Filter::new")
+            }
+            Self::Asymmetric { value, .. } => *value = initial,
+            Self::Auto {
+                value,
+                min_window,
+                window,
+                history,
+                ..
+            } => {
+                *value = initial;
+                *window = *min_window;
+                *history = VecDeque::from([initial]);
+            }
+        }
+    }
+
+    pub(crate) fn next(&mut self, val: f64) -> f64 {
+        match self {
+            Self::Wma(wma, _) => wma.next(&val),
+            Self::Asymmetric { value, rise, fall } => {
+                let alpha = if val > *value { *rise } else { *fall };
+                *value += alpha * (val - *value);
+                *value
+            }
+            Self::Auto {
+                value,
+                min_window,
+                max_window,
+                window,
+                history,
+            } => {
+                if history.len() == HISTORY {
+                    history.pop_front();
+                }
+                history.push_back(val);
+
+                let noise = (jitter(history) / NOISY).min(1.0);
+                let span = (*max_window - *min_window) as f64;
+                let next_window = *min_window + (noise * span).round() as u8;
+                if next_window != *window {
+                    debug!("Auto filter window: {} -> {}", window, next_window);
+                    *window = next_window;
+                }
+
+                *value += alpha(*window) * (val - *value);
+                *value
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auto() -> Filter {
+        Filter::new(
+            &FilterConfig::Auto {
+                min_window: 2,
+                max_window: 30,
+            },
+            1.0,
+        )
+        .unwrap()
+    }
+
+    fn window(filter: &Filter) -> u8 {
+        match filter {
+            Filter::Auto { window, .. } => *window,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn auto_window_stays_narrow_for_clean_readings() {
+        let mut filter = auto();
+        // A steady ramp is a change in light, not noise
+        for i in 0..HISTORY {
+            filter.next(1.0 + i as f64 * 0.05);
+        }
+        assert_eq!(window(&filter), 2);
+    }
+
+    #[test]
+    fn auto_window_widens_for_noisy_readings() {
+        let mut filter = auto();
+        for i in 0..HISTORY {
+            filter.next(if i % 2 == 0 { 1.2 } else { 0.8 });
+        }
+        assert_eq!(window(&filter), 30);
+    }
+
+    #[test]
+    fn reset_forgets_past_readings() {
+        let configs = [
+            FilterConfig::Wma { window: 10 },
+            FilterConfig::Asymmetric {
+                rise_window: 10,
+                fall_window: 10,
+            },
+            FilterConfig::Auto {
+                min_window: 2,
+                max_window: 30,
+            },
+        ];
+        for config in configs {
+            let mut filter = Filter::new(&config, 1.0).unwrap();
+            for i in 0..HISTORY {
+                filter.next(if i % 2 == 0 { 1.2 } else { 0.8 });
+            }
+            filter.reset(5.0);
+            assert!((filter.next(5.0) - 5.0).abs() < 1e-9);
+        }
+        let mut filter = auto();
+        for i in 0..HISTORY {
+            filter.next(if i % 2 == 0 { 1.2 } else { 0.8 });
+        }
+        filter.reset(5.0);
+        assert_eq!(window(&filter), 2);
+    }
+
+    #[test]
+    fn auto_window_bounds_are_checked() {
+        let config = FilterConfig::Auto {
+            min_window: 10,
+            max_window: 5,
+        };
+        assert!(Filter::new(&config, 1.0).is_err());
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{ambient_brightness::FLOOR, clock::Clock, sensor::Sensor, Error, Result};
+
+/// What the daemon is doing with the brightness
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Active,
+    /// Dimmed for inactivity
+    Idle,
+    /// Not reading the sensor while idle or locked
+    Suspended,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Active => write!(f, "active"),
+            Self::Idle => write!(f, "idle"),
+            Self::Suspended => write!(f, "suspended"),
+        }
+    }
+}
+
+/// Snapshot of how the daemon is doing, as reported by the ping command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub uptime: Duration,
+    /// Time since the last successful sensor read
+    pub last_read: Option<Duration>,
+    /// Time since the last successful brightness write
+    pub last_write: Option<Duration>,
+    pub sensor_errors: u32,
+    pub write_errors: u32,
+    /// Whether the sensor keeps returning the same reading
+    pub sensor_stuck: bool,
+    /// Outputs whose last update failed
+    pub failing_outputs: u32,
+    /// Ambient percent of the last update, not reported in privacy mode
+    pub ambient: Option<u32>,
+    /// Sensor reading of the last update, not reported in privacy mode
+    pub lux: Option<u32>,
+    pub mode: Mode,
+    /// Time since the mode last changed, or since the start
+    pub mode_for: Duration,
+    /// Brightness changes made since the start, across all outputs
+    pub adjustments: u32,
+    /// Output updates that failed since the start
+    pub output_errors: u32,
+    /// Time since each output was last adjusted, by name
+    pub last_adjusted: Vec<(String, Duration)>,
+    /// Where each output with an offset was last set, not reported in
+    /// privacy mode
+    pub levels: Vec<Level>,
+}
+
+/// Percent an output was last set to and the manual offset it includes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Level {
+    pub name: String,
+    pub percent: u32,
+    pub offset: i8,
+}
+
+#[derive(Default)]
+struct State {
+    last_read: Option<Instant>,
+    last_write: Option<Instant>,
+    sensor_errors: u32,
+    write_errors: u32,
+    /// Last reading and when it last differed from the one before
+    reading: Option<(f64, Instant)>,
+    sensor_stuck: bool,
+    /// Updates in a row that failed, by output
+    failing: HashMap<String, u32>,
+    ambient: Option<u32>,
+    lux: Option<u32>,
+    mode: Mode,
+    mode_since: Option<Instant>,
+    adjustments: u32,
+    output_errors: u32,
+    adjusted: HashMap<String, Instant>,
+    levels: Vec<Level>,
+}
+
+/// Shared between the controller, which updates it, and the control server,
+/// which reports it
+pub struct Health {
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Health {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            started: clock.now(),
+            clock,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State, Instant)) {
+        let now = self.clock.now();
+        f(&mut self.state.lock().expect("Health poisoned"), now);
+    }
+
+    pub(crate) fn read(&self, result: &Result<f64>) {
+        self.update(|state, now| match result {
+            Ok(value) => {
+                state.last_read = Some(now);
+                if state.reading.is_none_or(|(last, _)| last != *value) {
+                    state.reading = Some((*value, now));
+                    state.sensor_stuck = false;
+                }
+            }
+            Err(_) => state.sensor_errors += 1,
+        })
+    }
+
+    /// Ambient percent and sensor reading that brightness was last set from
+    pub(crate) fn sample(&self, ambient: u32, lux: f64) {
+        self.update(|state, _| {
+            state.ambient = Some(ambient);
+            state.lux = Some(lux.round() as u32);
+        })
+    }
+
+    /// Where the outputs were set by the last update or command
+    pub(crate) fn levels(&self, levels: Vec<Level>) {
+        self.update(|state, _| state.levels = levels)
+    }
+
+    pub(crate) fn mode(&self, mode: Mode) {
+        self.update(|state, now| {
+            if state.mode != mode {
+                state.mode = mode;
+                state.mode_since = Some(now);
+            }
+        })
+    }
+
+    /// How long the sensor has returned the same reading, once that's at least
+    /// `after`. Counting starts over from here, so the next report comes
+    /// another `after` later unless the reading changes. Readings below the
+    /// sensor floor never count, since a pitch-black room reads a steady 0.
+    pub(crate) fn stuck(&self, after: Duration) -> Option<Duration> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().expect("Health poisoned");
+        let (value, since) = state.reading?;
+        if value < FLOOR {
+            return None;
+        }
+        let unchanged = now.duration_since(since);
+        if unchanged < after {
+            return None;
+        }
+        state.reading = Some((value, now));
+        state.sensor_stuck = true;
+        Some(unchanged)
+    }
+
+    pub(crate) fn write<T>(&self, result: &Result<T>) {
+        self.update(|state, now| match result {
+            Ok(_) => state.last_write = Some(now),
+            Err(_) => state.write_errors += 1,
+        })
+    }
+
+    /// Records whether an output's update worked and changed its brightness,
+    /// returning how many updates in a row had failed before this one
+    pub(crate) fn output(&self, name: &str, result: &Result<bool>) -> u32 {
+        let now = self.clock.now();
+        let mut state = self.state.lock().expect("Health poisoned");
+        match result {
+            Ok(adjusted) => {
+                if *adjusted {
+                    state.adjustments = state.adjustments.saturating_add(1);
+                    state.adjusted.insert(name.to_string(), now);
+                }
+                state.failing.remove(name).unwrap_or(0)
+            }
+            Err(_) => {
+                state.output_errors = state.output_errors.saturating_add(1);
+                let failures = state.failing.entry(name.to_string()).or_default();
+                *failures += 1;
+                *failures - 1
+            }
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let now = self.clock.now();
+        let state = self.state.lock().expect("Health poisoned");
+        Status {
+            uptime: now.duration_since(self.started),
+            last_read: state.last_read.map(|x| now.duration_since(x)),
+            last_write: state.last_write.map(|x| now.duration_since(x)),
+            sensor_errors: state.sensor_errors,
+            write_errors: state.write_errors,
+            sensor_stuck: state.sensor_stuck,
+            failing_outputs: state.failing.len() as u32,
+            ambient: state.ambient,
+            lux: state.lux,
+            mode: state.mode,
+            mode_for: now.duration_since(state.mode_since.unwrap_or(self.started)),
+            adjustments: state.adjustments,
+            output_errors: state.output_errors,
+            last_adjusted: {
+                let mut adjusted = state
+                    .adjusted
+                    .iter()
+                    .map(|(name, at)| (name.clone(), now.duration_since(*at)))
+                    .collect::<Vec<_>>();
+                adjusted.sort();
+                adjusted
+            },
+            levels: state.levels.clone(),
+        }
+    }
+}
+
+impl Status {
+    /// Fills in a template like `{percent}% {lux}lx {mode}`. Times are in
+    /// seconds, missing values print as `-`, and `{{` and `}}` are literal braces.
+    pub fn format(&self, template: &str) -> Result<String> {
+        let mut out = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    out.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    out.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| {
+                        Error::Config(format!("Unclosed {{ in status format {:?}", template))
+                    })?;
+                    out.push_str(&self.field(&rest[..end])?);
+                    chars = rest[end + 1..].chars();
+                }
+                c => out.push(c),
+            }
+        }
+        Ok(out)
+    }
+
+    fn field(&self, name: &str) -> Result<String> {
+        let secs =
+            |x: Option<Duration>| x.map_or("-".to_string(), |x| format!("{:.1}", x.as_secs_f64()));
+        let or_dash = |x: Option<u32>| x.map_or("-".to_string(), |x| x.to_string());
+        Ok(match name {
+            "uptime" => secs(Some(self.uptime)),
+            "last_read" => secs(self.last_read),
+            "last_write" => secs(self.last_write),
+            "sensor_errors" => self.sensor_errors.to_string(),
+            "write_errors" => self.write_errors.to_string(),
+            "stuck" => if self.sensor_stuck { "yes" } else { "no" }.to_string(),
+            "failing_outputs" => self.failing_outputs.to_string(),
+            "percent" => or_dash(self.ambient),
+            "lux" => or_dash(self.lux),
+            "mode" => self.mode.to_string(),
+            "mode_for" => secs(Some(self.mode_for)),
+            "adjustments" => self.adjustments.to_string(),
+            "output_errors" => self.output_errors.to_string(),
+            _ => return Err(Error::Config(format!("Unknown status field {{{}}}", name))),
+        })
+    }
+}
+
+/// Tracks reads of the wrapped sensor
+pub(crate) struct MonitoredSensor {
+    sensor: Box<dyn Sensor>,
+    health: Arc<Health>,
+}
+
+impl MonitoredSensor {
+    pub(crate) fn new(sensor: Box<dyn Sensor>, health: Arc<Health>) -> Self {
+        Self { sensor, health }
+    }
+}
+
+impl Sensor for MonitoredSensor {
+    fn read(&self) -> Result<f64> {
+        let result = self.sensor.read();
+        self.health.read(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn status() -> Status {
+        Status {
+            uptime: Duration::from_millis(61500),
+            last_read: Some(Duration::from_secs(2)),
+            last_write: None,
+            sensor_errors: 1,
+            write_errors: 0,
+            sensor_stuck: false,
+            failing_outputs: 0,
+            ambient: Some(42),
+            lux: Some(310),
+            mode: Mode::Idle,
+            mode_for: Duration::from_secs(30),
+            adjustments: 12,
+            output_errors: 2,
+            last_adjusted: vec![("intel_backlight".to_string(), Duration::from_secs(5))],
+            levels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn format_fills_in_fields() {
+        assert_eq!(
+            status().format("{percent}% ☀ {lux}lx {mode}").unwrap(),
+            "42% ☀ 310lx idle"
+        );
+        assert_eq!(
+            status()
+                .format("{uptime} {last_read} {last_write} {stuck} {{x}}")
+                .unwrap(),
+            "61.5 2.0 - no {x}"
+        );
+        assert_eq!(
+            status()
+                .format("{mode} for {mode_for}s, {adjustments} changes, {output_errors} errors")
+                .unwrap(),
+            "idle for 30.0s, 12 changes, 2 errors"
+        );
+    }
+
+    #[test]
+    fn steady_darkness_is_not_stuck() {
+        let clock = Arc::new(MockClock::new());
+        let health = Health::new(clock.clone());
+        health.read(&Ok(0.0));
+        clock.advance(Duration::from_secs(60));
+        health.read(&Ok(0.0));
+        assert_eq!(health.stuck(Duration::from_secs(10)), None);
+
+        health.read(&Ok(5.0));
+        clock.advance(Duration::from_secs(60));
+        health.read(&Ok(5.0));
+        assert_eq!(
+            health.stuck(Duration::from_secs(10)),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn tracks_modes_and_adjustments() {
+        let clock = Arc::new(MockClock::new());
+        let health = Health::new(clock.clone());
+        clock.advance(Duration::from_secs(10));
+        health.mode(Mode::Active);
+        assert_eq!(health.status().mode_for, Duration::from_secs(10));
+
+        health.mode(Mode::Idle);
+        health.output("kbd", &Ok(true));
+        health.output("screen", &Ok(false));
+        health.output("screen", &Err(Error::NotFound("screen".to_string())));
+        clock.advance(Duration::from_secs(3));
+        health.output("screen", &Ok(true));
+
+        let status = health.status();
+        assert_eq!(status.mode_for, Duration::from_secs(3));
+        assert_eq!((status.adjustments, status.output_errors), (2, 1));
+        assert_eq!(
+            status.last_adjusted,
+            [
+                ("kbd".to_string(), Duration::from_secs(3)),
+                ("screen".to_string(), Duration::ZERO)
+            ]
+        );
+    }
+
+    #[test]
+    fn format_rejects_bad_templates() {
+        assert!(status().format("{brightness}").is_err());
+        assert!(status().format("{percent").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+//! Split between a small privileged instance that owns brightness writes and
+//! the user instance that reads the sensor and serves the control socket.
+//! Devices set to `backend = "helper"` send each write to the helper over its
+//! own socket, one write per connection.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crossbeam::channel::Receiver;
+use log::{debug, info, warn};
+
+use crate::{
+    config::{Backend, Config},
+    control_server::{peer_cred, remove_stale, set_permissions},
+    protocol::{decode_profile_reply, encode_profile_reply},
+    sysfs::{Device, Sysfs},
+    Error, Result,
+};
+
+/// How long either side waits for the other
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Classes under /sys/class the helper writes to
+const SUBSYSTEMS: &[&str] = &["backlight", "leds"];
+
+/// Write request: the subsystem and the device name, each after its length as
+/// a byte, then the raw level as a big endian u32
+fn encode_write(subsystem: &str, name: &str, level: u32) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    for field in [subsystem, name] {
+        let len = u8::try_from(field.len())
+            .map_err(|_| Error::Protocol(format!("{} is too long for the helper", field)))?;
+        request.push(len);
+        request.extend_from_slice(field.as_bytes());
+    }
+    request.write_u32::<BigEndian>(level).expect("Vec write");
+    Ok(request)
+}
+
+fn decode_write(reader: &mut impl Read) -> Result<(String, String, u32)> {
+    let mut field = || -> Result<String> {
+        let mut field = vec![0; reader.read_u8()? as usize];
+        reader.read_exact(&mut field)?;
+        String::from_utf8(field)
+            .map_err(|e| Error::Protocol(format!("Helper request isn't UTF-8: {}", e)))
+    };
+    let subsystem = field()?;
+    let name = field()?;
+    Ok((subsystem, name, reader.read_u32::<BigEndian>()?))
+}
+
+/// Sets a device through the helper listening on `socket`, failing with the
+/// helper's reason when it refuses. Replies are the same as for profile edits.
+pub(crate) fn write(socket: &Path, subsystem: &str, name: &str, level: u32) -> Result<()> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Couldn't reach the helper at {}: {}", socket.display(), e),
+        )
+    })?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(&encode_write(subsystem, name, level)?)?;
+
+    let mut header = [0; 3];
+    stream.read_exact(&mut header)?;
+    let mut message = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+    stream.read_exact(&mut message)?;
+    decode_profile_reply(header[0], message).map_err(|e| {
+        Error::Io(io::Error::other(format!(
+            "Helper didn't set {}/{}: {}",
+            subsystem, name, e
+        )))
+    })
+}
+
+/// Writes brightness for user instances, as an instance that may write sysfs,
+/// e.g. a system service running as root
+pub struct Helper {
+    listener: UnixListener,
+    socket_path: PathBuf,
+    sysfs: Sysfs,
+    /// Names of the devices it may write, or empty for all of them
+    devices: Vec<String>,
+    /// Devices written so far, opened on their first write
+    opened: HashMap<(String, String), Device>,
+}
+
+impl Helper {
+    /// Listens on the socket from `[helper]`
+    pub fn new(config: &Config) -> Result<Self> {
+        Self::bind(&config.helper.socket, config)
+    }
+
+    pub fn bind(socket_path: impl AsRef<Path>, config: &Config) -> Result<Self> {
+        let socket_path = socket_path.as_ref();
+        if let Some(dir) = socket_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        remove_stale(socket_path)?;
+        let listener = UnixListener::bind(socket_path)?;
+        set_permissions(
+            socket_path,
+            config.helper.mode,
+            config.helper.group.as_deref(),
+        )?;
+        info!(
+            "Writing brightness for user instances on {}",
+            socket_path.display()
+        );
+
+        Ok(Self {
+            listener,
+            socket_path: socket_path.to_path_buf(),
+            sysfs: Sysfs::default(),
+            devices: config.helper.devices.clone(),
+            opened: HashMap::new(),
+        })
+    }
+
+    /// Looks for devices under `root` instead of `/sys`
+    pub fn sysfs_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.sysfs = Sysfs::new(root);
+        self
+    }
+
+    /// Serves writes until `close` receives, then removes the socket
+    pub fn run(mut self, close: Receiver<()>) -> Result<()> {
+        let stopped = AtomicBool::new(false);
+        let socket_path = self.socket_path.clone();
+        let listener = self.listener.try_clone()?;
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _ = close.recv();
+                stopped.store(true, Ordering::SeqCst);
+                // Wakes the accept below
+                let _ = UnixStream::connect(&socket_path);
+            });
+
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(mut stream) => self.serve(&mut stream),
+                    Err(e) => warn!("Couldn't accept a helper connection: {}", e),
+                }
+            }
+        });
+
+        match fs::remove_file(&self.socket_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn serve(&mut self, stream: &mut UnixStream) {
+        let peer = peer_cred(stream).map_or("?".to_string(), |x| x.uid.to_string());
+        let result = stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(Error::from)
+            .and_then(|()| decode_write(stream))
+            .and_then(|(subsystem, name, level)| {
+                debug!("uid {} sets {}/{} to {}", peer, subsystem, name, level);
+                self.write(&subsystem, &name, level)
+            });
+        if let Err(e) = &result {
+            warn!("Refused a write from uid {}: {}", peer, e);
+        }
+        if let Err(e) = stream.write_all(&encode_profile_reply(&result)) {
+            debug!("Couldn't answer uid {}: {}", peer, e);
+        }
+    }
+
+    fn write(&mut self, subsystem: &str, name: &str, level: u32) -> Result<()> {
+        if !SUBSYSTEMS.contains(&subsystem) {
+            return Err(Error::Protocol(format!(
+                "{} isn't a brightness class",
+                subsystem
+            )));
+        }
+        // Names are looked up as one directory, never a path out of the class
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(Error::Protocol(format!("Invalid device name {:?}", name)));
+        }
+        if !self.devices.is_empty() && !self.devices.iter().any(|x| x == name) {
+            return Err(Error::Protocol(format!(
+                "{} isn't among the helper's devices",
+                name
+            )));
+        }
+
+        let key = (subsystem.to_string(), name.to_string());
+        let device = match self.opened.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(self.sysfs.device(subsystem, name, Backend::Sysfs)?)
+            }
+        };
+        if level > device.max_brightness {
+            return Err(Error::Protocol(format!(
+                "{} is above the maximum of {}/{}, {}",
+                level, subsystem, name, device.max_brightness
+            )));
+        }
+        let result = device.write_brightness(level);
+        // Opened again next time, in case it was unplugged
+        if result.is_err() {
+            self.opened.remove(&key);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn helper(dir: &TempDir, devices: &[&str]) -> Helper {
+        let led = dir.path().join("class/leds/input3::capslock");
+        fs::create_dir_all(&led).unwrap();
+        fs::write(led.join("max_brightness"), "1\n").unwrap();
+        fs::write(led.join("brightness"), "0\n").unwrap();
+
+        let mut config = Config::default();
+        config.helper.devices = devices.iter().map(|x| x.to_string()).collect();
+        Helper::bind(dir.path().join("helper.sock"), &config)
+            .unwrap()
+            .sysfs_root(dir.path())
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let request = encode_write("leds", "input3::capslock", 70000).unwrap();
+        assert_eq!(
+            decode_write(&mut &request[..]).unwrap(),
+            ("leds".to_string(), "input3::capslock".to_string(), 70000)
+        );
+        assert!(decode_write(&mut &request[..6]).is_err());
+        assert!(encode_write("leds", &"x".repeat(300), 1).is_err());
+    }
+
+    #[test]
+    fn writes_only_brightness_within_range() {
+        let dir = TempDir::new().unwrap();
+        let mut helper = helper(&dir, &[]);
+        let brightness = dir.path().join("class/leds/input3::capslock/brightness");
+
+        helper.write("leds", "input3::capslock", 1).unwrap();
+        assert_eq!(fs::read_to_string(&brightness).unwrap().trim(), "1");
+
+        assert!(helper.write("leds", "input3::capslock", 2).is_err());
+        assert!(helper.write("power", "input3::capslock", 1).is_err());
+        assert!(helper.write("leds", "..", 1).is_err());
+        assert!(helper.write("leds", "../backlight/x", 1).is_err());
+        assert!(helper.write("leds", "missing", 1).is_err());
+        assert_eq!(fs::read_to_string(&brightness).unwrap().trim(), "1");
+    }
+
+    #[test]
+    fn keeps_to_its_devices() {
+        let dir = TempDir::new().unwrap();
+        let mut helper = helper(&dir, &["intel_backlight"]);
+        assert!(helper.write("leds", "input3::capslock", 1).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use log::{debug, info};
+
+use crate::{
+    config::HidConfig,
+    levels::rounded_level,
+    output::{Output, StepCurve},
+    redact::Lux,
+    Error, Result,
+};
+
+pub(crate) fn find_hidraw(vendor_id: u16, product_id: u16) -> Result<PathBuf> {
+    for entry in fs::read_dir("/sys/class/hidraw")? {
+        let entry = entry?;
+        let uevent = match fs::read_to_string(entry.path().join("device/uevent")) {
+            Ok(uevent) => uevent,
+            Err(_) => continue,
+        };
+
+        // HID_ID=<bus>:<vendor>:<product>, all hex
+        let matches = uevent
+            .lines()
+            .filter_map(|line| line.strip_prefix("HID_ID="))
+            .any(|id| {
+                let mut parts = id.split(':').skip(1);
+                let vendor = parts.next().and_then(|x| u32::from_str_radix(x, 16).ok());
+                let product = parts.next().and_then(|x| u32::from_str_radix(x, 16).ok());
+                vendor == Some(vendor_id as u32) && product == Some(product_id as u32)
+            });
+
+        if matches {
+            return Ok(PathBuf::from("/dev").join(entry.file_name()));
+        }
+    }
+
+    Err(Error::NotFound(format!(
+        "hidraw device {:04x}:{:04x}",
+        vendor_id, product_id
+    )))
+}
+
+pub(crate) struct HidBrightness {
+    /// Not opened in dry-run mode
+    device: Option<File>,
+    path: PathBuf,
+    /// The path as shown in logs and status, kept to hand out without allocating
+    name: String,
+    report: Vec<u8>,
+    level_index: usize,
+    max_level: u8,
+    curve: StepCurve,
+    cur_level: Option<u8>,
+}
+
+impl HidBrightness {
+    pub(crate) fn new(config: &HidConfig, curve: StepCurve, dry_run: bool) -> Result<Self> {
+        if config.level_index >= config.report.len() {
+            return Err(Error::Config(format!(
+                "HID level_index {} is outside of the {} byte report",
+                config.level_index,
+                config.report.len()
+            )));
+        }
+
+        let path = find_hidraw(config.vendor_id, config.product_id)?;
+        let device = if dry_run {
+            None
+        } else {
+            Some(OpenOptions::new().write(true).open(&path)?)
+        };
+        info!("Using HID Backlight: {}", path.display());
+
+        Ok(Self {
+            device,
+            name: path.display().to_string(),
+            path,
+            report: config.report.clone(),
+            level_index: config.level_index,
+            max_level: config.max_level,
+            curve,
+            cur_level: None,
+        })
+    }
+}
+
+impl Output for HidBrightness {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        // Same curve as the laptop keyboard, scaled to the device's range
+        let new_level = rounded_level(self.curve.percent(new_val), self.max_level as u32) as u8;
+
+        debug!(
+            "HID: nv:{:?}, nl:{:?}, cl:{:?}",
+            Lux(new_val),
+            new_level,
+            self.cur_level
+        );
+        if self.cur_level != Some(new_level) {
+            info!(
+                "Adjusting HID Backlight {}: val:{:?} old:{:?} new:{:?}",
+                self.path.display(),
+                Lux(new_val),
+                self.cur_level,
+                new_level
+            );
+            self.report[self.level_index] = new_level;
+            match &mut self.device {
+                Some(device) => device.write_all(&self.report)?,
+                None => println!("Would write {:?} to {}", self.report, self.path.display()),
+            }
+            self.cur_level = Some(new_level);
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        Some(&mut self.curve)
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::fd::{FromRawFd, OwnedFd},
+    thread,
+};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{debug, info, warn};
+
+use crate::Result;
+
+/// Multicast group of the uevents the kernel sends, before udev rules run
+const KERNEL_GROUP: u32 = 1;
+
+/// Subsystems whose devices can be outputs, or external keyboards that turn
+/// the laptop keyboard off; drm reports monitors coming and going as changes
+/// on the card
+const SUBSYSTEMS: &[&str] = &["backlight", "leds", "drm", "input"];
+
+/// Subscribes to kernel uevents
+fn uevents() -> Result<File> {
+    // SAFETY: socket has no preconditions; the fd is owned right away
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    // SAFETY: sockaddr_nl is plain data, valid when zeroed
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = KERNEL_GROUP;
+    // SAFETY: fd is an open netlink socket and addr lives across the call
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(file)
+}
+
+/// Whether a uevent, `action@devpath` followed by NUL separated `KEY=value`
+/// pairs, adds or removes a possible output
+fn is_output_event(message: &[u8]) -> bool {
+    let mut action = None;
+    let mut subsystem = None;
+    for field in message.split(|x| *x == 0) {
+        let field = String::from_utf8_lossy(field);
+        if let Some(value) = field.strip_prefix("ACTION=") {
+            action = Some(value.to_string());
+        } else if let Some(value) = field.strip_prefix("SUBSYSTEM=") {
+            subsystem = Some(value.to_string());
+        }
+    }
+
+    match (action.as_deref(), subsystem.as_deref()) {
+        (Some("add" | "remove"), Some(subsystem)) => SUBSYSTEMS.contains(&subsystem),
+        (Some("change"), Some("drm")) => true,
+        _ => false,
+    }
+}
+
+fn forward(sender: &Sender<()>) -> Result<()> {
+    let mut socket = uevents()?;
+    info!("Watching for backlight hotplug");
+
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = socket.read(&mut buf)?;
+        if !is_output_event(&buf[..len]) {
+            continue;
+        }
+        debug!(
+            "Output hotplug: {}",
+            String::from_utf8_lossy(buf[..len].split(|x| *x == 0).next().unwrap_or(&[]))
+        );
+        if sender.send(()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends whenever a backlight, LED, monitor, or input device appears or goes
+/// away. The channel disconnects if uevents can't be received.
+pub fn watch() -> Receiver<()> {
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        if let Err(e) = forward(&sender) {
+            warn!("Not watching for backlight hotplug: {}", e);
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_output_events() {
+        let event = |action: &str, subsystem: &str| {
+            format!(
+                "{}@/devices/x\0ACTION={}\0DEVPATH=/devices/x\0SUBSYSTEM={}\0SEQNUM=1\0",
+                action, action, subsystem
+            )
+        };
+        assert!(is_output_event(event("add", "backlight").as_bytes()));
+        assert!(is_output_event(event("remove", "leds").as_bytes()));
+        assert!(is_output_event(event("change", "drm").as_bytes()));
+        assert!(!is_output_event(event("change", "backlight").as_bytes()));
+        assert!(!is_output_event(event("add", "usb").as_bytes()));
+        assert!(!is_output_event(b"libudev\0"));
+    }
+}
@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+
+use log::info;
+
+use crate::{sensor::Sensor, sysfs::Attribute, Result};
+
+pub(crate) struct HwmonSensor {
+    attribute: Attribute,
+}
+
+impl HwmonSensor {
+    pub(crate) fn new(path: PathBuf) -> Result<Self> {
+        let sensor = Self {
+            attribute: Attribute::open(path)?,
+        };
+        sensor.read()?;
+        info!("Using hwmon sensor: {}", sensor.attribute.path().display());
+        Ok(sensor)
+    }
+}
+
+impl Sensor for HwmonSensor {
+    fn read(&self) -> Result<f64> {
+        Ok(self.attribute.read::<u32>()? as f64)
+    }
+}
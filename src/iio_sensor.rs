@@ -0,0 +1,119 @@
+use industrial_io::{Channel, ChannelType, Context, Device};
+use log::{debug, info};
+
+use crate::{
+    config::{Combination, IioConfig},
+    sensor::Sensor,
+    Error, Result,
+};
+
+pub(crate) struct IioSensor {
+    chan: Channel,
+    /// IR channel read along with the broadband one, and how they combine
+    ir: Option<(Channel, Combination)>,
+    scale: f64,
+}
+
+fn is_light_channel(chan: &Channel) -> bool {
+    !chan.is_output()
+        && matches!(
+            chan.channel_type(),
+            ChannelType::Ligtht | ChannelType::Intensity
+        )
+}
+
+/// The first input channel measuring light, preferring illuminance over intensity.
+/// A configured channel id/name or modifier narrows down the candidates, and an
+/// IR channel leaves only the other intensity channels.
+fn light_channel(dev: &Device, config: &IioConfig) -> Option<Channel> {
+    let mut chans = dev
+        .channels()
+        .filter(|chan| match (&config.channel, &config.ir) {
+            (Some(channel), _) => {
+                !chan.is_output()
+                    && (chan.id().as_ref() == Some(channel)
+                        || chan.name().as_ref() == Some(channel))
+            }
+            (None, Some(ir)) => {
+                is_light_channel(chan)
+                    && chan.channel_type() == ChannelType::Intensity
+                    && chan.id().as_ref() != Some(ir)
+            }
+            (None, None) => is_light_channel(chan),
+        })
+        .filter(|chan| match &config.modifier {
+            Some(modifier) => chan
+                .id()
+                .is_some_and(|id| id.ends_with(&format!("_{}", modifier))),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    chans.sort_by_key(|chan| chan.channel_type() != ChannelType::Ligtht);
+    chans.into_iter().next()
+}
+
+impl IioSensor {
+    pub(crate) fn new(config: &IioConfig) -> Result<Self> {
+        let ctx = Context::new()?;
+
+        let dev = match &config.device {
+            Some(name) => ctx
+                .find_device(name)
+                .ok_or_else(|| Error::NotFound(format!("IIO device {}", name)))?,
+            None => ctx
+                .devices()
+                .find(|dev| {
+                    debug!("IIO Device: {:?}", dev.name());
+                    light_channel(dev, config).is_some()
+                })
+                .ok_or_else(|| Error::NotFound("an IIO device with a light channel".to_string()))?,
+        };
+        let chan = light_channel(&dev, config).ok_or_else(|| {
+            Error::Sensor(format!(
+                "IIO device {} has no matching light channel",
+                dev.name().unwrap_or_default()
+            ))
+        })?;
+        let name = dev.name().unwrap_or_default();
+        let scale = config.scale_for(&name);
+        let ir = match (&config.ir, config.combination_for(&name)?) {
+            (Some(ir), Some(combination)) => {
+                let chan = dev
+                    .channels()
+                    .find(|chan| !chan.is_output() && chan.id().as_ref() == Some(ir))
+                    .ok_or_else(|| {
+                        Error::NotFound(format!("IR channel {} of IIO device {}", ir, name))
+                    })?;
+                Some((chan, combination))
+            }
+            _ => None,
+        };
+        info!(
+            "Using IIO sensor: {} ({}), scale {}",
+            name,
+            chan.id().unwrap_or_default(),
+            scale
+        );
+
+        if let Some((ir, combination)) = &ir {
+            info!(
+                "Correcting with {} as {:?}",
+                ir.id().unwrap_or_default(),
+                combination
+            );
+        }
+
+        Ok(Self { chan, ir, scale })
+    }
+}
+
+impl Sensor for IioSensor {
+    fn read(&self) -> Result<f64> {
+        let broadband = self.chan.attr_read_int("raw")? as f64;
+        let lux = match &self.ir {
+            Some((ir, combination)) => combination.lux(broadband, ir.attr_read_int("raw")? as f64),
+            None => broadband,
+        };
+        Ok(lux * self.scale)
+    }
+}
@@ -1,58 +1,168 @@
-use anyhow::Result;
+use std::fs;
+
 use log::{debug, info};
-use logind_zbus::session::SessionProxyBlocking;
 
-use crate::read_value;
+#[cfg(feature = "dbus")]
+use crate::output::Report;
+use crate::{
+    brightness_writer::BrightnessWriter,
+    config::KbdConfig,
+    external_keyboard::ExternalKeyboards,
+    levels::{rounded_level, Clamp},
+    output::{Offset, Output, StepCurve},
+    redact::Lux,
+    sysfs::{Device, Sysfs},
+    Error, Result,
+};
+
+pub(crate) fn detect_kbd_led(sysfs: &Sysfs, config: &KbdConfig) -> Result<String> {
+    if let Some(name) = &config.name {
+        info!("Using configured KBD Backlight: {}", name);
+        return Ok(name.clone());
+    }
+
+    let leds = sysfs.class("leds");
+    let mut candidates = fs::read_dir(&leds)
+        .map_err(|source| Error::Sysfs {
+            path: leds.clone(),
+            source,
+        })?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.contains("kbd_backlight"))
+        .collect::<Vec<_>>();
+
+    // Known names in priority order first, then anything else alphabetically
+    candidates.sort_by_key(|name| {
+        let rank = config
+            .priority
+            .iter()
+            .position(|x| x == name)
+            .unwrap_or(usize::MAX);
+        (rank, name.clone())
+    });
+
+    debug!("KBD Backlight Candidates: {:?}", candidates);
+
+    let name = candidates
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::NotFound(format!("a keyboard backlight in {}", leds.display())))?;
+    info!("Detected KBD Backlight: {}", name);
+
+    Ok(name)
+}
 
 pub(crate) struct KBDBrightness<'a> {
-    proxy: &'a SessionProxyBlocking<'a>,
-    subsystem: &'a str,
-    name: &'a str,
+    writer: &'a BrightnessWriter,
+    device: Device,
+    curve: StepCurve,
+    offset: Offset,
+    limits: Clamp,
+    initial_level: u32,
+    external: Option<ExternalKeyboards>,
 }
 
 impl<'a> KBDBrightness<'a> {
     pub(crate) fn new(
-        proxy: &'a SessionProxyBlocking<'a>,
-        subsystem: &'a str,
-        name: &'a str,
-    ) -> Self {
-        Self {
-            proxy,
-            subsystem,
-            name,
-        }
+        writer: &'a BrightnessWriter,
+        device: Device,
+        curve: StepCurve,
+        limits: Clamp,
+        external: Option<ExternalKeyboards>,
+    ) -> Result<Self> {
+        let initial_level = device.brightness()?;
+
+        Ok(Self {
+            writer,
+            device,
+            curve,
+            offset: Offset::default(),
+            limits,
+            initial_level,
+            external,
+        })
     }
+}
 
-    fn read(&self) -> Result<u32> {
-        read_value(&format!(
-            "/sys/class/{}/{}/brightness",
-            self.subsystem, self.name
-        ))
+impl Output for KBDBrightness<'_> {
+    fn name(&self) -> &str {
+        &self.device.name
     }
 
-    pub(crate) fn adjust(&self, new_val: u32) -> Result<()> {
-        let new_level = match new_val {
-            v if v < 50 => 3,
-            v if v < 60 => 2,
-            v if v < 80 => 1,
-            _ => 0,
+    fn restore(&self) -> Result<()> {
+        let cur_brightness = self.device.brightness()?;
+
+        if cur_brightness != self.initial_level {
+            info!(
+                "Restoring KBD Backlight: old:{:?} new:{:?}",
+                cur_brightness, self.initial_level
+            );
+            self.writer
+                .set_brightness(&self.device, self.initial_level)?;
+        }
+
+        Ok(())
+    }
+
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        let new_pct = self.offset.apply(self.curve.percent(new_val));
+        // The internal keys aren't in use while an external keyboard is
+        let new_level = if self.external.as_mut().is_some_and(|x| x.active()) {
+            0
+        } else {
+            rounded_level(new_pct, self.device.max_brightness)
         };
+        let new_level = self.limits.apply(new_level).min(self.device.max_brightness);
 
-        let cur_brightness = self.read()?;
+        let cur_brightness = self.device.brightness()?;
 
         debug!(
             "KBD: nv:{:?}, nl:{:?}, cb:{:?}",
-            new_val, new_level, cur_brightness
+            Lux(new_val),
+            new_level,
+            cur_brightness
         );
         if cur_brightness != new_level {
             info!(
                 "Adjusting KBD Backlight: val:{:?} old:{:?} new:{:?}",
-                new_val, cur_brightness, new_level
+                Lux(new_val),
+                cur_brightness,
+                new_level
             );
-            self.proxy
-                .set_brightness(self.subsystem, self.name, new_level)?;
+            self.writer.set_brightness(&self.device, new_level)?;
+            return Ok(true);
         }
 
-        Ok(())
+        Ok(false)
+    }
+
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        Some(&mut self.curve)
+    }
+
+    fn increase(&mut self, amount: i8) {
+        self.offset.increase(amount)
+    }
+
+    fn decrease(&mut self, amount: i8) {
+        self.offset.decrease(amount)
+    }
+
+    fn set(&mut self, percent: u8) {
+        self.offset.set(percent)
+    }
+
+    fn percent(&self) -> Option<u32> {
+        Some(self.offset.percent())
+    }
+
+    fn offset(&self) -> Option<i8> {
+        Some(self.offset.offset())
+    }
+
+    #[cfg(feature = "dbus")]
+    fn report(&self, report: &mut Report) {
+        report.kbd_level = self.device.brightness().ok();
     }
 }
@@ -0,0 +1,102 @@
+use log::{debug, info};
+
+use crate::{
+    brightness_writer::BrightnessWriter,
+    levels::raw_level,
+    output::{exceeds_min_delta, Offset, Output, StepCurve},
+    redact::Lux,
+    sysfs::Device,
+    Result,
+};
+
+pub(crate) struct LEDBrightness<'a> {
+    writer: &'a BrightnessWriter,
+    device: Device,
+    curve: StepCurve,
+    offset: Offset,
+    min_delta: u32,
+}
+
+impl<'a> LEDBrightness<'a> {
+    pub(crate) fn new(
+        writer: &'a BrightnessWriter,
+        device: Device,
+        curve: StepCurve,
+        min_delta: u32,
+    ) -> Self {
+        Self {
+            writer,
+            device,
+            curve,
+            offset: Offset::default(),
+            min_delta,
+        }
+    }
+}
+
+impl Output for LEDBrightness<'_> {
+    fn name(&self) -> &str {
+        &self.device.name
+    }
+
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        let new_pct = self.offset.apply(self.curve.percent(new_val));
+        let new_level = raw_level(new_pct, self.device.max_brightness);
+
+        let cur_brightness = self.device.brightness()?;
+
+        debug!(
+            "LED {}: nv:{:?}, np:{:?}, nl:{:?}, cb:{:?}",
+            self.device.name,
+            Lux(new_val),
+            new_pct,
+            new_level,
+            cur_brightness
+        );
+        if cur_brightness != new_level
+            && exceeds_min_delta(
+                cur_brightness,
+                new_level,
+                self.device.max_brightness,
+                self.min_delta,
+            )
+        {
+            info!(
+                "Adjusting LED {}: val:{:?} old:{:?} new:{:?}->{:?}",
+                self.device.name,
+                Lux(new_val),
+                cur_brightness,
+                new_pct,
+                new_level
+            );
+            self.writer.set_brightness(&self.device, new_level)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        Some(&mut self.curve)
+    }
+
+    fn increase(&mut self, amount: i8) {
+        self.offset.increase(amount)
+    }
+
+    fn decrease(&mut self, amount: i8) {
+        self.offset.decrease(amount)
+    }
+
+    fn set(&mut self, percent: u8) {
+        self.offset.set(percent)
+    }
+
+    fn percent(&self) -> Option<u32> {
+        Some(self.offset.percent())
+    }
+
+    fn offset(&self) -> Option<i8> {
+        Some(self.offset.offset())
+    }
+}
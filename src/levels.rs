@@ -0,0 +1,298 @@
+use serde::Deserialize;
+
+use crate::output::StepCurve;
+
+/// Top of the built-in screen steps, so indoor light doesn't glare
+pub(crate) const SCREEN_MAX: u32 = 50;
+
+/// Screen brightness percent steps unless the config has its own, spread
+/// from the darkest step up to `max`
+pub(crate) fn screen_curve(max: u32) -> StepCurve {
+    let steps = [
+        (0, 5),
+        (1, 10),
+        (10, 15),
+        (20, 20),
+        (30, 25),
+        (40, 30),
+        (50, 35),
+        (60, 40),
+        (70, 45),
+        (80, 50),
+    ];
+    let floor = steps[0].1.min(max);
+    let spread = |pct: u32| {
+        floor
+            + ((pct - steps[0].1) * (max - floor) + (SCREEN_MAX - steps[0].1) / 2)
+                / (SCREEN_MAX - steps[0].1)
+    };
+    StepCurve::from_points(
+        steps
+            .into_iter()
+            .map(|(ambient, pct)| (ambient, spread(pct)))
+            .collect(),
+    )
+}
+
+/// Room light percent steps unless the config has its own: full in the dark,
+/// off once daylight is enough
+pub(crate) fn bulb_curve() -> StepCurve {
+    StepCurve::from_points(vec![
+        (0, 100),
+        (20, 80),
+        (35, 60),
+        (50, 35),
+        (65, 15),
+        (75, 0),
+    ])
+}
+
+/// Floor and ceiling an output's level never crosses, whatever the curve,
+/// offset, or idle scale say
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Bounds")]
+pub(crate) struct Clamp {
+    min: u32,
+    max: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Bounds {
+    min: u32,
+    max: u32,
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        let Clamp { min, max } = Clamp::default();
+        Self { min, max }
+    }
+}
+
+impl TryFrom<Bounds> for Clamp {
+    type Error = String;
+
+    fn try_from(bounds: Bounds) -> Result<Self, Self::Error> {
+        if bounds.min > bounds.max {
+            return Err(format!(
+                "limit min {} is above max {}",
+                bounds.min, bounds.max
+            ));
+        }
+        Ok(Self {
+            min: bounds.min,
+            max: bounds.max,
+        })
+    }
+}
+
+impl Default for Clamp {
+    fn default() -> Self {
+        Self {
+            min: 0,
+            max: u32::MAX,
+        }
+    }
+}
+
+impl Clamp {
+    #[cfg(any(
+        feature = "kbd",
+        feature = "screen",
+        all(feature = "macos", target_os = "macos"),
+        test
+    ))]
+    pub(crate) fn apply(self, level: u32) -> u32 {
+        level.clamp(self.min, self.max)
+    }
+}
+
+/// Ambient value curves are looked up at, for an ambient percent
+pub(crate) fn ambient_value(pct: f64) -> u32 {
+    // NaN, e.g. from a negative reading, casts to 0
+    pct.clamp(0.0, 100.0).round() as u32
+}
+
+/// Percent an output lands on once its manual offset moves the curve percent,
+/// capped at 100
+pub(crate) fn offset_percent(pct: u32, offset: i8) -> u32 {
+    pct.saturating_add_signed(offset as i32).min(100)
+}
+
+/// Raw brightness out of `max` for a percent, rounded down so that only 100%
+/// reaches `max`
+pub(crate) fn raw_level(pct: u32, max: u32) -> u32 {
+    (pct.min(100) as u64 * max as u64 / 100) as u32
+}
+
+/// Level out of `max` for a percent, rounded so that the 0–3 range of most
+/// keyboard backlights still lands on every step
+#[cfg(any(feature = "kbd", feature = "hid", feature = "bulb", test))]
+pub(crate) fn rounded_level(pct: u32, max: u32) -> u32 {
+    ((pct.min(100) as u64 * max as u64 + 50) / 100) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ambient_brightness::settled_percent;
+
+    /// Device ranges from single-step LEDs to values no driver reports
+    const MAXES: &[u32] = &[1, 2, 3, 100, 255, 937, 96000, 19_393_000, u32::MAX];
+
+    /// Xorshift, seeded so a failing case comes back on the next run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u32) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as u32
+        }
+    }
+
+    /// Random valid curve whose percents only fall, or only rise, as the
+    /// ambient light grows
+    fn monotonic_curve(rng: &mut Rng, falling: bool) -> StepCurve {
+        let mut ambient = 0;
+        let mut pct = if falling { 100 } else { 0 };
+        let mut points = (0..=rng.below(8))
+            .map(|_| {
+                ambient += rng.below(30);
+                pct = match falling {
+                    true => pct - rng.below(pct + 1),
+                    false => pct + rng.below(101 - pct),
+                };
+                (ambient, pct)
+            })
+            .collect::<Vec<_>>();
+        // Thresholds must differ, so a repeat drops the later point
+        points.dedup_by_key(|(ambient, _)| *ambient);
+        StepCurve::from_points(points)
+    }
+
+    /// Percent inputs from nothing through everything a curve and offset can add
+    fn percents() -> impl Iterator<Item = u32> {
+        (0..=300).chain([u32::MAX - 1, u32::MAX])
+    }
+
+    #[test]
+    fn ambient_values_rise_with_light_and_stay_in_range() {
+        let mut last = 0;
+        // Raw readings from 1e-10 to 1e10, three hundred per decade
+        for exponent in -3000..=3000 {
+            let value = ambient_value(settled_percent(10f64.powf(exponent as f64 / 300.0)));
+            assert!(value >= last && value <= 100, "{} after {}", value, last);
+            last = value;
+        }
+        assert_eq!(last, 100);
+        for raw in [0.0, -1.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(ambient_value(settled_percent(raw)) <= 100);
+        }
+        assert_eq!(ambient_value(f64::NAN), 0);
+        assert_eq!(ambient_value(-50.0), 0);
+        assert_eq!(ambient_value(1e300), 100);
+    }
+
+    #[test]
+    fn offsets_are_monotonic_and_capped() {
+        for offset in i8::MIN..=i8::MAX {
+            let mut last = 0;
+            for pct in percents() {
+                let offset_pct = offset_percent(pct, offset);
+                assert!(offset_pct >= last && offset_pct <= 100);
+                if offset < i8::MAX {
+                    assert!(offset_percent(pct, offset + 1) >= offset_pct);
+                }
+                last = offset_pct;
+            }
+        }
+        assert_eq!(offset_percent(40, 0), 40);
+        assert_eq!(offset_percent(40, -128), 0);
+        assert_eq!(offset_percent(40, 127), 100);
+    }
+
+    #[test]
+    fn levels_are_monotonic_and_within_the_device_range() {
+        for &max in MAXES {
+            let (mut last_raw, mut last_rounded) = (0, 0);
+            for pct in percents() {
+                let (raw, rounded) = (raw_level(pct, max), rounded_level(pct, max));
+                assert!(
+                    raw >= last_raw && raw <= max,
+                    "{}% of {}: {}",
+                    pct,
+                    max,
+                    raw
+                );
+                assert!(rounded >= last_rounded && rounded <= max);
+                assert!(rounded >= raw);
+                (last_raw, last_rounded) = (raw, rounded);
+            }
+            assert_eq!((raw_level(0, max), rounded_level(0, max)), (0, 0));
+            assert_eq!((raw_level(100, max), rounded_level(100, max)), (max, max));
+            // Only a full percent reaches the top of the range
+            assert!(raw_level(99, max) < max);
+        }
+        // Every step of a 0–3 keyboard backlight is reachable
+        assert_eq!(
+            [0, 33, 66, 100].map(|pct| rounded_level(pct, 3)),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn screen_steps_spread_to_their_ceiling() {
+        let built_in = screen_curve(SCREEN_MAX);
+        assert_eq!(built_in.percent(0), 5);
+        assert_eq!(built_in.percent(100), 50);
+
+        let outdoor = screen_curve(100);
+        assert_eq!(outdoor.percent(0), 5);
+        assert_eq!(outdoor.percent(50), 68);
+        assert_eq!(outdoor.percent(100), 100);
+        assert_eq!(screen_curve(3).percent(100), 3);
+    }
+
+    #[test]
+    fn monotonic_curves_map_monotonically_end_to_end() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let fixed = [
+            (screen_curve(SCREEN_MAX), false),
+            (screen_curve(100), false),
+            (bulb_curve(), true),
+            (StepCurve::default(), true),
+        ];
+        let random = (0..500)
+            .map(|i| (monotonic_curve(&mut rng, i % 2 == 0), i % 2 == 0))
+            .collect::<Vec<_>>();
+        for (curve, falling) in fixed.into_iter().chain(random) {
+            let offset = rng.below(256) as i32 as i8;
+            let max = MAXES[rng.below(MAXES.len() as u32) as usize];
+            let mut last = None;
+            for exponent in -100..=800 {
+                let value = ambient_value(settled_percent(10f64.powf(exponent as f64 / 100.0)));
+                let pct = curve.percent(value);
+                assert!(pct <= 100, "{:?} at {}: {}", curve, value, pct);
+                let level = raw_level(offset_percent(pct, offset), max);
+                assert!(level <= max);
+                if let Some(last) = last {
+                    let ordered = if falling {
+                        level <= last
+                    } else {
+                        level >= last
+                    };
+                    assert!(
+                        ordered,
+                        "{:?} offset {} went {} -> {}",
+                        curve, offset, last, level
+                    );
+                }
+                last = Some(level);
+            }
+            assert!(curve.percent(u32::MAX) <= 100);
+        }
+    }
+}
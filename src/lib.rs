@@ -0,0 +1,91 @@
+pub mod acpi_events;
+mod ambient_brightness;
+#[cfg(feature = "hwmon")]
+mod applesmc_sensor;
+#[cfg(feature = "async-client")]
+pub mod async_control_client;
+mod backoff;
+mod brightness_writer;
+#[cfg(feature = "bulb")]
+mod bulb;
+pub mod bundle;
+pub mod calibrate;
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod config_watch;
+#[cfg(feature = "content")]
+mod content_luminance;
+#[cfg(feature = "control")]
+pub mod control_client;
+#[cfg(feature = "control")]
+pub mod control_server;
+pub mod controller;
+#[cfg(feature = "dbus")]
+mod dbus_service;
+#[cfg(feature = "screen")]
+mod desktop;
+mod environment;
+mod error;
+#[cfg(feature = "kbd")]
+mod external_keyboard;
+mod filter;
+pub mod health;
+#[cfg(feature = "control")]
+pub mod helper;
+#[cfg(feature = "hid")]
+mod hid_brightness;
+pub mod hotplug;
+#[cfg(feature = "hwmon")]
+mod hwmon_sensor;
+#[cfg(feature = "iio")]
+mod iio_sensor;
+#[cfg(feature = "kbd")]
+mod kbd_brightness;
+mod led_brightness;
+mod levels;
+#[cfg(all(feature = "macos", target_os = "macos"))]
+mod macos;
+pub mod monitor;
+mod orientation;
+mod output;
+#[cfg(feature = "screen")]
+mod output_power;
+pub mod preview;
+mod privileges;
+#[cfg(feature = "control")]
+pub mod protocol;
+mod quirks;
+pub mod record;
+mod redact;
+pub mod sandbox;
+#[cfg(feature = "screen")]
+mod screen_brightness;
+pub mod screencast;
+pub mod sensor;
+mod session;
+pub mod session_lock;
+mod snapshot;
+mod sysfs;
+#[cfg(feature = "sysfs")]
+mod sysfs_iio_sensor;
+mod systemd;
+mod tablet_mode;
+#[cfg(feature = "tray")]
+pub mod tray;
+#[cfg(feature = "control")]
+pub mod tune;
+mod watchdog;
+#[cfg(feature = "control")]
+pub mod xbacklight;
+
+pub use error::{Error, Result};
+
+/// logind session of the calling process
+const SESSION_PATH: &str = "/org/freedesktop/login1/session/auto";
+
+/// Object and interface the daemon serves and signals from on the session bus
+const DBUS_PATH: &str = "/io/github/jeffutter/IioAmbientBrightness";
+const DBUS_INTERFACE: &str = "io.github.jeffutter.IioAmbientBrightness";
+
+const SCREEN_SUBSYSTEM: &str = "backlight";
@@ -0,0 +1,283 @@
+//! The ambient light sensor and built-in display of Macs running macOS, for
+//! running the pipeline without sysfs. The sensor is the `AppleLMUController`
+//! IOKit service; the display is set through the DisplayServices framework,
+//! loaded at runtime since it is private.
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    ptr,
+};
+
+use log::{debug, info};
+
+use crate::{
+    levels::Clamp,
+    output::{exceeds_min_delta, Offset, Output, StepCurve},
+    redact::Lux,
+    sensor::Sensor,
+    Error, Result,
+};
+
+type IoObject = u32;
+type KernReturn = i32;
+
+const KERN_SUCCESS: KernReturn = 0;
+/// `kIOMainPortDefault`
+const MAIN_PORT: u32 = 0;
+/// Selector of `AppleLMUController` returning both sensor readings
+const GET_SENSOR_READING: u32 = 0;
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IOServiceMatching(name: *const c_char) -> *mut c_void;
+    fn IOServiceGetMatchingService(main_port: u32, matching: *mut c_void) -> IoObject;
+    fn IOServiceOpen(
+        service: IoObject,
+        owning_task: u32,
+        kind: u32,
+        connect: *mut IoObject,
+    ) -> KernReturn;
+    fn IOServiceClose(connect: IoObject) -> KernReturn;
+    fn IOObjectRelease(object: IoObject) -> KernReturn;
+    #[allow(clippy::too_many_arguments)]
+    fn IOConnectCallMethod(
+        connection: IoObject,
+        selector: u32,
+        input: *const u64,
+        input_count: u32,
+        input_struct: *const c_void,
+        input_struct_size: usize,
+        output: *mut u64,
+        output_count: *mut u32,
+        output_struct: *mut c_void,
+        output_struct_size: *mut usize,
+    ) -> KernReturn;
+    static mach_task_self_: u32;
+}
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGMainDisplayID() -> u32;
+}
+
+/// The light sensor behind the keyboard or the camera of Intel Macs, in its own
+/// units. Models with a left and right sensor report the brighter one.
+pub(crate) struct LmuSensor {
+    connection: IoObject,
+}
+
+impl LmuSensor {
+    pub(crate) fn new() -> Result<Self> {
+        // SAFETY: the matching dictionary is consumed by
+        // IOServiceGetMatchingService, and the service released after opening
+        let connection = unsafe {
+            let matching = IOServiceMatching(c"AppleLMUController".as_ptr());
+            let service = IOServiceGetMatchingService(MAIN_PORT, matching);
+            if service == 0 {
+                return Err(Error::NotFound(
+                    "an AppleLMUController light sensor".to_string(),
+                ));
+            }
+            let mut connection = 0;
+            let ret = IOServiceOpen(service, mach_task_self_, 0, &mut connection);
+            IOObjectRelease(service);
+            if ret != KERN_SUCCESS {
+                return Err(Error::Sensor(format!(
+                    "Couldn't open AppleLMUController: {:#x}",
+                    ret
+                )));
+            }
+            connection
+        };
+
+        let sensor = Self { connection };
+        sensor.read()?;
+        info!("Using the AppleLMUController light sensor");
+        Ok(sensor)
+    }
+}
+
+impl Sensor for LmuSensor {
+    fn read(&self) -> Result<f64> {
+        let mut values = [0u64; 2];
+        let mut count = values.len() as u32;
+        // SAFETY: values holds count outputs, and no structs are passed
+        let ret = unsafe {
+            IOConnectCallMethod(
+                self.connection,
+                GET_SENSOR_READING,
+                ptr::null(),
+                0,
+                ptr::null(),
+                0,
+                values.as_mut_ptr(),
+                &mut count,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if ret != KERN_SUCCESS {
+            return Err(Error::Sensor(format!(
+                "Couldn't read AppleLMUController: {:#x}",
+                ret
+            )));
+        }
+        Ok(values[..count as usize].iter().copied().max().unwrap_or(0) as f64)
+    }
+}
+
+impl Drop for LmuSensor {
+    fn drop(&mut self) {
+        // SAFETY: the connection was opened in new and isn't used afterwards
+        unsafe { IOServiceClose(self.connection) };
+    }
+}
+
+type GetBrightness = unsafe extern "C" fn(display: u32, brightness: *mut f32) -> KernReturn;
+type SetBrightness = unsafe extern "C" fn(display: u32, brightness: f32) -> KernReturn;
+
+const DISPLAY_SERVICES: &CStr =
+    c"/System/Library/PrivateFrameworks/DisplayServices.framework/DisplayServices";
+
+/// A symbol of DisplayServices
+fn symbol(handle: *mut c_void, name: &CStr) -> Result<*mut c_void> {
+    // SAFETY: handle is an open library and name a valid C string
+    let symbol = unsafe { libc::dlsym(handle, name.as_ptr()) };
+    if symbol.is_null() {
+        return Err(Error::NotFound(format!(
+            "{} in DisplayServices",
+            name.to_string_lossy()
+        )));
+    }
+    Ok(symbol)
+}
+
+/// The main display, in percent of its full brightness
+pub(crate) struct MacDisplay {
+    display: u32,
+    get: GetBrightness,
+    set: SetBrightness,
+    curve: StepCurve,
+    offset: Offset,
+    limits: Clamp,
+    min_delta: u32,
+}
+
+impl MacDisplay {
+    pub(crate) fn new(curve: StepCurve, limits: Clamp, min_delta: u32) -> Result<Self> {
+        // SAFETY: the path is a valid C string; the library stays loaded for
+        // the life of the process
+        let handle = unsafe { libc::dlopen(DISPLAY_SERVICES.as_ptr(), libc::RTLD_LAZY) };
+        if handle.is_null() {
+            return Err(Error::NotFound("the DisplayServices framework".to_string()));
+        }
+        // SAFETY: both symbols have these signatures in DisplayServices
+        let (get, set) = unsafe {
+            (
+                std::mem::transmute::<*mut c_void, GetBrightness>(symbol(
+                    handle,
+                    c"DisplayServicesGetBrightness",
+                )?),
+                std::mem::transmute::<*mut c_void, SetBrightness>(symbol(
+                    handle,
+                    c"DisplayServicesSetBrightness",
+                )?),
+            )
+        };
+
+        let display = Self {
+            // SAFETY: CGMainDisplayID has no preconditions
+            display: unsafe { CGMainDisplayID() },
+            get,
+            set,
+            curve,
+            offset: Offset::default(),
+            limits,
+            min_delta,
+        };
+        info!(
+            "Using display {}: currently {}%",
+            display.display,
+            display.brightness()?
+        );
+        Ok(display)
+    }
+
+    fn brightness(&self) -> Result<u32> {
+        let mut brightness = 0.0;
+        // SAFETY: brightness lives across the call
+        let ret = unsafe { (self.get)(self.display, &mut brightness) };
+        if ret != KERN_SUCCESS {
+            return Err(Error::NotFound(format!(
+                "brightness of display {}: {:#x}",
+                self.display, ret
+            )));
+        }
+        Ok((brightness * 100.0).round() as u32)
+    }
+}
+
+impl Output for MacDisplay {
+    fn name(&self) -> &str {
+        "display"
+    }
+
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        let new_level = self
+            .limits
+            .apply(self.offset.apply(self.curve.percent(new_val)))
+            .min(100);
+        let cur_brightness = self.brightness()?;
+
+        debug!(
+            "Display: nv:{:?}, np:{:?}, cb:{:?}",
+            Lux(new_val),
+            new_level,
+            cur_brightness
+        );
+        if cur_brightness == new_level
+            || !exceeds_min_delta(cur_brightness, new_level, 100, self.min_delta)
+        {
+            return Ok(false);
+        }
+        info!(
+            "Adjusting display: val:{:?} old:{:?} new:{:?}",
+            Lux(new_val),
+            cur_brightness,
+            new_level
+        );
+        // SAFETY: set takes the display and a brightness between 0 and 1
+        let ret = unsafe { (self.set)(self.display, new_level as f32 / 100.0) };
+        if ret != KERN_SUCCESS {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "Couldn't set display {}: {:#x}",
+                self.display, ret
+            ))));
+        }
+        Ok(true)
+    }
+
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        Some(&mut self.curve)
+    }
+
+    fn increase(&mut self, amount: i8) {
+        self.offset.increase(amount)
+    }
+
+    fn decrease(&mut self, amount: i8) {
+        self.offset.decrease(amount)
+    }
+
+    fn set(&mut self, percent: u8) {
+        self.offset.set(percent)
+    }
+
+    fn percent(&self) -> Option<u32> {
+        Some(self.offset.percent())
+    }
+
+    fn offset(&self) -> Option<i8> {
+        Some(self.offset.offset())
+    }
+}
@@ -1,246 +1,801 @@
-mod ambient_brightness;
-mod control_client;
-mod control_server;
-mod kbd_brightness;
-mod screen_brightness;
-
-use std::{
-    fs,
-    sync::{
-        atomic::{self, AtomicBool},
-        Arc,
-    },
-    time::Duration,
-};
+use std::{io, path::PathBuf, process, sync::Arc, thread, time::Duration};
 
-use ambient_brightness::AmbientBrightness;
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use crossbeam::{
-    channel::{bounded, tick, Receiver},
-    select,
-};
+use clap::{CommandFactory, Parser, Subcommand};
+use crossbeam::channel::{bounded, never, unbounded, Receiver};
 use env_logger::Env;
-use kbd_brightness::KBDBrightness;
-use log::{info, trace};
-use logind_zbus::session::SessionProxyBlocking;
-use ouroboros::self_referencing;
-use screen_brightness::ScreenBrightness;
-use zbus::blocking::Connection;
-
-use crate::{
+#[cfg(feature = "tray")]
+use iio_ambient_brightness::tray;
+use iio_ambient_brightness::{
+    acpi_events, bundle, calibrate,
+    clock::{Clock, SystemClock},
+    config::{self, Config},
+    config_watch,
+    controller::Builder,
+    hotplug, monitor, preview,
+    record::{self, Recorder},
+    sandbox, screencast, session_lock,
+};
+#[cfg(feature = "control")]
+use iio_ambient_brightness::{
+    command::{Appearance, OutputKind},
     control_client::ControlClient,
-    control_server::{Command, ControlServer},
+    control_server::ControlServer,
+    health::{Health, Status},
+    helper::Helper,
+    protocol::ProfileEdit,
+    tune, xbacklight,
+};
+use log::{info, warn};
+use signal_hook::{
+    consts::{SIGINT, SIGQUIT, SIGTERM},
+    iterator::Signals,
 };
 
 #[derive(Parser)]
-#[command(version, about)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Server
-    #[arg(
-        short,
-        required_unless_present = "activity",
-        required_unless_present = "offset",
-        conflicts_with = "activity",
-        conflicts_with = "offset",
-        default_value_t = false
-    )]
-    server: bool,
+    /// Config file; by default `iio_ambient_brightness/config.toml` under
+    /// `$XDG_CONFIG_HOME` or `~/.config`, then under `/etc`, if either exists
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
 
-    #[command(flatten)]
-    idle: Idle,
+    /// Named instance, e.g. one per seat, with its own socket and
+    /// `[instances.<name>]` config section
+    #[arg(long, global = true)]
+    instance: Option<String>,
+
+    /// Print brightness changes instead of applying them
+    #[arg(long, visible_alias = "print", global = true)]
+    dry_run: bool,
+
+    /// Record sensor readings, commands, and writes to a file
+    #[arg(long, global = true)]
+    record: Option<PathBuf>,
+
+    /// Control socket to serve or send to; by default in `$XDG_RUNTIME_DIR`
+    #[arg(long, global = true)]
+    socket_path: Option<PathBuf>,
 
     #[command(flatten)]
-    offset: Offset,
+    legacy: Legacy,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-#[derive(Parser)]
-#[group(required = false, multiple = false)]
-struct Idle {
-    /// Idle Idle
+#[derive(clap::Args, Clone)]
+struct ServerArgs {
+    /// Permissions of the control socket in octal, e.g. 0660
+    #[arg(long, value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+
+    /// Group owning the control socket, by name or gid
+    #[arg(long)]
+    socket_group: Option<String>,
+
+    /// Write a newline to this fd once started, for s6 and runit style
+    /// readiness notification
+    #[arg(long, value_name = "FD")]
+    ready_fd: Option<i32>,
+
+    /// Screen backlight to drive, by its name under /sys/class/backlight,
+    /// instead of the configured or detected one
+    #[arg(long, value_name = "NAME")]
+    screen: Option<String>,
+
+    /// Keyboard backlight to drive, by its name under /sys/class/leds,
+    /// instead of the configured or detected one
+    #[arg(long, value_name = "NAME")]
+    kbd: Option<String>,
+}
+
+// Flags from before the subcommands, still accepted for existing scripts and
+// service files
+#[derive(clap::Args)]
+struct Legacy {
+    /// Same as `server`
     #[arg(
         short,
-        group = "activity",
-        conflicts_with = "server",
-        default_value_t = false
+        hide = true,
+        conflicts_with_all = ["idle", "active", "increase", "decrease", "replay", "ping", "daemon_version"]
     )]
+    server: bool,
+
+    /// Same as `idle`
+    #[arg(short, hide = true, conflicts_with = "active")]
     idle: bool,
 
-    /// Not Idle Idle
-    #[arg(
-        short,
-        group = "activity",
-        conflicts_with = "server",
-        default_value_t = false
-    )]
+    /// Same as `active`
+    #[arg(short, hide = true)]
     active: bool,
-}
 
-#[derive(Parser)]
-#[group(required = false, multiple = false)]
-struct Offset {
-    /// Increase
-    #[arg(
-        long,
-        group = "offset",
-        conflicts_with = "server",
-        default_value = None
-    )]
+    /// Same as `increase`
+    #[arg(long, hide = true, conflicts_with = "decrease")]
     increase: Option<i8>,
 
-    /// Decrease
-    #[arg(
-        long,
-        group = "offset",
-        conflicts_with = "server",
-        default_value = None
-    )]
+    /// Same as `decrease`
+    #[arg(long, hide = true)]
     decrease: Option<i8>,
+
+    /// Same as `replay`
+    #[arg(long, hide = true)]
+    replay: Option<PathBuf>,
+
+    /// Same as `status`
+    #[arg(long, visible_alias = "status", hide = true)]
+    ping: bool,
+
+    /// Same as `status --format`
+    #[arg(long, hide = true, requires = "ping")]
+    format: Option<String>,
+
+    /// Same as `daemon-version`
+    #[arg(long, hide = true)]
+    daemon_version: bool,
+
+    /// Same as `server --socket-mode`
+    #[arg(long = "socket-mode", hide = true, requires = "server", value_parser = parse_mode)]
+    legacy_socket_mode: Option<u32>,
+
+    /// Same as `server --socket-group`
+    #[arg(long = "socket-group", hide = true, requires = "server")]
+    legacy_socket_group: Option<String>,
+
+    /// Same as `server --ready-fd`
+    #[arg(long = "ready-fd", hide = true, requires = "server")]
+    legacy_ready_fd: Option<i32>,
 }
 
-fn read_value(path: &str) -> Result<u32> {
-    let val = fs::read_to_string(path)?;
-    let res = val.trim().parse()?;
-    Ok(res)
-}
-
-#[self_referencing]
-struct AmbientBrightnessController<'a> {
-    ambient_brightness: AmbientBrightness,
-    proxy: SessionProxyBlocking<'a>,
-    #[borrows(proxy)]
-    #[not_covariant]
-    kbd_brightness: KBDBrightness<'this>,
-    #[borrows(proxy)]
-    #[not_covariant]
-    screen_brightness: ScreenBrightness<'this>,
-    close_receiver: Receiver<()>,
-    command_receiver: Receiver<Command>,
-}
-
-impl<'a> AmbientBrightnessController<'a> {
-    fn create(close_receiver: Receiver<()>, command_receiver: Receiver<Command>) -> Result<Self> {
-        let connection = Connection::system()?;
-        let proxy = SessionProxyBlocking::builder(&connection)
-            .path("/org/freedesktop/login1/session/auto")?
-            .build()?;
-
-        let ambient_brightness = AmbientBrightness::new()?.init()?;
-
-        Self::try_new(
-            ambient_brightness,
-            proxy,
-            |proxy: &SessionProxyBlocking| {
-                Ok(KBDBrightness::new(proxy, "leds", "asus::kbd_backlight"))
-            },
-            |proxy: &SessionProxyBlocking| {
-                ScreenBrightness::new(proxy, "backlight", "intel_backlight")
-            },
-            close_receiver,
-            command_receiver,
-        )
-    }
-
-    fn update(&mut self) -> Result<()> {
-        let new_val = self.with_ambient_brightness_mut(|x| x.update())?;
-        trace!("New Val POST: {}", new_val);
-        self.with_kbd_brightness(|x| x.adjust(new_val))?;
-        self.with_screen_brightness(|x| x.adjust(new_val))?;
-        Ok(())
-    }
-
-    fn run(mut self) -> Result<()> {
-        let ticker = tick(Duration::from_secs(5));
-        self.update()?;
-
-        loop {
-            select! {
-                recv(self.borrow_close_receiver()) -> _ => {
-                    info!("Received Shutdown");
-                    break
-                },
-                recv(self.borrow_command_receiver()) -> msg => match msg {
-                    Err(e) => {
-                        info!("Command Channel Terminated: {:#}", e);
-                        break;
-                    },
-                    Ok(msg) => match msg {
-                        Command::Idle => {
-                            self.with_ambient_brightness_mut(|x| x.idle());
-                            self.update()?
-                        },
-
-                        Command::Active => {
-                            self.with_ambient_brightness_mut(|x| x.active());
-                            self.update()?
-                        },
-                        Command::Increase(amount) => {
-                            self.with_screen_brightness_mut(|x| x.increase(amount));
-                            self.update()?
-                        },
-                        Command::Decrease(amount) => {
-                            self.with_screen_brightness_mut(|x| x.decrease(amount));
-                            self.update()?
-                        }
-                    },
-                },
-                recv(ticker) -> _  => {
-                        self.update()?
-                },
+impl Legacy {
+    /// Commands the flags stand for, in the order they used to run
+    fn commands(&self) -> Vec<Commands> {
+        let mut commands = Vec::new();
+        if self.server {
+            commands.push(Commands::Server(ServerArgs {
+                socket_mode: self.legacy_socket_mode,
+                socket_group: self.legacy_socket_group.clone(),
+                ready_fd: self.legacy_ready_fd,
+                screen: None,
+                kbd: None,
+            }));
+        }
+        if let Some(recording) = &self.replay {
+            commands.push(Commands::Replay {
+                recording: recording.clone(),
+            });
+        }
+        #[cfg(feature = "control")]
+        {
+            if self.ping {
+                commands.push(Commands::Status {
+                    format: self.format.clone(),
+                });
+            }
+            if self.daemon_version {
+                commands.push(Commands::DaemonVersion);
+            }
+            if self.idle {
+                commands.push(Commands::Idle);
+            }
+            if self.active {
+                commands.push(Commands::Active);
+            }
+            if let Some(amount) = self.increase {
+                commands.push(Commands::Increase {
+                    amount,
+                    output: None,
+                });
+            }
+            if let Some(amount) = self.decrease {
+                commands.push(Commands::Decrease {
+                    amount,
+                    output: None,
+                });
             }
         }
+        commands
+    }
 
-        Ok(())
+    /// Whether any flag needs the control socket
+    #[cfg(not(feature = "control"))]
+    fn needs_control(&self) -> bool {
+        self.ping
+            || self.daemon_version
+            || self.idle
+            || self.active
+            || self.increase.is_some()
+            || self.decrease.is_some()
     }
 }
 
-fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
-    let exit_bool = Arc::new(AtomicBool::new(false));
-    let (close_sender, close_receiver) = bounded(1);
+#[derive(Subcommand)]
+enum Commands {
+    /// Follow the sensor, adjusting brightness until stopped, and take
+    /// commands on the control socket
+    Server(ServerArgs),
+    /// Replay a recording against the recorded devices and print what happens
+    Replay { recording: PathBuf },
+    /// Print the ambient to brightness mapping for the loaded config
+    Preview,
+    /// Read the sensor once, apply the resulting brightness, and exit
+    Once,
+    /// Sample the sensor in a few lighting conditions and write the preferred
+    /// screen and keyboard curves to the config file given with --config
+    Calibrate,
+    /// Write the config, with its includes and profiles, as one portable file
+    Export {
+        /// Bundle to write; by default stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Replace the config file given with --config by an exported bundle,
+    /// keeping the old one as .bak
+    Import { bundle: PathBuf },
+    /// Continuously print sensor readings and target levels without adjusting anything
+    Monitor {
+        /// Milliseconds between readings
+        #[arg(long, default_value_t = 500)]
+        interval: u64,
+    },
+    /// Print the running daemon's uptime, last sensor read and write, error
+    /// counts, last ambient reading, and where each output is set
+    #[cfg(feature = "control")]
+    #[command(visible_alias = "ping")]
+    Status {
+        /// Print the status on one line from a template, e.g.
+        /// '{percent}% {lux}lx {mode}'. Fields: uptime, last_read,
+        /// last_write, sensor_errors, write_errors, stuck, failing_outputs,
+        /// percent, lux, mode, mode_for, adjustments, and output_errors
+        #[arg(long)]
+        format: Option<String>,
+    },
+    /// Print the running daemon's version, protocol version, enabled features,
+    /// and sensor backend
+    #[cfg(feature = "control")]
+    DaemonVersion,
+    /// Dim outputs as if the session went idle
+    #[cfg(feature = "control")]
+    Idle,
+    /// Bring outputs back from idle
+    #[cfg(feature = "control")]
+    Active,
+    /// Raise the screen by this many percent over the curve
+    #[cfg(feature = "control")]
+    Increase {
+        #[arg(allow_negative_numbers = true)]
+        amount: i8,
+        /// Raise only this kind of output, e.g. `kbd`, even if it doesn't
+        /// follow offsets otherwise
+        #[arg(long)]
+        output: Option<OutputKind>,
+    },
+    /// Lower the screen by this many percent under the curve
+    #[cfg(feature = "control")]
+    Decrease {
+        #[arg(allow_negative_numbers = true)]
+        amount: i8,
+        /// Lower only this kind of output
+        #[arg(long)]
+        output: Option<OutputKind>,
+    },
+    /// Move the screen to this percent, by offsetting it from the curve
+    #[cfg(feature = "control")]
+    Set {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        percent: u8,
+        /// Move only this kind of output, e.g. `kbd` to 100 to override it
+        #[arg(long)]
+        output: Option<OutputKind>,
+    },
+    /// Take xbacklight's arguments, e.g. `-inc 10` or `-set 50`, and send them
+    /// to the daemon. Running the binary through a symlink named xbacklight
+    /// does the same.
+    #[cfg(feature = "control")]
+    Xbacklight {
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        args: Vec<String>,
+    },
+    /// Define or change a profile in the running daemon's config file, e.g.
+    /// `profile night 'screen = { curve = [[0, 5], [50, 20]] }'`
+    #[cfg(feature = "control")]
+    Profile {
+        name: String,
+        /// TOML keys to merge into the profile
+        #[arg(default_value = "")]
+        overlay: String,
+        /// Switch to the profile too
+        #[arg(long)]
+        activate: bool,
+    },
+    /// Stop adjusting an output, e.g. `disable kbd`, leaving it to other tools
+    /// while the rest keep following the sensor
+    #[cfg(feature = "control")]
+    Disable { output: OutputKind },
+    /// Hand an output back to automatic control after `disable`
+    #[cfg(feature = "control")]
+    Enable { output: OutputKind },
+    /// Hold the desktop color scheme at `dark` or `light`, or let `[environment]`
+    /// switch it with the room again with `auto`
+    #[cfg(feature = "control")]
+    Appearance { appearance: Appearance },
+    /// Start the smoothing over from the current reading, e.g. right after
+    /// switching the room lights
+    #[cfg(feature = "control")]
+    Resync,
+    /// Save the outputs' levels and the mode under a name, e.g. `talk`
+    #[cfg(feature = "control")]
+    Snapshot { name: String },
+    /// Bring back the levels and mode saved under a name
+    #[cfg(feature = "control")]
+    Restore { name: String },
+    /// Edit the screen curve on the terminal, with the running daemon following
+    /// each change, then save it to the config file given with --config
+    #[cfg(feature = "control")]
+    Tune {
+        /// Edit the keyboard curve instead
+        #[arg(long)]
+        kbd: bool,
+    },
+    /// Write brightness for user instances whose devices have
+    /// `backend = "helper"`, e.g. as a system service allowed to write sysfs
+    #[cfg(feature = "control")]
+    Helper,
+    /// Show the running daemon in the system tray, with a menu to pause it,
+    /// switch between the profiles in --config, and nudge the brightness
+    #[cfg(feature = "tray")]
+    Tray,
+}
+
+impl Commands {
+    /// Whether the command writes the config file, which only ever happens to
+    /// one named with --config
+    fn writes_config(&self) -> bool {
+        match self {
+            Commands::Calibrate | Commands::Import { .. } => true,
+            #[cfg(feature = "control")]
+            Commands::Tune { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the binary was started through a symlink named xbacklight
+#[cfg(feature = "control")]
+fn invoked_as_xbacklight() -> bool {
+    std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .is_some_and(|path| path.file_name().is_some_and(|name| name == "xbacklight"))
+}
 
-    let exit_bool1 = exit_bool.clone();
-    ctrlc::set_handler(move || {
-        exit_bool1.store(true, atomic::Ordering::Relaxed);
-        close_sender
-            .send(())
-            .expect("Could not send signal on channel.")
-    })
-    .context("Error setting Ctrl-C handler")?;
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("{} is not an octal mode: {}", mode, e))
+}
 
-    let args = Args::parse();
+/// Config for the selected instance
+fn load(args: &Args) -> Result<Config> {
+    Ok(
+        Config::load_instance(args.config.as_deref(), args.instance.as_deref())?
+            .socket_path(args.socket_path.clone()),
+    )
+}
 
-    if args.server {
-        let (control_server, command_receiver) = ControlServer::new()?;
-        let ambient_brightness_controller =
-            AmbientBrightnessController::create(close_receiver, command_receiver)?;
+/// Config changes to apply while running, when there is a config file, still
+/// driving the backlights named on the command line
+fn reloads(args: &Args, server_args: &ServerArgs) -> Receiver<Config> {
+    let Some(path) = &args.config else {
+        return never();
+    };
+    let configs = config_watch::watch_instance(path, args.instance.clone());
+    let (screen, kbd) = (server_args.screen.clone(), server_args.kbd.clone());
+    if screen.is_none() && kbd.is_none() {
+        return configs;
+    }
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        for config in configs {
+            if sender
+                .send(config.devices(screen.clone(), kbd.clone()))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    receiver
+}
 
-        let join_handle = control_server.run(exit_bool.clone());
-        ambient_brightness_controller.run()?;
+/// Controller builder for the common flags
+fn builder<'c>(args: &Args, config: &'c Config) -> Result<Builder<'c>> {
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let mut builder = Builder::new(config)
+        .dry_run(args.dry_run)
+        .clock(clock.clone());
+    if let Some(path) = &args.record {
+        builder = builder.recorder(Arc::new(Recorder::create(path, clock)?));
+    }
+    Ok(builder)
+}
 
-        info!("Waiting for Server Thread to stop.");
-        join_handle
-            .join()
-            .map_err(|e| anyhow!("Error waiting for Server Thread: {:?}", e))??;
-    } else {
-        let mut client = ControlClient::new()?;
+#[cfg(feature = "control")]
+fn print_status(status: &Status) {
+    let ago = |x: Option<Duration>| match x {
+        Some(x) => format!("{:.1}s ago", x.as_secs_f64()),
+        None => "never".to_string(),
+    };
 
-        if args.idle.idle {
-            client.idle()?;
+    println!("uptime: {:.1}s", status.uptime.as_secs_f64());
+    println!("last sensor read: {}", ago(status.last_read));
+    println!("last write: {}", ago(status.last_write));
+    println!("sensor errors: {}", status.sensor_errors);
+    println!("write errors: {}", status.write_errors);
+    println!(
+        "sensor stuck: {}",
+        if status.sensor_stuck { "yes" } else { "no" }
+    );
+    if status.failing_outputs > 0 {
+        println!("failing outputs: {}", status.failing_outputs);
+    }
+    println!("output errors: {}", status.output_errors);
+    println!(
+        "mode: {} for {:.1}s",
+        status.mode,
+        status.mode_for.as_secs_f64()
+    );
+    println!("adjustments: {}", status.adjustments);
+    for (name, since) in &status.last_adjusted {
+        println!("  {} adjusted {}", name, ago(Some(*since)));
+    }
+    if let (Some(ambient), Some(lux)) = (status.ambient, status.lux) {
+        println!("ambient: {}% ({} lx)", ambient, lux);
+    }
+    for level in &status.levels {
+        println!(
+            "  {} at {}% (offset {:+})",
+            level.name, level.percent, level.offset
+        );
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+    #[cfg(feature = "control")]
+    if invoked_as_xbacklight() {
+        let args = std::env::args().skip(1).collect::<Vec<_>>();
+        let config = Config::load(config::default_path().as_deref())?;
+        return Ok(xbacklight::run(&config, &args)?);
+    }
+
+    let (close_sender, close_receiver) = bounded(1);
+
+    // The first signal shuts down cleanly, restoring brightness; a second one
+    // exits right away in case shutdown is stuck
+    let mut signals =
+        Signals::new([SIGTERM, SIGINT, SIGQUIT]).context("Error setting signal handlers")?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            warn!("Received signal {}, shutting down", signal);
+            let _ = close_sender.send(());
         }
-        if args.idle.active {
-            client.active()?;
+        if let Some(signal) = signals.next() {
+            warn!("Received signal {} again, exiting", signal);
+            process::exit(128 + signal);
         }
+    });
+
+    let mut args = Args::parse();
+    #[cfg(not(feature = "control"))]
+    if args.legacy.needs_control() {
+        return Err(anyhow!("Control support was not compiled in"));
+    }
+    let legacy = args.command.is_none();
+    let commands = match args.command.take() {
+        Some(command) => vec![command],
+        None => args.legacy.commands(),
+    };
+    if legacy && !commands.is_empty() {
+        warn!(
+            "Flags like -s, -i, and --increase are deprecated and will be removed, \
+             use the subcommands instead, e.g. `server` or `increase 10`"
+        );
+    }
+    if commands.is_empty() {
+        Args::command().print_help()?;
+        process::exit(2);
+    }
+    if args.config.is_none() && !commands.iter().any(Commands::writes_config) {
+        args.config = config::default_path();
+    }
+    if let Some(path) = &args.config {
+        info!("Using config file {}", path.display());
+    }
+    for command in commands {
+        run(&args, command, &close_receiver)?;
+    }
+
+    Ok(())
+}
 
-        if let Some(amount) = args.offset.increase {
-            client.increase(amount)?;
+fn run(args: &Args, command: Commands, close_receiver: &Receiver<()>) -> Result<()> {
+    match command {
+        Commands::Server(server_args) => server(args, &server_args, close_receiver.clone())?,
+        Commands::Replay { recording } => {
+            let config = load(args)?;
+            record::replay(&config, &recording, Box::new(io::stdout()))?;
+        }
+        Commands::Preview => {
+            let config = load(args)?;
+            preview::print(&config)?;
+        }
+        Commands::Once => {
+            let config = load(args)?;
+            builder(args, &config)?.once()?;
+        }
+        Commands::Calibrate => {
+            let path = args
+                .config
+                .as_deref()
+                .context("calibrate needs --config to know which file to write")?;
+            calibrate::run(path)?;
         }
-        if let Some(amount) = args.offset.decrease {
-            client.decrease(amount)?;
+        Commands::Export { output } => {
+            let mut out: Box<dyn io::Write> = match &output {
+                Some(path) => Box::new(
+                    std::fs::File::create(path)
+                        .with_context(|| format!("Couldn't create {}", path.display()))?,
+                ),
+                None => Box::new(io::stdout()),
+            };
+            bundle::export(args.config.as_deref(), &mut out)?;
         }
+        Commands::Import { bundle } => {
+            let path = args
+                .config
+                .as_deref()
+                .context("import needs --config to know which file to write")?;
+            bundle::import(&bundle, path)?;
+            println!("Imported {} into {}", bundle.display(), path.display());
+        }
+        Commands::Monitor { interval } => {
+            let config = load(args)?;
+            monitor::run(
+                &config,
+                Duration::from_millis(interval),
+                close_receiver.clone(),
+            )?;
+        }
+        #[cfg(feature = "control")]
+        Commands::Status { format } => {
+            let status = client(args)?.ping()?;
+            match &format {
+                Some(template) => println!("{}", status.format(template)?),
+                None => print_status(&status),
+            }
+        }
+        #[cfg(feature = "control")]
+        Commands::DaemonVersion => {
+            let version = client(args)?.version()?;
+            println!("daemon: {}", version.daemon);
+            println!("protocol: {}", version.protocol);
+            println!("features: {}", version.features.join(" "));
+            println!("sensor: {}", version.sensor);
+        }
+        #[cfg(feature = "control")]
+        Commands::Idle => client(args)?.idle()?,
+        #[cfg(feature = "control")]
+        Commands::Active => client(args)?.active()?,
+        #[cfg(feature = "control")]
+        Commands::Increase { amount, output } => match output {
+            Some(kind) => client(args)?.nudge(kind, amount)?,
+            None => client(args)?.increase(amount)?,
+        },
+        #[cfg(feature = "control")]
+        Commands::Decrease { amount, output } => match output {
+            Some(kind) => client(args)?.nudge(kind, amount.saturating_neg())?,
+            None => client(args)?.decrease(amount)?,
+        },
+        #[cfg(feature = "control")]
+        Commands::Set { percent, output } => match output {
+            Some(kind) => client(args)?.set_output(kind, percent)?,
+            None => client(args)?.set(percent)?,
+        },
+        #[cfg(feature = "control")]
+        Commands::Xbacklight {
+            args: xbacklight_args,
+        } => {
+            let config = load(args)?;
+            xbacklight::run(&config, &xbacklight_args)?;
+        }
+        #[cfg(feature = "control")]
+        Commands::Profile {
+            name,
+            overlay,
+            activate,
+        } => {
+            client(args)?.edit_profile(ProfileEdit {
+                name,
+                overlay,
+                activate,
+            })?;
+        }
+        #[cfg(feature = "control")]
+        Commands::Disable { output } => client(args)?.disable(output)?,
+        #[cfg(feature = "control")]
+        Commands::Enable { output } => client(args)?.enable(output)?,
+        #[cfg(feature = "control")]
+        Commands::Appearance { appearance } => client(args)?.appearance(appearance)?,
+        #[cfg(feature = "control")]
+        Commands::Resync => client(args)?.resync()?,
+        #[cfg(feature = "control")]
+        Commands::Snapshot { name } => client(args)?.snapshot(&name)?,
+        #[cfg(feature = "control")]
+        Commands::Restore { name } => client(args)?.restore(&name)?,
+        #[cfg(feature = "control")]
+        Commands::Tune { kbd } => {
+            let path = args
+                .config
+                .as_deref()
+                .context("tune needs --config to know which file to write")?;
+            tune::run(
+                &Config::load_instance(Some(path), args.instance.as_deref())?
+                    .socket_path(args.socket_path.clone()),
+                path,
+                kbd,
+            )?;
+        }
+        #[cfg(feature = "control")]
+        Commands::Helper => {
+            let config = load(args)?;
+            let helper = Helper::new(&config)?;
+            sandbox::apply(&config, args.config.as_deref())?;
+            helper.run(close_receiver.clone())?;
+        }
+        #[cfg(feature = "tray")]
+        Commands::Tray => {
+            let config = load(args)?;
+            tray::run(&config, args.config.as_deref(), close_receiver.clone())?;
+        }
+    }
+    Ok(())
+}
 
-        info!("Done");
+/// Client for the selected instance's daemon
+#[cfg(feature = "control")]
+fn client(args: &Args) -> Result<ControlClient> {
+    Ok(ControlClient::new(&load(args)?)?)
+}
+
+#[cfg(feature = "control")]
+fn server(args: &Args, server_args: &ServerArgs, close_receiver: Receiver<()>) -> Result<()> {
+    let config = load(args)?
+        .socket_permissions(server_args.socket_mode, server_args.socket_group.clone())
+        .devices(server_args.screen.clone(), server_args.kbd.clone());
+    let health = Arc::new(Health::new(Arc::new(SystemClock)));
+    let (control_server, command_receiver) = ControlServer::new(&config, health.clone())?;
+    let control_server = control_server.config_file(args.config.clone());
+    let stopper = control_server.stopper();
+    let mut builder = builder(args, &config)?;
+    if let Some(fd) = server_args.ready_fd {
+        builder = builder.ready_fd(fd);
     }
+    // Before any threads, which Landlock wouldn't cover
+    sandbox::apply(&config, args.config.as_deref())?;
+    acpi_events::watch(&config, control_server.command_sender());
+    let join_handle = control_server.run();
+    builder
+        .health(health)
+        .close_receiver(close_receiver)
+        .command_receiver(command_receiver)
+        .lock_receiver(session_lock::watch(&config))
+        .capture_receiver(screencast::watch(&config))
+        .reload_receiver(reloads(args, server_args))
+        .hotplug_receiver(hotplug::watch())
+        .run()?;
 
+    stopper.stop()?;
+    info!("Waiting for Server Thread to stop.");
+    join_handle
+        .join()
+        .map_err(|e| anyhow!("Error waiting for Server Thread: {:?}", e))??;
     Ok(())
 }
+
+#[cfg(not(feature = "control"))]
+fn server(args: &Args, server_args: &ServerArgs, close_receiver: Receiver<()>) -> Result<()> {
+    let config = load(args)?.devices(server_args.screen.clone(), server_args.kbd.clone());
+    let (command_sender, command_receiver) = bounded(1);
+    let mut builder = builder(args, &config)?;
+    if let Some(fd) = server_args.ready_fd {
+        builder = builder.ready_fd(fd);
+    }
+    // Before any threads, which Landlock wouldn't cover
+    sandbox::apply(&config, args.config.as_deref())?;
+    acpi_events::watch(&config, command_sender);
+    builder
+        .close_receiver(close_receiver)
+        .command_receiver(command_receiver)
+        .lock_receiver(session_lock::watch(&config))
+        .capture_receiver(screencast::watch(&config))
+        .reload_receiver(reloads(args, server_args))
+        .hotplug_receiver(hotplug::watch())
+        .run()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(line: &str) -> Vec<String> {
+        let mut args = Args::try_parse_from(line.split_whitespace()).unwrap();
+        match args.command.take() {
+            Some(command) => vec![command],
+            None => args.legacy.commands(),
+        }
+        .iter()
+        .map(|x| {
+            match x {
+                Commands::Server(_) => "server",
+                Commands::Replay { .. } => "replay",
+                #[cfg(feature = "control")]
+                Commands::Status { .. } => "status",
+                #[cfg(feature = "control")]
+                Commands::Idle => "idle",
+                #[cfg(feature = "control")]
+                Commands::Increase { .. } => "increase",
+                _ => "other",
+            }
+            .to_string()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn cli_is_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn old_flags_still_work() {
+        assert_eq!(commands("x -s --config a.toml"), ["server"]);
+        assert_eq!(commands("x server --config a.toml"), ["server"]);
+        assert_eq!(commands("x -s --socket-mode 0660"), ["server"]);
+        assert_eq!(commands("x server --ready-fd 3"), ["server"]);
+        assert_eq!(
+            commands("x server --screen acpi_video0 --kbd tpacpi::kbd_backlight"),
+            ["server"]
+        );
+        assert!(Args::try_parse_from(["x", "--socket-mode", "0660"]).is_err());
+        assert_eq!(commands("x --replay r.jsonl"), ["replay"]);
+        assert_eq!(commands("x"), Vec::<String>::new());
+        assert!(Args::try_parse_from(["x", "-s", "-i"]).is_err());
+        assert!(Args::try_parse_from(["x", "-s", "once"]).is_err());
+    }
+
+    #[test]
+    fn only_named_config_files_are_written() {
+        let writes = |line: &str| {
+            let args = Args::try_parse_from(line.split_whitespace()).unwrap();
+            args.command.unwrap().writes_config()
+        };
+        assert!(writes("x calibrate"));
+        assert!(writes("x import bundle.toml"));
+        assert!(!writes("x server"));
+        assert!(!writes("x export"));
+    }
+
+    #[test]
+    #[cfg(feature = "control")]
+    fn old_control_flags_still_work() {
+        assert_eq!(commands("x -i --increase 5"), ["idle", "increase"]);
+        assert_eq!(commands("x --ping --format {mode}"), ["status"]);
+        assert_eq!(commands("x --status"), ["status"]);
+        assert_eq!(commands("x ping"), ["status"]);
+        assert_eq!(commands("x increase -5"), ["increase"]);
+        assert!(Args::try_parse_from(["x", "-i", "-a"]).is_err());
+        assert!(Args::try_parse_from(["x", "set", "101"]).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam::{
+    channel::{tick, Receiver},
+    select,
+};
+
+use crate::{
+    ambient_brightness::AmbientBrightness, config::Config, sensor, sysfs::Sysfs, Result,
+    SCREEN_SUBSYSTEM,
+};
+
+/// Prints every sample and the levels it would produce without adjusting anything
+pub fn run(config: &Config, interval: Duration, close_receiver: Receiver<()>) -> Result<()> {
+    let sysfs = Sysfs::default();
+    let mut ambient_brightness =
+        AmbientBrightness::new(sensor::selected(&sysfs, config)?, config.filter.clone()).init()?;
+    let max_brightness = config
+        .screen
+        .backlight(&sysfs)
+        .and_then(|x| sysfs.read_max_brightness(SCREEN_SUBSYSTEM, &x))
+        .ok();
+    let ticker = tick(interval);
+
+    println!(
+        "{:>14} {:>10} {:>8} {:>8} {:>7} {:>7} {:>5}",
+        "time", "raw", "smoothed", "ambient", "screen", "level", "kbd"
+    );
+
+    let screen_curve = config.screen.curve();
+    loop {
+        select! {
+            recv(close_receiver) -> _ => break,
+            recv(ticker) -> _ => {
+                let sample = ambient_brightness.sample()?;
+                let screen = screen_curve.percent(sample.value);
+                let level = match max_brightness {
+                    Some(max) => ((screen * max) / 100).to_string(),
+                    None => "-".to_string(),
+                };
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+                println!(
+                    "{:>14.3} {:>10} {:>8.4} {:>7.2}% {:>6}% {:>7} {:>4}%",
+                    time.as_secs_f64(),
+                    sample.raw,
+                    sample.smoothed,
+                    sample.percent,
+                    screen,
+                    level,
+                    config.kbd.curve.percent(sample.value)
+                );
+            },
+        }
+    }
+
+    Ok(())
+}
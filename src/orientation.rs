@@ -0,0 +1,113 @@
+//! Screen orientation from an IIO accelerometer, to hold brightness while the
+//! light sensor faces the table or the keyboard
+
+use std::{cell::Cell, fs, path::PathBuf};
+
+use log::{info, warn};
+
+use crate::{
+    config::OrientationConfig,
+    sysfs::{Attribute, Sysfs},
+    Error, Result,
+};
+
+/// Whether gravity along `z` points out of the screen, within `angle` degrees
+/// of straight down. Face up reads positive `z`, unless `invert` flips it.
+fn faces_down([x, y, z]: [f64; 3], angle: f64, invert: bool) -> bool {
+    let magnitude = (x * x + y * y + z * z).sqrt();
+    if magnitude == 0.0 {
+        return false;
+    }
+    let z = if invert { -z } else { z };
+    -z / magnitude >= angle.to_radians().cos()
+}
+
+/// First IIO device with accelerometer channels, optionally by name
+fn find_device(sysfs: &Sysfs, name: Option<&str>) -> Result<PathBuf> {
+    let iio_devices = sysfs.path("bus/iio/devices");
+    let mut devices = fs::read_dir(&iio_devices)
+        .map_err(|source| Error::Sysfs {
+            path: iio_devices.clone(),
+            source,
+        })?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    devices.sort();
+
+    devices
+        .into_iter()
+        .find(|dev| {
+            dev.join("in_accel_z_raw").exists()
+                && name.is_none_or(|name| {
+                    fs::read_to_string(dev.join("name")).is_ok_and(|x| x.trim() == name)
+                })
+        })
+        .ok_or_else(|| Error::NotFound("an IIO accelerometer in sysfs".to_string()))
+}
+
+/// Reads the accelerometer each tick and tells when the screen faces down
+pub(crate) struct Accelerometer {
+    axes: [Attribute; 3],
+    angle: f64,
+    invert: bool,
+    face_down: Cell<bool>,
+}
+
+impl Accelerometer {
+    pub(crate) fn new(sysfs: &Sysfs, config: &OrientationConfig) -> Result<Self> {
+        let dev = find_device(sysfs, config.device.as_deref())?;
+        let axis = |axis: &str| Attribute::open(dev.join(format!("in_accel_{}_raw", axis)));
+        let accelerometer = Self {
+            axes: [axis("x")?, axis("y")?, axis("z")?],
+            angle: config.angle,
+            invert: config.invert,
+            face_down: Cell::new(false),
+        };
+        accelerometer.read()?;
+        info!("Using accelerometer: {}", dev.display());
+        Ok(accelerometer)
+    }
+
+    /// Raw readings; the scale doesn't matter for the direction of gravity
+    fn read(&self) -> Result<[f64; 3]> {
+        let [x, y, z] = &self.axes;
+        Ok([x.read()?, y.read()?, z.read()?])
+    }
+
+    /// Whether the screen faces down. Read errors count as facing up, so the
+    /// sensor keeps driving brightness.
+    pub(crate) fn face_down(&self) -> bool {
+        let face_down = match self.read() {
+            Ok(axes) => faces_down(axes, self.angle, self.invert),
+            Err(e) => {
+                warn!("Couldn't read the accelerometer: {}", e);
+                false
+            }
+        };
+        if face_down != self.face_down.replace(face_down) {
+            if face_down {
+                info!("Screen faces down, holding brightness");
+            } else {
+                info!("Screen faces up again, following the sensor");
+            }
+        }
+        face_down
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_face_down_within_angle() {
+        assert!(!faces_down([0.0, 0.0, 9.8], 30.0, false));
+        assert!(faces_down([0.0, 0.0, -9.8], 30.0, false));
+        assert!(faces_down([0.0, 0.0, 9.8], 30.0, true));
+        // Tilted 45 degrees off straight down
+        assert!(!faces_down([0.0, 6.9, -6.9], 30.0, false));
+        assert!(faces_down([0.0, 6.9, -6.9], 50.0, false));
+        assert!(!faces_down([0.0, 0.0, 0.0], 30.0, false));
+    }
+}
@@ -0,0 +1,1127 @@
+use std::{
+    mem,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    ambient_brightness::{percent, Sample},
+    clock::Clock,
+    command::OutputKind,
+    config::{Ambient, FilterConfig},
+    filter::Filter,
+    levels::{ambient_value, offset_percent},
+    Error, Result,
+};
+
+/// Anything the ambient pipeline can drive from the smoothed ambient value
+pub(crate) trait Output {
+    /// Names the output in log messages
+    fn name(&self) -> &str;
+
+    /// Whether it changed the brightness
+    fn adjust(&mut self, new_val: u32) -> Result<bool>;
+
+    /// The curve ambient light is mapped with, swapped for blends while
+    /// crossfading to a new profile
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        None
+    }
+
+    /// Manual offset commands, ignored by outputs without an offset
+    fn increase(&mut self, _amount: i8) {}
+
+    fn decrease(&mut self, _amount: i8) {}
+
+    /// Offsets the output so it lands on `percent` at the current ambient light
+    fn set(&mut self, _percent: u8) {}
+
+    /// Percent the output was last adjusted to, offset included, for outputs
+    /// with an offset
+    fn percent(&self) -> Option<u32> {
+        None
+    }
+
+    /// Manual offset on top of the curve, for outputs with an offset
+    fn offset(&self) -> Option<i8> {
+        None
+    }
+
+    /// Called once when the daemon shuts down cleanly
+    fn restore(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Fills in the output's current brightness, for monitoring
+    #[cfg(feature = "dbus")]
+    fn report(&self, _report: &mut Report) {}
+}
+
+/// Current brightness of the outputs that have monitored values
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg(feature = "dbus")]
+pub(crate) struct Report {
+    pub(crate) screen_percent: Option<u32>,
+    pub(crate) kbd_level: Option<u32>,
+}
+
+/// What to change when writes to an output are refused
+fn permission_hint(error: &Error) -> &'static str {
+    match error {
+        Error::DBus(_) => {
+            "logind only accepts brightness changes from the active session, so run the \
+             daemon inside the graphical session, e.g. as a systemd user service"
+        }
+        _ => {
+            "grant write access with a udev rule, e.g. one setting GROUP=\"video\" and \
+             MODE=\"0664\" on the brightness attribute, and add the user to that group, or \
+             leave the attribute read-only so writes go through logind"
+        }
+    }
+}
+
+/// Stops driving an output once it isn't allowed to write, so the others keep
+/// following the sensor
+pub(crate) struct Degradable<'a> {
+    output: Box<dyn Output + 'a>,
+    degraded: bool,
+}
+
+impl<'a> Degradable<'a> {
+    pub(crate) fn new(output: Box<dyn Output + 'a>) -> Self {
+        Self {
+            output,
+            degraded: false,
+        }
+    }
+}
+
+impl Output for Degradable<'_> {
+    fn name(&self) -> &str {
+        self.output.name()
+    }
+
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        if self.degraded {
+            return Ok(false);
+        }
+
+        match self.output.adjust(new_val) {
+            Err(e) if e.is_permission_denied() => {
+                error!(
+                    "Not allowed to set {}, leaving it alone until restart: {}. To fix it, {}",
+                    self.output.name(),
+                    e,
+                    permission_hint(&e)
+                );
+                self.degraded = true;
+                Ok(false)
+            }
+            result => result,
+        }
+    }
+
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        self.output.curve()
+    }
+
+    fn increase(&mut self, amount: i8) {
+        self.output.increase(amount)
+    }
+
+    fn decrease(&mut self, amount: i8) {
+        self.output.decrease(amount)
+    }
+
+    fn set(&mut self, percent: u8) {
+        self.output.set(percent)
+    }
+
+    fn percent(&self) -> Option<u32> {
+        self.output.percent()
+    }
+
+    fn offset(&self) -> Option<i8> {
+        self.output.offset()
+    }
+
+    fn restore(&self) -> Result<()> {
+        if self.degraded {
+            return Ok(());
+        }
+        self.output.restore()
+    }
+
+    #[cfg(feature = "dbus")]
+    fn report(&self, report: &mut Report) {
+        self.output.report(report)
+    }
+}
+
+/// How one output follows the shared ambient signal
+pub(crate) struct Tuning<'c> {
+    /// Smooths readings for this output alone instead of using the shared filter
+    pub(crate) filter: Option<&'c FilterConfig>,
+    /// Share of the ambient percent followed while idle
+    pub(crate) idle_scale: f64,
+    /// Whether increase, decrease, and set commands reach the output
+    pub(crate) offsets: bool,
+    /// How long a new level is held before the sensor may change it again
+    pub(crate) dwell: Duration,
+    /// How long the output takes to move to a new level the sensor asks for
+    pub(crate) transition: Duration,
+}
+
+/// Fixed percent an output holds in a pitch-black room, entered below one
+/// ambient percent and left above another so the room's faintest light
+/// doesn't flip it back and forth
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Darkness {
+    pub(crate) below: f64,
+    pub(crate) above: f64,
+    pub(crate) percent: u32,
+}
+
+/// Eases an output from the targets of its old mode or profile to the new ones
+struct Fade {
+    started: Instant,
+    /// Idle scale faded from
+    scale: f64,
+    /// Curves faded from and to, after a profile change swapped them
+    curves: Option<(StepCurve, StepCurve)>,
+}
+
+impl Fade {
+    /// Share of the way through a fade lasting `duration`, 1 once it's done
+    fn progress(&self, now: Instant, duration: Duration) -> f64 {
+        if duration.is_zero() {
+            return 1.0;
+        }
+        (now.duration_since(self.started).as_secs_f64() / duration.as_secs_f64()).min(1.0)
+    }
+}
+
+/// Moves an output from one percent on its curve to another in small steps
+struct Ramp {
+    started: Instant,
+    from: u32,
+    to: u32,
+}
+
+impl Ramp {
+    /// Percent at `now` of a ramp lasting `duration`, and whether it's done
+    fn percent(&self, now: Instant, duration: Duration) -> (u32, bool) {
+        let progress =
+            (now.duration_since(self.started).as_secs_f64() / duration.as_secs_f64()).min(1.0);
+        let pct = self.from as f64 + (self.to as f64 - self.from as f64) * progress;
+        (pct.round() as u32, progress >= 1.0)
+    }
+}
+
+/// Drives an output from each [`Sample`] with its own [`Tuning`]
+pub(crate) struct Tuned<'a> {
+    output: Degradable<'a>,
+    kind: OutputKind,
+    filter: Option<Filter>,
+    idle_scale: f64,
+    offsets: bool,
+    dwell: Duration,
+    transition: Duration,
+    crossfade: Duration,
+    clock: Arc<dyn Clock>,
+    /// Last change the sensor made. Commands clear it, so they apply right away.
+    changed: Option<Instant>,
+    fade: Option<Fade>,
+    /// Whether the last sample was taken while idle
+    idle: bool,
+    /// Ambient percent of the last sample, before the idle scale
+    last: Option<f64>,
+    darkness: Option<Darkness>,
+    /// Whether the room is dark enough for `darkness`
+    dark: bool,
+    ramp: Option<Ramp>,
+    /// Percent on the curve the output was last moved to
+    shown: Option<u32>,
+}
+
+impl<'a> Tuned<'a> {
+    /// `initial` starts the output's own filter, in the log-scaled units of
+    /// [`Sample::level`]. Changes of mode and profile are faded over `crossfade`.
+    pub(crate) fn new(
+        output: Degradable<'a>,
+        kind: OutputKind,
+        tuning: Tuning,
+        initial: f64,
+        crossfade: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        Ok(Self {
+            output,
+            kind,
+            filter: tuning
+                .filter
+                .map(|config| Filter::new(config, initial))
+                .transpose()?,
+            idle_scale: tuning.idle_scale,
+            offsets: tuning.offsets,
+            dwell: tuning.dwell,
+            transition: tuning.transition,
+            crossfade,
+            clock,
+            changed: None,
+            fade: None,
+            idle: false,
+            last: None,
+            darkness: None,
+            dark: false,
+            ramp: None,
+            shown: None,
+        })
+    }
+
+    /// Holds the output at a fixed level whenever the room is pitch black
+    pub(crate) fn darkness(mut self, darkness: Option<Darkness>) -> Self {
+        self.darkness = darkness;
+        self
+    }
+
+    /// Which of the outputs commands can single out this one belongs to
+    pub(crate) fn kind(&self) -> OutputKind {
+        self.kind
+    }
+
+    /// Whether the output is still on its way to new targets
+    pub(crate) fn fading(&self) -> bool {
+        self.fade.is_some() || self.ramp.is_some()
+    }
+
+    /// Idle scale of the current mode
+    fn target_scale(&self) -> f64 {
+        if self.idle {
+            self.idle_scale
+        } else {
+            1.0
+        }
+    }
+
+    /// Idle scale at `now`, partway there while fading
+    fn scale(&self, now: Instant) -> f64 {
+        match &self.fade {
+            Some(fade) => {
+                let progress = fade.progress(now, self.crossfade);
+                fade.scale + (self.target_scale() - fade.scale) * progress
+            }
+            None => self.target_scale(),
+        }
+    }
+
+    /// Takes over from the output `old` replaced on a reload, fading from its
+    /// curve and scale when they differ
+    pub(crate) fn fade_from(&mut self, old: &mut Tuned) {
+        let now = self.clock.now();
+        self.idle = old.idle;
+        self.last = old.last;
+        self.shown = old.shown;
+        self.dark = old.dark && self.darkness.is_some();
+        if self.crossfade.is_zero() {
+            return;
+        }
+
+        let scale = old.scale(now);
+        let curves = match (old.output.curve(), self.output.curve()) {
+            (Some(from), Some(to)) if from != to => Some((from.clone(), to.clone())),
+            _ => None,
+        };
+        if curves.is_some() || scale != self.target_scale() {
+            debug!("Fading {} to the new profile", self.output.name());
+            self.fade = Some(Fade {
+                started: now,
+                scale,
+                curves,
+            });
+        }
+    }
+
+    /// Lands on `percent` from a snapshot or a command for this output's
+    /// kind, even for outputs the other commands don't move
+    pub(crate) fn return_to(&mut self, percent: u8) {
+        self.changed = None;
+        self.output.set(percent)
+    }
+
+    /// Offsets the output by `amount`, raising it if positive, even if the
+    /// other commands don't move it
+    pub(crate) fn nudge(&mut self, amount: i8) {
+        self.changed = None;
+        match amount < 0 {
+            true => self.output.decrease(amount.saturating_neg()),
+            false => self.output.increase(amount),
+        }
+    }
+
+    /// Starts the output's own filter, if any, over at `level`
+    pub(crate) fn resync(&mut self, level: f64) {
+        if let Some(filter) = &mut self.filter {
+            filter.reset(level);
+        }
+    }
+
+    /// Whether the brightness changed, like [`Output::adjust`]
+    pub(crate) fn follow(&mut self, sample: &Sample) -> Result<bool> {
+        if sample.resynced {
+            self.resync(sample.level);
+        }
+        let smoothed = match &mut self.filter {
+            Some(filter) => filter.next(sample.level),
+            None => sample.smoothed,
+        };
+        let now = self.clock.now();
+        if sample.idle != self.idle && !self.crossfade.is_zero() {
+            debug!("Fading {} to the new mode", self.output.name());
+            let scale = self.scale(now);
+            // A profile fade carries on toward its curve from wherever it got to
+            let curves = match self.fade.take() {
+                Some(Fade {
+                    curves: Some((_, to)),
+                    ..
+                }) => self.output.curve().map(|from| (from.clone(), to)),
+                _ => None,
+            };
+            self.fade = Some(Fade {
+                started: now,
+                scale,
+                curves,
+            });
+        }
+        self.idle = sample.idle;
+        self.last = Some(percent(smoothed));
+        self.track_darkness(percent(smoothed));
+        self.apply(now)
+    }
+
+    /// Enters or leaves darkness at its thresholds; in between, it stays put
+    fn track_darkness(&mut self, pct: f64) {
+        let Some(darkness) = self.darkness else {
+            return;
+        };
+        let dark = if pct < darkness.below {
+            true
+        } else if pct > darkness.above {
+            false
+        } else {
+            self.dark
+        };
+        if dark != self.dark {
+            match dark {
+                true => info!(
+                    "Room is pitch black, holding {} at {}%",
+                    self.name(),
+                    darkness.percent
+                ),
+                false => info!("Room is lit again, {} follows its curve", self.name()),
+            }
+            self.dark = dark;
+            // The new level applies right away rather than after the dwell time
+            self.changed = None;
+        }
+    }
+
+    /// Percent to show at `now` on the way to `target`, starting a ramp
+    /// toward it from wherever the output is when the target moves
+    fn ramp_to(&mut self, target: u32, now: Instant) -> u32 {
+        // Crossfades already move in small steps
+        if self.transition.is_zero() || self.fade.is_some() {
+            self.ramp = None;
+            self.shown = Some(target);
+            return target;
+        }
+        let current = match &self.ramp {
+            Some(ramp) => ramp.percent(now, self.transition).0,
+            None => *self.shown.get_or_insert(target),
+        };
+        if self.ramp.as_ref().map_or(current, |x| x.to) != target {
+            self.ramp = Some(Ramp {
+                started: now,
+                from: current,
+                to: target,
+            });
+        }
+        let Some(ramp) = &self.ramp else {
+            return target;
+        };
+        let (pct, done) = ramp.percent(now, self.transition);
+        if done {
+            debug!("Finished moving {} to {}%", self.output.name(), pct);
+            self.ramp = None;
+        }
+        self.shown = Some(pct);
+        pct
+    }
+
+    /// Adjusts the output for ambient percent `pct`, at the darkness level
+    /// instead of its curve while the room is pitch black, and partway there
+    /// while ramping
+    fn adjust_to(&mut self, pct: f64, now: Instant) -> Result<bool> {
+        let value = ambient_value(pct);
+        let darkness = self.darkness.filter(|_| self.dark);
+        let Some(curve) = self.output.curve() else {
+            return self.output.adjust(value);
+        };
+        let target = match darkness {
+            Some(darkness) => darkness.percent,
+            None => curve.percent(value),
+        };
+        let shown = self.ramp_to(target, now);
+        if darkness.is_none() && shown == target {
+            return self.output.adjust(value);
+        }
+        let Some(curve) = self.output.curve() else {
+            return self.output.adjust(value);
+        };
+        let lit = mem::replace(curve, StepCurve::from_points(vec![(0, shown)]));
+        let result = self.output.adjust(value);
+        if let Some(curve) = self.output.curve() {
+            *curve = lit;
+        }
+        result
+    }
+
+    /// Moves a fading output along between samples
+    pub(crate) fn step(&mut self) -> Result<bool> {
+        let now = self.clock.now();
+        self.apply(now)
+    }
+
+    /// Adjusts the output to the last sample, as far into any fade as `now` is
+    fn apply(&mut self, now: Instant) -> Result<bool> {
+        let Some(pct) = self.last else {
+            return Ok(false);
+        };
+        let pct = pct * self.scale(now);
+
+        let fading = match &self.fade {
+            Some(fade) => {
+                let progress = fade.progress(now, self.crossfade);
+                if let (Some((from, to)), Some(curve)) = (&fade.curves, self.output.curve()) {
+                    *curve = from.blend(to, progress);
+                }
+                if progress >= 1.0 {
+                    debug!("Finished fading {}", self.output.name());
+                    self.fade = None;
+                }
+                true
+            }
+            None => false,
+        };
+        if fading || self.ramp.is_some() {
+            // Fades and ramps move in small steps the dwell time would hold back
+            return self.adjust_to(pct, now);
+        }
+
+        // Keeps ambient light near a curve step from flickering between levels
+        if let Some(changed) = self.changed {
+            if now.duration_since(changed) < self.dwell {
+                debug!("Holding {} for its dwell time", self.output.name());
+                return Ok(false);
+            }
+        }
+        let adjusted = self.adjust_to(pct, now)?;
+        if adjusted && !self.dwell.is_zero() {
+            self.changed = Some(now);
+        }
+        Ok(adjusted)
+    }
+}
+
+impl Output for Tuned<'_> {
+    fn name(&self) -> &str {
+        self.output.name()
+    }
+
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        self.output.adjust(new_val)
+    }
+
+    fn increase(&mut self, amount: i8) {
+        if self.offsets {
+            self.changed = None;
+            self.output.increase(amount)
+        }
+    }
+
+    fn decrease(&mut self, amount: i8) {
+        if self.offsets {
+            self.changed = None;
+            self.output.decrease(amount)
+        }
+    }
+
+    fn set(&mut self, percent: u8) {
+        if self.offsets {
+            self.changed = None;
+            self.output.set(percent)
+        }
+    }
+
+    fn percent(&self) -> Option<u32> {
+        self.output.percent()
+    }
+
+    fn offset(&self) -> Option<i8> {
+        self.output.offset()
+    }
+
+    fn restore(&self) -> Result<()> {
+        self.output.restore()
+    }
+
+    #[cfg(feature = "dbus")]
+    fn report(&self, report: &mut Report) {
+        self.output.report(report)
+    }
+}
+
+/// Manual offset on top of an output's curve, moved by increase, decrease, and
+/// set commands
+#[derive(Default)]
+pub(crate) struct Offset {
+    offset: i8,
+    /// Percent from the curve at the last adjustment, before the offset
+    last_pct: u32,
+}
+
+impl Offset {
+    /// Offsets a percent from the curve, remembering it for [`Offset::set`]
+    pub(crate) fn apply(&mut self, pct: u32) -> u32 {
+        self.last_pct = pct;
+        offset_percent(pct, self.offset)
+    }
+
+    pub(crate) fn increase(&mut self, amount: i8) {
+        self.offset = self.offset.saturating_add(amount);
+    }
+
+    pub(crate) fn decrease(&mut self, amount: i8) {
+        self.offset = self.offset.saturating_sub(amount);
+    }
+
+    /// Lands on `percent` at the last adjustment's curve percent
+    pub(crate) fn set(&mut self, percent: u8) {
+        self.offset =
+            (percent as i32 - self.last_pct as i32).clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+    }
+
+    /// Percent of the last adjustment, offset included
+    pub(crate) fn percent(&self) -> u32 {
+        offset_percent(self.last_pct, self.offset)
+    }
+
+    pub(crate) fn offset(&self) -> i8 {
+        self.offset
+    }
+}
+
+/// Whether moving from `cur` to `new` (both raw, out of `max`) changes the
+/// brightness by at least `min_delta` percent
+pub(crate) fn exceeds_min_delta(cur: u32, new: u32, max: u32, min_delta: u32) -> bool {
+    cur.abs_diff(new) as u64 * 100 >= min_delta as u64 * max.max(1) as u64
+}
+
+/// Restores every output, carrying on past ones that fail, and returns the
+/// first failure
+pub(crate) fn restore_all<'o, O: Output + 'o>(
+    outputs: impl IntoIterator<Item = &'o O>,
+) -> Result<()> {
+    let mut first = None;
+    for output in outputs {
+        if let Err(e) = output.restore() {
+            warn!("Couldn't restore {}: {}", output.name(), e);
+            first.get_or_insert(e);
+        }
+    }
+    first.map_or(Ok(()), Err)
+}
+
+/// Step curve of `(ambient, percent)` points. The percent of the last point at
+/// or below the ambient value applies; values below the first point use the
+/// first point's percent. In the config, the ambient side may be given in lux,
+/// e.g. `["500 lux", 60]`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "Vec<(Ambient, u32)>")]
+pub(crate) struct StepCurve(Vec<(u32, u32)>);
+
+impl StepCurve {
+    /// Points that are already sorted and within range
+    pub(crate) fn from_points(points: Vec<(u32, u32)>) -> Self {
+        Self(points)
+    }
+
+    #[cfg(feature = "control")]
+    pub(crate) fn points(&self) -> &[(u32, u32)] {
+        &self.0
+    }
+
+    pub(crate) fn percent(&self, ambient: u32) -> u32 {
+        self.0
+            .iter()
+            .take_while(|(threshold, _)| *threshold <= ambient)
+            .last()
+            .or(self.0.first())
+            .map(|(_, pct)| *pct)
+            .unwrap_or(0)
+    }
+
+    /// This curve with no percent above `max`
+    pub(crate) fn capped(&self, max: u32) -> StepCurve {
+        Self(
+            self.0
+                .iter()
+                .map(|(threshold, pct)| (*threshold, (*pct).min(max)))
+                .collect(),
+        )
+    }
+
+    /// This curve with a step at every ambient percent between its points,
+    /// so it rises or falls in a line from one point to the next
+    pub(crate) fn interpolated(&self) -> StepCurve {
+        let mut points = self
+            .0
+            .windows(2)
+            .flat_map(|pair| {
+                let ((from, low), (to, high)) = (pair[0], pair[1]);
+                (from..to).map(move |threshold| {
+                    let progress = (threshold - from) as f64 / (to - from) as f64;
+                    let pct = low as f64 + (high as f64 - low as f64) * progress;
+                    (threshold, pct.round() as u32)
+                })
+            })
+            .collect::<Vec<_>>();
+        points.extend(self.0.last());
+        Self(points)
+    }
+
+    /// This curve with `pct` from `from` up, e.g. where the sensor saturates
+    /// outdoors
+    pub(crate) fn boosted(&self, from: u32, pct: u32) -> StepCurve {
+        let mut points = self
+            .0
+            .iter()
+            .copied()
+            .take_while(|(threshold, _)| *threshold < from)
+            .collect::<Vec<_>>();
+        points.push((from, pct));
+        Self(points)
+    }
+
+    /// The curve `progress` of the way from this one to `other`, stepping
+    /// wherever either of them does
+    pub(crate) fn blend(&self, other: &StepCurve, progress: f64) -> StepCurve {
+        if progress <= 0.0 {
+            return self.clone();
+        }
+        if progress >= 1.0 {
+            return other.clone();
+        }
+        let mut thresholds = self
+            .0
+            .iter()
+            .chain(&other.0)
+            .map(|(threshold, _)| *threshold)
+            .collect::<Vec<_>>();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        Self(
+            thresholds
+                .into_iter()
+                .map(|threshold| {
+                    let from = self.percent(threshold) as f64;
+                    let to = other.percent(threshold) as f64;
+                    (threshold, (from + (to - from) * progress).round() as u32)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl TryFrom<Vec<(Ambient, u32)>> for StepCurve {
+    type Error = String;
+
+    fn try_from(points: Vec<(Ambient, u32)>) -> Result<Self, Self::Error> {
+        if let Some((ambient, _)) = points.iter().find(|(ambient, _)| ambient.0 < 0.0) {
+            return Err(format!("curve ambient {} is below 0", ambient.0));
+        }
+        let mut points = points
+            .into_iter()
+            .map(|(ambient, pct)| (ambient.0.round() as u32, pct))
+            .collect::<Vec<_>>();
+        if points.is_empty() {
+            return Err("curve needs at least one point".to_string());
+        }
+        if let Some((_, pct)) = points.iter().find(|(_, pct)| *pct > 100) {
+            return Err(format!("curve percent {} is above 100", pct));
+        }
+        points.sort_by_key(|(threshold, _)| *threshold);
+        Ok(Self(points))
+    }
+}
+
+impl Default for StepCurve {
+    /// The keyboard backlight steps: bright in the dark, off in daylight
+    fn default() -> Self {
+        Self::from_points(vec![(0, 100), (50, 66), (60, 33), (80, 0)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io, path::PathBuf, rc::Rc};
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    /// Counts writes, refusing every one of them
+    struct Refused(Rc<Cell<u32>>);
+
+    impl Output for Refused {
+        fn name(&self) -> &str {
+            "refused"
+        }
+
+        fn adjust(&mut self, _new_val: u32) -> Result<bool> {
+            self.0.set(self.0.get() + 1);
+            Err(Error::Sysfs {
+                path: PathBuf::from("/sys/class/leds/refused/brightness"),
+                source: io::Error::from(io::ErrorKind::PermissionDenied),
+            })
+        }
+    }
+
+    #[test]
+    fn permission_denied_degrades_output() {
+        let adjusted = Rc::new(Cell::new(0));
+        let mut output = Degradable::new(Box::new(Refused(adjusted.clone())));
+        assert!(output.adjust(10).is_ok());
+        assert!(output.adjust(20).is_ok());
+        assert!(output.restore().is_ok());
+        assert_eq!(adjusted.get(), 1);
+    }
+
+    /// Notes whether it was restored, failing if asked to
+    struct Restores {
+        restored: Rc<Cell<bool>>,
+        fails: bool,
+    }
+
+    impl Output for Restores {
+        fn name(&self) -> &str {
+            "restores"
+        }
+
+        fn adjust(&mut self, _new_val: u32) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn restore(&self) -> Result<()> {
+            self.restored.set(true);
+            if self.fails {
+                return Err(Error::NotFound("restores".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn restore_carries_on_past_failures() {
+        let restored = [Rc::new(Cell::new(false)), Rc::new(Cell::new(false))];
+        let outputs = [
+            Restores {
+                restored: restored[0].clone(),
+                fails: true,
+            },
+            Restores {
+                restored: restored[1].clone(),
+                fails: false,
+            },
+        ];
+        assert!(restore_all(&outputs).is_err());
+        assert!(restored.iter().all(|x| x.get()));
+    }
+
+    #[test]
+    fn offset_saturates_and_sets() {
+        let mut offset = Offset::default();
+        assert_eq!(offset.apply(40), 40);
+        offset.decrease(100);
+        offset.decrease(100);
+        assert_eq!(offset.apply(40), 0);
+        offset.set(70);
+        assert_eq!(offset.apply(40), 70);
+    }
+
+    #[test]
+    fn other_errors_are_returned() {
+        struct Broken;
+
+        impl Output for Broken {
+            fn name(&self) -> &str {
+                "broken"
+            }
+
+            fn adjust(&mut self, _new_val: u32) -> Result<bool> {
+                Err(Error::NotFound("broken".to_string()))
+            }
+        }
+
+        let mut output = Degradable::new(Box::new(Broken));
+        assert!(output.adjust(10).is_err());
+        assert!(output.adjust(20).is_err());
+    }
+
+    /// Records the last value it was adjusted to
+    struct Levels(Rc<Cell<u32>>);
+
+    impl Output for Levels {
+        fn name(&self) -> &str {
+            "levels"
+        }
+
+        fn adjust(&mut self, new_val: u32) -> Result<bool> {
+            Ok(self.0.replace(new_val) != new_val)
+        }
+    }
+
+    fn sample(smoothed: f64, idle: bool) -> Sample {
+        Sample {
+            raw: 0.0,
+            level: smoothed,
+            smoothed,
+            percent: percent(smoothed),
+            idle,
+            resynced: false,
+            value: 0,
+        }
+    }
+
+    #[test]
+    fn dwell_holds_new_levels() {
+        let level = Rc::new(Cell::new(0));
+        let clock = Arc::new(MockClock::new());
+        let tuning = Tuning {
+            filter: None,
+            idle_scale: 1.0,
+            offsets: true,
+            dwell: Duration::from_secs(10),
+            transition: Duration::ZERO,
+        };
+        let output = Degradable::new(Box::new(Levels(level.clone())));
+        let mut output = Tuned::new(
+            output,
+            OutputKind::Led,
+            tuning,
+            0.0,
+            Duration::ZERO,
+            clock.clone(),
+        )
+        .unwrap();
+        let sample = |smoothed| sample(smoothed, false);
+
+        output.follow(&sample(3.0)).unwrap();
+        assert_eq!(level.get(), 50);
+        clock.advance(Duration::from_secs(5));
+        output.follow(&sample(1.5)).unwrap();
+        assert_eq!(level.get(), 50);
+        clock.advance(Duration::from_secs(5));
+        output.follow(&sample(1.5)).unwrap();
+        assert_eq!(level.get(), 25);
+
+        // Commands aren't held back
+        output.increase(5);
+        output.follow(&sample(3.0)).unwrap();
+        assert_eq!(level.get(), 50);
+    }
+
+    #[test]
+    fn fades_into_idle() {
+        let level = Rc::new(Cell::new(0));
+        let clock = Arc::new(MockClock::new());
+        let tuning = Tuning {
+            filter: None,
+            idle_scale: 0.5,
+            offsets: true,
+            dwell: Duration::from_secs(60),
+            transition: Duration::ZERO,
+        };
+        let output = Degradable::new(Box::new(Levels(level.clone())));
+        let mut output = Tuned::new(
+            output,
+            OutputKind::Led,
+            tuning,
+            0.0,
+            Duration::from_secs(10),
+            clock.clone(),
+        )
+        .unwrap();
+
+        output.follow(&sample(3.0, false)).unwrap();
+        assert_eq!(level.get(), 50);
+        assert!(!output.fading());
+
+        output.follow(&sample(3.0, true)).unwrap();
+        assert_eq!(level.get(), 50);
+        clock.advance(Duration::from_secs(5));
+        output.step().unwrap();
+        assert_eq!(level.get(), 38);
+        clock.advance(Duration::from_secs(5));
+        output.step().unwrap();
+        assert_eq!(level.get(), 25);
+        assert!(!output.fading());
+    }
+
+    /// Records the percent its curve gives for the last value
+    struct Curved(StepCurve, Rc<Cell<u32>>);
+
+    impl Output for Curved {
+        fn name(&self) -> &str {
+            "curved"
+        }
+
+        fn adjust(&mut self, new_val: u32) -> Result<bool> {
+            let pct = self.0.percent(new_val);
+            Ok(self.1.replace(pct) != pct)
+        }
+
+        fn curve(&mut self) -> Option<&mut StepCurve> {
+            Some(&mut self.0)
+        }
+    }
+
+    #[test]
+    fn holds_darkness_between_thresholds() {
+        let level = Rc::new(Cell::new(0));
+        let tuning = Tuning {
+            filter: None,
+            idle_scale: 1.0,
+            offsets: true,
+            dwell: Duration::ZERO,
+            transition: Duration::ZERO,
+        };
+        let curve = StepCurve::from_points(vec![(0, 5), (10, 30)]);
+        let output = Degradable::new(Box::new(Curved(curve.clone(), level.clone())));
+        let mut output = Tuned::new(
+            output,
+            OutputKind::Kbd,
+            tuning,
+            0.0,
+            Duration::ZERO,
+            Arc::new(MockClock::new()),
+        )
+        .unwrap()
+        .darkness(Some(Darkness {
+            below: 5.0,
+            above: 10.0,
+            percent: 100,
+        }));
+        // Ambient percents, as 6 is the top of the log-scaled level
+        let sample = |pct: f64| sample(pct * 6.0 / 100.0, false);
+
+        output.follow(&sample(20.0)).unwrap();
+        assert_eq!(level.get(), 30);
+        output.follow(&sample(7.0)).unwrap();
+        assert_eq!(level.get(), 5);
+        output.follow(&sample(2.0)).unwrap();
+        assert_eq!(level.get(), 100);
+        output.follow(&sample(7.0)).unwrap();
+        assert_eq!(level.get(), 100);
+        output.follow(&sample(12.0)).unwrap();
+        assert_eq!(level.get(), 30);
+        assert_eq!(output.output.curve().unwrap(), &curve);
+    }
+
+    #[test]
+    fn ramps_to_new_steps() {
+        let level = Rc::new(Cell::new(0));
+        let clock = Arc::new(MockClock::new());
+        let tuning = Tuning {
+            filter: None,
+            idle_scale: 1.0,
+            offsets: true,
+            dwell: Duration::ZERO,
+            transition: Duration::from_secs(1),
+        };
+        let curve = StepCurve::from_points(vec![(0, 100), (10, 20)]);
+        let output = Degradable::new(Box::new(Curved(curve.clone(), level.clone())));
+        let mut output = Tuned::new(
+            output,
+            OutputKind::Kbd,
+            tuning,
+            0.0,
+            Duration::ZERO,
+            clock.clone(),
+        )
+        .unwrap();
+        let sample = |pct: f64| sample(pct * 6.0 / 100.0, false);
+
+        // The first level applies right away
+        output.follow(&sample(2.0)).unwrap();
+        assert_eq!(level.get(), 100);
+        assert!(!output.fading());
+
+        output.follow(&sample(20.0)).unwrap();
+        assert_eq!(level.get(), 100);
+        assert!(output.fading());
+        clock.advance(Duration::from_millis(500));
+        output.step().unwrap();
+        assert_eq!(level.get(), 60);
+
+        // Turning back starts from where the output is
+        output.follow(&sample(2.0)).unwrap();
+        assert_eq!(level.get(), 60);
+        clock.advance(Duration::from_secs(1));
+        output.step().unwrap();
+        assert_eq!(level.get(), 100);
+        assert!(!output.fading());
+        assert_eq!(output.output.curve().unwrap(), &curve);
+    }
+
+    #[test]
+    fn blends_step_curves() {
+        let from = StepCurve::from_points(vec![(0, 100), (50, 0)]);
+        let to = StepCurve::from_points(vec![(0, 50), (20, 10)]);
+        let half = from.blend(&to, 0.5);
+        assert_eq!(
+            half,
+            StepCurve::from_points(vec![(0, 75), (20, 55), (50, 5)])
+        );
+        assert_eq!(from.blend(&to, 0.0), from);
+        assert_eq!(from.blend(&to, 1.0), to);
+    }
+
+    #[test]
+    fn interpolates_step_curves() {
+        let curve = StepCurve::from_points(vec![(0, 5), (4, 25), (6, 5)]);
+        let filled = vec![(0, 5), (1, 10), (2, 15), (3, 20), (4, 25), (5, 15), (6, 5)];
+        assert_eq!(curve.interpolated(), StepCurve::from_points(filled));
+        let flat = StepCurve::from_points(vec![(10, 40)]);
+        assert_eq!(flat.interpolated(), flat);
+    }
+
+    #[test]
+    fn caps_and_boosts_step_curves() {
+        let curve = StepCurve::from_points(vec![(0, 5), (60, 40), (80, 70), (95, 80)]);
+        assert_eq!(
+            curve.capped(50),
+            StepCurve::from_points(vec![(0, 5), (60, 40), (80, 50), (95, 50)])
+        );
+        assert_eq!(
+            curve.boosted(90, 100),
+            StepCurve::from_points(vec![(0, 5), (60, 40), (80, 70), (90, 100)])
+        );
+        assert_eq!(
+            curve.boosted(0, 100),
+            StepCurve::from_points(vec![(0, 100)])
+        );
+    }
+}
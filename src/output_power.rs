@@ -0,0 +1,140 @@
+//! Power state of Wayland outputs, from the wlr-output-power-management
+//! protocol through a command such as wlopm
+
+use std::{fs, process::Command};
+
+use log::{debug, info};
+
+use crate::{config::PowerConfig, sysfs::Sysfs, Error, Result, SCREEN_SUBSYSTEM};
+
+/// Wayland output name of a backlight's DRM connector, e.g. `eDP-1` for
+/// `card0-eDP-1`. Backlights from ACPI or the firmware have no connector.
+fn connector(sysfs: &Sysfs, name: &str) -> Option<String> {
+    let link = fs::read_link(sysfs.class(SCREEN_SUBSYSTEM).join(name).join("device")).ok()?;
+    let (_, connector) = link
+        .file_name()?
+        .to_str()?
+        .strip_prefix("card")?
+        .split_once('-')?;
+    Some(connector.to_string())
+}
+
+/// Power mode of `output` in `<name> on|off` lines, if listed
+fn parse(listing: &str, output: &str) -> Option<bool> {
+    listing.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some(output) {
+            return None;
+        }
+        match fields.next() {
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            _ => None,
+        }
+    })
+}
+
+/// Tells whether the output showing a backlit screen is powered, so writes to
+/// it can wait until the compositor turns it back on
+pub(crate) struct OutputPower {
+    command: Vec<String>,
+    output: String,
+    on: bool,
+    /// Cleared once the command fails, e.g. outside of a wlroots compositor
+    available: bool,
+}
+
+impl OutputPower {
+    /// None when checks are disabled or the backlight's output is unknown
+    pub(crate) fn new(config: &PowerConfig, sysfs: &Sysfs, name: &str) -> Option<Self> {
+        if config.command.is_empty() {
+            return None;
+        }
+        let Some(output) = config.output.clone().or_else(|| connector(sysfs, name)) else {
+            debug!(
+                "No Wayland output known for {}, not checking whether it's powered",
+                name
+            );
+            return None;
+        };
+        Some(Self {
+            command: config.command.clone(),
+            output,
+            on: true,
+            available: true,
+        })
+    }
+
+    fn query(&self) -> Result<Option<bool>> {
+        let output = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::Sensor(format!(
+                "{} failed: {}",
+                self.command[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(parse(
+            &String::from_utf8_lossy(&output.stdout),
+            &self.output,
+        ))
+    }
+
+    /// Whether the output is on. Outputs the compositor doesn't list count as
+    /// on, and so do all of them once the command has failed.
+    pub(crate) fn is_on(&mut self) -> bool {
+        if !self.available {
+            return true;
+        }
+        let on = match self.query() {
+            Ok(on) => on.unwrap_or(true),
+            Err(e) => {
+                debug!("Not checking whether {} is powered: {}", self.output, e);
+                self.available = false;
+                true
+            }
+        };
+        if on != self.on {
+            info!(
+                "Output {} powered {}",
+                self.output,
+                if on { "on" } else { "off" }
+            );
+            self.on = on;
+        }
+        on
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn parses_power_modes() {
+        let listing = "eDP-1 on\nDP-2 off\nHDMI-A-1 unknown\n";
+        assert_eq!(parse(listing, "eDP-1"), Some(true));
+        assert_eq!(parse(listing, "DP-2"), Some(false));
+        assert_eq!(parse(listing, "HDMI-A-1"), None);
+        assert_eq!(parse(listing, "DP-1"), None);
+    }
+
+    #[test]
+    fn finds_drm_connector() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sysfs = Sysfs::new(dir.path());
+        let backlight = sysfs.class(SCREEN_SUBSYSTEM).join("intel_backlight");
+        fs::create_dir_all(&backlight).unwrap();
+        symlink("../../card0-eDP-1", backlight.join("device")).unwrap();
+
+        assert_eq!(
+            connector(&sysfs, "intel_backlight").as_deref(),
+            Some("eDP-1")
+        );
+        assert_eq!(connector(&sysfs, "acpi_video0"), None);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::{
+    ambient_brightness::settled_percent, config::Config, levels::ambient_value, sysfs::Sysfs,
+    Result, SCREEN_SUBSYSTEM,
+};
+
+/// Raw sensor readings sampled for the preview, roughly three per decade
+const SAMPLES: &[f64] = &[
+    1.0, 3.0, 10.0, 30.0, 100.0, 300.0, 1000.0, 3000.0, 10000.0, 30000.0, 100000.0, 300000.0,
+    1000000.0, 2500000.0,
+];
+
+pub fn print(config: &Config) -> Result<()> {
+    let sysfs = Sysfs::default();
+    let max_brightness = config
+        .screen
+        .backlight(&sysfs)
+        .and_then(|x| sysfs.read_max_brightness(SCREEN_SUBSYSTEM, &x))
+        .ok();
+
+    print!(
+        "{:>10} {:>8} {:>7} {:>7} {:>5}",
+        "raw", "ambient", "screen", "level", "kbd"
+    );
+    for led in &config.led {
+        print!(" {:>8}", led.name);
+    }
+    println!();
+
+    let screen_curve = config.screen.curve();
+    for raw in SAMPLES {
+        let ambient = ambient_value(settled_percent(*raw));
+        let screen = screen_curve.percent(ambient);
+        let level = match max_brightness {
+            Some(max) => ((screen * max) / 100).to_string(),
+            None => "-".to_string(),
+        };
+
+        print!(
+            "{:>10} {:>7}% {:>6}% {:>7} {:>4}%",
+            raw,
+            ambient,
+            screen,
+            level,
+            config.kbd.curve.percent(ambient)
+        );
+        for led in &config.led {
+            print!(" {:>7}%", led.curve.percent(ambient));
+        }
+        println!();
+    }
+
+    Ok(())
+}
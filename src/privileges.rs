@@ -0,0 +1,114 @@
+//! Switching from root to an unprivileged user once the devices are open, for
+//! setups that start the daemon as root only for sysfs write access. Files
+//! opened before the switch stay writable; outputs plugged in afterwards go
+//! through logind instead.
+
+use std::{ffi::CString, io};
+
+use log::info;
+
+use crate::{config::Config, Error, Result};
+
+/// A group's gid, by name or gid
+pub(crate) fn find_gid(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group)
+        .map_err(|_| Error::Config(format!("Invalid group name {:?}", group)))?;
+    // SAFETY: name is a valid C string, and the entry is only read before the
+    // next getgr* call
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(Error::NotFound(format!("group {}", group)));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// A user's uid and primary gid, by name or uid
+fn find_user(user: &str) -> Result<(u32, u32)> {
+    let entry = match user.parse::<u32>() {
+        // SAFETY: getpwuid has no preconditions
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = CString::new(user)
+                .map_err(|_| Error::Config(format!("Invalid user name {:?}", user)))?;
+            // SAFETY: name is a valid C string
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+    if entry.is_null() {
+        return Err(Error::NotFound(format!("user {}", user)));
+    }
+    // SAFETY: the entry is only read before the next getpw* call
+    Ok(unsafe { ((*entry).pw_uid, (*entry).pw_gid) })
+}
+
+fn check(ret: libc::c_int) -> Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Switches to the configured `user` and `group` when running as root, for
+/// every thread of the process. Without a `user`, or when not root, nothing
+/// changes.
+pub(crate) fn drop_privileges(config: &Config) -> Result<()> {
+    let Some(user) = &config.user else {
+        return Ok(());
+    };
+    // SAFETY: geteuid has no preconditions and always succeeds
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 {
+        info!("Not running as root, staying as uid {}", euid);
+        return Ok(());
+    }
+
+    let (uid, primary) = find_user(user)?;
+    let gid = match &config.group {
+        Some(group) => find_gid(group)?,
+        None => primary,
+    };
+    // The control socket was bound as root; handing it over lets it be
+    // removed on shutdown
+    #[cfg(feature = "control")]
+    {
+        let socket = crate::control_server::configured_socket_path(config);
+        if let Err(e) = std::os::unix::fs::chown(&socket, Some(uid), Some(gid)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+    }
+
+    // SAFETY: the group list is a single valid gid; glibc applies the set*id
+    // calls to all threads
+    check(unsafe { libc::setgroups(1, &gid) })?;
+    check(unsafe { libc::setgid(gid) })?;
+    check(unsafe { libc::setuid(uid) })?;
+    // With the saved uid gone as well, there's no way back to root
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(Error::Config(format!(
+            "Still able to become root after switching to {}",
+            user
+        )));
+    }
+    info!("Switched to uid {} gid {}", uid, gid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_users_and_groups() {
+        assert_eq!(find_user("0").unwrap().0, 0);
+        assert_eq!(find_user("root").unwrap(), (0, 0));
+        assert_eq!(find_gid("0").unwrap(), 0);
+        assert!(find_user("no-such-user-here").is_err());
+        assert!(find_gid("no-such-group-here").is_err());
+    }
+}
@@ -0,0 +1,606 @@
+//! Control socket wire format: one request per connection, an opcode byte
+//! followed by its argument. Commands get no reply.
+
+use std::time::Duration;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    command::{Appearance, Command, OutputKind},
+    health::{Level, Mode, Status},
+    Error, Result,
+};
+
+pub(crate) const IDLE: u8 = 0;
+pub(crate) const ACTIVE: u8 = 1;
+pub(crate) const INCREASE: u8 = 2;
+pub(crate) const DECREASE: u8 = 3;
+pub(crate) const SET: u8 = 5;
+/// Opcode asking for a [`Status`] reply instead of sending a command
+pub(crate) const PING: u8 = 4;
+/// Opcode asking for a [`Version`] reply
+pub(crate) const VERSION: u8 = 6;
+/// Opcode defining or changing a profile, see [`ProfileEdit`]
+pub(crate) const PROFILE: u8 = 7;
+/// Opcodes handing an output kind back to, or taking it from, automatic control
+pub(crate) const ENABLE: u8 = 8;
+pub(crate) const DISABLE: u8 = 9;
+/// Opcode holding the color scheme, or handing it back to the room
+pub(crate) const APPEARANCE: u8 = 10;
+pub(crate) const RESYNC: u8 = 11;
+/// Opcodes saving or restoring a snapshot, with its name after its length
+pub(crate) const SNAPSHOT: u8 = 12;
+pub(crate) const RESTORE: u8 = 13;
+/// Opcodes offsetting, or setting, only the outputs of one kind, with the kind
+/// before the amount
+pub(crate) const NUDGE: u8 = 14;
+pub(crate) const SET_OUTPUT: u8 = 15;
+
+/// Bumped whenever an opcode or reply changes, so clients can tell what the
+/// running daemon understands
+pub const PROTOCOL_VERSION: u8 = 10;
+
+/// Length of a ping reply before its list of outputs, see [`encode_status`]
+pub(crate) const STATUS_LEN: usize = 64;
+
+/// How long clients wait for a ping reply
+pub(crate) const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything a client can ask of the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Command(Command),
+    Ping,
+    Version,
+    Profile(ProfileEdit),
+}
+
+/// Changes to a profile in the daemon's config file, which it then reloads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileEdit {
+    pub name: String,
+    /// TOML keys merged into the profile, e.g. `screen = { curve = [[0, 5]] }`
+    pub overlay: String,
+    /// Switch to the profile too
+    pub activate: bool,
+}
+
+/// Refuses snapshot names that don't fit the wire format and recordings: 1 to
+/// 255 bytes, without spaces
+pub(crate) fn check_snapshot_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > u8::MAX as usize || name.contains(char::is_whitespace) {
+        return Err(Error::Protocol(format!(
+            "Snapshot names are 1 to 255 bytes without spaces, not {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl ProfileEdit {
+    /// Refuses names and overlays too long for their length fields, which
+    /// would otherwise be cut short and throw the framing off
+    fn check(&self) -> Result<()> {
+        if self.name.len() > u8::MAX as usize {
+            return Err(Error::Protocol(format!(
+                "Profile names are at most 255 bytes, not {}",
+                self.name.len()
+            )));
+        }
+        if self.overlay.len() > u16::MAX as usize {
+            return Err(Error::Protocol(format!(
+                "Profile overlays are at most 65535 bytes, not {}",
+                self.overlay.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// What the daemon answers to a [`Request`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Commands are handed to the daemon without an acknowledgement
+    Sent,
+    Status(Status),
+    Version(Version),
+    /// Whether a profile edit was saved, or why not
+    Profile(std::result::Result<(), String>),
+}
+
+/// What the running daemon is and supports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// Semver of the daemon's crate
+    pub daemon: String,
+    /// See [`PROTOCOL_VERSION`]
+    pub protocol: u8,
+    /// Cargo features it was built with, e.g. `kbd` and `screen`
+    pub features: Vec<String>,
+    /// Configured sensor backend, e.g. `iio` or `hwmon`
+    pub sensor: String,
+}
+
+impl Version {
+    /// This build's version, with the sensor backend from its config
+    pub(crate) fn current(sensor: &str) -> Self {
+        let features = [
+            ("kbd", cfg!(feature = "kbd")),
+            ("screen", cfg!(feature = "screen")),
+            ("hid", cfg!(feature = "hid")),
+            ("bulb", cfg!(feature = "bulb")),
+            ("content", cfg!(feature = "content")),
+            ("control", cfg!(feature = "control")),
+            ("dbus", cfg!(feature = "dbus")),
+            ("iio", cfg!(feature = "iio")),
+            ("sysfs", cfg!(feature = "sysfs")),
+            ("hwmon", cfg!(feature = "hwmon")),
+        ];
+        Self {
+            daemon: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            features: features
+                .into_iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            sensor: sensor.to_string(),
+        }
+    }
+}
+
+impl Request {
+    /// The request on the wire, refusing what its length fields can't frame
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let request = match self {
+            Self::Command(Command::Idle) => vec![IDLE],
+            Self::Command(Command::Active) => vec![ACTIVE],
+            Self::Command(Command::Increase(amount)) => vec![INCREASE, *amount as u8],
+            Self::Command(Command::Decrease(amount)) => vec![DECREASE, *amount as u8],
+            Self::Command(Command::Set(percent)) => vec![SET, *percent],
+            Self::Command(Command::Nudge(kind, amount)) => {
+                vec![NUDGE, encode_kind(*kind), *amount as u8]
+            }
+            Self::Command(Command::SetOutput(kind, percent)) => {
+                vec![SET_OUTPUT, encode_kind(*kind), *percent]
+            }
+            Self::Command(Command::Enable(kind)) => vec![ENABLE, encode_kind(*kind)],
+            Self::Command(Command::Disable(kind)) => vec![DISABLE, encode_kind(*kind)],
+            Self::Command(Command::Appearance(appearance)) => {
+                vec![APPEARANCE, encode_appearance(*appearance)]
+            }
+            Self::Command(Command::Resync) => vec![RESYNC],
+            Self::Command(Command::Snapshot(name)) => {
+                check_snapshot_name(name)?;
+                encode_name(SNAPSHOT, name)
+            }
+            Self::Command(Command::Restore(name)) => {
+                check_snapshot_name(name)?;
+                encode_name(RESTORE, name)
+            }
+            Self::Ping => vec![PING],
+            Self::Version => vec![VERSION],
+            Self::Profile(edit) => {
+                edit.check()?;
+                let mut request = vec![PROFILE, edit.activate as u8];
+                request.push(edit.name.len() as u8);
+                request.extend_from_slice(edit.name.as_bytes());
+                request
+                    .write_u16::<BigEndian>(edit.overlay.len() as u16)
+                    .expect("Vec write");
+                request.extend_from_slice(edit.overlay.as_bytes());
+                request
+            }
+        };
+        Ok(request)
+    }
+}
+
+/// An opcode followed by a name of up to 255 bytes
+fn encode_name(opcode: u8, name: &str) -> Vec<u8> {
+    let mut request = vec![opcode, name.len() as u8];
+    request.extend_from_slice(name.as_bytes());
+    request
+}
+
+/// Output kinds as 0 kbd, 1 screen, 2 led, 3 hid, or 4 bulb
+fn encode_kind(kind: OutputKind) -> u8 {
+    match kind {
+        OutputKind::Kbd => 0,
+        OutputKind::Screen => 1,
+        OutputKind::Led => 2,
+        OutputKind::Hid => 3,
+        OutputKind::Bulb => 4,
+    }
+}
+
+pub(crate) fn decode_kind(kind: u8) -> Result<OutputKind> {
+    OutputKind::ALL
+        .into_iter()
+        .find(|x| encode_kind(*x) == kind)
+        .ok_or_else(|| Error::Protocol(format!("Unknown output kind: {}", kind)))
+}
+
+/// Appearances as 0 auto, 1 dark, or 2 light
+fn encode_appearance(appearance: Appearance) -> u8 {
+    match appearance {
+        Appearance::Auto => 0,
+        Appearance::Dark => 1,
+        Appearance::Light => 2,
+    }
+}
+
+pub(crate) fn decode_appearance(appearance: u8) -> Result<Appearance> {
+    Appearance::ALL
+        .into_iter()
+        .find(|x| encode_appearance(*x) == appearance)
+        .ok_or_else(|| Error::Protocol(format!("Unknown appearance: {}", appearance)))
+}
+
+/// Milliseconds in a ping reply, saturating at `u64::MAX`, which also means never
+fn millis(duration: Option<Duration>) -> u64 {
+    duration.map_or(u64::MAX, |x| x.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// Ping reply: uptime, time since the last read, and time since the last write
+/// in milliseconds, then the sensor and write error counts, all big endian, and
+/// 1 if the sensor is stuck. Then the ambient percent and sensor reading, with
+/// `u32::MAX` for none, the mode as 0 active, 1 idle, or 2 suspended, and the
+/// number of outputs whose last update failed. Then the milliseconds in the
+/// mode, the adjustment and output error counts, and the big endian length of
+/// the lists of outputs that follow. The first list starts with its number of
+/// outputs, each a length prefixed name and the milliseconds since its last
+/// adjustment; the rest of the reply lists levels, each a length prefixed
+/// name, the percent, and the offset as a signed byte.
+pub(crate) fn encode_status(status: &Status) -> Vec<u8> {
+    let mut reply = Vec::with_capacity(STATUS_LEN);
+    for value in [
+        millis(Some(status.uptime)),
+        millis(status.last_read),
+        millis(status.last_write),
+    ] {
+        reply.write_u64::<BigEndian>(value).expect("Vec write");
+    }
+    for value in [status.sensor_errors, status.write_errors] {
+        reply.write_u32::<BigEndian>(value).expect("Vec write");
+    }
+    reply.push(status.sensor_stuck as u8);
+    for value in [status.ambient, status.lux] {
+        reply
+            .write_u32::<BigEndian>(value.unwrap_or(u32::MAX))
+            .expect("Vec write");
+    }
+    reply.push(match status.mode {
+        Mode::Active => 0,
+        Mode::Idle => 1,
+        Mode::Suspended => 2,
+    });
+    reply
+        .write_u32::<BigEndian>(status.failing_outputs)
+        .expect("Vec write");
+    reply
+        .write_u64::<BigEndian>(millis(Some(status.mode_for)))
+        .expect("Vec write");
+    for value in [status.adjustments, status.output_errors] {
+        reply.write_u32::<BigEndian>(value).expect("Vec write");
+    }
+
+    // The number of adjusted outputs comes first, filled in once known
+    let mut outputs = vec![0, 0];
+    let mut adjusted = 0u16;
+    // The lists have to fit their length prefix
+    let fits = |outputs: &[u8], name: &[u8]| outputs.len() + name.len() + 10 <= u16::MAX as usize;
+    for (name, since) in &status.last_adjusted {
+        let name = name.as_bytes();
+        if !fits(&outputs, name) {
+            break;
+        }
+        outputs
+            .write_u16::<BigEndian>(name.len() as u16)
+            .expect("Vec write");
+        outputs.extend_from_slice(name);
+        outputs
+            .write_u64::<BigEndian>(millis(Some(*since)))
+            .expect("Vec write");
+        adjusted += 1;
+    }
+    outputs[..2].copy_from_slice(&adjusted.to_be_bytes());
+    for level in &status.levels {
+        let name = level.name.as_bytes();
+        if !fits(&outputs, name) {
+            break;
+        }
+        outputs
+            .write_u16::<BigEndian>(name.len() as u16)
+            .expect("Vec write");
+        outputs.extend_from_slice(name);
+        outputs
+            .write_u32::<BigEndian>(level.percent)
+            .expect("Vec write");
+        outputs.push(level.offset as u8);
+    }
+    reply
+        .write_u16::<BigEndian>(outputs.len() as u16)
+        .expect("Vec write");
+    reply.extend_from_slice(&outputs);
+    reply
+}
+
+/// Length of the list of outputs following a ping reply's fixed part
+pub(crate) fn status_outputs_len(reply: &[u8; STATUS_LEN]) -> usize {
+    u16::from_be_bytes([reply[STATUS_LEN - 2], reply[STATUS_LEN - 1]]) as usize
+}
+
+/// Decodes a ping reply's fixed part and the list of outputs after it
+pub(crate) fn decode_status(reply: &[u8; STATUS_LEN], mut outputs: &[u8]) -> Result<Status> {
+    let mut reply = &reply[..];
+    let mut millis = || match reply.read_u64::<BigEndian>().expect("Short reply") {
+        u64::MAX => None,
+        x => Some(Duration::from_millis(x)),
+    };
+    let uptime = millis().unwrap_or(Duration::MAX);
+    let last_read = millis();
+    let last_write = millis();
+
+    let mut read_u32 = || reply.read_u32::<BigEndian>().expect("Short reply");
+    let sensor_errors = read_u32();
+    let write_errors = read_u32();
+    let sensor_stuck = reply.read_u8().expect("Short reply") != 0;
+    let mut optional = || match reply.read_u32::<BigEndian>().expect("Short reply") {
+        u32::MAX => None,
+        x => Some(x),
+    };
+    let ambient = optional();
+    let lux = optional();
+    let mode = match reply.read_u8().expect("Short reply") {
+        1 => Mode::Idle,
+        2 => Mode::Suspended,
+        _ => Mode::Active,
+    };
+    let failing_outputs = reply.read_u32::<BigEndian>().expect("Short reply");
+    let mode_for = Duration::from_millis(reply.read_u64::<BigEndian>().expect("Short reply"));
+    let mut read_u32 = || reply.read_u32::<BigEndian>().expect("Short reply");
+    let adjustments = read_u32();
+    let output_errors = read_u32();
+
+    let short = |_| Error::Protocol("Short ping reply".to_string());
+    let name = |outputs: &mut &[u8]| {
+        let len = outputs.read_u16::<BigEndian>().map_err(short)? as usize;
+        if outputs.len() < len {
+            return Err(Error::Protocol("Short ping reply".to_string()));
+        }
+        let (name, rest) = outputs.split_at(len);
+        *outputs = rest;
+        Ok(String::from_utf8_lossy(name).into_owned())
+    };
+    let adjusted = outputs.read_u16::<BigEndian>().map_err(short)?;
+    let mut last_adjusted = Vec::new();
+    for _ in 0..adjusted {
+        let name = name(&mut outputs)?;
+        let since = outputs.read_u64::<BigEndian>().map_err(short)?;
+        last_adjusted.push((name, Duration::from_millis(since)));
+    }
+    let mut levels = Vec::new();
+    while !outputs.is_empty() {
+        let name = name(&mut outputs)?;
+        let percent = outputs.read_u32::<BigEndian>().map_err(short)?;
+        let offset = outputs.read_i8().map_err(short)?;
+        levels.push(Level {
+            name,
+            percent,
+            offset,
+        });
+    }
+
+    Ok(Status {
+        uptime,
+        last_read,
+        last_write,
+        sensor_errors,
+        write_errors,
+        sensor_stuck,
+        failing_outputs,
+        ambient,
+        lux,
+        mode,
+        mode_for,
+        adjustments,
+        output_errors,
+        last_adjusted,
+        levels,
+    })
+}
+
+/// Version reply: its length as a big endian u16, then the protocol version,
+/// and the daemon version, space separated features, and sensor backend, each
+/// a big endian u16 length followed by UTF-8
+pub(crate) fn encode_version(version: &Version) -> Vec<u8> {
+    let mut body = vec![version.protocol];
+    for field in [
+        version.daemon.as_str(),
+        &version.features.join(" "),
+        version.sensor.as_str(),
+    ] {
+        body.write_u16::<BigEndian>(field.len() as u16)
+            .expect("Vec write");
+        body.extend_from_slice(field.as_bytes());
+    }
+
+    let mut reply = Vec::with_capacity(2 + body.len());
+    reply
+        .write_u16::<BigEndian>(body.len() as u16)
+        .expect("Vec write");
+    reply.extend_from_slice(&body);
+    reply
+}
+
+/// Decodes a version reply after its length prefix
+pub(crate) fn decode_version(mut body: &[u8]) -> Result<Version> {
+    let short = |_| Error::Protocol("Short version reply".to_string());
+    let protocol = body.read_u8().map_err(short)?;
+    let mut field = || -> Result<String> {
+        let len = body.read_u16::<BigEndian>().map_err(short)? as usize;
+        if body.len() < len {
+            return Err(Error::Protocol("Short version reply".to_string()));
+        }
+        let (field, rest) = body.split_at(len);
+        body = rest;
+        String::from_utf8(field.to_vec())
+            .map_err(|e| Error::Protocol(format!("Version reply isn't UTF-8: {}", e)))
+    };
+    let daemon = field()?;
+    let features = field()?.split_whitespace().map(str::to_string).collect();
+    let sensor = field()?;
+
+    Ok(Version {
+        daemon,
+        protocol,
+        features,
+        sensor,
+    })
+}
+
+/// Profile reply: 0 when saved, 1 otherwise, then the error as a big endian
+/// u16 length followed by UTF-8, empty when saved
+pub(crate) fn encode_profile_reply(result: &Result<()>) -> Vec<u8> {
+    let message = match result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    let mut reply = vec![result.is_err() as u8];
+    reply
+        .write_u16::<BigEndian>(message.len() as u16)
+        .expect("Vec write");
+    reply.extend_from_slice(message.as_bytes());
+    reply
+}
+
+pub(crate) fn decode_profile_reply(
+    status: u8,
+    message: Vec<u8>,
+) -> std::result::Result<(), String> {
+    match status {
+        0 => Ok(()),
+        _ => Err(String::from_utf8_lossy(&message).into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_round_trips() {
+        let status = Status {
+            uptime: Duration::from_millis(1500),
+            last_read: Some(Duration::from_millis(20)),
+            last_write: None,
+            sensor_errors: 3,
+            write_errors: 1,
+            sensor_stuck: true,
+            failing_outputs: 2,
+            ambient: Some(42),
+            lux: None,
+            mode: Mode::Suspended,
+            mode_for: Duration::from_millis(900),
+            adjustments: 7,
+            output_errors: 4,
+            last_adjusted: vec![
+                ("asus::kbd_backlight".to_string(), Duration::from_millis(10)),
+                ("intel_backlight".to_string(), Duration::from_millis(300)),
+            ],
+            levels: vec![Level {
+                name: "intel_backlight".to_string(),
+                percent: 35,
+                offset: -10,
+            }],
+        };
+        let reply = encode_status(&status);
+        let (fixed, outputs) = reply.split_at(STATUS_LEN);
+        let fixed: &[u8; STATUS_LEN] = fixed.try_into().unwrap();
+        assert_eq!(status_outputs_len(fixed), outputs.len());
+        assert_eq!(decode_status(fixed, outputs).unwrap(), status);
+        assert!(decode_status(fixed, &outputs[..5]).is_err());
+    }
+
+    #[test]
+    fn version_round_trips() {
+        let version = Version::current("hwmon");
+        let reply = encode_version(&version);
+        assert_eq!(
+            u16::from_be_bytes([reply[0], reply[1]]) as usize,
+            reply.len() - 2
+        );
+        assert_eq!(decode_version(&reply[2..]).unwrap(), version);
+        assert!(decode_version(&reply[2..reply.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn encodes_requests() {
+        assert_eq!(
+            Request::Command(Command::Decrease(-3)).encode().unwrap(),
+            [3, 0xfd]
+        );
+        assert_eq!(
+            Request::Command(Command::Set(40)).encode().unwrap(),
+            [5, 40]
+        );
+        assert_eq!(
+            Request::Command(Command::Disable(OutputKind::Led))
+                .encode()
+                .unwrap(),
+            [9, 2]
+        );
+        assert_eq!(
+            Request::Command(Command::Nudge(OutputKind::Kbd, -33))
+                .encode()
+                .unwrap(),
+            [14, 0, 0xdf]
+        );
+        for kind in OutputKind::ALL {
+            assert_eq!(decode_kind(encode_kind(kind)).unwrap(), kind);
+        }
+        assert!(decode_kind(5).is_err());
+        assert_eq!(
+            Request::Command(Command::Appearance(Appearance::Light))
+                .encode()
+                .unwrap(),
+            [10, 2]
+        );
+        for appearance in Appearance::ALL {
+            assert_eq!(
+                decode_appearance(encode_appearance(appearance)).unwrap(),
+                appearance
+            );
+        }
+        assert_eq!(
+            Request::Profile(ProfileEdit {
+                name: "night".to_string(),
+                overlay: "a = 1".to_string(),
+                activate: true,
+            })
+            .encode()
+            .unwrap(),
+            b"\x07\x01\x05night\x00\x05a = 1"
+        );
+    }
+
+    #[test]
+    fn refuses_requests_too_long_to_frame() {
+        let edit = |name: usize, overlay: usize| ProfileEdit {
+            name: "n".repeat(name),
+            overlay: "#".repeat(overlay),
+            activate: false,
+        };
+        let encode = |edit| Request::Profile(edit).encode();
+        assert!(encode(edit(255, 65535)).is_ok());
+        assert!(encode(edit(256, 0)).is_err());
+        assert!(encode(edit(1, 65536)).is_err());
+
+        let snapshot = |name: &str| Request::Command(Command::Snapshot(name.to_string())).encode();
+        assert!(snapshot(&"n".repeat(255)).is_ok());
+        assert!(snapshot(&"n".repeat(256)).is_err());
+        assert!(snapshot("").is_err());
+        assert!(snapshot("before work").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+
+use log::info;
+use toml::Table;
+
+use crate::{Error, Result};
+
+const DMI_PRODUCT_NAME: &str = "/sys/class/dmi/id/product_name";
+
+/// Built-in settings for laptops that don't work with the defaults, by DMI
+/// product name prefix. The first match applies, so more specific prefixes
+/// come first.
+const QUIRKS: &[(&str, &str)] = &[
+    (
+        "MacBookPro",
+        r#"
+[sensor]
+type = "applesmc"
+
+[screen]
+name = "gmux_backlight"
+
+[kbd]
+name = "smc::kbd_backlight"
+curve = [[0, 100], [40, 50], [55, 20], [70, 0]]
+"#,
+    ),
+    (
+        "MacBookAir",
+        r#"
+[sensor]
+type = "applesmc"
+
+[screen]
+name = "intel_backlight"
+
+[kbd]
+name = "smc::kbd_backlight"
+curve = [[0, 100], [40, 50], [55, 20], [70, 0]]
+"#,
+    ),
+    (
+        "Surface",
+        r#"
+[sensor]
+type = "sysfs"
+device = "als"
+"#,
+    ),
+    (
+        "Laptop 13 (AMD",
+        r#"
+[sensor]
+type = "sysfs"
+device = "als"
+
+[screen]
+name = "amdgpu_bl1"
+
+[kbd]
+name = "chromeos::kbd_backlight"
+"#,
+    ),
+    (
+        "XPS 13",
+        r#"
+[sensor]
+type = "sysfs"
+device = "als"
+
+[kbd]
+name = "dell::kbd_backlight"
+curve = [[0, 100], [50, 50], [70, 0]]
+"#,
+    ),
+];
+
+/// Settings for this machine, empty when it isn't a known one
+pub(crate) fn detected() -> Result<Table> {
+    match fs::read_to_string(DMI_PRODUCT_NAME) {
+        Ok(product) => table(product.trim()),
+        Err(_) => Ok(Table::new()),
+    }
+}
+
+fn table(product: &str) -> Result<Table> {
+    match QUIRKS
+        .iter()
+        .find(|(prefix, _)| product.starts_with(prefix))
+    {
+        Some((prefix, quirk)) => {
+            info!("Using built-in settings for {}", product);
+            toml::from_str(quirk).map_err(|e| {
+                Error::Config(format!(
+                    "Couldn't parse built-in settings for {}: {}",
+                    prefix, e
+                ))
+            })
+        }
+        None => Ok(Table::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml::Value;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn every_quirk_is_a_valid_config() {
+        for (prefix, _) in QUIRKS {
+            let quirk = table(prefix).unwrap();
+            assert!(!quirk.is_empty(), "{}", prefix);
+            Value::Table(quirk)
+                .try_into::<Config>()
+                .unwrap_or_else(|e| panic!("{}: {}", prefix, e));
+        }
+    }
+
+    #[test]
+    fn matches_product_name_prefix() {
+        let macbook = table("MacBookPro11,1").unwrap();
+        assert_eq!(macbook["sensor"]["type"].as_str(), Some("applesmc"));
+        assert!(table("ThinkPad X1 Carbon").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,305 @@
+//! Session recordings: every sensor reading, tick, command, and write with its
+//! time since the start, one event per line, e.g.
+//!
+//! ```text
+//! 0 device leds/asus::kbd_backlight 3 1
+//! 0 sensor 12.5
+//! 5000 tick
+//! 5000 sensor 14
+//! 5000 write leds/asus::kbd_backlight 3
+//! 7200 command increase 10
+//! ```
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt, fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{Clock, MockClock},
+    command::Command,
+    config::Config,
+    controller::Builder,
+    sensor::Sensor,
+    Error, Result,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// State of an output device when the session started
+    Device {
+        subsystem: String,
+        name: String,
+        max_brightness: u32,
+        brightness: u32,
+    },
+    Sensor(f64),
+    Tick,
+    Command(Command),
+    /// The session was locked or unlocked
+    Lock(bool),
+    /// Screen capture started or ended
+    Capture(bool),
+    Write {
+        subsystem: String,
+        name: String,
+        level: u32,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Device {
+                subsystem,
+                name,
+                max_brightness,
+                brightness,
+            } => write!(
+                f,
+                "device {}/{} {} {}",
+                subsystem, name, max_brightness, brightness
+            ),
+            Self::Sensor(value) => write!(f, "sensor {}", value),
+            Self::Tick => write!(f, "tick"),
+            Self::Command(command) => write!(f, "command {}", command),
+            Self::Lock(true) => write!(f, "lock"),
+            Self::Lock(false) => write!(f, "unlock"),
+            Self::Capture(true) => write!(f, "capture start"),
+            Self::Capture(false) => write!(f, "capture end"),
+            Self::Write {
+                subsystem,
+                name,
+                level,
+            } => write!(f, "write {}/{} {}", subsystem, name, level),
+        }
+    }
+}
+
+fn field<T: FromStr>(field: Option<&str>) -> Option<T> {
+    field?.parse().ok()
+}
+
+fn device(field: Option<&str>) -> Option<(String, String)> {
+    let (subsystem, name) = field?.split_once('/')?;
+    Some((subsystem.to_string(), name.to_string()))
+}
+
+impl FromStr for Event {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut fields = line.split_whitespace();
+        let event = match fields.next() {
+            Some("device") => device(fields.next()).and_then(|(subsystem, name)| {
+                Some(Self::Device {
+                    subsystem,
+                    name,
+                    max_brightness: field(fields.next())?,
+                    brightness: field(fields.next())?,
+                })
+            }),
+            Some("sensor") => field(fields.next()).map(Self::Sensor),
+            Some("tick") => Some(Self::Tick),
+            Some("lock") => Some(Self::Lock(true)),
+            Some("unlock") => Some(Self::Lock(false)),
+            Some("capture") => match fields.next() {
+                Some("start") => Some(Self::Capture(true)),
+                Some("end") => Some(Self::Capture(false)),
+                _ => None,
+            },
+            Some("command") => match fields.next() {
+                Some("idle") => Some(Command::Idle),
+                Some("active") => Some(Command::Active),
+                Some("increase") => field(fields.next()).map(Command::Increase),
+                Some("decrease") => field(fields.next()).map(Command::Decrease),
+                Some("set") => field(fields.next()).map(Command::Set),
+                Some("nudge") => field(fields.next())
+                    .zip(field(fields.next()))
+                    .map(|(kind, amount)| Command::Nudge(kind, amount)),
+                Some("set-output") => field(fields.next())
+                    .zip(field(fields.next()))
+                    .map(|(kind, percent)| Command::SetOutput(kind, percent)),
+                Some("enable") => field(fields.next()).map(Command::Enable),
+                Some("disable") => field(fields.next()).map(Command::Disable),
+                Some("appearance") => field(fields.next()).map(Command::Appearance),
+                Some("resync") => Some(Command::Resync),
+                Some("snapshot") => fields.next().map(|x| Command::Snapshot(x.to_string())),
+                Some("restore") => fields.next().map(|x| Command::Restore(x.to_string())),
+                _ => None,
+            }
+            .map(Self::Command),
+            Some("write") => device(fields.next()).and_then(|(subsystem, name)| {
+                Some(Self::Write {
+                    subsystem,
+                    name,
+                    level: field(fields.next())?,
+                })
+            }),
+            _ => None,
+        };
+
+        match (event, fields.next()) {
+            (Some(event), None) => Ok(event),
+            _ => Err(Error::Record(format!("Invalid event: {:?}", line))),
+        }
+    }
+}
+
+/// Writes timestamped events as they happen
+pub struct Recorder {
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    pub fn new(out: Box<dyn Write + Send>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn create(path: &Path, clock: Arc<dyn Clock>) -> Result<Self> {
+        let file = fs::File::create(path)
+            .map_err(|e| Error::Record(format!("Couldn't create {}: {}", path.display(), e)))?;
+        Ok(Self::new(Box::new(file), clock))
+    }
+
+    pub fn record(&self, event: &Event) -> Result<()> {
+        let elapsed = self.clock.now().duration_since(self.start);
+        let mut out = self.out.lock().expect("Recorder poisoned");
+        writeln!(out, "{} {}", elapsed.as_millis(), event)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Records every reading of the wrapped sensor
+pub(crate) struct RecordingSensor {
+    sensor: Box<dyn Sensor>,
+    recorder: Arc<Recorder>,
+}
+
+impl RecordingSensor {
+    pub(crate) fn new(sensor: Box<dyn Sensor>, recorder: Arc<Recorder>) -> Self {
+        Self { sensor, recorder }
+    }
+}
+
+impl Sensor for RecordingSensor {
+    fn read(&self) -> Result<f64> {
+        let value = self.sensor.read()?;
+        self.recorder.record(&Event::Sensor(value))?;
+        Ok(value)
+    }
+}
+
+/// Returns the recorded readings in order
+struct ReplaySensor {
+    values: RefCell<VecDeque<f64>>,
+}
+
+impl Sensor for ReplaySensor {
+    fn read(&self) -> Result<f64> {
+        self.values.borrow_mut().pop_front().ok_or_else(|| {
+            Error::Record(
+                "Ran out of recorded sensor readings; recordings made in privacy mode \
+                 can't be replayed"
+                    .to_string(),
+            )
+        })
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<(Duration, Event)>> {
+    let file = fs::File::open(path)
+        .map_err(|e| Error::Record(format!("Couldn't open {}: {}", path.display(), e)))?;
+
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|line| {
+            let line = line?;
+            let (millis, event) = line
+                .trim()
+                .split_once(' ')
+                .ok_or_else(|| Error::Record(format!("Invalid event: {:?}", line)))?;
+            let millis = millis
+                .parse()
+                .map_err(|_| Error::Record(format!("Invalid timestamp: {:?}", line)))?;
+            Ok((Duration::from_millis(millis), event.parse()?))
+        })
+        .collect()
+}
+
+/// Temporary stand-in for `/sys`, removed on drop
+struct ReplayRoot(PathBuf);
+
+impl ReplayRoot {
+    fn create(events: &[(Duration, Event)]) -> Result<Self> {
+        let root =
+            Self(std::env::temp_dir().join(format!("ambient_brightness_replay_{}", process::id())));
+
+        for (_, event) in events {
+            if let Event::Device {
+                subsystem,
+                name,
+                max_brightness,
+                brightness,
+            } = event
+            {
+                let dir = root.0.join("class").join(subsystem).join(name);
+                fs::create_dir_all(&dir)?;
+                fs::write(dir.join("max_brightness"), max_brightness.to_string())?;
+                fs::write(dir.join("brightness"), brightness.to_string())?;
+            }
+        }
+
+        Ok(root)
+    }
+}
+
+impl Drop for ReplayRoot {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Feeds a recording back through the pipeline against the recorded devices,
+/// writing the events it produces to `out` in the recording format. HID outputs
+/// are skipped since they aren't recorded.
+pub fn replay(config: &Config, path: &Path, out: Box<dyn Write + Send>) -> Result<()> {
+    let events = load(path)?;
+    let root = ReplayRoot::create(&events)?;
+    let clock = Arc::new(MockClock::new());
+    let sensor = ReplaySensor {
+        values: RefCell::new(
+            events
+                .iter()
+                .filter_map(|(_, event)| match event {
+                    Event::Sensor(value) => Some(*value),
+                    _ => None,
+                })
+                .collect(),
+        ),
+    };
+    let recorder = Recorder::new(out, clock.clone());
+
+    Builder::new(config)
+        .sysfs_root(&root.0)
+        .sensor(Box::new(sensor))
+        .clock(clock.clone())
+        .recorder(Arc::new(recorder))
+        .without_hid()
+        .replay(clock, events)
+}
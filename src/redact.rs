@@ -0,0 +1,34 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set from the config when the controller starts; logging is process wide,
+/// so the switch is too
+static PRIVATE: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_private(private: bool) {
+    PRIVATE.store(private, Ordering::Relaxed);
+}
+
+/// Formats a light reading, or a placeholder in privacy mode, since ambient
+/// light history shows when someone is around
+pub(crate) struct Lux<T>(pub(crate) T);
+
+impl<T: fmt::Display> fmt::Display for Lux<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if PRIVATE.load(Ordering::Relaxed) {
+            return write!(f, "<redacted>");
+        }
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Lux<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if PRIVATE.load(Ordering::Relaxed) {
+            return write!(f, "<redacted>");
+        }
+        self.0.fmt(f)
+    }
+}
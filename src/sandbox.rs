@@ -0,0 +1,441 @@
+//! Opt-in hardening applied once the daemon is set up: Landlock keeps file
+//! access to the paths it works with, and a seccomp filter refuses syscalls it
+//! never makes. Both are inherited by the commands it runs, and Landlock only
+//! covers the threads started after it.
+
+use std::{
+    env,
+    ffi::CString,
+    fs, io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+use crate::{config::Config, snapshot, Error, Result};
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+const REMOVE_FILE: u64 = 1 << 5;
+const MAKE_REG: u64 = 1 << 8;
+const MAKE_SOCK: u64 = 1 << 9;
+/// Every right of the first Landlock ABI, which is all the daemon needs
+const HANDLED: u64 = (1 << 13) - 1;
+/// Rights that apply to a file rather than a directory's entries
+const FILE_RIGHTS: u64 = EXECUTE | WRITE_FILE | READ_FILE;
+
+const READ: u64 = READ_FILE | READ_DIR;
+/// Files replaced next to themselves, and sockets bound and removed
+const EDIT: u64 = READ | WRITE_FILE | REMOVE_FILE | MAKE_REG | MAKE_SOCK;
+/// A file's directory when the file is written under a new name and renamed
+/// over the old one
+const REPLACE: u64 = READ_FILE | WRITE_FILE | REMOVE_FILE | MAKE_REG;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscalls for tracing, kernel modules, mounts, namespaces, and the clock,
+/// none of which the daemon or the commands it runs have any use for
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_reboot,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_personality,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+];
+
+/// Applies the sandbox when `sandbox = true`. Where the kernel lacks Landlock
+/// or seccomp, that half is skipped with a warning.
+pub fn apply(config: &Config, config_path: Option<&Path>) -> Result<()> {
+    if !config.sandbox {
+        return Ok(());
+    }
+    no_new_privs()?;
+    // Snapshots can't make the state directory once file access is restricted
+    if let Some(dir) = snapshot::state_path(config).parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            warn!("Couldn't create {} for snapshots: {}", dir.display(), e);
+        }
+    }
+    match landlock(&rules(config, config_path)) {
+        Ok(()) => info!("Restricted file access with Landlock"),
+        Err(Error::Io(e)) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP)) => {
+            warn!(
+                "Not restricting file access, Landlock isn't available: {}",
+                e
+            )
+        }
+        Err(e) => return Err(e),
+    }
+    match AUDIT_ARCH {
+        Some(arch) => {
+            seccomp(&filter(arch), libc::SECCOMP_FILTER_FLAG_TSYNC)?;
+            info!("Restricted syscalls with seccomp");
+        }
+        None => warn!("Not restricting syscalls, seccomp isn't supported on this architecture"),
+    }
+    Ok(())
+}
+
+/// Lets the process restrict itself without privileges, and keeps what it
+/// runs from gaining any through setuid binaries
+fn no_new_privs() -> Result<()> {
+    // SAFETY: prctl with PR_SET_NO_NEW_PRIVS only reads its integer arguments
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Paths the daemon reads, writes, or runs commands from, with their rights.
+/// The control socket and audit log are already open, so they need none.
+fn rules(config: &Config, config_path: Option<&Path>) -> Vec<(PathBuf, u64)> {
+    let mut rules = vec![
+        // Sensors and the rest of sysfs are only read
+        (PathBuf::from("/sys"), READ),
+        (PathBuf::from("/dev/null"), READ_FILE | WRITE_FILE),
+        (PathBuf::from("/proc"), READ),
+        // Name resolution for bulbs, time zones, and libraries loaded late
+        (PathBuf::from("/etc"), READ),
+        (PathBuf::from("/usr"), READ | EXECUTE),
+        (PathBuf::from("/lib"), READ | EXECUTE),
+        (PathBuf::from("/lib64"), READ | EXECUTE),
+        (PathBuf::from("/nix/store"), READ | EXECUTE),
+    ];
+    rules.extend(outputs());
+    rules.extend(nodes(config));
+    // Commands such as the power check and brightnessctl
+    if let Some(path) = env::var_os("PATH") {
+        rules.extend(env::split_paths(&path).map(|x| (x, READ | EXECUTE)));
+    }
+    // Sockets the daemon and its commands use, e.g. for dconf
+    if let Some(runtime) = env::var_os("XDG_RUNTIME_DIR") {
+        rules.push((PathBuf::from(runtime), EDIT));
+    }
+    // Profile edits and snapshots replace their file next to itself
+    let replaced = [
+        config_path.map(Path::to_path_buf),
+        Some(snapshot::state_path(config)),
+    ];
+    rules.extend(
+        replaced
+            .into_iter()
+            .flatten()
+            .filter_map(|x| Some((x.parent()?.to_path_buf(), REPLACE))),
+    );
+    // The helper removes its socket when it stops
+    if let Some(dir) = config.helper.socket.parent() {
+        rules.push((dir.to_path_buf(), REMOVE_FILE));
+    }
+    rules
+}
+
+/// Backlights and LEDs found at startup, whose brightness is written. Their
+/// entries link to the device directories the rules land on.
+fn outputs() -> Vec<(PathBuf, u64)> {
+    ["/sys/class/backlight", "/sys/class/leds"]
+        .into_iter()
+        .filter_map(|class| fs::read_dir(class).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| (entry.path(), READ | WRITE_FILE))
+        .collect()
+}
+
+/// Device nodes the configured outputs and switches use
+fn nodes(config: &Config) -> Vec<(PathBuf, u64)> {
+    let mut nodes = Vec::new();
+    #[cfg(feature = "hid")]
+    nodes.extend(
+        config
+            .hid
+            .iter()
+            .filter_map(|x| crate::hid_brightness::find_hidraw(x.vendor_id, x.product_id).ok())
+            .map(|x| (x, WRITE_FILE)),
+    );
+    // Keyboards plugged in later show up here too
+    if config.kbd.external.is_some() || config.tablet.is_some() {
+        nodes.push((PathBuf::from("/dev/input"), READ));
+    }
+    if let Some(switch) = config.tablet.as_ref().and_then(|x| x.switch.clone()) {
+        nodes.push((switch, READ_FILE));
+    }
+    nodes
+}
+
+fn landlock(rules: &[(PathBuf, u64)]) -> Result<()> {
+    // SAFETY: a null attribute with the version flag only queries the ABI
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: HANDLED,
+    };
+    // SAFETY: attr lives across the call and its size is passed with it
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: the fd was just returned to us and nothing else owns it
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+
+    for (path, access) in rules {
+        // Paths that don't exist on this machine are left out
+        let Ok(metadata) = fs::metadata(path) else {
+            continue;
+        };
+        let access = if metadata.is_dir() {
+            *access
+        } else {
+            access & FILE_RIGHTS
+        };
+        let name = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::Config(format!("Invalid path {}", path.display())))?;
+        // SAFETY: name is a valid C string; the fd is owned right away
+        let parent = unsafe { libc::open(name.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if parent < 0 {
+            continue;
+        }
+        let parent = unsafe { OwnedFd::from_raw_fd(parent) };
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: parent.as_raw_fd(),
+        };
+        // SAFETY: both fds are open and rule lives across the call
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    // SAFETY: the ruleset fd is open; no_new_privs is already set
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// BPF program failing each denied syscall with EPERM, and killing the process
+/// for syscalls made through another architecture's table
+fn filter(arch: u32) -> Vec<libc::sock_filter> {
+    // Offsets of nr and arch in seccomp_data
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    let mut program = vec![
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+        statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR),
+    ];
+    // The x32 ABI shares the x86_64 architecture, with this bit set in nr
+    #[cfg(target_arch = "x86_64")]
+    program.extend([
+        jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            0x4000_0000,
+            0,
+            1,
+        ),
+        statement(libc::BPF_RET | libc::BPF_K, deny),
+    ]);
+    for nr in DENIED {
+        program.extend([
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *nr as u32,
+                0,
+                1,
+            ),
+            statement(libc::BPF_RET | libc::BPF_K, deny),
+        ]);
+    }
+    program.push(statement(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ALLOW,
+    ));
+    program
+}
+
+fn seccomp(program: &[libc::sock_filter], flags: libc::c_ulong) -> Result<()> {
+    let program = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    // SAFETY: program points at the filter, which outlives the call
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            flags,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // Each test restricts a thread of its own, away from the rest of the tests
+
+    #[test]
+    fn keeps_files_to_the_rules() {
+        let allowed = TempDir::new().unwrap();
+        let other = TempDir::new().unwrap();
+        fs::write(allowed.path().join("brightness"), "1\n").unwrap();
+        fs::write(other.path().join("brightness"), "1\n").unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                no_new_privs().unwrap();
+                match landlock(&[(allowed.path().to_path_buf(), READ)]) {
+                    Err(Error::Io(e))
+                        if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP)) =>
+                    {
+                        return
+                    }
+                    result => result.unwrap(),
+                }
+                assert!(fs::read(allowed.path().join("brightness")).is_ok());
+                assert!(fs::write(allowed.path().join("brightness"), "2\n").is_err());
+                assert!(fs::read(other.path().join("brightness")).is_err());
+            });
+        });
+    }
+
+    #[test]
+    fn writes_stay_off_the_rest_of_sys_and_dev() {
+        let config: Config = toml::from_str("").unwrap();
+        let rules = rules(&config, Some(Path::new("/etc/ambient/config.toml")));
+        assert!(rules.contains(&(PathBuf::from("/sys"), READ)));
+        assert!(!rules.iter().any(|(path, _)| path == Path::new("/dev")));
+        // The config's directory may only have files replaced in it
+        assert!(rules.contains(&(PathBuf::from("/etc/ambient"), REPLACE)));
+    }
+
+    #[test]
+    fn refuses_denied_syscalls() {
+        let Some(arch) = AUDIT_ARCH else {
+            return;
+        };
+        thread::spawn(move || {
+            no_new_privs().unwrap();
+            // Without TSYNC, only this thread is filtered
+            seccomp(&filter(arch), 0).unwrap();
+            // SAFETY: 0xffffffff only queries the persona
+            let ret = unsafe { libc::syscall(libc::SYS_personality, 0xffff_ffffu32) };
+            assert_eq!(ret, -1);
+            assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+            // SAFETY: getpid has no preconditions
+            assert!(unsafe { libc::syscall(libc::SYS_getpid) } > 0);
+        })
+        .join()
+        .unwrap();
+    }
+}
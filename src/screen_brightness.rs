@@ -1,92 +1,140 @@
-use anyhow::Result;
 use log::{debug, info};
-use logind_zbus::session::SessionProxyBlocking;
 
-use crate::read_value;
+#[cfg(feature = "content")]
+use crate::content_luminance::ContentLuminance;
+#[cfg(feature = "dbus")]
+use crate::output::Report;
+use crate::{
+    brightness_writer::BrightnessWriter,
+    levels::{raw_level, Clamp},
+    output::{exceeds_min_delta, Offset, Output, StepCurve},
+    output_power::OutputPower,
+    redact::Lux,
+    sysfs::Device,
+    Result,
+};
 
 pub(crate) struct ScreenBrightness<'a> {
-    proxy: &'a SessionProxyBlocking<'a>,
-    subsystem: &'a str,
-    name: &'a str,
-    max_brightness: u32,
-    offset: i8,
+    writer: &'a BrightnessWriter,
+    device: Device,
+    curve: StepCurve,
+    #[cfg(feature = "content")]
+    content: Option<ContentLuminance>,
+    offset: Offset,
+    limits: Clamp,
+    min_delta: u32,
+    power: Option<OutputPower>,
 }
 
 impl<'a> ScreenBrightness<'a> {
     pub(crate) fn new(
-        proxy: &'a SessionProxyBlocking<'a>,
-        subsystem: &'a str,
-        name: &'a str,
-    ) -> Result<Self> {
-        let max_brightness =
-            read_value(&format!("/sys/class/{}/{}/max_brightness", subsystem, name))?;
-
-        Ok(Self {
-            proxy,
-            subsystem,
-            name,
-            max_brightness,
-            offset: 0,
-        })
-    }
-
-    fn read(&self) -> Result<u32> {
-        read_value(&format!(
-            "/sys/class/{}/{}/brightness",
-            self.subsystem, self.name
-        ))
+        writer: &'a BrightnessWriter,
+        device: Device,
+        curve: StepCurve,
+        #[cfg(feature = "content")] content: Option<ContentLuminance>,
+        limits: Clamp,
+        min_delta: u32,
+        power: Option<OutputPower>,
+    ) -> Self {
+        Self {
+            writer,
+            device,
+            curve,
+            #[cfg(feature = "content")]
+            content,
+            offset: Offset::default(),
+            limits,
+            min_delta,
+            power,
+        }
     }
+}
 
-    fn pct_to_brightness(&self, pct: u32) -> u32 {
-        (pct * (self.max_brightness)) / 100
+impl Output for ScreenBrightness<'_> {
+    fn name(&self) -> &str {
+        &self.device.name
     }
 
-    pub(crate) fn adjust(&self, new_val: u32) -> Result<()> {
-        let new_pct: u32 = match new_val {
-            v if v < 1 => 5,
-            v if v < 10 => 10,
-            v if v < 20 => 15,
-            v if v < 30 => 20,
-            v if v < 40 => 25,
-            v if v < 50 => 30,
-            v if v < 60 => 35,
-            v if v < 70 => 40,
-            v if v < 80 => 45,
-            _ => 50,
-        };
+    fn adjust(&mut self, new_val: u32) -> Result<bool> {
+        if let Some(power) = &mut self.power {
+            if !power.is_on() {
+                return Ok(false);
+            }
+        }
 
-        let offset_new_pct = match self.offset {
-            0..=i8::MAX => new_pct.saturating_add(self.offset.unsigned_abs() as u32),
-            i8::MIN..=-1 => new_pct.saturating_sub(self.offset.unsigned_abs() as u32),
+        let new_pct = self.curve.percent(new_val);
+        #[cfg(feature = "content")]
+        let new_pct = match &self.content {
+            Some(content) => content.adjust(new_pct),
+            None => new_pct,
         };
+        let offset_new_pct = self.limits.apply(self.offset.apply(new_pct));
 
-        let new_level = self
-            .pct_to_brightness(offset_new_pct)
-            .min(self.max_brightness);
+        let new_level = raw_level(offset_new_pct, self.device.max_brightness);
 
-        let cur_brightness = self.read()?;
+        let cur_brightness = self.device.brightness()?;
 
         debug!(
             "Backlight: nv:{:?}, np:{:?}, onp:{:?}, nl:{:?}, cb:{:?}",
-            new_val, new_pct, offset_new_pct, new_level, cur_brightness
+            Lux(new_val),
+            new_pct,
+            offset_new_pct,
+            new_level,
+            cur_brightness
         );
-        if cur_brightness != new_level {
+        if cur_brightness != new_level
+            && exceeds_min_delta(
+                cur_brightness,
+                new_level,
+                self.device.max_brightness,
+                self.min_delta,
+            )
+        {
             info!(
                 "Adjusting Screen Backlight: val:{:?} old:{:?} new:{:?}({:?})->{:?}",
-                new_val, cur_brightness, new_pct, offset_new_pct, new_level
+                Lux(new_val),
+                cur_brightness,
+                new_pct,
+                offset_new_pct,
+                new_level
             );
-            self.proxy
-                .set_brightness(self.subsystem, self.name, new_level)?;
+            self.writer.set_brightness(&self.device, new_level)?;
+            return Ok(true);
         }
 
-        Ok(())
+        Ok(false)
+    }
+
+    fn curve(&mut self) -> Option<&mut StepCurve> {
+        Some(&mut self.curve)
+    }
+
+    fn increase(&mut self, amount: i8) {
+        self.offset.increase(amount)
+    }
+
+    fn decrease(&mut self, amount: i8) {
+        self.offset.decrease(amount)
+    }
+
+    fn set(&mut self, percent: u8) {
+        self.offset.set(percent)
+    }
+
+    fn percent(&self) -> Option<u32> {
+        Some(self.offset.percent())
     }
 
-    pub(crate) fn increase(&mut self, amount: i8) {
-        self.offset += amount;
+    fn offset(&self) -> Option<i8> {
+        Some(self.offset.offset())
     }
 
-    pub(crate) fn decrease(&mut self, amount: i8) {
-        self.offset -= amount;
+    #[cfg(feature = "dbus")]
+    fn report(&self, report: &mut Report) {
+        report.screen_percent = self
+            .device
+            .brightness()
+            .ok()
+            .map(|x| x * 100 / self.device.max_brightness.max(1));
     }
 }
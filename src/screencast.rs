@@ -0,0 +1,114 @@
+//! Screen recording and casting, noticed by the capture sessions open on the
+//! session bus, so brightness can hold still while it shows up in a recording.
+//! Neither Mutter nor the portal announce new sessions, so their object trees
+//! are looked at every few seconds.
+
+use std::{thread, time::Duration};
+
+use crossbeam::channel::{never, unbounded, Receiver, Sender};
+use log::{debug, info, warn};
+use zbus::blocking::{fdo::IntrospectableProxy, Connection};
+
+use crate::{
+    config::{Config, ScreencastConfig},
+    Result,
+};
+
+/// Mutter's own sessions, which GNOME's recorder and its portal backend use
+const MUTTER: &str = "org.gnome.Mutter.ScreenCast";
+const MUTTER_SESSIONS: &str = "/org/gnome/Mutter/ScreenCast/Session";
+
+/// The portal's sessions, one node per client holding one per session
+const PORTAL: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_SESSIONS: &str = "/org/freedesktop/portal/desktop/session";
+
+/// Names of the child nodes in introspection data
+fn children(xml: &str) -> Vec<&str> {
+    // The first node is the one introspected
+    xml.split("<node")
+        .skip(2)
+        .filter_map(|x| Some(x.trim_start().strip_prefix("name=\"")?.split_once('"')?.0))
+        .collect()
+}
+
+/// Sessions `depth` levels below `path`. A service that isn't running has none.
+fn sessions(connection: &Connection, service: &str, path: &str, depth: usize) -> Result<usize> {
+    let proxy = IntrospectableProxy::builder(connection)
+        .destination(service)?
+        .path(path)?
+        .build()?;
+    let xml = match proxy.introspect() {
+        Ok(xml) => xml,
+        Err(e) => {
+            debug!("No sessions from {}: {}", service, e);
+            return Ok(0);
+        }
+    };
+    let children = children(&xml);
+    if depth == 0 {
+        return Ok(children.len());
+    }
+    children
+        .iter()
+        .map(|x| sessions(connection, service, &format!("{}/{}", path, x), depth - 1))
+        .sum()
+}
+
+fn capturing(connection: &Connection, config: &ScreencastConfig) -> Result<bool> {
+    if config.mutter && sessions(connection, MUTTER, MUTTER_SESSIONS, 0)? > 0 {
+        return Ok(true);
+    }
+    Ok(config.portal && sessions(connection, PORTAL, PORTAL_SESSIONS, 1)? > 0)
+}
+
+fn forward(config: &ScreencastConfig, sender: &Sender<bool>) -> Result<()> {
+    let connection = Connection::session()?;
+    info!("Watching for screen recording and casting");
+
+    let mut was = false;
+    loop {
+        let now = capturing(&connection, config)?;
+        if now != was {
+            info!("Screen capture {}", if now { "started" } else { "ended" });
+            if sender.send(now).is_err() {
+                return Ok(());
+            }
+            was = now;
+        }
+        thread::sleep(Duration::from_secs(config.interval));
+    }
+}
+
+/// With `[screencast]` in the config, sends `true` once the screen is being
+/// recorded or cast and `false` once that ends. The channel disconnects if the
+/// session bus can't be reached.
+pub fn watch(config: &Config) -> Receiver<bool> {
+    let Some(screencast) = config.screencast.clone() else {
+        return never();
+    };
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        if let Err(e) = forward(&screencast, &sender) {
+            warn!("Not watching for screen capture: {}", e);
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_child_nodes() {
+        let xml = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+            "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+            <node>
+              <interface name="org.freedesktop.DBus.Introspectable"/>
+              <node name="u0"/>
+              <node name="u1"/>
+            </node>"#;
+        assert_eq!(children(xml), vec!["u0", "u1"]);
+        assert!(children("<node>\n</node>").is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+#[cfg(feature = "iio")]
+use crate::iio_sensor::IioSensor;
+#[cfg(all(feature = "macos", target_os = "macos"))]
+use crate::macos::LmuSensor;
+#[cfg(feature = "sysfs")]
+use crate::sysfs_iio_sensor::SysfsIioSensor;
+#[cfg(feature = "hwmon")]
+use crate::{applesmc_sensor::AppleSmcSensor, hwmon_sensor::HwmonSensor};
+use crate::{
+    backoff::RetryingSensor,
+    config::{Combination, Config, SensorConfig},
+    sysfs::Sysfs,
+    tablet_mode::ConvertibleSensor,
+    Error, Result,
+};
+
+/// Source of raw ambient light readings
+pub trait Sensor {
+    fn read(&self) -> Result<f64>;
+}
+
+impl Combination {
+    /// Lux for a broadband and an IR reading, never below 0
+    #[cfg(any(feature = "iio", feature = "sysfs", test))]
+    pub(crate) fn lux(&self, broadband: f64, ir: f64) -> f64 {
+        if broadband <= 0.0 {
+            return 0.0;
+        }
+        let lux = match self {
+            Self::Tsl2561 => {
+                let ratio = ir / broadband;
+                match ratio {
+                    r if r <= 0.5 => 0.0304 * broadband - 0.062 * broadband * r.powf(1.4),
+                    r if r <= 0.61 => 0.0224 * broadband - 0.031 * ir,
+                    r if r <= 0.8 => 0.0128 * broadband - 0.0153 * ir,
+                    r if r <= 1.3 => 0.00146 * broadband - 0.00112 * ir,
+                    _ => 0.0,
+                }
+            }
+            Self::Tsl2591 => (broadband - ir) * (1.0 - ir / broadband),
+            Self::Linear { ir_factor } => broadband - ir_factor * ir,
+        };
+        lux.max(0.0)
+    }
+}
+
+/// Opens the configured sensor. Without a sensor config, MacBooks whose only
+/// light sensor sits behind the SMC fall back to it.
+pub(crate) fn from_config(sysfs: &Sysfs, config: &SensorConfig) -> Result<Box<dyn Sensor>> {
+    match open(sysfs, config) {
+        #[cfg(feature = "hwmon")]
+        Err(Error::NotFound(what)) if *config == SensorConfig::default() => {
+            AppleSmcSensor::new(sysfs)
+                .map(|sensor| Box::new(sensor) as Box<dyn Sensor>)
+                .map_err(|_| Error::NotFound(what))
+        }
+        result => result,
+    }
+}
+
+/// The configured sensor, or on convertibles with a tablet sensor, whichever
+/// of the two the tablet mode switch selects, with reads retried
+pub(crate) fn selected(sysfs: &Sysfs, config: &Config) -> Result<Box<dyn Sensor>> {
+    let sensor = from_config(sysfs, &config.sensor)?;
+    let sensor: Box<dyn Sensor> = match &config.tablet {
+        Some(tablet) => Box::new(ConvertibleSensor::new(
+            sysfs,
+            sensor,
+            open(sysfs, &tablet.sensor)?,
+            tablet.switch.as_deref(),
+        )?),
+        None => sensor,
+    };
+    Ok(Box::new(RetryingSensor::new(sensor, config.retry.clone())))
+}
+
+#[cfg_attr(
+    not(all(feature = "sysfs", feature = "hwmon")),
+    allow(unused_variables)
+)]
+fn open(sysfs: &Sysfs, config: &SensorConfig) -> Result<Box<dyn Sensor>> {
+    match config {
+        #[cfg(feature = "iio")]
+        SensorConfig::Iio(config) => Ok(Box::new(IioSensor::new(config)?)),
+        #[cfg(not(feature = "iio"))]
+        SensorConfig::Iio(_) => Err(Error::Config("IIO support was not compiled in".to_string())),
+        #[cfg(feature = "sysfs")]
+        SensorConfig::Sysfs(config) => Ok(Box::new(SysfsIioSensor::new(sysfs, config)?)),
+        #[cfg(not(feature = "sysfs"))]
+        SensorConfig::Sysfs(_) => Err(Error::Config(
+            "sysfs IIO support was not compiled in".to_string(),
+        )),
+        #[cfg(feature = "hwmon")]
+        SensorConfig::Hwmon { path } => Ok(Box::new(HwmonSensor::new(path.clone())?)),
+        #[cfg(not(feature = "hwmon"))]
+        SensorConfig::Hwmon { .. } => Err(Error::Config(
+            "hwmon support was not compiled in".to_string(),
+        )),
+        #[cfg(feature = "hwmon")]
+        SensorConfig::Applesmc => Ok(Box::new(AppleSmcSensor::new(sysfs)?)),
+        #[cfg(not(feature = "hwmon"))]
+        SensorConfig::Applesmc => Err(Error::Config(
+            "hwmon support was not compiled in".to_string(),
+        )),
+        #[cfg(all(feature = "macos", target_os = "macos"))]
+        SensorConfig::Macos => Ok(Box::new(LmuSensor::new()?)),
+        #[cfg(not(all(feature = "macos", target_os = "macos")))]
+        SensorConfig::Macos => Err(Error::Config(
+            "macOS support was not compiled in".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combinations_subtract_infrared() {
+        // Daylight is about a quarter IR on the TSL2561
+        let lux = Combination::Tsl2561.lux(1000.0, 250.0);
+        assert!((lux - (30.4 - 62.0 * 0.25f64.powf(1.4))).abs() < 1e-9);
+        // Mostly IR, e.g. an incandescent bulb, lands on the steeper pieces
+        assert!(Combination::Tsl2561.lux(1000.0, 700.0) < Combination::Tsl2561.lux(1000.0, 500.0));
+        assert_eq!(Combination::Tsl2561.lux(1000.0, 1400.0), 0.0);
+
+        assert_eq!(Combination::Tsl2591.lux(1000.0, 500.0), 250.0);
+        assert_eq!(
+            Combination::Linear { ir_factor: 2.0 }.lux(1000.0, 100.0),
+            800.0
+        );
+        assert_eq!(
+            Combination::Linear { ir_factor: 2.0 }.lux(100.0, 100.0),
+            0.0
+        );
+        assert_eq!(Combination::Tsl2591.lux(0.0, 0.0), 0.0);
+    }
+}
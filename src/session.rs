@@ -0,0 +1,180 @@
+//! The user's logind session, followed across logins. Run as a user service,
+//! the daemon outlives the session it started in, so it binds to a session by
+//! its own path and moves on to the next one once logind removes it.
+
+use std::thread;
+
+use crossbeam::channel::{unbounded, Receiver};
+use log::{debug, info};
+use logind_zbus::{
+    manager::ManagerProxyBlocking, seat::SeatProxyBlocking, session::SessionProxyBlocking,
+};
+use zbus::{
+    blocking::{Connection, MessageIterator},
+    message::Type,
+    proxy::CacheProperties,
+    zvariant::{ObjectPath, OwnedObjectPath},
+    MatchRule,
+};
+
+use crate::{Result, SESSION_PATH};
+
+/// Builds an uncached proxy for the session at `path`
+fn proxy<P>(connection: &Connection, path: P) -> Result<SessionProxyBlocking<'static>>
+where
+    P: TryInto<ObjectPath<'static>>,
+    P::Error: Into<zbus::Error>,
+{
+    Ok(SessionProxyBlocking::builder(connection)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()?)
+}
+
+/// The session `auto` currently stands for, or the active session on `seat`,
+/// by its own path, so the proxy keeps naming that login rather than whichever
+/// comes after it
+pub(crate) fn current(
+    connection: &Connection,
+    seat: Option<&str>,
+) -> Result<SessionProxyBlocking<'static>> {
+    let manager = ManagerProxyBlocking::new(connection)?;
+    let path = match seat {
+        Some(seat) => SeatProxyBlocking::builder(connection)
+            .path(manager.get_seat(seat)?)?
+            .cache_properties(CacheProperties::No)
+            .build()?
+            .active_session()?
+            .path()
+            .clone(),
+        None => manager.get_session(&proxy(connection, SESSION_PATH)?.id()?)?,
+    };
+    proxy(connection, path)
+}
+
+/// Every signal logind sends, from its manager and all sessions
+pub(crate) fn signals(connection: &Connection) -> Result<MessageIterator> {
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender("org.freedesktop.login1")?
+        .build();
+    Ok(MessageIterator::for_match_rule(rule, connection, None)?)
+}
+
+/// Sends the path of every session logind removes, until the receiver is
+/// dropped or logind can't be reached
+fn removals(connection: &Connection) -> Receiver<OwnedObjectPath> {
+    let (sender, receiver) = unbounded();
+    let connection = connection.clone();
+    thread::spawn(move || {
+        let forward = || -> Result<()> {
+            let manager = ManagerProxyBlocking::new(&connection)?;
+            for removed in manager.receive_session_removed()? {
+                if sender.send(removed.args()?.object_path).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = forward() {
+            debug!("Not following session removals: {}", e);
+        }
+    });
+    receiver
+}
+
+/// Whether a call failed because logind no longer has the session
+pub(crate) fn is_gone(error: &zbus::Error) -> bool {
+    match error {
+        zbus::Error::MethodError(name, _, _) => {
+            name.as_str() == "org.freedesktop.DBus.Error.UnknownObject"
+        }
+        zbus::Error::FDO(e) => matches!(**e, zbus::fdo::Error::UnknownObject(_)),
+        _ => false,
+    }
+}
+
+/// A session proxy that's let go once logind removes its session, and bound to
+/// the current session again on next use
+#[derive(Default)]
+pub(crate) struct Binding {
+    /// Seat whose active session is bound, instead of the caller's
+    seat: Option<String>,
+    connection: Option<Connection>,
+    removed: Option<Receiver<OwnedObjectPath>>,
+    session: Option<SessionProxyBlocking<'static>>,
+}
+
+impl Binding {
+    pub(crate) fn new(seat: Option<String>) -> Self {
+        Self {
+            seat,
+            ..Self::default()
+        }
+    }
+
+    /// The bound session, rebinding first if it has ended
+    pub(crate) fn get(&mut self) -> Result<&SessionProxyBlocking<'static>> {
+        let connection = match &self.connection {
+            Some(connection) => connection.clone(),
+            None => {
+                let connection = Connection::system()?;
+                self.removed = Some(removals(&connection));
+                self.connection.insert(connection).clone()
+            }
+        };
+
+        if let (Some(session), Some(removed)) = (&self.session, &self.removed) {
+            let mut ended = false;
+            for path in removed.try_iter() {
+                ended |= path.as_ref() == *session.inner().path();
+            }
+            if ended {
+                info!("Session {} ended", session.inner().path());
+                self.session = None;
+            }
+        }
+
+        match &mut self.session {
+            Some(session) => Ok(session),
+            session => {
+                let bound = current(&connection, self.seat.as_deref())?;
+                info!("Bound to session {}", bound.inner().path());
+                Ok(session.insert(bound))
+            }
+        }
+    }
+
+    /// Lets go of the session, e.g. when logind says it's gone before its
+    /// removal arrives
+    pub(crate) fn reset(&mut self) {
+        self.session = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zbus::names::OwnedErrorName;
+
+    use super::*;
+
+    #[test]
+    fn tells_ended_sessions_from_other_errors() {
+        let error = |name: &str| {
+            let name = OwnedErrorName::try_from(name.to_string()).unwrap();
+            zbus::Error::MethodError(
+                name,
+                None,
+                zbus::message::Message::method("/", "X")
+                    .unwrap()
+                    .build(&())
+                    .unwrap(),
+            )
+        };
+        assert!(is_gone(&error("org.freedesktop.DBus.Error.UnknownObject")));
+        assert!(!is_gone(&error("org.freedesktop.DBus.Error.AccessDenied")));
+        assert!(is_gone(&zbus::Error::FDO(Box::new(
+            zbus::fdo::Error::UnknownObject("gone".to_string())
+        ))));
+    }
+}
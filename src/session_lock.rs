@@ -0,0 +1,84 @@
+use std::thread;
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{debug, info, warn};
+use logind_zbus::manager::{SessionNew, SessionRemoved};
+use zbus::fdo::PropertiesChanged;
+
+use crate::{config::Config, session, Result};
+
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+fn forward(seat: Option<&str>, sender: &Sender<bool>) -> Result<()> {
+    let connection = zbus::blocking::Connection::system()?;
+    let signals = session::signals(&connection)?;
+    let mut bound = Some(session::current(&connection, seat)?);
+
+    for message in signals {
+        let message = message?;
+        if let Some(removed) = SessionRemoved::from_message(message.clone()) {
+            let path = removed.args()?.object_path;
+            if bound
+                .as_ref()
+                .is_some_and(|x| *x.inner().path() == path.as_ref())
+            {
+                info!("Session {} ended, waiting for the next", path);
+                bound = None;
+            }
+        } else if SessionNew::from_message(message.clone()).is_some() {
+            if bound.is_some() {
+                continue;
+            }
+            // The new login may not be the user's display session yet
+            match session::current(&connection, seat) {
+                Ok(session) => {
+                    let locked = session.locked_hint()?;
+                    info!(
+                        "Following session {}, LockedHint: {}",
+                        session.inner().path(),
+                        locked
+                    );
+                    bound = Some(session);
+                    if sender.send(locked).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => debug!("No session to follow yet: {}", e),
+            }
+        } else if let Some(changed) = PropertiesChanged::from_message(message.clone()) {
+            let Some(session) = &bound else { continue };
+            if message.header().path() != Some(session.inner().path()) {
+                continue;
+            }
+            let args = changed.args()?;
+            if args.interface_name != SESSION_INTERFACE
+                || !(args.changed_properties.contains_key("LockedHint")
+                    || args.invalidated_properties.contains(&"LockedHint"))
+            {
+                continue;
+            }
+            let locked = session.locked_hint()?;
+            info!("Session LockedHint: {}", locked);
+            if sender.send(locked).is_err() {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Follows the logind session's LockedHint, sending every change, and moves on
+/// to the next session after the user logs out and back in. With a `seat` in
+/// the config, follows that seat's active session instead. The channel
+/// disconnects if logind can't be reached.
+pub fn watch(config: &Config) -> Receiver<bool> {
+    let seat = config.seat.clone();
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        if let Err(e) = forward(seat.as_deref(), &sender) {
+            warn!("Not following the session lock: {}", e);
+        }
+    });
+    receiver
+}
@@ -0,0 +1,113 @@
+//! Named snapshots of the outputs' levels and the mode, kept in a state file
+//! so a setup, e.g. for presenting, can be brought back later. Levels come
+//! back as offsets from the curves at the light of the time, so outputs keep
+//! following the sensor from there.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, Error, Result};
+
+/// Directory under `$XDG_STATE_HOME` the state file goes in
+const STATE_DIR: &str = "iio_ambient_brightness";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) idle: bool,
+    /// Percent of each output, by name
+    pub(crate) outputs: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    snapshots: BTreeMap<String, Snapshot>,
+}
+
+/// The configured state file, or `state.toml` in the user's state directory
+pub(crate) fn state_path(config: &Config) -> PathBuf {
+    if let Some(path) = &config.state {
+        return path.clone();
+    }
+    let dir = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|x| x.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|x| Path::new(&x).join(".local/state")))
+        .unwrap_or_else(env::temp_dir);
+    dir.join(STATE_DIR).join("state.toml")
+}
+
+fn read(path: &Path) -> Result<State> {
+    let contents = match fs::read_to_string(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(State::default()),
+        contents => contents?,
+    };
+    toml::from_str(&contents).map_err(|e| {
+        Error::Config(format!(
+            "Couldn't parse state file {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Saves `snapshot` as `name`, replacing any snapshot of that name
+pub(crate) fn save(path: &Path, name: &str, snapshot: Snapshot) -> Result<()> {
+    let mut state = read(path)?;
+    state.snapshots.insert(name.to_string(), snapshot);
+    let contents = toml::to_string(&state)
+        .map_err(|e| Error::Config(format!("Couldn't write state: {}", e)))?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Renamed into place, so a crash never leaves half a file
+    let written = path.with_extension("toml.new");
+    fs::write(&written, contents)?;
+    fs::rename(&written, path)?;
+    Ok(())
+}
+
+pub(crate) fn load(path: &Path, name: &str) -> Result<Snapshot> {
+    read(path)?
+        .snapshots
+        .remove(name)
+        .ok_or_else(|| Error::NotFound(format!("snapshot {} in {}", name, path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn snapshots_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state/state.toml");
+        let snapshot = Snapshot {
+            idle: true,
+            outputs: BTreeMap::from([("intel_backlight".to_string(), 80)]),
+        };
+
+        assert!(load(&path, "talk").is_err());
+        save(&path, "talk", snapshot.clone()).unwrap();
+        save(
+            &path,
+            "night",
+            Snapshot {
+                idle: false,
+                outputs: BTreeMap::new(),
+            },
+        )
+        .unwrap();
+        assert_eq!(load(&path, "talk").unwrap(), snapshot);
+        assert!(load(&path, "other").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::info;
+
+use crate::{config::Backend, Error, Result};
+
+/// Bytes read from a polled attribute at once, well above any number sysfs
+/// reports
+const READ_BUF: usize = 128;
+
+fn sysfs_error(path: &Path) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    move |source| Error::Sysfs {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn parse<T: FromStr>(path: &Path, val: &str) -> Result<T> {
+    val.trim().parse().map_err(|_| Error::Parse {
+        path: path.to_path_buf(),
+        value: val.trim().to_string(),
+    })
+}
+
+/// Reads and parses a sysfs attribute once
+pub(crate) fn read_value<T: FromStr>(path: impl AsRef<Path>) -> Result<T> {
+    let path = path.as_ref();
+    let val = fs::read_to_string(path).map_err(sysfs_error(path))?;
+    parse(path, &val)
+}
+
+/// Directory sysfs paths are resolved against, `/sys` unless overridden for tests
+#[derive(Debug, Clone)]
+pub(crate) struct Sysfs {
+    root: PathBuf,
+}
+
+impl Sysfs {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub(crate) fn path(&self, relative: impl AsRef<Path>) -> PathBuf {
+        self.root.join(relative)
+    }
+
+    pub(crate) fn class(&self, subsystem: &str) -> PathBuf {
+        self.path("class").join(subsystem)
+    }
+
+    pub(crate) fn read_max_brightness(&self, subsystem: &str, name: &str) -> Result<u32> {
+        read_value(self.class(subsystem).join(name).join("max_brightness"))
+    }
+
+    pub(crate) fn device(&self, subsystem: &str, name: &str, backend: Backend) -> Result<Device> {
+        Device::open(self, subsystem, name, backend)
+    }
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Self::new("/sys")
+    }
+}
+
+/// A sysfs attribute kept open between reads. It is opened read-write when
+/// permissions allow, and read-only otherwise.
+pub(crate) struct Attribute {
+    path: PathBuf,
+    file: File,
+    writable: bool,
+}
+
+impl Attribute {
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let (file, writable) = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => (file, true),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                (File::open(&path).map_err(sysfs_error(&path))?, false)
+            }
+            Err(e) => return Err(sysfs_error(&path)(e)),
+        };
+
+        Ok(Self {
+            path,
+            file,
+            writable,
+        })
+    }
+
+    /// Runs `f` on the current contents, read with a single pread into a
+    /// buffer on the stack, so polling doesn't allocate
+    pub(crate) fn read_with<R>(&self, f: impl FnOnce(&str) -> Result<R>) -> Result<R> {
+        let mut buf = [0u8; READ_BUF];
+        let len = self
+            .file
+            .read_at(&mut buf, 0)
+            .map_err(sysfs_error(&self.path))?;
+        if len == buf.len() {
+            // Longer than any value polled; read the rest the slow way
+            let mut val = Vec::new();
+            (&self.file)
+                .seek(SeekFrom::Start(0))
+                .and_then(|_| (&self.file).read_to_end(&mut val))
+                .map_err(sysfs_error(&self.path))?;
+            return f(&String::from_utf8_lossy(&val));
+        }
+        let val = std::str::from_utf8(&buf[..len]).map_err(|_| Error::Parse {
+            path: self.path.clone(),
+            value: String::from_utf8_lossy(&buf[..len]).trim().to_string(),
+        })?;
+        f(val)
+    }
+
+    pub(crate) fn read<T: FromStr>(&self) -> Result<T> {
+        self.read_with(|val| parse(&self.path, val))
+    }
+
+    /// Replaces the contents, as `echo val > path` would
+    pub(crate) fn write<T: Display>(&self, val: T) -> Result<()> {
+        let mut buf = [0u8; READ_BUF];
+        let mut cursor = &mut buf[..];
+        write!(cursor, "{}", val).map_err(sysfs_error(&self.path))?;
+        let len = READ_BUF - cursor.len();
+        self.file
+            .set_len(0)
+            .and_then(|_| self.file.write_all_at(&buf[..len], 0))
+            .map_err(sysfs_error(&self.path))
+    }
+
+    pub(crate) fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    #[cfg(any(feature = "sysfs", feature = "hwmon"))]
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A backlight or LED under /sys/class/{subsystem}/{name}
+pub(crate) struct Device {
+    pub(crate) subsystem: String,
+    pub(crate) name: String,
+    pub(crate) max_brightness: u32,
+    pub(crate) backend: Backend,
+    brightness: Attribute,
+}
+
+impl Device {
+    fn open(sysfs: &Sysfs, subsystem: &str, name: &str, backend: Backend) -> Result<Self> {
+        let max_brightness = sysfs.read_max_brightness(subsystem, name)?;
+        let brightness = Attribute::open(sysfs.class(subsystem).join(name).join("brightness"))?;
+
+        info!(
+            "{}/{}: max:{} writes via {}",
+            subsystem,
+            name,
+            max_brightness,
+            match backend {
+                Backend::Auto if brightness.is_writable() => "sysfs",
+                Backend::Auto => "logind",
+                Backend::Sysfs => "sysfs",
+                Backend::Logind => "logind",
+                Backend::Brightnessctl => "brightnessctl",
+                Backend::Light => "light",
+                Backend::Helper => "the helper",
+            }
+        );
+
+        Ok(Self {
+            subsystem: subsystem.to_string(),
+            name: name.to_string(),
+            max_brightness,
+            backend,
+            brightness,
+        })
+    }
+
+    pub(crate) fn brightness(&self) -> Result<u32> {
+        self.brightness.read()
+    }
+
+    /// Writes directly to sysfs; only possible when [`Device::is_writable`]
+    pub(crate) fn write_brightness(&self, level: u32) -> Result<()> {
+        self.brightness.write(level)
+    }
+
+    /// Whether the brightness attribute itself can be written
+    pub(crate) fn is_writable(&self) -> bool {
+        self.brightness.is_writable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rereads_and_replaces_values() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("brightness");
+        fs::write(&path, "1000\n").unwrap();
+        let attribute = Attribute::open(&path).unwrap();
+        assert_eq!(attribute.read::<u32>().unwrap(), 1000);
+
+        attribute.write(50).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "50");
+        assert_eq!(attribute.read::<u32>().unwrap(), 50);
+
+        let long = format!("{}\n", "7".repeat(READ_BUF + 10));
+        fs::write(&path, &long).unwrap();
+        assert_eq!(attribute.read_with(|x| Ok(x.len())).unwrap(), long.len());
+    }
+}
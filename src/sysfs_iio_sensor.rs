@@ -0,0 +1,142 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+
+use crate::{
+    config::{Combination, IioConfig},
+    sensor::Sensor,
+    sysfs::{Attribute, Sysfs},
+    Error, Result,
+};
+
+/// Reads IIO light channels straight from sysfs, without libiio
+pub(crate) struct SysfsIioSensor {
+    attribute: Attribute,
+    /// IR channel read along with the broadband one, and how they combine
+    ir: Option<(Attribute, Combination)>,
+    scale: f64,
+}
+
+/// Channel id (e.g. `illuminance` or `intensity_both`) of a light channel attribute
+fn channel_id(file_name: &str) -> Option<&str> {
+    let id = file_name.strip_prefix("in_")?;
+    let id = id
+        .strip_suffix("_raw")
+        .or_else(|| id.strip_suffix("_input"))?;
+    (id.starts_with("illuminance") || id.starts_with("intensity")).then_some(id)
+}
+
+/// The light channel attribute to read, preferring illuminance over intensity and
+/// `_raw` over `_input`. A configured channel id or modifier narrows down the
+/// candidates, and an IR channel leaves only the other intensity channels.
+fn light_attribute(dev: &Path, config: &IioConfig) -> Option<PathBuf> {
+    let mut attrs = fs::read_dir(dev)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|file_name| match channel_id(file_name) {
+            Some(id) => {
+                let broadband = match &config.ir {
+                    Some(ir) => id != ir && id.starts_with("intensity"),
+                    None => true,
+                };
+                config
+                    .channel
+                    .as_ref()
+                    .map_or(broadband, |channel| channel == id)
+                    && config
+                        .modifier
+                        .as_ref()
+                        .is_none_or(|modifier| id.ends_with(&format!("_{}", modifier)))
+            }
+            None => false,
+        })
+        .collect::<Vec<_>>();
+    attrs.sort_by_key(|file_name| {
+        (
+            !file_name.starts_with("in_illuminance"),
+            !file_name.ends_with("_raw"),
+            file_name.clone(),
+        )
+    });
+    attrs
+        .into_iter()
+        .next()
+        .map(|file_name| dev.join(file_name))
+}
+
+impl SysfsIioSensor {
+    pub(crate) fn new(sysfs: &Sysfs, config: &IioConfig) -> Result<Self> {
+        let iio_devices = sysfs.path("bus/iio/devices");
+        let mut devices = fs::read_dir(&iio_devices)
+            .map_err(|source| Error::Sysfs {
+                path: iio_devices.clone(),
+                source,
+            })?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        devices.sort();
+
+        let (path, name) = devices
+            .iter()
+            .map(|dev| {
+                let name = fs::read_to_string(dev.join("name")).unwrap_or_default();
+                (dev, name.trim().to_string())
+            })
+            .filter(|(_, name)| {
+                debug!("IIO Device: {}", name);
+                config.device.as_ref().is_none_or(|device| device == name)
+            })
+            .find_map(|(dev, name)| light_attribute(dev, config).map(|path| (path, name)))
+            .ok_or_else(|| Error::NotFound("a matching IIO light channel in sysfs".to_string()))?;
+
+        let ir = match (&config.ir, config.combination_for(&name)?) {
+            (Some(ir), Some(combination)) => {
+                let dev = path.parent().expect("attribute in a device directory");
+                let path = ["raw", "input"]
+                    .iter()
+                    .map(|suffix| dev.join(format!("in_{}_{}", ir, suffix)))
+                    .find(|path| path.exists())
+                    .ok_or_else(|| {
+                        Error::NotFound(format!("IR channel {} of IIO device {}", ir, name))
+                    })?;
+                Some((Attribute::open(path)?, combination))
+            }
+            _ => None,
+        };
+        let sensor = Self {
+            attribute: Attribute::open(path)?,
+            ir,
+            scale: config.scale_for(&name),
+        };
+        sensor.read()?;
+        info!(
+            "Using sysfs IIO sensor: {}, scale {}",
+            sensor.attribute.path().display(),
+            sensor.scale
+        );
+        if let Some((ir, combination)) = &sensor.ir {
+            info!(
+                "Correcting with {} as {:?}",
+                ir.path().display(),
+                combination
+            );
+        }
+        Ok(sensor)
+    }
+}
+
+impl Sensor for SysfsIioSensor {
+    fn read(&self) -> Result<f64> {
+        let broadband = self.attribute.read::<f64>()?;
+        let lux = match &self.ir {
+            Some((ir, combination)) => combination.lux(broadband, ir.read()?),
+            None => broadband,
+        };
+        Ok(lux * self.scale)
+    }
+}
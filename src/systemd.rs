@@ -0,0 +1,153 @@
+//! Running as a systemd service: readiness, shutdown, and watchdog pings sent
+//! to `$NOTIFY_SOCKET` for `Type=notify` units, and the control socket taken
+//! from systemd when a socket unit listens on it
+
+#[cfg(feature = "control")]
+use std::os::{
+    fd::{FromRawFd, RawFd},
+    unix::net::UnixListener,
+};
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process,
+    time::Duration,
+};
+
+#[cfg(feature = "control")]
+use log::debug;
+use log::warn;
+
+/// First fd systemd passes to socket-activated services, see sd_listen_fds(3)
+#[cfg(feature = "control")]
+const LISTEN_FDS_START: RawFd = 3;
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    // A leading @ names a socket in the abstract namespace
+    let address = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Tells systemd about `state`, e.g. `READY=1`, if it's listening
+pub(crate) fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, state) {
+        warn!("Couldn't notify systemd of {}: {}", state, e);
+    }
+}
+
+fn for_us(pid: Option<&str>, own: u32) -> bool {
+    pid.is_none_or(|x| x.parse() == Ok(own))
+}
+
+/// Half the watchdog timeout systemd gives this process, as sd_watchdog_enabled(3)
+/// recommends, or None without `WatchdogSec=`
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    ping_interval(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    )
+}
+
+fn ping_interval(usec: Option<&str>, pid: Option<&str>, own: u32) -> Option<Duration> {
+    if !for_us(pid, own) {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok().filter(|x| *x > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Number of sockets systemd passed to this process
+#[cfg(feature = "control")]
+fn listen_fds(fds: Option<&str>, pid: Option<&str>, own: u32) -> usize {
+    // Unlike the watchdog's, the pid is always set for socket activation
+    if pid.is_none() || !for_us(pid, own) {
+        return 0;
+    }
+    fds.and_then(|x| x.parse().ok()).unwrap_or(0)
+}
+
+/// The control socket a systemd socket unit listens on for this service
+#[cfg(feature = "control")]
+pub(crate) fn listener() -> Option<UnixListener> {
+    let fds = listen_fds(
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_PID").ok().as_deref(),
+        process::id(),
+    );
+    if fds == 0 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("Using the first of the {} sockets systemd passed", fds);
+    }
+    // SAFETY: fcntl only flags the fd, which systemd passes open
+    if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        warn!(
+            "Not using the socket systemd passed: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    debug!("Listening on the socket systemd passed");
+    // SAFETY: the fd is open, and passed to this process to own
+    Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn sends_states_as_datagrams() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn only_takes_what_is_meant_for_this_process() {
+        assert_eq!(
+            ping_interval(Some("10000000"), None, 7),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            ping_interval(Some("10000000"), Some("7"), 7),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(ping_interval(Some("10000000"), Some("8"), 7), None);
+        assert_eq!(ping_interval(Some("0"), None, 7), None);
+        assert_eq!(ping_interval(None, None, 7), None);
+    }
+
+    #[test]
+    #[cfg(feature = "control")]
+    fn only_takes_sockets_meant_for_this_process() {
+        assert_eq!(listen_fds(Some("1"), Some("7"), 7), 1);
+        assert_eq!(listen_fds(Some("1"), Some("8"), 7), 0);
+        assert_eq!(listen_fds(Some("1"), None, 7), 0);
+        assert_eq!(listen_fds(None, Some("7"), 7), 0);
+    }
+}
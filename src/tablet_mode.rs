@@ -0,0 +1,177 @@
+//! Sensor selection on convertibles, following the tablet mode switch the
+//! kernel reports through evdev
+
+use std::{
+    cell::Cell,
+    fs::{self, File},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{sensor::Sensor, sysfs::Sysfs, Error, Result};
+
+/// Switch code of the tablet mode switch, from linux/input-event-codes.h
+const SW_TABLET_MODE: u32 = 0x01;
+
+/// Bytes holding every switch state, SW_CNT bits
+const SW_BYTES: usize = 0x11_usize.div_ceil(8);
+
+/// `EVIOCGSW(len)`: reads the state of all switches into a bitmask
+fn eviocgsw(len: usize) -> libc::c_ulong {
+    const IOC_READ: libc::c_ulong = 2;
+    (IOC_READ << 30) | ((len as libc::c_ulong) << 16) | ((b'E' as libc::c_ulong) << 8) | 0x1b
+}
+
+/// Whether a `capabilities/sw` bitmask, space separated hex words with the
+/// lowest last, includes the tablet mode switch
+fn has_tablet_switch(capabilities: &str) -> bool {
+    capabilities
+        .split_whitespace()
+        .last()
+        .and_then(|x| u64::from_str_radix(x, 16).ok())
+        .is_some_and(|x| x & (1 << SW_TABLET_MODE) != 0)
+}
+
+/// First evdev device reporting a tablet mode switch
+fn detect(sysfs: &Sysfs) -> Result<PathBuf> {
+    let input = sysfs.class("input");
+    let mut events = fs::read_dir(&input)
+        .map_err(|source| Error::Sysfs {
+            path: input.clone(),
+            source,
+        })?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with("event"))
+        .collect::<Vec<_>>();
+    events.sort();
+
+    events
+        .into_iter()
+        .find(|name| {
+            fs::read_to_string(input.join(name).join("device/capabilities/sw"))
+                .is_ok_and(|x| has_tablet_switch(&x))
+        })
+        .map(|name| Path::new("/dev/input").join(name))
+        .ok_or_else(|| Error::NotFound(format!("a tablet mode switch in {}", input.display())))
+}
+
+/// The tablet mode switch, read on demand
+struct TabletSwitch {
+    path: PathBuf,
+    file: File,
+}
+
+impl TabletSwitch {
+    fn open(path: PathBuf) -> Result<Self> {
+        let file = File::open(&path).map_err(|source| Error::Sysfs {
+            path: path.clone(),
+            source,
+        })?;
+        Ok(Self { path, file })
+    }
+
+    fn is_tablet(&self) -> Result<bool> {
+        let mut state = [0u8; SW_BYTES];
+        // SAFETY: state is writable for the length passed to the ioctl
+        let ret = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                eviocgsw(state.len()) as _,
+                state.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Sysfs {
+                path: self.path.clone(),
+                source: std::io::Error::last_os_error(),
+            });
+        }
+        let bit = SW_TABLET_MODE as usize;
+        Ok(state[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Reads the lid sensor in laptop mode and the base sensor in tablet and tent
+/// mode, where the lid faces away from the user or the table
+pub(crate) struct ConvertibleSensor {
+    laptop: Box<dyn Sensor>,
+    tablet: Box<dyn Sensor>,
+    switch: TabletSwitch,
+    tablet_mode: Cell<bool>,
+}
+
+impl ConvertibleSensor {
+    /// Uses the switch at `switch`, or the first one found
+    pub(crate) fn new(
+        sysfs: &Sysfs,
+        laptop: Box<dyn Sensor>,
+        tablet: Box<dyn Sensor>,
+        switch: Option<&Path>,
+    ) -> Result<Self> {
+        let path = match switch {
+            Some(path) => path.to_path_buf(),
+            None => detect(sysfs)?,
+        };
+        let switch = TabletSwitch::open(path)?;
+        let tablet_mode = switch.is_tablet()?;
+        info!(
+            "Tablet mode switch: {} ({})",
+            switch.path.display(),
+            if tablet_mode { "tablet" } else { "laptop" }
+        );
+        Ok(Self {
+            laptop,
+            tablet,
+            switch,
+            tablet_mode: Cell::new(tablet_mode),
+        })
+    }
+}
+
+impl Sensor for ConvertibleSensor {
+    fn read(&self) -> Result<f64> {
+        let tablet_mode = self.switch.is_tablet()?;
+        if tablet_mode != self.tablet_mode.replace(tablet_mode) {
+            info!(
+                "Switched to {} mode, reading its sensor",
+                if tablet_mode { "tablet" } else { "laptop" }
+            );
+        }
+        if tablet_mode {
+            self.tablet.read()
+        } else {
+            self.laptop.read()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_tablet_switch_capability() {
+        assert!(has_tablet_switch("2"));
+        assert!(has_tablet_switch("1 3"));
+        assert!(!has_tablet_switch("1"));
+        assert!(!has_tablet_switch("2 0"));
+        assert!(!has_tablet_switch(""));
+    }
+
+    #[test]
+    fn detects_first_tablet_switch() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sysfs = Sysfs::new(dir.path());
+        for (name, sw) in [("event2", "0"), ("event7", "2"), ("event9", "2")] {
+            let caps = sysfs.class("input").join(name).join("device/capabilities");
+            fs::create_dir_all(&caps).unwrap();
+            fs::write(caps.join("sw"), format!("{}\n", sw)).unwrap();
+        }
+        fs::create_dir_all(sysfs.class("input").join("mouse0")).unwrap();
+
+        assert_eq!(detect(&sysfs).unwrap(), PathBuf::from("/dev/input/event7"));
+    }
+}
@@ -0,0 +1,615 @@
+//! Tray icon showing what the daemon is doing, with a menu to pause it, switch
+//! profiles, and nudge the brightness. It is a StatusNotifierItem with a
+//! dbusmenu, talking to the daemon through [`ControlClient`].
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_io::block_on;
+use crossbeam::{
+    channel::{unbounded, Receiver, Sender},
+    select,
+};
+use log::{debug, warn};
+use toml::Value as TomlValue;
+use zbus::{
+    blocking::{connection, object_server::InterfaceRef, Connection},
+    fdo, interface,
+    object_server::SignalContext,
+    zvariant::{OwnedObjectPath, OwnedValue, StructureBuilder, Value},
+};
+
+use crate::{
+    command::Command,
+    config::{resolved_table, Config},
+    control_client::ControlClient,
+    control_server::configured_socket_path,
+    health::{Mode, Status},
+    protocol::ProfileEdit,
+    Error, Result,
+};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+
+/// How often the icon follows the daemon when nothing is clicked
+const REFRESH: Duration = Duration::from_secs(2);
+
+/// Percent the brighter and dimmer entries, and scrolling, move the screen by
+const STEP: i8 = 10;
+
+/// What the icon shows, refreshed from pings and the config file
+#[derive(Debug, Clone, Default, PartialEq)]
+struct State {
+    /// `None` while the daemon can't be reached
+    status: Option<Status>,
+    profiles: Vec<String>,
+    active: Option<String>,
+}
+
+impl State {
+    fn read(socket: &Path, config_file: Option<&Path>) -> Self {
+        let status = ControlClient::connect(socket).and_then(|mut x| x.ping());
+        if let Err(e) = &status {
+            debug!("Daemon unreachable: {}", e);
+        }
+        let table = config_file.and_then(|path| resolved_table(Some(path)).ok());
+        let profiles = table
+            .as_ref()
+            .and_then(|x| x.get("profiles"))
+            .and_then(TomlValue::as_table)
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default();
+        let active = table
+            .as_ref()
+            .and_then(|x| x.get("profile"))
+            .and_then(TomlValue::as_str)
+            .map(str::to_string);
+        Self {
+            status: status.ok(),
+            profiles,
+            active,
+        }
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        self.status.as_ref().map(|x| x.mode)
+    }
+
+    fn icon(&self) -> &'static str {
+        match self.mode() {
+            Some(Mode::Active) => "display-brightness-symbolic",
+            Some(Mode::Idle) => "display-brightness-low-symbolic",
+            Some(Mode::Suspended) => "media-playback-pause-symbolic",
+            None => "dialog-warning-symbolic",
+        }
+    }
+
+    fn description(&self) -> String {
+        let Some(status) = &self.status else {
+            return "The daemon isn't running".to_string();
+        };
+        // Privacy mode keeps readings to the daemon
+        let template = match (status.ambient, status.lux) {
+            (Some(_), Some(_)) => "{percent}% ambient, {lux} lx, {mode}",
+            _ => "{mode}",
+        };
+        status.format(template).expect("Valid template")
+    }
+
+    fn entries(&self) -> Vec<Entry> {
+        let mut entries = vec![Entry::label(match self.mode() {
+            Some(mode) => format!("Brightness: {}", mode),
+            None => "Brightness: not running".to_string(),
+        })];
+        if self.status.is_none() {
+            return entries;
+        }
+
+        entries.push(match self.mode() {
+            Some(Mode::Active) => Entry::action("Pause", Action::Pause),
+            _ => Entry::action("Resume", Action::Resume),
+        });
+        entries.push(Entry::Separator);
+        entries.push(Entry::action("Brighter", Action::Brighter));
+        entries.push(Entry::action("Dimmer", Action::Dimmer));
+        if !self.profiles.is_empty() {
+            entries.push(Entry::Separator);
+            for profile in &self.profiles {
+                entries.push(Entry::Radio {
+                    label: profile.clone(),
+                    action: Action::Profile(profile.clone()),
+                    selected: self.active.as_ref() == Some(profile),
+                });
+            }
+        }
+        entries
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Pause,
+    Resume,
+    Brighter,
+    Dimmer,
+    Profile(String),
+}
+
+/// A menu entry; its dbusmenu id is its index plus one, 0 being the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    Label(String),
+    Action(String, Action),
+    Radio {
+        label: String,
+        action: Action,
+        selected: bool,
+    },
+    Separator,
+}
+
+impl Entry {
+    fn label(label: String) -> Self {
+        Self::Label(label)
+    }
+
+    fn action(label: &str, action: Action) -> Self {
+        Self::Action(label.to_string(), action)
+    }
+
+    fn properties(&self) -> HashMap<String, OwnedValue> {
+        let mut properties = HashMap::new();
+        let mut set = |name: &str, value: Value| {
+            properties.insert(
+                name.to_string(),
+                value.try_to_owned().expect("Plain values own"),
+            );
+        };
+        match self {
+            Self::Label(label) => {
+                set("label", label.as_str().into());
+                set("enabled", false.into());
+            }
+            Self::Action(label, _) => set("label", label.as_str().into()),
+            Self::Radio {
+                label, selected, ..
+            } => {
+                set("label", label.as_str().into());
+                set("toggle-type", "radio".into());
+                set("toggle-state", (*selected as i32).into());
+            }
+            Self::Separator => set("type", "separator".into()),
+        }
+        properties
+    }
+
+    fn target(&self) -> Option<&Action> {
+        match self {
+            Self::Action(_, action) | Self::Radio { action, .. } => Some(action),
+            Self::Label(_) | Self::Separator => None,
+        }
+    }
+}
+
+/// Carries out a menu click or scroll
+fn perform(socket: &Path, action: &Action) -> Result<()> {
+    let mut client = ControlClient::connect(socket)?;
+    match action {
+        Action::Pause => client.send(Command::Idle),
+        Action::Resume => client.send(Command::Active),
+        Action::Brighter => client.send(Command::Increase(STEP)),
+        Action::Dimmer => client.send(Command::Decrease(STEP)),
+        Action::Profile(name) => client.edit_profile(ProfileEdit {
+            name: name.clone(),
+            overlay: String::new(),
+            activate: true,
+        }),
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    socket: PathBuf,
+    /// Wakes the refresh loop after a click, so the icon catches up right away
+    poke: Sender<()>,
+}
+
+impl Shared {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Tray state poisoned")
+    }
+
+    fn perform(&self, action: &Action) {
+        if let Err(e) = perform(&self.socket, action) {
+            warn!("Couldn't reach the daemon: {}", e);
+        }
+        let _ = self.poke.send(());
+    }
+}
+
+/// Icon name, pixmaps as width, height, and ARGB data, title, and description
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+struct Item {
+    shared: Arc<Shared>,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+    #[zbus(property)]
+    fn category(&self) -> &str {
+        "Hardware"
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> &str {
+        "iio-ambient-brightness"
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> &str {
+        "Ambient brightness"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "Active"
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> String {
+        self.shared.state().icon().to_string()
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> ToolTip {
+        let state = self.shared.state();
+        (
+            state.icon().to_string(),
+            Vec::new(),
+            "Ambient brightness".to_string(),
+            state.description(),
+        )
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(MENU_PATH).expect("Valid path")
+    }
+
+    /// Clicking the icon pauses or resumes
+    fn activate(&self, _x: i32, _y: i32) {
+        let action = match self.shared.state().mode() {
+            Some(Mode::Active) => Action::Pause,
+            _ => Action::Resume,
+        };
+        self.shared.perform(&action);
+    }
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    /// Scrolling over the icon nudges the brightness
+    fn scroll(&self, delta: i32, orientation: &str) {
+        if orientation.eq_ignore_ascii_case("vertical") && delta != 0 {
+            let action = match delta < 0 {
+                true => Action::Brighter,
+                false => Action::Dimmer,
+            };
+            self.shared.perform(&action);
+        }
+    }
+
+    #[zbus(signal)]
+    async fn new_icon(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_tool_tip(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+/// dbusmenu layout: an id, its properties, and its children as layouts in
+/// variants
+type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+struct Menu {
+    shared: Arc<Shared>,
+    revision: u32,
+}
+
+impl Menu {
+    fn entries(&self) -> Vec<Entry> {
+        self.shared.state().entries()
+    }
+
+    fn properties(&self, id: i32) -> Option<HashMap<String, OwnedValue>> {
+        match id {
+            0 => Some(HashMap::from([(
+                "children-display".to_string(),
+                Value::from("submenu")
+                    .try_to_owned()
+                    .expect("Plain values own"),
+            )])),
+            id => self
+                .entries()
+                .get(usize::try_from(id).ok()? - 1)
+                .map(Entry::properties),
+        }
+    }
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl Menu {
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The menu is flat, so any parent but the root has no children
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        _recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> fdo::Result<(u32, Layout)> {
+        let properties = self
+            .properties(parent_id)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No menu entry {}", parent_id)))?;
+        let children = match parent_id {
+            0 => self
+                .entries()
+                .iter()
+                .enumerate()
+                .map(|(index, entry)| {
+                    let layout = StructureBuilder::new()
+                        .add_field(index as i32 + 1)
+                        .add_field(entry.properties())
+                        .add_field(Vec::<Value>::new())
+                        .build();
+                    Value::from(layout).try_to_owned()
+                })
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|e| fdo::Error::Failed(e.to_string()))?,
+            _ => Vec::new(),
+        };
+        Ok((self.revision, (parent_id, properties, children)))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.properties(id)?)))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: &str) -> fdo::Result<OwnedValue> {
+        self.properties(id)
+            .and_then(|mut x| x.remove(name))
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No {} on menu entry {}", name, id)))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: OwnedValue, _timestamp: u32) {
+        if event_id != "clicked" {
+            return;
+        }
+        let entry = usize::try_from(id)
+            .ok()
+            .and_then(|x| self.entries().get(x.wrapping_sub(1)).cloned());
+        if let Some(action) = entry.as_ref().and_then(Entry::target) {
+            self.shared.perform(action);
+        }
+    }
+
+    /// Ids of events for entries that don't exist
+    fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+        let count = self.entries().len() as i32;
+        let mut missing = Vec::new();
+        for (id, event_id, data, timestamp) in events {
+            match id >= 1 && id <= count {
+                true => self.event(id, &event_id, data, timestamp),
+                false => missing.push(id),
+            }
+        }
+        missing
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (Vec::new(), Vec::new())
+    }
+
+    #[zbus(signal)]
+    async fn layout_updated(
+        ctxt: &SignalContext<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+/// Shows the icon until `close_receiver` fires. Profiles come from the config
+/// file at `config_file`, which should be the one the daemon runs with.
+pub fn run(
+    config: &Config,
+    config_file: Option<&Path>,
+    close_receiver: Receiver<()>,
+) -> Result<()> {
+    let socket = configured_socket_path(config);
+    let (poke, poked) = unbounded();
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State::read(&socket, config_file)),
+        socket: socket.clone(),
+        poke,
+    });
+
+    let name = format!("org.kde.StatusNotifierItem-{}-1", process::id());
+    let connection = connection::Builder::session()?
+        .name(name.as_str())?
+        .serve_at(
+            ITEM_PATH,
+            Item {
+                shared: shared.clone(),
+            },
+        )?
+        .serve_at(
+            MENU_PATH,
+            Menu {
+                shared: shared.clone(),
+                revision: 0,
+            },
+        )?
+        .build()?;
+    register(&connection, &name)?;
+    let item = connection.object_server().interface::<_, Item>(ITEM_PATH)?;
+    let menu = connection.object_server().interface::<_, Menu>(MENU_PATH)?;
+
+    loop {
+        select! {
+            recv(close_receiver) -> _ => return Ok(()),
+            recv(poked) -> _ => (),
+            default(REFRESH) => (),
+        }
+        let state = State::read(&socket, config_file);
+        let old = std::mem::replace(&mut *shared.state(), state.clone());
+        if let Err(e) = signal(&item, &menu, &old, &state) {
+            warn!("Couldn't update the tray icon: {}", e);
+        }
+    }
+}
+
+fn register(connection: &Connection, name: &str) -> Result<()> {
+    connection
+        .call_method(
+            Some("org.kde.StatusNotifierWatcher"),
+            "/StatusNotifierWatcher",
+            Some("org.kde.StatusNotifierWatcher"),
+            "RegisterStatusNotifierItem",
+            &name,
+        )
+        .map_err(|e| {
+            Error::Protocol(format!(
+                "Couldn't add the tray icon, the desktop needs a StatusNotifierItem tray: {}",
+                e
+            ))
+        })?;
+    Ok(())
+}
+
+/// Tells the tray what changed between two states
+fn signal(
+    item: &InterfaceRef<Item>,
+    menu: &InterfaceRef<Menu>,
+    old: &State,
+    new: &State,
+) -> Result<()> {
+    block_on(async {
+        if old.icon() != new.icon() {
+            Item::new_icon(item.signal_context()).await?;
+        }
+        if old.description() != new.description() {
+            Item::new_tool_tip(item.signal_context()).await?;
+        }
+        if old.entries() != new.entries() {
+            let mut menu_ref = menu.get_mut();
+            menu_ref.revision += 1;
+            Menu::layout_updated(menu.signal_context(), menu_ref.revision, 0).await?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(mode: Mode) -> Status {
+        Status {
+            uptime: Duration::from_secs(60),
+            last_read: None,
+            last_write: None,
+            sensor_errors: 0,
+            write_errors: 0,
+            sensor_stuck: false,
+            failing_outputs: 0,
+            ambient: Some(40),
+            lux: Some(120),
+            mode,
+            mode_for: Duration::from_secs(60),
+            adjustments: 0,
+            output_errors: 0,
+            last_adjusted: Vec::new(),
+            levels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn menu_follows_the_daemon() {
+        assert_eq!(
+            State::default().entries(),
+            [Entry::label("Brightness: not running".to_string())]
+        );
+
+        let state = State {
+            status: Some(status(Mode::Active)),
+            profiles: vec!["day".to_string(), "night".to_string()],
+            active: Some("night".to_string()),
+        };
+        let entries = state.entries();
+        assert_eq!(entries[1].target(), Some(&Action::Pause));
+        assert_eq!(
+            entries[6..],
+            [
+                Entry::Radio {
+                    label: "day".to_string(),
+                    action: Action::Profile("day".to_string()),
+                    selected: false,
+                },
+                Entry::Radio {
+                    label: "night".to_string(),
+                    action: Action::Profile("night".to_string()),
+                    selected: true,
+                },
+            ]
+        );
+        assert_eq!(state.description(), "40% ambient, 120 lx, active");
+
+        let paused = State {
+            status: Some(status(Mode::Suspended)),
+            ..State::default()
+        };
+        assert_eq!(paused.entries()[1].target(), Some(&Action::Resume));
+        assert_eq!(paused.entries().len(), 5);
+        assert_ne!(paused.icon(), state.icon());
+    }
+}
@@ -0,0 +1,613 @@
+//! Editing a curve on the terminal while the running daemon follows along
+
+use std::{
+    io::{self, Write},
+    mem,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use toml::{Table, Value};
+
+use crate::{
+    config::{read_table, write_validated, Config},
+    control_client::ControlClient,
+    health::Status,
+    protocol::ProfileEdit,
+    Error, Result,
+};
+
+/// Profile the edits are applied through until they are saved or dropped
+const PROFILE: &str = "tune";
+
+/// Time between pings for the ambient reading
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Keys held down are sent as one edit once they pause this long
+const SETTLE: Duration = Duration::from_millis(150);
+
+/// Chart layout: rows of 5 percent from 100 down to 0 starting below the
+/// title, and columns of 2 ambient percent after the axis labels, both 1-based
+/// as the terminal reports mouse positions
+const CHART_TOP: u16 = 2;
+const CHART_LEFT: u16 = 7;
+const ROWS: u32 = 20;
+const COLUMNS: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Left,
+    Right,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Char(char),
+    Escape,
+    /// Left button pressed at a terminal column and row
+    Click(u16, u16),
+    /// Moved with the left button held
+    Drag(u16, u16),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Nothing,
+    Changed,
+    Save,
+    Quit,
+}
+
+/// Splits terminal input into keys, including SGR mouse reports
+fn keys(mut input: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    while let Some((&first, rest)) = input.split_first() {
+        input = rest;
+        if first != 0x1b {
+            if let Some(c) = char::from_u32(first as u32) {
+                keys.push(Key::Char(c));
+            }
+            continue;
+        }
+        let Some(sequence) = input.strip_prefix(b"[") else {
+            keys.push(Key::Escape);
+            continue;
+        };
+        let end = sequence
+            .iter()
+            .position(|x| x.is_ascii_alphabetic() || *x == b'~')
+            .unwrap_or(sequence.len().saturating_sub(1));
+        let (body, last) = (&sequence[..end], sequence.get(end).copied());
+        input = &sequence[(end + 1).min(sequence.len())..];
+        match (body, last) {
+            (b"", Some(b'A')) => keys.push(Key::Up),
+            (b"", Some(b'B')) => keys.push(Key::Down),
+            (b"", Some(b'C')) => keys.push(Key::Right),
+            (b"", Some(b'D')) => keys.push(Key::Left),
+            (b"5", Some(b'~')) => keys.push(Key::PageUp),
+            (b"6", Some(b'~')) => keys.push(Key::PageDown),
+            (body, Some(b'M')) if body.starts_with(b"<") => {
+                let fields = String::from_utf8_lossy(&body[1..])
+                    .split(';')
+                    .map(|x| x.parse::<u16>().ok())
+                    .collect::<Option<Vec<_>>>();
+                match fields.as_deref() {
+                    Some(&[0, x, y]) => keys.push(Key::Click(x, y)),
+                    Some(&[32, x, y]) => keys.push(Key::Drag(x, y)),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+    keys
+}
+
+/// Curve points and the one being edited
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Editor {
+    points: Vec<(u32, u32)>,
+    selected: usize,
+}
+
+impl Editor {
+    fn new(points: &[(u32, u32)]) -> Self {
+        Self {
+            points: points.to_vec(),
+            selected: 0,
+        }
+    }
+
+    fn percent(&self, ambient: u32) -> u32 {
+        self.points
+            .iter()
+            .take_while(|(threshold, _)| *threshold <= ambient)
+            .last()
+            .or(self.points.first())
+            .map_or(0, |(_, pct)| *pct)
+    }
+
+    /// Ambient values the selected point can move between without passing
+    /// its neighbours
+    fn room(&self) -> (u32, u32) {
+        let low = match self.selected {
+            0 => 0,
+            i => self.points[i - 1].0 + 1,
+        };
+        let high = self
+            .points
+            .get(self.selected + 1)
+            .map_or(100, |(threshold, _)| threshold - 1);
+        (low, high)
+    }
+
+    fn set(&mut self, ambient: u32, pct: u32) -> Action {
+        let (low, high) = self.room();
+        let point = (ambient.clamp(low, high.max(low)), pct.min(100));
+        if self.points[self.selected] == point {
+            return Action::Nothing;
+        }
+        self.points[self.selected] = point;
+        Action::Changed
+    }
+
+    fn nudge(&mut self, ambient: i32, pct: i32) -> Action {
+        let (threshold, current) = self.points[self.selected];
+        self.set(
+            threshold.saturating_add_signed(ambient),
+            current.saturating_add_signed(pct),
+        )
+    }
+
+    /// Adds a point halfway to the next one, or past the last
+    fn add(&mut self) -> Action {
+        let (threshold, pct) = self.points[self.selected];
+        let next = self.points.get(self.selected + 1).map_or(101, |x| x.0);
+        let ambient = match next - threshold {
+            1 => return Action::Nothing,
+            room if next == 101 => threshold + (room / 2).min(10),
+            room => threshold + room / 2,
+        };
+        if ambient > 100 {
+            return Action::Nothing;
+        }
+        self.selected += 1;
+        self.points.insert(self.selected, (ambient, pct));
+        Action::Changed
+    }
+
+    fn remove(&mut self) -> Action {
+        if self.points.len() == 1 {
+            return Action::Nothing;
+        }
+        self.points.remove(self.selected);
+        self.selected = self.selected.min(self.points.len() - 1);
+        Action::Changed
+    }
+
+    /// Chart position of a terminal cell, clamped to the chart
+    fn at(x: u16, y: u16) -> (u32, u32) {
+        let column = (x.saturating_sub(CHART_LEFT) as u32).min(COLUMNS);
+        let row = (y.saturating_sub(CHART_TOP) as u32).min(ROWS);
+        (column * 100 / COLUMNS, 100 - row * 100 / ROWS)
+    }
+
+    fn key(&mut self, key: Key) -> Action {
+        match key {
+            Key::Left | Key::Char('h') => {
+                self.selected = self.selected.saturating_sub(1);
+                Action::Nothing
+            }
+            Key::Right | Key::Char('l') => {
+                self.selected = (self.selected + 1).min(self.points.len() - 1);
+                Action::Nothing
+            }
+            Key::Up | Key::Char('k') => self.nudge(0, 1),
+            Key::Down | Key::Char('j') => self.nudge(0, -1),
+            Key::PageUp | Key::Char('K') => self.nudge(0, 10),
+            Key::PageDown | Key::Char('J') => self.nudge(0, -10),
+            Key::Char('<') | Key::Char('H') => self.nudge(-1, 0),
+            Key::Char('>') | Key::Char('L') => self.nudge(1, 0),
+            Key::Char('a') => self.add(),
+            Key::Char('x') => self.remove(),
+            Key::Char('s') => Action::Save,
+            Key::Char('q') | Key::Char('\x03') | Key::Escape => Action::Quit,
+            Key::Click(x, y) => {
+                let (ambient, _) = Self::at(x, y);
+                self.selected = (0..self.points.len())
+                    .min_by_key(|&i| self.points[i].0.abs_diff(ambient))
+                    .unwrap_or(0);
+                Action::Nothing
+            }
+            Key::Drag(x, y) => {
+                let (ambient, pct) = Self::at(x, y);
+                self.set(ambient, pct)
+            }
+            Key::Char(_) => Action::Nothing,
+        }
+    }
+
+    fn curve(&self) -> Value {
+        Value::Array(
+            self.points
+                .iter()
+                .map(|(ambient, pct)| {
+                    Value::Array(vec![
+                        Value::Integer((*ambient).into()),
+                        Value::Integer((*pct).into()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn render(&self, title: &str, ambient: &str, message: &str) -> String {
+        let mut screen = format!("\x1b[H\x1b[2J{}\r\n", title);
+        let current = ambient_column(ambient);
+        for row in 0..=ROWS {
+            let level = 100 - row * 100 / ROWS;
+            match level % 25 {
+                0 => screen.push_str(&format!("{:>4}% |", level)),
+                _ => screen.push_str("      |"),
+            }
+            for column in 0..=COLUMNS {
+                let ambient = column * 100 / COLUMNS;
+                let point = self
+                    .points
+                    .iter()
+                    .position(|(x, pct)| x * COLUMNS / 100 == column && row_of(*pct) == row);
+                let filled = self.percent(ambient) >= level;
+                let cell = match point {
+                    Some(i) if i == self.selected => "\x1b[1;33m●\x1b[0m",
+                    Some(_) => "o",
+                    None if current == Some(column) && filled => "▓",
+                    None if current == Some(column) => "│",
+                    None if filled => "█",
+                    None => " ",
+                };
+                screen.push_str(cell);
+            }
+            screen.push_str("\r\n");
+        }
+        screen.push_str(&format!(
+            "      +{}\r\n       0%{:>23}{:>27}\r\n\r\n",
+            "-".repeat(COLUMNS as usize + 1),
+            "ambient 50%",
+            "100%"
+        ));
+        screen.push_str(&format!("{}\r\n", ambient));
+        for (i, (ambient, pct)) in self.points.iter().enumerate() {
+            match i == self.selected {
+                true => screen.push_str(&format!("\x1b[7m[{}, {}]\x1b[0m ", ambient, pct)),
+                false => screen.push_str(&format!("[{}, {}] ", ambient, pct)),
+            }
+        }
+        screen.push_str(
+            "\r\n\r\n←/→ select  ↑/↓ ±1%  PgUp/PgDn ±10%  </> move  a add  x remove  \
+             drag with the mouse\r\ns save  q quit without saving\r\n",
+        );
+        screen.push_str(message);
+        screen
+    }
+}
+
+fn row_of(pct: u32) -> u32 {
+    (100 - pct.min(100) + 100 / ROWS / 2) * ROWS / 100
+}
+
+/// Chart column of the ambient percent in a status line from [`describe`]
+fn ambient_column(line: &str) -> Option<u32> {
+    let pct = line
+        .strip_prefix("Ambient: ")?
+        .split('%')
+        .next()?
+        .parse::<u32>()
+        .ok()?;
+    Some(pct.min(100) * COLUMNS / 100)
+}
+
+fn describe(status: &Result<Status>) -> String {
+    match status {
+        Ok(Status {
+            ambient: Some(ambient),
+            lux: Some(lux),
+            ..
+        }) => format!("Ambient: {}% ({} lx)", ambient, lux),
+        Ok(Status {
+            ambient: Some(ambient),
+            ..
+        }) => format!("Ambient: {}%", ambient),
+        Ok(_) => "Ambient: not reported, e.g. in privacy mode".to_string(),
+        Err(e) => format!("Ambient: daemon unreachable: {}", e),
+    }
+}
+
+/// TOML table of `section`'s curve, as merged into the profile
+fn overlay(section: &str, curve: Value) -> String {
+    let mut table = Table::new();
+    table.insert(
+        section.to_string(),
+        Value::Table(Table::from_iter([("curve".to_string(), curve)])),
+    );
+    toml::to_string(&table).expect("Curve serializes")
+}
+
+/// Drops the tuning profile from `table`, switching back to `previous`
+fn restore(table: &mut Table, previous: Option<&str>) {
+    if let Some(Value::Table(profiles)) = table.get_mut("profiles") {
+        profiles.remove(PROFILE);
+        if profiles.is_empty() {
+            table.remove("profiles");
+        }
+    }
+    match previous {
+        Some(previous) => table.insert("profile".to_string(), Value::String(previous.to_string())),
+        None => table.remove("profile"),
+    };
+}
+
+/// Puts `curve` where it applies once the tuning profile is gone: the
+/// previous profile when that sets its own curve, otherwise the section
+fn save(table: &mut Table, previous: Option<&str>, section: &str, curve: Value) -> Result<()> {
+    restore(table, previous);
+    let in_profile = previous.and_then(|previous| {
+        table
+            .get("profiles")?
+            .get(previous)?
+            .get(section)?
+            .get("curve")
+            .map(|_| previous.to_string())
+    });
+    let mut target = table;
+    if let Some(previous) = &in_profile {
+        for key in ["profiles", previous] {
+            target = match target.get_mut(key) {
+                Some(Value::Table(table)) => table,
+                _ => unreachable!("checked above"),
+            };
+        }
+    }
+    match target
+        .entry(section)
+        .or_insert_with(|| Value::Table(Table::new()))
+    {
+        Value::Table(section) => {
+            section.insert("curve".to_string(), curve);
+            Ok(())
+        }
+        _ => Err(Error::Config(format!("{} isn't a table", section))),
+    }
+}
+
+/// Puts the terminal into raw mode with mouse reports on an alternate screen,
+/// and back when dropped
+struct Terminal {
+    original: libc::termios,
+}
+
+impl Terminal {
+    fn enter() -> Result<Self> {
+        // SAFETY: termios is plain data, filled in by tcgetattr
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } < 0 {
+            return Err(Error::Config(format!(
+                "tune needs a terminal: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        let original = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 0;
+        // SAFETY: termios came from tcgetattr on the same fd
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut out = io::stdout();
+        out.write_all(b"\x1b[?1049h\x1b[?25l\x1b[?1002h\x1b[?1006h")?;
+        out.flush()?;
+        Ok(Self { original })
+    }
+
+    /// Input that arrives within `timeout`, empty when there is none
+    fn read(&self, timeout: Duration) -> Result<Vec<u8>> {
+        let mut fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: one valid pollfd
+        let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as i32) };
+        if ready <= 0 {
+            return Ok(Vec::new());
+        }
+        let mut buf = [0u8; 256];
+        // SAFETY: buf is valid for its length
+        let len = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        Ok(buf[..len.max(0) as usize].to_vec())
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        let _ = out.write_all(b"\x1b[?1006l\x1b[?1002l\x1b[?25h\x1b[?1049l");
+        let _ = out.flush();
+        // SAFETY: restores the settings read in enter
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+/// Edits the screen curve, or the keyboard's, in the config file at `path`,
+/// which should be the one the daemon runs with. Edits apply right away
+/// through a profile; saving writes them to the config, quitting drops them.
+pub fn run(config: &Config, path: &Path, kbd: bool) -> Result<()> {
+    let screen = config.screen.curve();
+    let (section, curve) = match kbd {
+        true => ("kbd", &config.kbd.curve),
+        false => ("screen", &screen),
+    };
+    let previous = read_table(path)?
+        .get("profile")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let title = format!("Tuning the {} curve in {}", section, path.display());
+    let mut editor = Editor::new(curve.points());
+    let mut message = String::new();
+    let mut ambient = describe(&ControlClient::new(config).and_then(|mut x| x.ping()));
+    let mut pinged = Instant::now();
+    let mut pending: Option<Instant> = None;
+
+    let terminal = Terminal::enter()?;
+    let saved = loop {
+        io::stdout().write_all(editor.render(&title, &ambient, &message).as_bytes())?;
+        io::stdout().flush()?;
+
+        let timeout = match pending {
+            Some(_) => SETTLE,
+            None => PING_INTERVAL.saturating_sub(pinged.elapsed()),
+        };
+        let input = terminal.read(timeout)?;
+        let mut action = None;
+        for key in keys(&input) {
+            match editor.key(key) {
+                Action::Changed => pending = Some(Instant::now()),
+                Action::Nothing => (),
+                done => {
+                    action = Some(done);
+                    break;
+                }
+            }
+        }
+        match action {
+            Some(Action::Save) => break true,
+            Some(Action::Quit) => break false,
+            _ => (),
+        }
+
+        if pending.is_some_and(|x| x.elapsed() >= SETTLE) {
+            pending = None;
+            let edit = ProfileEdit {
+                name: PROFILE.to_string(),
+                overlay: overlay(section, editor.curve()),
+                activate: true,
+            };
+            message = match ControlClient::new(config).and_then(|mut x| x.edit_profile(edit)) {
+                Ok(()) => String::new(),
+                Err(e) => format!("Not applied: {}", e),
+            };
+        }
+        if pinged.elapsed() >= PING_INTERVAL {
+            ambient = describe(&ControlClient::new(config).and_then(|mut x| x.ping()));
+            pinged = Instant::now();
+        }
+    };
+    drop(terminal);
+
+    let mut table = read_table(path)?;
+    match saved {
+        true => save(&mut table, previous.as_deref(), section, editor.curve())?,
+        false => restore(&mut table, previous.as_deref()),
+    }
+    write_validated(path, &table)?;
+    match saved {
+        true => println!("Saved the {} curve to {}", section, path.display()),
+        false => println!("Left the {} curve in {} as it was", section, path.display()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_keys_and_mouse_reports() {
+        assert_eq!(
+            keys(b"k\x1b[A\x1b[6~\x1b[<0;12;5M\x1b[<32;14;6M\x1b[<0;14;6mq\x1b"),
+            vec![
+                Key::Char('k'),
+                Key::Up,
+                Key::PageDown,
+                Key::Click(12, 5),
+                Key::Drag(14, 6),
+                Key::Char('q'),
+                Key::Escape,
+            ]
+        );
+    }
+
+    #[test]
+    fn edits_stay_between_neighbours() {
+        let mut editor = Editor::new(&[(0, 5), (10, 20), (40, 30)]);
+        assert_eq!(editor.key(Key::Right), Action::Nothing);
+        for _ in 0..40 {
+            editor.key(Key::Char('>'));
+        }
+        editor.key(Key::PageUp);
+        assert_eq!(editor.points[1], (39, 30));
+        assert_eq!(editor.key(Key::Char('a')), Action::Nothing);
+
+        editor.key(Key::Left);
+        assert_eq!(editor.key(Key::Char('a')), Action::Changed);
+        assert_eq!(editor.points, [(0, 5), (19, 5), (39, 30), (40, 30)]);
+        editor.key(Key::Char('x'));
+        assert_eq!(editor.points, [(0, 5), (39, 30), (40, 30)]);
+
+        editor.key(Key::Char('l'));
+        editor.key(Key::Char('a'));
+        assert_eq!(editor.points[3], (50, 30));
+        for _ in 0..200 {
+            editor.key(Key::Char('k'));
+        }
+        assert_eq!(editor.points[3], (50, 100));
+    }
+
+    #[test]
+    fn mouse_selects_and_drags_points() {
+        let mut editor = Editor::new(&[(0, 5), (50, 20), (80, 40)]);
+        editor.key(Key::Click(CHART_LEFT + 24, CHART_TOP + 3));
+        assert_eq!(editor.selected, 1);
+        assert_eq!(
+            editor.key(Key::Drag(CHART_LEFT + 30, CHART_TOP + 10)),
+            Action::Changed
+        );
+        assert_eq!(editor.points[1], (60, 50));
+        // Past the next point and below the chart
+        editor.key(Key::Drag(CHART_LEFT + 45, CHART_TOP + 30));
+        assert_eq!(editor.points[1], (79, 0));
+    }
+
+    #[test]
+    fn saving_replaces_the_tuning_profile() {
+        let table = |contents: &str| toml::from_str::<Table>(contents).unwrap();
+        let curve = Editor::new(&[(0, 10), (50, 40)]).curve();
+        let tuning = r#"
+            profile = "tune"
+            [screen]
+            name = "intel_backlight"
+            [profiles.tune.screen]
+            curve = [[0, 1]]
+        "#;
+
+        let mut saved = table(tuning);
+        save(&mut saved, None, "screen", curve.clone()).unwrap();
+        assert_eq!(
+            saved,
+            table("[screen]\nname = \"intel_backlight\"\ncurve = [[0, 10], [50, 40]]")
+        );
+
+        let mut restored = table(&format!(
+            "{}\n[profiles.night.screen]\ncurve = [[0, 5]]",
+            tuning
+        ));
+        restore(&mut restored, Some("night"));
+        assert_eq!(restored["profile"].as_str(), Some("night"));
+        assert!(restored["profiles"].get("tune").is_none());
+
+        // A profile with its own curve keeps getting it
+        save(&mut restored, Some("night"), "screen", curve.clone()).unwrap();
+        assert_eq!(restored["profiles"]["night"]["screen"]["curve"], curve);
+        assert!(restored["screen"].get("curve").is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    process,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::{
+    channel::{bounded, Sender},
+    select,
+};
+use log::{error, info};
+
+use crate::{clock::Clock, config::WatchdogConfig};
+
+/// What the update loop is busy with, if anything
+#[derive(Default)]
+pub(crate) struct Heartbeat {
+    busy: Mutex<Option<(&'static str, Instant)>>,
+}
+
+impl Heartbeat {
+    pub(crate) fn start(&self, step: &'static str, now: Instant) {
+        *self.busy.lock().expect("Heartbeat poisoned") = Some((step, now));
+    }
+
+    pub(crate) fn finish(&self) {
+        *self.busy.lock().expect("Heartbeat poisoned") = None;
+    }
+
+    /// The step that has been running for at least `timeout`. Waiting for the
+    /// next tick or command never counts, so suspended updates aren't stalls.
+    fn stalled(&self, now: Instant, timeout: Duration) -> Option<(&'static str, Duration)> {
+        let busy = *self.busy.lock().expect("Heartbeat poisoned");
+        busy.map(|(step, since)| (step, now.duration_since(since)))
+            .filter(|(_, elapsed)| *elapsed >= timeout)
+    }
+}
+
+/// Checks the heartbeat once per tick until dropped
+pub(crate) struct Watchdog {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub(crate) fn spawn(
+        config: &WatchdogConfig,
+        tick: Duration,
+        clock: Arc<dyn Clock>,
+        heartbeat: Arc<Heartbeat>,
+    ) -> Self {
+        if config.ticks == 0 {
+            return Self {
+                stop: None,
+                handle: None,
+            };
+        }
+
+        let (stop, stop_receiver) = bounded(0);
+        let timeout = tick * config.ticks;
+        let abort = config.abort;
+        let ticker = clock.ticker(tick);
+
+        let handle = thread::spawn(move || {
+            let mut reported = false;
+            loop {
+                select! {
+                    recv(stop_receiver) -> _ => break,
+                    recv(ticker) -> _ => match heartbeat.stalled(clock.now(), timeout) {
+                        Some((step, elapsed)) if !reported => {
+                            error!(
+                                "Update loop stuck in {} for {:.1}s",
+                                step,
+                                elapsed.as_secs_f64()
+                            );
+                            if abort {
+                                error!("Aborting so the service manager can restart us");
+                                process::abort();
+                            }
+                            reported = true;
+                        }
+                        Some(_) => (),
+                        None => {
+                            if reported {
+                                info!("Update loop recovered");
+                            }
+                            reported = false;
+                        }
+                    },
+                }
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_loop_never_stalls() {
+        let heartbeat = Heartbeat::default();
+        let now = Instant::now();
+        assert_eq!(heartbeat.stalled(now, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn busy_step_stalls_after_timeout() {
+        let heartbeat = Heartbeat::default();
+        let start = Instant::now();
+        heartbeat.start("tick", start);
+
+        let timeout = Duration::from_secs(30);
+        assert_eq!(
+            heartbeat.stalled(start + Duration::from_secs(29), timeout),
+            None
+        );
+        assert_eq!(
+            heartbeat.stalled(start + timeout, timeout),
+            Some(("tick", timeout))
+        );
+
+        heartbeat.finish();
+        assert_eq!(heartbeat.stalled(start + timeout, timeout), None);
+    }
+}
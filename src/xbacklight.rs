@@ -0,0 +1,116 @@
+//! Accepts xbacklight's arguments, e.g. `-inc 10` or `=50`, so existing
+//! keybindings can drive the daemon instead
+
+use crate::{
+    config::Config, control_client::ControlClient, sysfs::Sysfs, Error, Result, SCREEN_SUBSYSTEM,
+};
+
+/// What an xbacklight command line asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Increase(i8),
+    Decrease(i8),
+    Set(u8),
+    Get,
+}
+
+/// xbacklight options taking a value that don't apply to the daemon
+const IGNORED: &[&str] = &["-time", "-steps", "-fps", "-display", "-ctrl"];
+
+fn percent(value: Option<&str>) -> Result<u8> {
+    let value = value
+        .ok_or_else(|| Error::Config("xbacklight option is missing its percent".to_string()))?;
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|x| (0.0..=100.0).contains(x))
+        .map(|x| x.round() as u8)
+        .ok_or_else(|| Error::Config(format!("{} is not a percent", value)))
+}
+
+/// The last action on the command line wins, as with xbacklight, and no
+/// action at all prints the brightness
+pub fn parse(args: &[String]) -> Result<Action> {
+    let mut action = Action::Get;
+    let mut args = args.iter().map(String::as_str);
+    while let Some(arg) = args.next() {
+        action = match arg {
+            "-inc" => Action::Increase(percent(args.next())? as i8),
+            "-dec" => Action::Decrease(percent(args.next())? as i8),
+            "-set" => Action::Set(percent(args.next())?),
+            "-get" | "-getf" => Action::Get,
+            arg if IGNORED.contains(&arg) => {
+                args.next();
+                continue;
+            }
+            // Shorthands: +10, -10, and =50
+            arg => match (
+                arg.strip_prefix('+'),
+                arg.strip_prefix('-'),
+                arg.strip_prefix('='),
+            ) {
+                (Some(value), _, _) => Action::Increase(percent(Some(value))? as i8),
+                (_, Some(value), _) => Action::Decrease(percent(Some(value))? as i8),
+                (_, _, Some(value)) => Action::Set(percent(Some(value))?),
+                _ => {
+                    return Err(Error::Config(format!(
+                        "Unknown xbacklight argument {:?}",
+                        arg
+                    )))
+                }
+            },
+        };
+    }
+    Ok(action)
+}
+
+/// Sends the command to the daemon, or prints the screen brightness percent
+pub fn run(config: &Config, args: &[String]) -> Result<()> {
+    match parse(args)? {
+        Action::Increase(amount) => ControlClient::new(config)?.increase(amount),
+        Action::Decrease(amount) => ControlClient::new(config)?.decrease(amount),
+        Action::Set(percent) => ControlClient::new(config)?.set(percent),
+        Action::Get => {
+            let sysfs = Sysfs::default();
+            let device = sysfs.device(
+                SCREEN_SUBSYSTEM,
+                &config.screen.backlight(&sysfs)?,
+                config.screen.backend,
+            )?;
+            let percent = device.brightness()? as f64 * 100.0 / device.max_brightness.max(1) as f64;
+            println!("{:.6}", percent);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parses_options_and_shorthands() {
+        assert_eq!(parse(&args("-inc 10")).unwrap(), Action::Increase(10));
+        assert_eq!(
+            parse(&args("-dec 5.4 -time 200")).unwrap(),
+            Action::Decrease(5)
+        );
+        assert_eq!(parse(&args("-steps 1 -set 50")).unwrap(), Action::Set(50));
+        assert_eq!(parse(&args("+20")).unwrap(), Action::Increase(20));
+        assert_eq!(parse(&args("-15")).unwrap(), Action::Decrease(15));
+        assert_eq!(parse(&args("=0")).unwrap(), Action::Set(0));
+        assert_eq!(parse(&args("")).unwrap(), Action::Get);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&args("-inc")).is_err());
+        assert!(parse(&args("-set 150")).is_err());
+        assert!(parse(&args("-bogus")).is_err());
+        assert!(parse(&args("brighter")).is_err());
+    }
+}
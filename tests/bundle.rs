@@ -0,0 +1,53 @@
+use std::fs;
+
+use iio_ambient_brightness::{bundle, config::Config};
+use tempfile::TempDir;
+
+#[test]
+fn exported_bundles_import_on_their_own() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("common.toml"), "min_delta = 5\n").unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(
+        &path,
+        "include = \"common.toml\"\nsuspend_after = 60\n[profiles.night]\nmin_delta = 20\n",
+    )
+    .unwrap();
+
+    let mut out = Vec::new();
+    bundle::export(Some(&path), &mut out).unwrap();
+    let exported: toml::Table = toml::from_str(&String::from_utf8(out.clone()).unwrap()).unwrap();
+    assert_eq!(exported["min_delta"].as_integer(), Some(5));
+    assert!(exported.get("include").is_none());
+    assert!(exported["profiles"].get("night").is_some());
+
+    // Onto another machine, with its own config
+    let other = TempDir::new().unwrap();
+    let bundle_path = other.path().join("bundle.toml");
+    fs::write(&bundle_path, &out).unwrap();
+    let target = other.path().join("config.toml");
+    fs::write(&target, "min_delta = 1\n").unwrap();
+    bundle::import(&bundle_path, &target).unwrap();
+
+    let imported: toml::Table = toml::from_str(&fs::read_to_string(&target).unwrap()).unwrap();
+    assert_eq!(imported["min_delta"].as_integer(), Some(5));
+    assert_eq!(imported["suspend_after"].as_integer(), Some(60));
+    assert!(imported.get("bundle").is_none());
+    assert_eq!(
+        fs::read_to_string(target.with_extension("toml.bak")).unwrap(),
+        "min_delta = 1\n"
+    );
+    Config::load(Some(&target)).unwrap();
+}
+
+#[test]
+fn plain_configs_are_not_bundles() {
+    let dir = TempDir::new().unwrap();
+    let bundle_path = dir.path().join("bundle.toml");
+    fs::write(&bundle_path, "min_delta = 5\n").unwrap();
+    let target = dir.path().join("config.toml");
+    fs::write(&target, "min_delta = 1\n").unwrap();
+
+    assert!(bundle::import(&bundle_path, &target).is_err());
+    assert_eq!(fs::read_to_string(&target).unwrap(), "min_delta = 1\n");
+}
@@ -0,0 +1,55 @@
+use std::{cell::Cell, fs, io::Cursor, time::Duration};
+
+use iio_ambient_brightness::{calibrate::calibrate, config::Config, sensor::Sensor};
+use tempfile::TempDir;
+
+/// Reads dark, then indoor, then daylight values, ten readings each
+struct Conditions(Cell<u32>);
+
+impl Sensor for Conditions {
+    fn read(&self) -> iio_ambient_brightness::Result<f64> {
+        let reads = self.0.get();
+        self.0.set(reads + 1);
+        Ok(match reads / 10 {
+            0 => 1.0,
+            1 => 1000.0,
+            _ => 2500000.0,
+        })
+    }
+}
+
+#[test]
+fn calibration_writes_curves() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, "min_delta = 5\n").unwrap();
+
+    // Suggestions are taken in the dark; a bad answer is asked again
+    let answers = "\n\n\n\n150\n60\n30\n\n20\n0\n";
+    let mut out = Vec::new();
+    calibrate(
+        &Conditions(Cell::new(0)),
+        &Config::default(),
+        &path,
+        &mut Cursor::new(answers),
+        &mut out,
+        Duration::ZERO,
+    )
+    .unwrap();
+
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("Ambient: 0%"));
+    assert!(out.contains("Ambient: 50%"));
+    assert!(out.contains("Ambient: 100%"));
+    assert!(out.contains("Enter a percent from 0 to 100"));
+
+    let written: toml::Table = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    let expected: toml::Table = toml::from_str(
+        "min_delta = 5\n\
+         [screen]\ncurve = [[0, 5], [25, 60], [75, 20]]\n\
+         [kbd]\ncurve = [[0, 100], [25, 30], [75, 0]]\n",
+    )
+    .unwrap();
+    assert_eq!(written, expected);
+    Config::load(Some(&path)).unwrap();
+}
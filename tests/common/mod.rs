@@ -0,0 +1,157 @@
+//! Fake `/sys` tree and scripted sensor shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use iio_ambient_brightness::{clock::MockClock, config::Config, sensor::Sensor, Result};
+use tempfile::TempDir;
+
+/// Brightest raw reading the pipeline distinguishes
+pub const BRIGHT: f64 = 2500000.0;
+pub const DARK: f64 = 1.0;
+
+pub const KBD: &str = "asus::kbd_backlight";
+pub const SCREEN: &str = "intel_backlight";
+
+/// Temp directory laid out like `/sys/class/{leds,backlight}`
+pub struct FakeSysfs {
+    dir: TempDir,
+}
+
+impl FakeSysfs {
+    /// A keyboard backlight with 3 levels at level 1 and a 1000 step screen at 500
+    pub fn new() -> Self {
+        let sysfs = Self {
+            dir: TempDir::new().expect("create temp dir"),
+        };
+        sysfs.add_device("leds", KBD, 3, 1);
+        sysfs.add_device("backlight", SCREEN, 1000, 500);
+        sysfs
+    }
+
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn add_device(&self, subsystem: &str, name: &str, max_brightness: u32, brightness: u32) {
+        let dir = self.device_dir(subsystem, name);
+        fs::create_dir_all(&dir).expect("create device dir");
+        fs::write(dir.join("max_brightness"), format!("{}\n", max_brightness))
+            .expect("write max_brightness");
+        fs::write(dir.join("brightness"), format!("{}\n", brightness)).expect("write brightness");
+    }
+
+    pub fn remove_device(&self, subsystem: &str, name: &str) {
+        fs::remove_dir_all(self.device_dir(subsystem, name)).expect("remove device dir");
+    }
+
+    /// Raw readings of an IIO accelerometer, created on first use
+    pub fn set_accel(&self, axes: [i32; 3]) {
+        let dir = self.dir.path().join("bus/iio/devices/iio:device0");
+        fs::create_dir_all(&dir).expect("create accelerometer dir");
+        fs::write(dir.join("name"), "accel_3d\n").expect("write name");
+        for (axis, value) in ["x", "y", "z"].iter().zip(axes) {
+            fs::write(
+                dir.join(format!("in_accel_{}_raw", axis)),
+                format!("{}\n", value),
+            )
+            .expect("write axis");
+        }
+    }
+
+    pub fn brightness(&self, subsystem: &str, name: &str) -> u32 {
+        let path = self.device_dir(subsystem, name).join("brightness");
+        loop {
+            let brightness = fs::read_to_string(&path).expect("read brightness");
+            // Caught between the controller truncating and writing the file
+            if brightness.is_empty() {
+                thread::yield_now();
+                continue;
+            }
+            return brightness.trim().parse().expect("parse brightness");
+        }
+    }
+
+    /// Waits for a device to reach `expected`, for use while the controller
+    /// runs on another thread
+    pub fn wait_for(&self, subsystem: &str, name: &str, expected: u32) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let brightness = self.brightness(subsystem, name);
+            if brightness == expected {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "{}/{} stayed at {}, expected {}",
+                subsystem,
+                name,
+                brightness,
+                expected
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Writes `contents` as a config file and loads it
+    pub fn config(&self, contents: &str) -> Config {
+        let path = self.dir.path().join("config.toml");
+        fs::write(&path, contents).expect("write config");
+        Config::load(Some(&path)).expect("load config")
+    }
+
+    fn device_dir(&self, subsystem: &str, name: &str) -> PathBuf {
+        self.dir.path().join("class").join(subsystem).join(name)
+    }
+}
+
+/// Config that follows the sensor without smoothing
+pub const UNFILTERED: &str = r#"
+[filter]
+type = "asymmetric"
+rise_window = 1
+fall_window = 1
+"#;
+
+/// How long the controller waits to merge more offset commands into one
+pub const BURST: Duration = Duration::from_millis(100);
+
+/// Ends the burst of offset commands the controller is merging, once it's
+/// waiting on one
+pub fn end_burst(clock: &MockClock) {
+    while clock.timers() == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    clock.advance(BURST);
+}
+
+/// Sensor returning whatever the test last set through its handle
+#[derive(Clone)]
+pub struct ScriptedSensor {
+    value: Arc<Mutex<f64>>,
+}
+
+impl ScriptedSensor {
+    pub fn new(value: f64) -> Self {
+        Self {
+            value: Arc::new(Mutex::new(value)),
+        }
+    }
+
+    pub fn set(&self, value: f64) {
+        *self.value.lock().unwrap() = value;
+    }
+}
+
+impl Sensor for ScriptedSensor {
+    fn read(&self) -> Result<f64> {
+        Ok(*self.value.lock().unwrap())
+    }
+}
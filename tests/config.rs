@@ -0,0 +1,36 @@
+use std::{fs, thread, time::Duration};
+
+use iio_ambient_brightness::config_watch;
+use tempfile::TempDir;
+
+#[test]
+fn watch_sends_valid_changes() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, "min_delta = 1\n").unwrap();
+    let reloads = config_watch::watch(&path);
+
+    // The watch starts on its own thread, so keep saving until it notices
+    let mut reloaded = None;
+    for _ in 0..50 {
+        fs::write(&path, "min_delta = 2\n").unwrap();
+        reloaded = reloads.recv_timeout(Duration::from_millis(100)).ok();
+        if reloaded.is_some() {
+            break;
+        }
+    }
+    assert!(reloaded.is_some());
+    while reloads.try_recv().is_ok() {}
+
+    // Broken edits and other files are skipped
+    fs::write(&path, "min_delta = \"lots\"\n").unwrap();
+    fs::write(dir.path().join("other.toml"), "min_delta = 3\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(reloads.try_recv().is_err());
+
+    // Saving by renaming over the file counts too
+    let saved = dir.path().join("config.toml.new");
+    fs::write(&saved, "min_delta = 4\n").unwrap();
+    fs::rename(&saved, &path).unwrap();
+    assert!(reloads.recv_timeout(Duration::from_secs(5)).is_ok());
+}
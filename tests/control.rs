@@ -0,0 +1,580 @@
+#![cfg(feature = "control")]
+
+use std::{sync::Arc, thread, time::Duration};
+
+use iio_ambient_brightness::{
+    clock::MockClock,
+    command::{Appearance, Command, OutputKind},
+    config::Config,
+    control_client::ControlClient,
+    control_server::ControlServer,
+    health::{Health, Mode, Status},
+    protocol::{ProfileEdit, Request, Response, PROTOCOL_VERSION},
+};
+use tempfile::TempDir;
+
+fn health() -> Arc<Health> {
+    Arc::new(Health::new(Arc::new(MockClock::new())))
+}
+
+#[test]
+fn client_commands_reach_the_controller() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let (server, command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    type Send = fn(&mut ControlClient) -> iio_ambient_brightness::Result<()>;
+    let cases: [(Send, Command); 13] = [
+        (|client| client.idle(), Command::Idle),
+        (|client| client.active(), Command::Active),
+        (|client| client.increase(5), Command::Increase(5)),
+        (|client| client.decrease(-3), Command::Decrease(-3)),
+        (|client| client.set(40), Command::Set(40)),
+        (
+            |client| client.nudge(OutputKind::Kbd, -33),
+            Command::Nudge(OutputKind::Kbd, -33),
+        ),
+        (
+            |client| client.set_output(OutputKind::Kbd, 100),
+            Command::SetOutput(OutputKind::Kbd, 100),
+        ),
+        (
+            |client| client.disable(OutputKind::Kbd),
+            Command::Disable(OutputKind::Kbd),
+        ),
+        (
+            |client| client.enable(OutputKind::Screen),
+            Command::Enable(OutputKind::Screen),
+        ),
+        (
+            |client| client.appearance(Appearance::Dark),
+            Command::Appearance(Appearance::Dark),
+        ),
+        (|client| client.resync(), Command::Resync),
+        (
+            |client| client.snapshot("talk"),
+            Command::Snapshot("talk".to_string()),
+        ),
+        (
+            |client| client.restore("talk"),
+            Command::Restore("talk".to_string()),
+        ),
+    ];
+    for (send, expected) in cases {
+        // The server reads a single command per connection
+        let mut client = ControlClient::connect(&socket_path).unwrap();
+        send(&mut client).unwrap();
+        let command = command_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(command, expected);
+    }
+    let mut client = ControlClient::connect(&socket_path).unwrap();
+    assert!(client.snapshot("two words").is_err());
+    assert!(client.restore("").is_err());
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn unknown_opcodes_are_ignored() {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let (server, command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    UnixStream::connect(&socket_path)
+        .unwrap()
+        .write_all(&[42])
+        .unwrap();
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .active()
+        .unwrap();
+
+    let command = command_receiver
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(command, Command::Active);
+    assert!(command_receiver.try_recv().is_err());
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn malformed_requests_dont_stop_the_server() {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let audit_path = dir.path().join("audit.log");
+    let config: Config = toml::from_str(&format!(
+        "[control]\naudit = {:?}",
+        audit_path.display().to_string()
+    ))
+    .unwrap();
+    let (server, command_receiver) = ControlServer::bind(&socket_path, &config, health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    // Nothing at all, each command opcode without its argument, an unknown
+    // output, profile edits cut off in the name, before the overlay, and with
+    // a bad name, and snapshot names that are empty or have spaces
+    let requests: [&[u8]; 12] = [
+        &[],
+        &[2],
+        &[3],
+        &[5],
+        &[8],
+        &[9],
+        &[9, 7],
+        &[7, 1, 5, b'n', b'i'],
+        &[7, 1, 1, b'n', 0],
+        &[7, 1, 1, 0xff, 0, 0],
+        &[12, 0],
+        &[13, 3, b'a', b' ', b'b'],
+    ];
+    for request in requests {
+        UnixStream::connect(&socket_path)
+            .unwrap()
+            .write_all(request)
+            .unwrap();
+    }
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .set(30)
+        .unwrap();
+
+    assert_eq!(
+        command_receiver.recv_timeout(Duration::from_secs(5)),
+        Ok(Command::Set(30))
+    );
+    assert!(command_receiver.try_recv().is_err());
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let lines = audit.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), requests.len() + 1);
+    assert!(lines[..requests.len()]
+        .iter()
+        .all(|line| line.contains(" failed: ")));
+    assert!(lines[requests.len()].ends_with(" command set 30"));
+}
+
+#[test]
+fn slow_clients_dont_hold_up_others() {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let (server, command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    // Half of an increase, with the amount still to come
+    let mut slow = UnixStream::connect(&socket_path).unwrap();
+    slow.write_all(&[2]).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .ping()
+        .unwrap();
+    assert!(command_receiver.try_recv().is_err());
+
+    slow.write_all(&[5]).unwrap();
+    let command = command_receiver
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(command, Command::Increase(5));
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn long_requests_are_cut_off() {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let audit_path = dir.path().join("audit.log");
+    let config: Config = toml::from_str(&format!(
+        "[control]\naudit = {:?}",
+        audit_path.display().to_string()
+    ))
+    .unwrap();
+    let (server, _command_receiver) = ControlServer::bind(&socket_path, &config, health()).unwrap();
+    let stopper = server.stopper();
+
+    // Queued before the server runs, so it's all read at once: a profile edit
+    // that keeps going past its longest name and overlay
+    let mut long = UnixStream::connect(&socket_path).unwrap();
+    let mut request = vec![7, 0, 255];
+    request.resize(70_000, b'#');
+    long.write_all(&request).unwrap();
+    let handle = server.run();
+
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .ping()
+        .unwrap();
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    assert!(audit
+        .lines()
+        .next()
+        .unwrap()
+        .ends_with(" failed: Control protocol error: Request too long"));
+}
+
+#[test]
+fn commands_dont_wait_for_the_controller() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let (server, command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    // Nothing takes these until all are sent, and pings still get answers
+    for _ in 0..3 {
+        ControlClient::connect(&socket_path)
+            .unwrap()
+            .increase(5)
+            .unwrap();
+    }
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .ping()
+        .unwrap();
+    for _ in 0..3 {
+        let command = command_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(command, Command::Increase(5));
+    }
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn connections_past_the_limit_are_closed() {
+    use std::{io::Read, os::unix::net::UnixStream};
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let (server, _command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    let idle: Vec<_> = (0..64)
+        .map(|_| UnixStream::connect(&socket_path).unwrap())
+        .collect();
+    let mut extra = UnixStream::connect(&socket_path).unwrap();
+    extra
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    assert_eq!(extra.read(&mut [0; 1]).unwrap(), 0);
+
+    // Room frees up once the idle ones go
+    drop(idle);
+    thread::sleep(Duration::from_millis(50));
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .ping()
+        .unwrap();
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn ping_reports_health() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let clock = Arc::new(MockClock::new());
+    let health = Arc::new(Health::new(clock.clone()));
+    let (server, command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    clock.advance(Duration::from_millis(1500));
+    let status = ControlClient::connect(&socket_path)
+        .unwrap()
+        .ping()
+        .unwrap();
+    assert_eq!(
+        status,
+        Status {
+            uptime: Duration::from_millis(1500),
+            last_read: None,
+            last_write: None,
+            sensor_errors: 0,
+            write_errors: 0,
+            sensor_stuck: false,
+            failing_outputs: 0,
+            ambient: None,
+            lux: None,
+            mode: Mode::Active,
+            mode_for: Duration::from_millis(1500),
+            adjustments: 0,
+            output_errors: 0,
+            last_adjusted: Vec::new(),
+            levels: Vec::new(),
+        }
+    );
+    assert!(command_receiver.try_recv().is_err());
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn typed_requests_get_typed_responses() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let (server, command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    let request = Request::Command(Command::Increase(7));
+    let response = ControlClient::connect(&socket_path)
+        .unwrap()
+        .request(request)
+        .unwrap();
+    assert_eq!(response, Response::Sent);
+    assert_eq!(
+        command_receiver.recv_timeout(Duration::from_secs(5)),
+        Ok(Command::Increase(7))
+    );
+
+    let response = ControlClient::connect(&socket_path)
+        .unwrap()
+        .request(Request::Ping)
+        .unwrap();
+    assert!(matches!(response, Response::Status(status) if status.sensor_errors == 0));
+
+    // Refused before anything is sent, rather than framed with a wrapped length
+    let request = Request::Profile(ProfileEdit {
+        name: "night".to_string(),
+        overlay: "#".repeat(70_000),
+        activate: false,
+    });
+    assert!(ControlClient::connect(&socket_path)
+        .unwrap()
+        .request(request)
+        .is_err());
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn version_reports_build_and_sensor() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let config: Config = toml::from_str("[sensor]\ntype = \"applesmc\"\n").unwrap();
+    let (server, command_receiver) = ControlServer::bind(&socket_path, &config, health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    let version = ControlClient::connect(&socket_path)
+        .unwrap()
+        .version()
+        .unwrap();
+    assert_eq!(version.daemon, env!("CARGO_PKG_VERSION"));
+    assert_eq!(version.protocol, PROTOCOL_VERSION);
+    assert_eq!(version.sensor, "applesmc");
+    assert!(version.features.iter().any(|x| x == "control"));
+    assert_eq!(
+        version.features.iter().any(|x| x == "kbd"),
+        cfg!(feature = "kbd")
+    );
+    assert!(command_receiver.try_recv().is_err());
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn profile_edits_are_saved_to_the_config() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(&config_path, "min_delta = 5\n").unwrap();
+    let (server, _command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.config_file(Some(config_path.clone())).run();
+
+    let edit = |overlay: &str| ProfileEdit {
+        name: "night".to_string(),
+        overlay: overlay.to_string(),
+        activate: true,
+    };
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .edit_profile(edit("screen = { curve = [[0, 5], [50, 20]] }"))
+        .unwrap();
+    let saved: toml::Table =
+        toml::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+    assert_eq!(saved["profile"].as_str(), Some("night"));
+    assert!(saved["profiles"]["night"]["screen"].get("curve").is_some());
+    Config::load(Some(&config_path)).unwrap();
+
+    // Edits that don't make a valid config leave the file alone
+    let response = ControlClient::connect(&socket_path)
+        .unwrap()
+        .request(Request::Profile(edit("screen = { curve = [] }")))
+        .unwrap();
+    assert!(matches!(response, Response::Profile(Err(e)) if e.contains("curve")));
+    let unchanged: toml::Table =
+        toml::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+    assert_eq!(saved, unchanged);
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[cfg(feature = "async-client")]
+#[test]
+fn async_client_talks_to_the_server() {
+    use futures_lite::future::block_on;
+    use iio_ambient_brightness::async_control_client::AsyncControlClient;
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let (server, command_receiver) =
+        ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    block_on(async {
+        let mut client = AsyncControlClient::connect(&socket_path).await.unwrap();
+        client.send(Command::Decrease(2)).await.unwrap();
+        let status = AsyncControlClient::connect(&socket_path)
+            .await
+            .unwrap()
+            .ping()
+            .await
+            .unwrap();
+        assert!(!status.sensor_stuck);
+    });
+    assert_eq!(
+        command_receiver.recv_timeout(Duration::from_secs(5)),
+        Ok(Command::Decrease(2))
+    );
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn stale_socket_is_replaced() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    drop(ControlServer::bind(&socket_path, &Config::default(), health()).unwrap());
+
+    assert!(socket_path.exists());
+    ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+}
+
+#[test]
+fn other_users_socket_is_kept() {
+    use std::os::unix::fs::lchown;
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    drop(ControlServer::bind(&socket_path, &Config::default(), health()).unwrap());
+    // Handing the socket to nobody needs root
+    if lchown(&socket_path, Some(65534), None).is_err() {
+        return;
+    }
+
+    assert!(ControlServer::bind(&socket_path, &Config::default(), health()).is_err());
+    assert!(socket_path.exists());
+}
+
+#[test]
+fn socket_mode_and_group_are_applied() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let gid = std::fs::metadata(dir.path()).unwrap().gid();
+    let config = Config::default().socket_permissions(Some(0o660), Some(gid.to_string()));
+    let _server = ControlServer::bind(&socket_path, &config, health()).unwrap();
+
+    let metadata = std::fs::metadata(&socket_path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+    assert_eq!(metadata.gid(), gid);
+}
+
+#[test]
+fn socket_is_private_by_default() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let _server = ControlServer::bind(&socket_path, &Config::default(), health()).unwrap();
+
+    let metadata = std::fs::metadata(&socket_path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn audit_log_records_requests() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("ambient_brightness.sock");
+    let audit_path = dir.path().join("audit.log");
+    let config: Config = toml::from_str(&format!(
+        "[control]\naudit = {:?}",
+        audit_path.display().to_string()
+    ))
+    .unwrap();
+    let (server, command_receiver) = ControlServer::bind(&socket_path, &config, health()).unwrap();
+    let stopper = server.stopper();
+    let handle = server.run();
+
+    let mut client = ControlClient::connect(&socket_path).unwrap();
+    client.increase(5).unwrap();
+    command_receiver
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap();
+    ControlClient::connect(&socket_path)
+        .unwrap()
+        .ping()
+        .unwrap();
+
+    stopper.stop().unwrap();
+    handle.join().unwrap().unwrap();
+
+    let uid = std::fs::metadata(&socket_path).unwrap().uid();
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let lines = audit.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(&format!(" uid={} ", uid)));
+    assert!(lines[0].ends_with(" command increase 5"));
+    assert!(lines[1].ends_with(" ping"));
+}
@@ -0,0 +1,36 @@
+#![cfg(all(feature = "control", feature = "kbd", feature = "screen"))]
+
+mod common;
+
+use std::thread;
+
+use common::{FakeSysfs, ScriptedSensor, DARK, KBD, SCREEN, UNFILTERED};
+use crossbeam::channel::bounded;
+use iio_ambient_brightness::{controller::Builder, helper::Helper};
+
+#[test]
+fn user_instance_writes_through_the_helper() {
+    let sysfs = FakeSysfs::new();
+    let socket = sysfs.root().join("helper.sock");
+    let config = sysfs.config(&format!(
+        "{}\n[kbd]\nbackend = \"helper\"\n\n[screen]\nbackend = \"helper\"\n\n[helper]\nsocket = {:?}\n",
+        UNFILTERED, socket
+    ));
+    let helper = Helper::bind(&socket, &config)
+        .unwrap()
+        .sysfs_root(sysfs.root());
+    let (close, closed) = bounded(1);
+    let helper = thread::spawn(move || helper.run(closed));
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once()
+        .unwrap();
+    sysfs.wait_for("leds", KBD, 3);
+    sysfs.wait_for("backlight", SCREEN, 50);
+
+    close.send(()).unwrap();
+    helper.join().unwrap().unwrap();
+    assert!(!socket.exists());
+}
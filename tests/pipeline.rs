@@ -0,0 +1,1021 @@
+#![cfg(all(feature = "kbd", feature = "screen"))]
+
+mod common;
+
+use std::{fs, io::Read, os::fd::FromRawFd, sync::Arc, thread, time::Duration};
+
+use common::{end_burst, FakeSysfs, ScriptedSensor, BRIGHT, DARK, KBD, SCREEN, UNFILTERED};
+use crossbeam::channel::bounded;
+use iio_ambient_brightness::{
+    clock::MockClock,
+    command::{Command, OutputKind},
+    controller::Builder,
+    health::{Health, Mode},
+};
+
+#[test]
+fn once_applies_dark_levels() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once()
+        .unwrap();
+
+    assert_eq!(sysfs.brightness("leds", KBD), 3);
+    assert_eq!(sysfs.brightness("backlight", SCREEN), 50);
+}
+
+#[test]
+fn once_applies_bright_levels() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(BRIGHT)))
+        .once()
+        .unwrap();
+
+    assert_eq!(sysfs.brightness("leds", KBD), 0);
+    assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+}
+
+#[test]
+fn once_keeps_within_limits() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!(
+        "{}\n[limits]\nscreen = {{ min = 30 }}\nkbd = {{ max = 2 }}\n",
+        UNFILTERED
+    ));
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once()
+        .unwrap();
+
+    assert_eq!(sysfs.brightness("leds", KBD), 2);
+    assert_eq!(sysfs.brightness("backlight", SCREEN), 300);
+}
+
+#[test]
+#[cfg(feature = "sysfs")]
+fn once_combines_broadband_and_ir() {
+    let sysfs = FakeSysfs::new();
+    let dev = sysfs.root().join("bus/iio/devices/iio:device0");
+    fs::create_dir_all(&dev).unwrap();
+    fs::write(dev.join("name"), "tsl2591\n").unwrap();
+    fs::write(dev.join("in_illuminance_input"), "100000\n").unwrap();
+    fs::write(dev.join("in_intensity_both_raw"), "1000\n").unwrap();
+    fs::write(dev.join("in_intensity_ir_raw"), "500\n").unwrap();
+    let config = sysfs.config(&format!(
+        "{}\n[sensor]\ntype = \"sysfs\"\nir = \"intensity_ir\"\n",
+        UNFILTERED
+    ));
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .once()
+        .unwrap();
+
+    // Lands where a sensor reading the combined lux does
+    let expected = FakeSysfs::new();
+    Builder::new(&expected.config(UNFILTERED))
+        .sysfs_root(expected.root())
+        .sensor(Box::new(ScriptedSensor::new(250.0)))
+        .once()
+        .unwrap();
+    assert_eq!(
+        sysfs.brightness("backlight", SCREEN),
+        expected.brightness("backlight", SCREEN)
+    );
+    assert_ne!(sysfs.brightness("backlight", SCREEN), 500);
+}
+
+#[test]
+fn once_tracks_health() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let clock = Arc::new(MockClock::new());
+    let health = Arc::new(Health::new(clock.clone()));
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .clock(clock.clone())
+        .health(health.clone())
+        .once()
+        .unwrap();
+    clock.advance(Duration::from_secs(2));
+
+    let status = health.status();
+    assert_eq!(status.last_read, Some(Duration::from_secs(2)));
+    assert_eq!(status.last_write, Some(Duration::from_secs(2)));
+    assert_eq!((status.sensor_errors, status.write_errors), (0, 0));
+    assert_eq!(status.ambient, Some(0));
+    assert_eq!(status.lux, Some(DARK as u32));
+    assert_eq!(status.mode, Mode::Active);
+    // Both the keyboard and the screen moved for the dark room
+    assert_eq!((status.adjustments, status.output_errors), (2, 0));
+    assert_eq!(
+        status.last_adjusted,
+        [
+            (KBD.to_string(), Duration::from_secs(2)),
+            (SCREEN.to_string(), Duration::from_secs(2))
+        ]
+    );
+    let levels = status
+        .levels
+        .iter()
+        .map(|x| (x.name.as_str(), x.percent, x.offset))
+        .collect::<Vec<_>>();
+    assert_eq!(levels, [(KBD, 100, 0), (SCREEN, 5, 0)]);
+}
+
+#[test]
+fn once_adjusts_the_rest_when_an_output_fails() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let clock = Arc::new(MockClock::new());
+    let health = Arc::new(Health::new(clock.clone()));
+    // The screen can't be adjusted without reading its current level
+    fs::write(
+        sysfs
+            .root()
+            .join("class/backlight")
+            .join(SCREEN)
+            .join("brightness"),
+        "unknown\n",
+    )
+    .unwrap();
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .clock(clock)
+        .health(health.clone())
+        .once()
+        .unwrap();
+
+    assert_eq!(sysfs.brightness("leds", KBD), 3);
+    assert_eq!(health.status().failing_outputs, 1);
+}
+
+#[test]
+fn once_drives_configured_leds() {
+    let sysfs = FakeSysfs::new();
+    sysfs.add_device("leds", "input3::capslock", 255, 0);
+    let config = sysfs.config(&format!(
+        r#"
+        [[led]]
+        name = "input3::capslock"
+        curve = [[0, 100], [50, 0]]
+        {}"#,
+        UNFILTERED
+    ));
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once()
+        .unwrap();
+
+    assert_eq!(sysfs.brightness("leds", "input3::capslock"), 255);
+}
+
+#[test]
+fn once_skips_powered_off_screen() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!(
+        r#"
+        [screen.power]
+        command = ["sh", "-c", "echo 'eDP-1 off'"]
+        output = "eDP-1"
+        {}"#,
+        UNFILTERED
+    ));
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once()
+        .unwrap();
+
+    assert_eq!(sysfs.brightness("leds", KBD), 3);
+    assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+}
+
+#[test]
+fn once_reports_dark_environment() {
+    let sysfs = FakeSysfs::new();
+    let events = sysfs.root().join("environment");
+    let config = sysfs.config(&format!(
+        r#"
+        [environment]
+        command = ["sh", "-c", "echo $IIO_AMBIENT_ENVIRONMENT > {}"]
+        {}"#,
+        events.display(),
+        UNFILTERED
+    ));
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once()
+        .unwrap();
+
+    // The command runs in the background
+    for _ in 0..100 {
+        if fs::read_to_string(&events).is_ok_and(|x| x.ends_with('\n')) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(fs::read_to_string(&events).unwrap(), "dark\n");
+}
+
+#[test]
+fn dry_run_leaves_devices_alone() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+
+    Builder::new(&config)
+        .dry_run(true)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once()
+        .unwrap();
+
+    assert_eq!(sysfs.brightness("leds", KBD), 1);
+    assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+}
+
+#[test]
+fn missing_keyboard_backlight_is_an_error() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!(
+        "[kbd]\nname = \"missing::kbd_backlight\"\n{}",
+        UNFILTERED
+    ));
+
+    let result = Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .once();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn run_applies_config_reloads() {
+    let sysfs = FakeSysfs::new();
+    sysfs.add_device("leds", "input3::capslock", 100, 0);
+    let config = sysfs.config(UNFILTERED);
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (reload_sender, reload_receiver) = bounded(0);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .reload_receiver(reload_receiver)
+                .run()
+        });
+
+        sysfs.wait_for("leds", KBD, 3);
+        assert_eq!(sysfs.brightness("leds", "input3::capslock"), 0);
+
+        // A new LED is driven right away
+        let with_led = format!("{}\n[[led]]\nname = \"input3::capslock\"\n", UNFILTERED);
+        reload_sender.send(sysfs.config(&with_led)).unwrap();
+        sysfs.wait_for("leds", "input3::capslock", 100);
+
+        // A config naming a missing LED leaves the running one in place
+        let missing = format!("{}\n[[led]]\nname = \"missing\"\n", UNFILTERED);
+        reload_sender.send(sysfs.config(&missing)).unwrap();
+
+        // As does one failing after its filter is set up, which keeps the
+        // running, unsmoothed filter
+        let invalid = "[filter]\ntype = \"wma\"\nwindow = 10\n\
+                       [darkness]\nbelow = 9.0\nabove = 3.0\n";
+        reload_sender.send(sysfs.config(invalid)).unwrap();
+
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("leds", KBD, 0);
+        sysfs.wait_for("leds", "input3::capslock", 0);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_reads_at_the_configured_interval() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!("interval = 20\n{}", UNFILTERED));
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .run()
+        });
+        sysfs.wait_for("leds", KBD, 3);
+
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("leds", KBD), 3);
+
+        clock.advance(Duration::from_secs(15));
+        sysfs.wait_for("leds", KBD, 0);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_crossfades_to_a_new_profile() {
+    let sysfs = FakeSysfs::new();
+    sysfs.add_device("leds", "input3::capslock", 100, 0);
+    let curve = |pct| {
+        format!(
+            "crossfade = 10\n{}\n[screen]\ncurve = [[0, {}]]\n",
+            UNFILTERED, pct
+        )
+    };
+    let config = sysfs.config(&curve(50));
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (reload_sender, reload_receiver) = bounded(0);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .reload_receiver(reload_receiver)
+                .run()
+        });
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        // New outputs have nothing to fade from
+        let with_led = format!("{}\n[[led]]\nname = \"input3::capslock\"\n", curve(10));
+        reload_sender.send(sysfs.config(&with_led)).unwrap();
+        sysfs.wait_for("leds", "input3::capslock", 100);
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+
+        // Halfway through the fade, then at the new curve
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("backlight", SCREEN, 300);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("backlight", SCREEN, 100);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_picks_up_hotplugged_screen() {
+    let sysfs = FakeSysfs::new();
+    sysfs.remove_device("backlight", SCREEN);
+    let config = sysfs.config(UNFILTERED);
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (hotplug_sender, hotplug_receiver) = bounded(0);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .hotplug_receiver(hotplug_receiver)
+                .run()
+        });
+
+        sysfs.wait_for("leds", KBD, 3);
+
+        sysfs.add_device("backlight", SCREEN, 1000, 500);
+        hotplug_sender.send(()).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_reports_stuck_sensor() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!(
+        "[watchdog]\nsensor_stuck_after = 10\n{}",
+        UNFILTERED
+    ));
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let health = Arc::new(Health::new(clock.clone()));
+    let (close_sender, close_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .health(health.clone())
+                .close_receiver(close_receiver)
+                .run()
+        });
+
+        sysfs.wait_for("leds", KBD, 3);
+        assert!(!health.status().sensor_stuck);
+
+        clock.advance(Duration::from_secs(15));
+        for _ in 0..500 {
+            if health.status().sensor_stuck {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(health.status().sensor_stuck);
+
+        // Any change in the reading clears it
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("leds", KBD, 0);
+        assert!(!health.status().sensor_stuck);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_follows_sensor_commands_and_restores() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+
+        // The first update happens right away
+        sysfs.wait_for("leds", KBD, 3);
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // Nothing changes until the next tick
+        sensor.set(BRIGHT);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("leds", KBD), 3);
+
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("leds", KBD, 0);
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        // Idle quarters the ambient value
+        command_sender.send(Command::Idle).unwrap();
+        sysfs.wait_for("leds", KBD, 3);
+        sysfs.wait_for("backlight", SCREEN, 200);
+
+        command_sender.send(Command::Active).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        command_sender.send(Command::Decrease(10)).unwrap();
+        end_burst(&clock);
+        sysfs.wait_for("backlight", SCREEN, 400);
+
+        command_sender.send(Command::Increase(20)).unwrap();
+        end_burst(&clock);
+        sysfs.wait_for("backlight", SCREEN, 600);
+
+        command_sender.send(Command::Set(30)).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 300);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+
+    // The keyboard goes back to where it started
+    assert_eq!(sysfs.brightness("leds", KBD), 1);
+}
+
+#[test]
+fn run_reports_outputs_it_couldnt_restore() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let sensor = ScriptedSensor::new(DARK);
+    let (close_sender, close_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .close_receiver(close_receiver)
+                .run()
+        });
+
+        sysfs.wait_for("leds", KBD, 3);
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // The keyboard's level can't be read back by the time it's put back
+        fs::write(
+            sysfs.root().join("class/leds").join(KBD).join("brightness"),
+            "gone\n",
+        )
+        .unwrap();
+        close_sender.send(()).unwrap();
+        assert!(handle.join().unwrap().is_err());
+    });
+}
+
+#[test]
+fn run_restores_snapshots() {
+    let sysfs = FakeSysfs::new();
+    let state = sysfs.root().join("state/state.toml");
+    let config = sysfs.config(&format!("state = {:?}\n{}", state, UNFILTERED));
+    let sensor = ScriptedSensor::new(BRIGHT);
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(Arc::new(MockClock::new()))
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        command_sender.send(Command::Set(80)).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 800);
+        command_sender
+            .send(Command::Snapshot("talk".to_string()))
+            .unwrap();
+        command_sender.send(Command::Set(20)).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 200);
+
+        command_sender
+            .send(Command::Restore("talk".to_string()))
+            .unwrap();
+        sysfs.wait_for("backlight", SCREEN, 800);
+        // Unknown snapshots leave everything as it is
+        command_sender
+            .send(Command::Restore("other".to_string()))
+            .unwrap();
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+
+    let saved = fs::read_to_string(&state).unwrap();
+    assert!(saved.contains("[snapshots.talk]"), "{}", saved);
+    assert_eq!(sysfs.brightness("backlight", SCREEN), 800);
+}
+
+#[test]
+fn run_resyncs_the_filter() {
+    let sysfs = FakeSysfs::new();
+    // Slow enough that only a resync gets to the bright levels at once
+    let config = sysfs.config("resync_above = 60\n[filter]\ntype = \"wma\"\nwindow = 50\n");
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // A jump past resync_above starts the filter over on its own
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        // Smaller ones take the filter's time, unless resynced by hand
+        sensor.set(1000.0);
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert!(sysfs.brightness("backlight", SCREEN) > 400);
+        command_sender.send(Command::Resync).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 350);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_notifies_readiness() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let mut notified = unsafe { fs::File::from_raw_fd(fds[0]) };
+    let (close_sender, close_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(ScriptedSensor::new(DARK)))
+                .clock(Arc::new(MockClock::new()))
+                .close_receiver(close_receiver)
+                .ready_fd(fds[1])
+                .run()
+        });
+
+        // A newline, then the daemon's end is closed
+        let mut ready = String::new();
+        notified.read_to_string(&mut ready).unwrap();
+        assert_eq!(ready, "\n");
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_leaves_disabled_outputs_alone() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+
+        sysfs.wait_for("leds", KBD, 3);
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // Commands are handled in order, so the screen moving means the
+        // keyboard was disabled
+        command_sender
+            .send(Command::Disable(OutputKind::Kbd))
+            .unwrap();
+        command_sender.send(Command::Increase(10)).unwrap();
+        end_burst(&clock);
+        sysfs.wait_for("backlight", SCREEN, 150);
+
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("backlight", SCREEN, 600);
+        assert_eq!(sysfs.brightness("leds", KBD), 3);
+
+        command_sender
+            .send(Command::Enable(OutputKind::Kbd))
+            .unwrap();
+        sysfs.wait_for("leds", KBD, 0);
+
+        command_sender
+            .send(Command::Disable(OutputKind::Kbd))
+            .unwrap();
+        command_sender.send(Command::Set(30)).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 300);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+
+    // Whoever took the keyboard over keeps it on shutdown
+    assert_eq!(sysfs.brightness("leds", KBD), 0);
+}
+
+#[test]
+fn run_recovers_from_a_reading_of_zero() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(
+        r#"
+        [filter]
+        type = "wma"
+        window = 3
+        "#,
+    );
+    let sensor = ScriptedSensor::new(0.0);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .run()
+        });
+        sysfs.wait_for("leds", KBD, 3);
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // A pitch-black 0 mustn't leave the filter stuck
+        sensor.set(BRIGHT);
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(50));
+        }
+        sysfs.wait_for("leds", KBD, 0);
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_nudges_one_kind_of_output() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let sensor = ScriptedSensor::new(DARK);
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor))
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+        sysfs.wait_for("leds", KBD, 3);
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // The keyboard moves though it ignores plain offsets
+        command_sender
+            .send(Command::Nudge(OutputKind::Kbd, -34))
+            .unwrap();
+        sysfs.wait_for("leds", KBD, 2);
+        command_sender
+            .send(Command::SetOutput(OutputKind::Screen, 30))
+            .unwrap();
+        sysfs.wait_for("backlight", SCREEN, 300);
+        command_sender.send(Command::Increase(10)).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 400);
+        assert_eq!(sysfs.brightness("leds", KBD), 2);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_tunes_outputs_separately() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!(
+        r#"
+        [kbd]
+        idle_scale = 1.0
+        offsets = true
+
+        [screen.filter]
+        type = "wma"
+        window = 10
+        {}"#,
+        UNFILTERED
+    ));
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // The keyboard jumps to the new light, the smoother screen only starts moving
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("leds", KBD, 0);
+        sysfs.wait_for("backlight", SCREEN, 150);
+
+        // The keyboard ignores idle and follows offsets
+        command_sender.send(Command::Idle).unwrap();
+        command_sender.send(Command::Increase(50)).unwrap();
+        end_burst(&clock);
+        sysfs.wait_for("leds", KBD, 2);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_holds_brightness_while_face_down() {
+    let sysfs = FakeSysfs::new();
+    sysfs.set_accel([0, 0, 980]);
+    let config = sysfs.config(&format!("[orientation]\n{}", UNFILTERED));
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .run()
+        });
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // Face down, the sensor sees the table instead of the room
+        sysfs.set_accel([0, 0, -980]);
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 50);
+
+        sysfs.set_accel([0, 0, 980]);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_suspends_while_idle_or_locked() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!("suspend_after = 60\n{}", UNFILTERED));
+    let sensor = ScriptedSensor::new(BRIGHT);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    // Rendezvous channels, so each send returns once the controller has taken it
+    let (command_sender, command_receiver) = bounded(0);
+    let (lock_sender, lock_receiver) = bounded(0);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .lock_receiver(lock_receiver)
+                .run()
+        });
+        sysfs.wait_for("leds", KBD, 0);
+
+        // Still updating while idle below the threshold
+        command_sender.send(Command::Idle).unwrap();
+        sysfs.wait_for("leds", KBD, 3);
+        sensor.set(DARK);
+        clock.advance(Duration::from_secs(55));
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        // Past the threshold the next tick suspends, and later ones never come
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        sensor.set(BRIGHT);
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(5));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 50);
+
+        // Activity resumes right away
+        command_sender.send(Command::Active).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        // Locking suspends without waiting for the threshold
+        lock_sender.send(true).unwrap();
+        sensor.set(DARK);
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+
+        // Activity doesn't end the lock's hold
+        command_sender.send(Command::Active).unwrap();
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+
+        lock_sender.send(false).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn run_holds_during_screen_capture() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let sensor = ScriptedSensor::new(BRIGHT);
+    let clock = Arc::new(MockClock::new());
+    let (close_sender, close_receiver) = bounded(1);
+    let (lock_sender, lock_receiver) = bounded(0);
+    let (capture_sender, capture_receiver) = bounded(0);
+    let (command_sender, command_receiver) = bounded(0);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .close_receiver(close_receiver)
+                .lock_receiver(lock_receiver)
+                .capture_receiver(capture_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+        sysfs.wait_for("backlight", SCREEN, 500);
+
+        capture_sender.send(true).unwrap();
+        sensor.set(DARK);
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+
+        // Neither activity nor a resync ends the capture's hold
+        command_sender.send(Command::Active).unwrap();
+        command_sender.send(Command::Resync).unwrap();
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+
+        // Locked as capture ends, then unlocking while capturing again: both
+        // hold until neither is left
+        lock_sender.send(true).unwrap();
+        capture_sender.send(false).unwrap();
+        capture_sender.send(true).unwrap();
+        lock_sender.send(false).unwrap();
+        clock.advance(Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sysfs.brightness("backlight", SCREEN), 500);
+
+        capture_sender.send(false).unwrap();
+        sysfs.wait_for("backlight", SCREEN, 50);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+}
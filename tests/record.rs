@@ -0,0 +1,167 @@
+#![cfg(all(feature = "kbd", feature = "screen"))]
+
+mod common;
+
+use std::{
+    fs,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use common::{end_burst, FakeSysfs, ScriptedSensor, BRIGHT, DARK, KBD, SCREEN, UNFILTERED};
+use crossbeam::channel::bounded;
+use iio_ambient_brightness::{
+    clock::MockClock,
+    command::Command,
+    controller::Builder,
+    record::{self, Event, Recorder},
+};
+
+/// Output shared with the test after the replay is done with it
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn event_lines_round_trip() {
+    for line in [
+        "device leds/asus::kbd_backlight 3 1",
+        "sensor 12.5",
+        "tick",
+        "lock",
+        "unlock",
+        "command idle",
+        "command increase 10",
+        "command decrease -5",
+        "write backlight/intel_backlight 500",
+    ] {
+        let event: Event = line.parse().unwrap();
+        assert_eq!(event.to_string(), line);
+    }
+
+    assert!("sensor".parse::<Event>().is_err());
+    assert!("command increase 500".parse::<Event>().is_err());
+    assert!("tick tock".parse::<Event>().is_err());
+}
+
+#[test]
+fn replay_reproduces_recording() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let recording = sysfs.root().join("session.log");
+    let sensor = ScriptedSensor::new(DARK);
+    let clock = Arc::new(MockClock::new());
+    let recorder = Arc::new(Recorder::create(&recording, clock.clone()).unwrap());
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(1);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(sensor.clone()))
+                .clock(clock.clone())
+                .recorder(recorder)
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+
+        sysfs.wait_for("leds", KBD, 3);
+        sensor.set(BRIGHT);
+        clock.advance(Duration::from_secs(5));
+        sysfs.wait_for("leds", KBD, 0);
+
+        clock.advance(Duration::from_millis(1200));
+        command_sender.send(Command::Decrease(10)).unwrap();
+        end_burst(&clock);
+        sysfs.wait_for("backlight", SCREEN, 400);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+
+    let recorded = fs::read_to_string(&recording).unwrap();
+    assert!(recorded.contains("5000 tick\n"));
+    assert!(recorded.contains("6300 command decrease 10\n"));
+    assert!(recorded.contains("6300 write backlight/intel_backlight 400\n"));
+
+    let replayed = Buffer::default();
+    record::replay(&config, &recording, Box::new(replayed.clone())).unwrap();
+    let replayed = String::from_utf8(replayed.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(replayed, recorded);
+}
+
+#[test]
+fn offset_bursts_apply_as_one_command() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(UNFILTERED);
+    let recording = sysfs.root().join("session.log");
+    let clock = Arc::new(MockClock::new());
+    let recorder = Arc::new(Recorder::create(&recording, clock.clone()).unwrap());
+    let (close_sender, close_receiver) = bounded(1);
+    let (command_sender, command_receiver) = bounded(8);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            Builder::new(&config)
+                .sysfs_root(sysfs.root())
+                .sensor(Box::new(ScriptedSensor::new(BRIGHT)))
+                .clock(clock.clone())
+                .recorder(recorder)
+                .close_receiver(close_receiver)
+                .command_receiver(command_receiver)
+                .run()
+        });
+        sysfs.wait_for("leds", KBD, 0);
+
+        // A held key
+        for _ in 0..4 {
+            command_sender.send(Command::Increase(10)).unwrap();
+        }
+        command_sender.send(Command::Decrease(5)).unwrap();
+        end_burst(&clock);
+        sysfs.wait_for("backlight", SCREEN, 850);
+
+        close_sender.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    });
+
+    let recorded = fs::read_to_string(&recording).unwrap();
+    assert!(recorded.contains("command increase 35\n"));
+    assert_eq!(recorded.matches(" command ").count(), 1);
+    assert_eq!(recorded.matches("write backlight/").count(), 1);
+}
+
+#[test]
+fn privacy_mode_leaves_sensor_readings_out() {
+    let sysfs = FakeSysfs::new();
+    let config = sysfs.config(&format!("privacy = true\n{}", UNFILTERED));
+    let recording = sysfs.root().join("session.log");
+    let clock = Arc::new(MockClock::new());
+    let recorder = Arc::new(Recorder::create(&recording, clock.clone()).unwrap());
+
+    Builder::new(&config)
+        .sysfs_root(sysfs.root())
+        .sensor(Box::new(ScriptedSensor::new(DARK)))
+        .clock(clock)
+        .recorder(recorder)
+        .once()
+        .unwrap();
+
+    let recorded = fs::read_to_string(&recording).unwrap();
+    assert!(recorded.contains(&format!("0 write leds/{} 3\n", KBD)));
+    assert!(!recorded.contains("sensor"));
+    assert!(record::replay(&config, &recording, Box::new(io::sink())).is_err());
+}